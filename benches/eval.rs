@@ -0,0 +1,79 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use umo::rt_ctx::RtCtx;
+use umo::testing::MockRtCtx;
+
+const FIB_SOURCE: &str = r#"use lang::"0.0.1";
+// Iterative, as a function cannot refer to itself
+let add = fn(x, y) { x + y };
+let fib = fn(n) {
+  let a = 0;
+  let b = 1;
+  let i = 0;
+  while i < n {
+    let t = add(a, b);
+    a = b;
+    b = t;
+    i = i + 1;
+  };
+  a
+};
+let j = 0;
+while j < 1000 {
+  fib(40);
+  j = j + 1;
+};
+puti(fib(40));
+"#;
+
+const SUM_SOURCE: &str = r#"use lang::"0.0.1";
+let sum = 0;
+let i = 0;
+while i < 100000 {
+  sum = sum + i;
+  i = i + 1;
+};
+puti(sum);
+"#;
+
+const STRINGS_SOURCE: &str = r#"use lang::"0.0.1";
+let s = "the quick brown fox";
+let total = 0;
+let i = 0;
+while i < 10000 {
+  if str_eq(s, "the quick brown fox") {
+    total = total + len(s);
+  };
+  puts(s);
+  i = i + 1;
+};
+puti(total);
+"#;
+
+fn bench_program(c: &mut Criterion, name: &str, source: &str) {
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            let ctx = MockRtCtx::new();
+            umo::run_source(&ctx, source).unwrap();
+        })
+    });
+}
+
+/// Same as `bench_program`, but through a trait object, to compare with the specialized one
+fn bench_program_dyn(c: &mut Criterion, name: &str, source: &str) {
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            let ctx = MockRtCtx::new();
+            umo::run_source(&ctx as &dyn RtCtx, source).unwrap();
+        })
+    });
+}
+
+fn bench_eval(c: &mut Criterion) {
+    bench_program(c, "fib", FIB_SOURCE);
+    bench_program_dyn(c, "fib_dyn", FIB_SOURCE);
+    bench_program(c, "sum", SUM_SOURCE);
+    bench_program(c, "strings", STRINGS_SOURCE);
+}
+
+criterion_group!(benches, bench_eval);
+criterion_main!(benches);
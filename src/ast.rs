@@ -1,11 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 
-use crate::cctx::{CCtx, Id};
+use crate::cctx::{CCtx, Id, Symbol};
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Ident {
-    pub name: String,
+    pub name: Symbol,
     pub id: Id,
 }
 
@@ -18,9 +18,13 @@ impl Ident {
 impl fmt::Debug for Ident {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.id.is_dummy() {
-            f.debug_tuple("Ident::from").field(&self.name).finish()
+            f.debug_tuple("Ident::from")
+                .field(&self.name.as_str())
+                .finish()
         } else {
-            f.debug_tuple("Ident::from").field(&self.name).finish()?;
+            f.debug_tuple("Ident::from")
+                .field(&self.name.as_str())
+                .finish()?;
             f.debug_tuple(".with_id").field(&self.id).finish()?;
             Ok(())
         }
@@ -30,45 +34,146 @@ impl fmt::Debug for Ident {
 impl From<&str> for Ident {
     fn from(name: &str) -> Self {
         Ident {
-            name: name.to_owned(),
+            name: Symbol::from(name),
             id: Id::dummy(),
         }
     }
 }
-impl From<String> for Ident {
-    fn from(name: String) -> Self {
+impl From<Symbol> for Ident {
+    fn from(name: Symbol) -> Self {
         Ident {
             name,
             id: Id::dummy(),
         }
     }
 }
+impl From<String> for Ident {
+    fn from(name: String) -> Self {
+        Ident::from(name.as_str())
+    }
+}
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub enum Stmt {
-    Let { lhs: Ident, init: Expr },
-    Expr { expr: Expr, use_value: bool },
+    Let {
+        lhs: Ident,
+        init: Option<Expr>,
+        /// Comments preceding the statement, kept only when the parser is asked to
+        leading_comments: Vec<String>,
+        /// Comments following the statement on the same line, or left inside it,
+        /// kept only when the parser is asked to
+        trailing_comments: Vec<String>,
+    },
+    Expr {
+        expr: Expr,
+        use_value: bool,
+        /// Comments preceding the statement, kept only when the parser is asked to
+        leading_comments: Vec<String>,
+        /// Comments following the statement on the same line, or left inside it,
+        /// kept only when the parser is asked to
+        trailing_comments: Vec<String>,
+    },
 }
 
 impl Stmt {
     pub fn let_(lhs: Ident, init: Expr) -> Self {
-        Stmt::Let { lhs, init }
+        Stmt::Let {
+            lhs,
+            init: Some(init),
+            leading_comments: vec![],
+            trailing_comments: vec![],
+        }
+    }
+    pub fn let_uninit(lhs: Ident) -> Self {
+        Stmt::Let {
+            lhs,
+            init: None,
+            leading_comments: vec![],
+            trailing_comments: vec![],
+        }
     }
     pub fn expr(expr: Expr, use_value: bool) -> Self {
-        Stmt::Expr { expr, use_value }
+        Stmt::Expr {
+            expr,
+            use_value,
+            leading_comments: vec![],
+            trailing_comments: vec![],
+        }
+    }
+    pub fn with_leading_comments(mut self, comments: Vec<String>) -> Self {
+        match &mut self {
+            Stmt::Let {
+                leading_comments, ..
+            }
+            | Stmt::Expr {
+                leading_comments, ..
+            } => *leading_comments = comments,
+        }
+        self
+    }
+    pub fn leading_comments(&self) -> &[String] {
+        match self {
+            Stmt::Let {
+                leading_comments, ..
+            }
+            | Stmt::Expr {
+                leading_comments, ..
+            } => leading_comments,
+        }
+    }
+    pub fn with_trailing_comments(mut self, comments: Vec<String>) -> Self {
+        match &mut self {
+            Stmt::Let {
+                trailing_comments, ..
+            }
+            | Stmt::Expr {
+                trailing_comments, ..
+            } => *trailing_comments = comments,
+        }
+        self
+    }
+    pub fn trailing_comments(&self) -> &[String] {
+        match self {
+            Stmt::Let {
+                trailing_comments, ..
+            }
+            | Stmt::Expr {
+                trailing_comments, ..
+            } => trailing_comments,
+        }
     }
 }
 
 impl fmt::Debug for Stmt {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Stmt::Let { lhs, init } => f.debug_tuple("Stmt:let_").field(lhs).field(init).finish(),
-            Stmt::Expr { expr, use_value } => f
+            Stmt::Let {
+                lhs,
+                init: Some(init),
+                ..
+            } => f.debug_tuple("Stmt:let_").field(lhs).field(init).finish()?,
+            Stmt::Let {
+                lhs, init: None, ..
+            } => f.debug_tuple("Stmt::let_uninit").field(lhs).finish()?,
+            Stmt::Expr {
+                expr, use_value, ..
+            } => f
                 .debug_tuple("Stmt::expr")
                 .field(expr)
                 .field(use_value)
-                .finish(),
+                .finish()?,
         }
+        if !self.leading_comments().is_empty() {
+            f.debug_tuple(".with_leading_comments")
+                .field(&self.leading_comments())
+                .finish()?;
+        }
+        if !self.trailing_comments().is_empty() {
+            f.debug_tuple(".with_trailing_comments")
+                .field(&self.trailing_comments())
+                .finish()?;
+        }
+        Ok(())
     }
 }
 
@@ -80,7 +185,15 @@ pub enum Expr {
     Branch {
         cond: Box<Expr>,
         then: Box<Expr>,
-        else_: Box<Expr>,
+        else_: Option<Box<Expr>>,
+    },
+    /// `if some(binder) = scrutinee { then } else { else_ }`;
+    /// the binder is only in scope in `then`
+    IfSome {
+        binder: Ident,
+        scrutinee: Box<Expr>,
+        then: Box<Expr>,
+        else_: Option<Box<Expr>>,
     },
     While {
         cond: Box<Expr>,
@@ -88,6 +201,8 @@ pub enum Expr {
     },
     Block {
         stmts: Vec<Stmt>,
+        /// Comments after the last statement, kept only when the parser is asked to
+        trailing_comments: Vec<String>,
     },
     Assign {
         lhs: Ident,
@@ -104,11 +219,51 @@ pub enum Expr {
     StringLiteral {
         value: String,
     },
+    BooleanLiteral {
+        value: bool,
+    },
     BinOp {
         op: BinOp,
         lhs: Box<Expr>,
         rhs: Box<Expr>,
     },
+    Lambda {
+        params: Vec<Ident>,
+        body: Box<Expr>,
+    },
+    ArrayLiteral {
+        elems: Vec<Expr>,
+    },
+    Index {
+        base: Box<Expr>,
+        index: Box<Expr>,
+    },
+    /// `start..end`; only appears as the index of `Expr::Index`
+    Range {
+        start: Box<Expr>,
+        end: Box<Expr>,
+    },
+    /// `try { body }`; yields `some(value)` on success and `none()` on runtime error
+    Try {
+        body: Box<Expr>,
+    },
+    /// `return value`; exits the innermost function, and is rejected directly in a `try` body
+    Return {
+        value: Box<Expr>,
+    },
+    /// `loop { body }`; repeats the body until a `break`
+    Loop {
+        body: Box<Expr>,
+    },
+    /// `break value`; exits the innermost `loop`, which evaluates to `value`
+    Break {
+        value: Box<Expr>,
+    },
+    /// An expression preceded by comments, kept only when the parser is asked to
+    Commented {
+        comments: Vec<String>,
+        expr: Box<Expr>,
+    },
 }
 
 impl Expr {
@@ -119,7 +274,30 @@ impl Expr {
         Expr::Branch {
             cond: Box::new(cond),
             then: Box::new(then),
-            else_: Box::new(else_),
+            else_: Some(Box::new(else_)),
+        }
+    }
+    pub fn branch_without_else(cond: Expr, then: Expr) -> Self {
+        Expr::Branch {
+            cond: Box::new(cond),
+            then: Box::new(then),
+            else_: None,
+        }
+    }
+    pub fn if_some(binder: Ident, scrutinee: Expr, then: Expr, else_: Expr) -> Self {
+        Expr::IfSome {
+            binder,
+            scrutinee: Box::new(scrutinee),
+            then: Box::new(then),
+            else_: Some(Box::new(else_)),
+        }
+    }
+    pub fn if_some_without_else(binder: Ident, scrutinee: Expr, then: Expr) -> Self {
+        Expr::IfSome {
+            binder,
+            scrutinee: Box::new(scrutinee),
+            then: Box::new(then),
+            else_: None,
         }
     }
     pub fn while_(cond: Expr, body: Expr) -> Self {
@@ -129,7 +307,10 @@ impl Expr {
         }
     }
     pub fn block(stmts: Vec<Stmt>) -> Self {
-        Expr::Block { stmts }
+        Expr::Block {
+            stmts,
+            trailing_comments: vec![],
+        }
     }
     pub fn assign(lhs: Ident, rhs: Expr) -> Self {
         Expr::Assign {
@@ -149,6 +330,9 @@ impl Expr {
     pub fn string_literal(value: String) -> Self {
         Expr::StringLiteral { value }
     }
+    pub fn boolean_literal(value: bool) -> Self {
+        Expr::BooleanLiteral { value }
+    }
     pub fn bin_op(op: BinOp, lhs: Expr, rhs: Expr) -> Self {
         Expr::BinOp {
             op,
@@ -156,24 +340,118 @@ impl Expr {
             rhs: Box::new(rhs),
         }
     }
+    pub fn lambda(params: Vec<Ident>, body: Expr) -> Self {
+        Expr::Lambda {
+            params,
+            body: Box::new(body),
+        }
+    }
+    pub fn array_literal(elems: Vec<Expr>) -> Self {
+        Expr::ArrayLiteral { elems }
+    }
+    pub fn index(base: Expr, index: Expr) -> Self {
+        Expr::Index {
+            base: Box::new(base),
+            index: Box::new(index),
+        }
+    }
+    pub fn range(start: Expr, end: Expr) -> Self {
+        Expr::Range {
+            start: Box::new(start),
+            end: Box::new(end),
+        }
+    }
+    pub fn try_(body: Expr) -> Self {
+        Expr::Try {
+            body: Box::new(body),
+        }
+    }
+    pub fn return_(value: Expr) -> Self {
+        Expr::Return {
+            value: Box::new(value),
+        }
+    }
+    pub fn loop_(body: Expr) -> Self {
+        Expr::Loop {
+            body: Box::new(body),
+        }
+    }
+    pub fn break_(value: Expr) -> Self {
+        Expr::Break {
+            value: Box::new(value),
+        }
+    }
+    pub fn commented(comments: Vec<String>, expr: Expr) -> Self {
+        Expr::Commented {
+            comments,
+            expr: Box::new(expr),
+        }
+    }
 }
 
 impl fmt::Debug for Expr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Expr::Var { ident } => f.debug_tuple("Expr::var").field(ident).finish(),
-            Expr::Branch { cond, then, else_ } => f
+            Expr::Branch {
+                cond,
+                then,
+                else_: Some(else_),
+            } => f
                 .debug_tuple("Expr::branch")
                 .field(cond)
                 .field(then)
                 .field(else_)
                 .finish(),
+            Expr::Branch {
+                cond,
+                then,
+                else_: None,
+            } => f
+                .debug_tuple("Expr::branch_without_else")
+                .field(cond)
+                .field(then)
+                .finish(),
+            Expr::IfSome {
+                binder,
+                scrutinee,
+                then,
+                else_: Some(else_),
+            } => f
+                .debug_tuple("Expr::if_some")
+                .field(binder)
+                .field(scrutinee)
+                .field(then)
+                .field(else_)
+                .finish(),
+            Expr::IfSome {
+                binder,
+                scrutinee,
+                then,
+                else_: None,
+            } => f
+                .debug_tuple("Expr::if_some_without_else")
+                .field(binder)
+                .field(scrutinee)
+                .field(then)
+                .finish(),
             Expr::While { cond, body } => f
                 .debug_tuple("Expr::while_")
                 .field(cond)
                 .field(body)
                 .finish(),
-            Expr::Block { stmts } => f.debug_tuple("Expr::block").field(stmts).finish(),
+            Expr::Block {
+                stmts,
+                trailing_comments,
+            } => {
+                f.debug_tuple("Expr::block").field(stmts).finish()?;
+                if !trailing_comments.is_empty() {
+                    f.debug_tuple(".with_trailing_comments")
+                        .field(trailing_comments)
+                        .finish()?;
+                }
+                Ok(())
+            }
             Expr::Assign { lhs, rhs } => {
                 f.debug_tuple("Expr::assign").field(lhs).field(rhs).finish()
             }
@@ -188,20 +466,167 @@ impl fmt::Debug for Expr {
             Expr::StringLiteral { value } => {
                 f.debug_tuple("Expr::string_literal").field(value).finish()
             }
+            Expr::BooleanLiteral { value } => {
+                f.debug_tuple("Expr::boolean_literal").field(value).finish()
+            }
             Expr::BinOp { op, lhs, rhs } => f
                 .debug_tuple("Expr::bin_op")
                 .field(op)
                 .field(lhs)
                 .field(rhs)
                 .finish(),
+            Expr::Lambda { params, body } => f
+                .debug_tuple("Expr::lambda")
+                .field(params)
+                .field(body)
+                .finish(),
+            Expr::ArrayLiteral { elems } => {
+                f.debug_tuple("Expr::array_literal").field(elems).finish()
+            }
+            Expr::Index { base, index } => f
+                .debug_tuple("Expr::index")
+                .field(base)
+                .field(index)
+                .finish(),
+            Expr::Range { start, end } => f
+                .debug_tuple("Expr::range")
+                .field(start)
+                .field(end)
+                .finish(),
+            Expr::Try { body } => f.debug_tuple("Expr::try_").field(body).finish(),
+            Expr::Return { value } => f.debug_tuple("Expr::return_").field(value).finish(),
+            Expr::Loop { body } => f.debug_tuple("Expr::loop_").field(body).finish(),
+            Expr::Break { value } => f.debug_tuple("Expr::break_").field(value).finish(),
+            Expr::Commented { comments, expr } => f
+                .debug_tuple("Expr::commented")
+                .field(comments)
+                .field(expr)
+                .finish(),
+        }
+    }
+}
+
+/// Read-only traversal over the AST.
+///
+/// The default methods descend into every child; override them to act on particular nodes,
+/// calling `walk_stmt`/`walk_expr` to continue the traversal.
+pub trait Visitor {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(self, stmt);
+    }
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+    /// A variable being declared: `let` bindings and lambda parameters
+    fn visit_decl(&mut self, _ident: &Ident) {}
+    /// A reference to a variable: variable expressions and assignment targets
+    fn visit_ref(&mut self, _ident: &Ident) {}
+}
+
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, stmt: &Stmt) {
+    match stmt {
+        Stmt::Let { lhs, init, .. } => {
+            if let Some(init) = init {
+                visitor.visit_expr(init);
+            }
+            visitor.visit_decl(lhs);
         }
+        Stmt::Expr { expr, .. } => visitor.visit_expr(expr),
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::Var { ident } => visitor.visit_ref(ident),
+        Expr::Branch { cond, then, else_ } => {
+            visitor.visit_expr(cond);
+            visitor.visit_expr(then);
+            if let Some(else_) = else_ {
+                visitor.visit_expr(else_);
+            }
+        }
+        Expr::IfSome {
+            binder,
+            scrutinee,
+            then,
+            else_,
+        } => {
+            visitor.visit_expr(scrutinee);
+            visitor.visit_decl(binder);
+            visitor.visit_expr(then);
+            if let Some(else_) = else_ {
+                visitor.visit_expr(else_);
+            }
+        }
+        Expr::While { cond, body } => {
+            visitor.visit_expr(cond);
+            visitor.visit_expr(body);
+        }
+        Expr::Block { stmts, .. } => {
+            for stmt in stmts {
+                visitor.visit_stmt(stmt);
+            }
+        }
+        Expr::Assign { lhs, rhs } => {
+            visitor.visit_expr(rhs);
+            visitor.visit_ref(lhs);
+        }
+        Expr::Call { callee, args } => {
+            visitor.visit_expr(callee);
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        }
+        Expr::IntegerLiteral { value: _ } => {}
+        Expr::StringLiteral { value: _ } => {}
+        Expr::BooleanLiteral { value: _ } => {}
+        Expr::BinOp { op: _, lhs, rhs } => {
+            visitor.visit_expr(lhs);
+            visitor.visit_expr(rhs);
+        }
+        Expr::Lambda { params, body } => {
+            for param in params {
+                visitor.visit_decl(param);
+            }
+            visitor.visit_expr(body);
+        }
+        Expr::ArrayLiteral { elems } => {
+            for elem in elems {
+                visitor.visit_expr(elem);
+            }
+        }
+        Expr::Index { base, index } => {
+            visitor.visit_expr(base);
+            visitor.visit_expr(index);
+        }
+        Expr::Range { start, end } => {
+            visitor.visit_expr(start);
+            visitor.visit_expr(end);
+        }
+        Expr::Try { body } => visitor.visit_expr(body),
+        Expr::Return { value } => visitor.visit_expr(value),
+        Expr::Loop { body } => visitor.visit_expr(body),
+        Expr::Break { value } => visitor.visit_expr(value),
+        Expr::Commented { expr, .. } => visitor.visit_expr(expr),
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum BinOp {
     Add,
+    Sub,
+    Mul,
+    Div,
     Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    /// `&&`, which evaluates the right-hand side only if the left-hand side is `true`
+    And,
+    /// `||`, which evaluates the right-hand side only if the left-hand side is `false`
+    Or,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
@@ -226,6 +651,20 @@ impl BuiltinIds {
 pub enum BuiltinKind {
     Puts,
     Puti,
+    StrEq,
+    Panic,
+    Len,
+    PanicWithInt,
+    Gets,
+    OptionNone,
+    OptionSome,
+    IsSome,
+    Unwrap,
+    CharAt,
+    TermWidth,
+    Args,
+    Putc,
+    Repeat,
 }
 
 impl BuiltinKind {
@@ -233,18 +672,158 @@ impl BuiltinKind {
         match self {
             BuiltinKind::Puts => "puts",
             BuiltinKind::Puti => "puti",
+            BuiltinKind::StrEq => "str_eq",
+            BuiltinKind::Panic => "panic",
+            BuiltinKind::Len => "len",
+            BuiltinKind::PanicWithInt => "panic_with_int",
+            BuiltinKind::Gets => "gets",
+            BuiltinKind::OptionNone => "none",
+            BuiltinKind::OptionSome => "some",
+            BuiltinKind::IsSome => "is_some",
+            BuiltinKind::Unwrap => "unwrap",
+            BuiltinKind::CharAt => "char_at",
+            BuiltinKind::TermWidth => "term_width",
+            BuiltinKind::Args => "args",
+            BuiltinKind::Putc => "putc",
+            BuiltinKind::Repeat => "repeat",
         }
     }
+    /// All the builtins, in the canonical order used for id assignment.
     fn iter() -> impl Iterator<Item = Self> {
-        static BUILTIN_KINDS: &[BuiltinKind] = &[BuiltinKind::Puts, BuiltinKind::Puti];
+        static BUILTIN_KINDS: &[BuiltinKind] = &[
+            BuiltinKind::Puts,
+            BuiltinKind::Puti,
+            BuiltinKind::StrEq,
+            BuiltinKind::Panic,
+            BuiltinKind::Len,
+            BuiltinKind::PanicWithInt,
+            BuiltinKind::Gets,
+            BuiltinKind::OptionNone,
+            BuiltinKind::OptionSome,
+            BuiltinKind::IsSome,
+            BuiltinKind::Unwrap,
+            BuiltinKind::CharAt,
+            BuiltinKind::TermWidth,
+            BuiltinKind::Args,
+            BuiltinKind::Putc,
+            BuiltinKind::Repeat,
+        ];
         BUILTIN_KINDS.iter().copied()
     }
 }
 
+/// A non-fatal diagnostic found once ids are assigned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    ShadowsBuiltin { name: Symbol },
+    UnusedVariable { name: Symbol },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::ShadowsBuiltin { name } => write!(f, "`{}` shadows builtin", name),
+            Warning::UnusedVariable { name } => write!(f, "unused variable `{}`", name),
+        }
+    }
+}
+
+/// Errors in resolving the names, which fail the program after all names are resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeError {
+    UndefinedVariable {
+        name: Symbol,
+    },
+    /// The `try` body runs as a closure, which would only change its own copy
+    AssignToCapturedInTry {
+        name: Symbol,
+    },
+    /// Lambdas capture by value, so this would only change the closure's copy
+    AssignToCapturedInLambda {
+        name: Symbol,
+    },
+    /// `return` would only leave the closure running the `try` body
+    ReturnInTry,
+}
+
+impl fmt::Display for ScopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScopeError::UndefinedVariable { name } => write!(f, "undefined variable `{}`", name),
+            ScopeError::AssignToCapturedInTry { name } => write!(
+                f,
+                "cannot assign to `{}` inside a `try` body that does not declare it",
+                name
+            ),
+            ScopeError::AssignToCapturedInLambda { name } => write!(
+                f,
+                "cannot assign to `{}` inside a function that does not declare it",
+                name
+            ),
+            ScopeError::ReturnInTry => write!(f, "cannot `return` from inside a `try` body"),
+        }
+    }
+}
+
+impl std::error::Error for ScopeError {}
+
+/// Warns about the `let` bindings in the statements that are never read, in order.
+///
+/// Assigning to a variable does not count as reading it.
+/// Names starting with `_` are exempt.
+pub fn unused_bindings(stmts: &[Stmt]) -> Vec<Warning> {
+    #[derive(Default)]
+    struct UseCounter {
+        lets: Vec<Ident>,
+        reads: HashSet<Id>,
+    }
+    impl Visitor for UseCounter {
+        fn visit_stmt(&mut self, stmt: &Stmt) {
+            if let Stmt::Let { lhs, .. } = stmt {
+                self.lets.push(lhs.clone());
+            }
+            walk_stmt(self, stmt);
+        }
+        fn visit_expr(&mut self, expr: &Expr) {
+            match expr {
+                Expr::Assign { rhs, .. } => self.visit_expr(rhs),
+                _ => walk_expr(self, expr),
+            }
+        }
+        fn visit_ref(&mut self, ident: &Ident) {
+            self.reads.insert(ident.id);
+        }
+    }
+    let mut counter = UseCounter::default();
+    for stmt in stmts {
+        counter.visit_stmt(stmt);
+    }
+    counter
+        .lets
+        .into_iter()
+        .filter(|lhs| !counter.reads.contains(&lhs.id) && !lhs.name.as_str().starts_with('_'))
+        .map(|lhs| Warning::UnusedVariable { name: lhs.name })
+        .collect()
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Scope {
-    bindings: HashMap<String, Id>,
-    binding_stack: Vec<(String, Option<Id>)>,
+    bindings: HashMap<Symbol, Id>,
+    binding_stack: Vec<(Symbol, Option<Id>)>,
+    builtins: HashSet<Id>,
+    warnings: Vec<Warning>,
+    errors: Vec<ScopeError>,
+    /// Function bodies around the current position, innermost last,
+    /// each of which captures the variables from outside by value
+    closures: Vec<ClosureKind>,
+    /// Number of `closures` at the declaration of each variable
+    decl_depths: HashMap<Id, usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClosureKind {
+    Lambda,
+    Try,
 }
 
 impl Scope {
@@ -252,15 +831,46 @@ impl Scope {
         let mut scope = Scope {
             bindings: HashMap::default(),
             binding_stack: vec![],
+            builtins: builtin_ids.builtins.keys().copied().collect(),
+            warnings: vec![],
+            errors: vec![],
+            closures: vec![],
+            decl_depths: HashMap::default(),
         };
-        for (builtin_id, builtin_kind) in &builtin_ids.builtins {
-            scope.insert(builtin_kind.name(), *builtin_id);
+        // Iterate in the canonical order rather than the HashMap order
+        for builtin_kind in BuiltinKind::iter() {
+            scope.insert(
+                Symbol::from(builtin_kind.name()),
+                builtin_ids.ids[&builtin_kind],
+            );
         }
         scope
     }
-    fn insert(&mut self, name: &str, id: Id) {
+    fn insert(&mut self, name: Symbol, id: Id) {
+        self.decl_depths.insert(id, self.closures.len());
         self.binding_stack
-            .push((name.to_owned(), self.bindings.insert(name.to_owned(), id)));
+            .push((name.clone(), self.bindings.insert(name, id)));
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn errors(&self) -> &[ScopeError] {
+        &self.errors
+    }
+
+    /// Resolves the name, recording an error if it is not bound.
+    fn lookup(&mut self, ident: &mut Ident) -> bool {
+        if let Some(&found_id) = self.bindings.get(&ident.name) {
+            ident.id = found_id;
+            true
+        } else {
+            self.errors.push(ScopeError::UndefinedVariable {
+                name: ident.name.clone(),
+            });
+            false
+        }
     }
 
     fn checkpoint(&self) -> usize {
@@ -288,10 +898,19 @@ pub fn assign_id_stmts(cctx: &CCtx, scope: &mut Scope, stmts: &mut Vec<Stmt>) {
 
 pub fn assign_id_stmt(cctx: &CCtx, scope: &mut Scope, stmt: &mut Stmt) {
     match stmt {
-        Stmt::Let { lhs, init } => {
-            assign_id_expr(cctx, scope, init);
+        Stmt::Let { lhs, init, .. } => {
+            if let Some(init) = init {
+                assign_id_expr(cctx, scope, init);
+            }
+            if let Some(old_id) = scope.bindings.get(&lhs.name) {
+                if scope.builtins.contains(old_id) {
+                    scope.warnings.push(Warning::ShadowsBuiltin {
+                        name: lhs.name.clone(),
+                    });
+                }
+            }
             lhs.id = cctx.id_gen.fresh();
-            scope.insert(&lhs.name, lhs.id);
+            scope.insert(lhs.name.clone(), lhs.id);
         }
         Stmt::Expr { expr, .. } => {
             assign_id_expr(cctx, scope, expr);
@@ -302,32 +921,52 @@ pub fn assign_id_stmt(cctx: &CCtx, scope: &mut Scope, stmt: &mut Stmt) {
 pub fn assign_id_expr(cctx: &CCtx, scope: &mut Scope, expr: &mut Expr) {
     match expr {
         Expr::Var { ident } => {
-            if let Some(&found_id) = scope.bindings.get(&ident.name) {
-                ident.id = found_id;
-            } else {
-                // TODO: better error handling
-                panic!("undefined variable: {}", ident.name);
-            }
+            scope.lookup(ident);
         }
         Expr::Branch { cond, then, else_ } => {
             assign_id_expr(cctx, scope, cond);
             assign_id_expr(cctx, scope, then);
-            assign_id_expr(cctx, scope, else_);
+            if let Some(else_) = else_ {
+                assign_id_expr(cctx, scope, else_);
+            }
+        }
+        Expr::IfSome {
+            binder,
+            scrutinee,
+            then,
+            else_,
+        } => {
+            assign_id_expr(cctx, scope, scrutinee);
+            let checkpoint = scope.checkpoint();
+            binder.id = cctx.id_gen.fresh();
+            scope.insert(binder.name.clone(), binder.id);
+            assign_id_expr(cctx, scope, then);
+            scope.rollback(checkpoint);
+            if let Some(else_) = else_ {
+                assign_id_expr(cctx, scope, else_);
+            }
         }
         Expr::While { cond, body } => {
             assign_id_expr(cctx, scope, cond);
             assign_id_expr(cctx, scope, body);
         }
-        Expr::Block { stmts } => {
+        Expr::Block { stmts, .. } => {
             assign_id_stmts(cctx, scope, stmts);
         }
         Expr::Assign { lhs, rhs } => {
             assign_id_expr(cctx, scope, rhs);
-            if let Some(&found_id) = scope.bindings.get(&lhs.name) {
-                lhs.id = found_id;
-            } else {
-                // TODO: better error handling
-                panic!("undefined variable: {}", lhs.name);
+            if scope.lookup(lhs) {
+                // The outermost closure not declaring the variable captures it
+                let name = lhs.name.clone();
+                match scope.closures.get(scope.decl_depths[&lhs.id]) {
+                    Some(ClosureKind::Lambda) => scope
+                        .errors
+                        .push(ScopeError::AssignToCapturedInLambda { name }),
+                    Some(ClosureKind::Try) => scope
+                        .errors
+                        .push(ScopeError::AssignToCapturedInTry { name }),
+                    None => {}
+                }
             }
         }
         Expr::Call { callee, args } => {
@@ -338,10 +977,55 @@ pub fn assign_id_expr(cctx: &CCtx, scope: &mut Scope, expr: &mut Expr) {
         }
         Expr::IntegerLiteral { .. } => {}
         Expr::StringLiteral { .. } => {}
+        Expr::BooleanLiteral { .. } => {}
         Expr::BinOp { op: _, lhs, rhs } => {
             assign_id_expr(cctx, scope, lhs);
             assign_id_expr(cctx, scope, rhs);
         }
+        Expr::Lambda { params, body } => {
+            let checkpoint = scope.checkpoint();
+            scope.closures.push(ClosureKind::Lambda);
+            for param in params {
+                param.id = cctx.id_gen.fresh();
+                scope.insert(param.name.clone(), param.id);
+            }
+            assign_id_expr(cctx, scope, body);
+            scope.closures.pop();
+            scope.rollback(checkpoint);
+        }
+        Expr::ArrayLiteral { elems } => {
+            for elem in elems {
+                assign_id_expr(cctx, scope, elem);
+            }
+        }
+        Expr::Index { base, index } => {
+            assign_id_expr(cctx, scope, base);
+            assign_id_expr(cctx, scope, index);
+        }
+        Expr::Range { start, end } => {
+            assign_id_expr(cctx, scope, start);
+            assign_id_expr(cctx, scope, end);
+        }
+        Expr::Try { body } => {
+            scope.closures.push(ClosureKind::Try);
+            assign_id_expr(cctx, scope, body);
+            scope.closures.pop();
+        }
+        Expr::Return { value } => {
+            assign_id_expr(cctx, scope, value);
+            if scope.closures.last() == Some(&ClosureKind::Try) {
+                scope.errors.push(ScopeError::ReturnInTry);
+            }
+        }
+        Expr::Loop { body } => {
+            assign_id_expr(cctx, scope, body);
+        }
+        Expr::Break { value } => {
+            assign_id_expr(cctx, scope, value);
+        }
+        Expr::Commented { expr, .. } => {
+            assign_id_expr(cctx, scope, expr);
+        }
     }
 }
 
@@ -351,24 +1035,19 @@ pub mod testing {
         use super::super::*;
 
         pub fn let_(name: &str, init: Expr) -> Stmt {
-            Stmt::Let {
-                lhs: Ident::from(name),
-                init,
-            }
+            Stmt::let_(Ident::from(name), init)
+        }
+
+        pub fn let_uninit(name: &str) -> Stmt {
+            Stmt::let_uninit(Ident::from(name))
         }
 
         pub fn expr(expr: Expr) -> Stmt {
-            Stmt::Expr {
-                expr,
-                use_value: false,
-            }
+            Stmt::expr(expr, false)
         }
 
         pub fn then_expr(expr: Expr) -> Stmt {
-            Stmt::Expr {
-                expr,
-                use_value: true,
-            }
+            Stmt::expr(expr, true)
         }
     }
     pub mod exprs {
@@ -384,7 +1063,15 @@ pub mod testing {
             Expr::Branch {
                 cond: Box::new(cond),
                 then: Box::new(then),
-                else_: Box::new(else_),
+                else_: Some(Box::new(else_)),
+            }
+        }
+
+        pub fn branch_without_else(cond: Expr, then: Expr) -> Expr {
+            Expr::Branch {
+                cond: Box::new(cond),
+                then: Box::new(then),
+                else_: None,
             }
         }
 
@@ -396,7 +1083,7 @@ pub mod testing {
         }
 
         pub fn block(stmts: Vec<Stmt>) -> Expr {
-            Expr::Block { stmts }
+            Expr::block(stmts)
         }
 
         pub fn assign(name: &str, rhs: Expr) -> Expr {
@@ -438,5 +1125,189 @@ pub mod testing {
                 rhs: Box::new(rhs),
             }
         }
+
+        pub fn lambda(params: &[&str], body: Expr) -> Expr {
+            Expr::Lambda {
+                params: params.iter().map(|&param| Ident::from(param)).collect(),
+                body: Box::new(body),
+            }
+        }
+
+        pub fn return_(value: Expr) -> Expr {
+            Expr::Return {
+                value: Box::new(value),
+            }
+        }
+
+        pub fn loop_(body: Expr) -> Expr {
+            Expr::Loop {
+                body: Box::new(body),
+            }
+        }
+
+        pub fn break_(value: Expr) -> Expr {
+            Expr::Break {
+                value: Box::new(value),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_ids_stable() {
+        let builtin_ids1 = BuiltinIds::new(&CCtx::new());
+        let builtin_ids2 = BuiltinIds::new(&CCtx::new());
+        assert_eq!(builtin_ids1, builtin_ids2);
+        let scope1 = Scope::new(&builtin_ids1);
+        let scope2 = Scope::new(&builtin_ids2);
+        assert_eq!(scope1.binding_stack, scope2.binding_stack);
+        let names = scope1
+            .binding_stack
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "puts",
+                "puti",
+                "str_eq",
+                "panic",
+                "len",
+                "panic_with_int",
+                "gets",
+                "none",
+                "some",
+                "is_some",
+                "unwrap",
+                "char_at",
+                "term_width",
+                "args",
+                "putc",
+                "repeat",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assign_id_shadows_builtin() {
+        let cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let mut scope = Scope::new(&builtin_ids);
+        // let puti = 1; let puti = puti; let x = 2;
+        let mut stmts = vec![
+            Stmt::let_(Ident::from("puti"), Expr::integer_literal(1)),
+            Stmt::let_(Ident::from("puti"), Expr::var(Ident::from("puti"))),
+            Stmt::let_(Ident::from("x"), Expr::integer_literal(2)),
+        ];
+        assign_id_stmts(&cctx, &mut scope, &mut stmts);
+        // Only the first `let` shadows the builtin itself
+        assert_eq!(
+            scope.warnings(),
+            [Warning::ShadowsBuiltin {
+                name: Symbol::from("puti")
+            }]
+        );
+        assert_eq!(scope.warnings()[0].to_string(), "`puti` shadows builtin");
+        let Stmt::Let { lhs, .. } = &stmts[0] else {
+            unreachable!()
+        };
+        assert_ne!(lhs.id, builtin_ids.ids[&BuiltinKind::Puti]);
+    }
+
+    #[test]
+    fn test_assign_id_undefined_variable() {
+        let cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let mut scope = Scope::new(&builtin_ids);
+        // let x = y; z = x;
+        let mut stmts = vec![
+            Stmt::let_(Ident::from("x"), Expr::var(Ident::from("y"))),
+            Stmt::expr(
+                Expr::assign(Ident::from("z"), Expr::var(Ident::from("x"))),
+                false,
+            ),
+        ];
+        assign_id_stmts(&cctx, &mut scope, &mut stmts);
+        assert_eq!(
+            scope.errors(),
+            [
+                ScopeError::UndefinedVariable {
+                    name: Symbol::from("y")
+                },
+                ScopeError::UndefinedVariable {
+                    name: Symbol::from("z")
+                },
+            ]
+        );
+        assert_eq!(scope.errors()[0].to_string(), "undefined variable `y`");
+    }
+
+    #[test]
+    fn test_unused_bindings() {
+        let cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let mut scope = Scope::new(&builtin_ids);
+        // let x = 1; let y = 2; let _z = 3; let w = 4; w = x;
+        let mut stmts = vec![
+            Stmt::let_(Ident::from("x"), Expr::integer_literal(1)),
+            Stmt::let_(Ident::from("y"), Expr::integer_literal(2)),
+            Stmt::let_(Ident::from("_z"), Expr::integer_literal(3)),
+            Stmt::let_(Ident::from("w"), Expr::integer_literal(4)),
+            Stmt::expr(
+                Expr::assign(Ident::from("w"), Expr::var(Ident::from("x"))),
+                false,
+            ),
+        ];
+        assign_id_stmts(&cctx, &mut scope, &mut stmts);
+        // `w` is only assigned to
+        let warnings = unused_bindings(&stmts);
+        assert_eq!(
+            warnings,
+            [
+                Warning::UnusedVariable {
+                    name: Symbol::from("y")
+                },
+                Warning::UnusedVariable {
+                    name: Symbol::from("w")
+                },
+            ]
+        );
+        assert_eq!(warnings[0].to_string(), "unused variable `y`");
+    }
+
+    #[test]
+    fn test_visitor_count_vars() {
+        struct VarCounter(usize);
+        impl Visitor for VarCounter {
+            fn visit_ref(&mut self, _ident: &Ident) {
+                self.0 += 1;
+            }
+        }
+        // f(x + y, fn(z) { then z; })[x]
+        let expr = Expr::index(
+            Expr::call(
+                Expr::var(Ident::from("f")),
+                vec![
+                    Expr::bin_op(
+                        BinOp::Add,
+                        Expr::var(Ident::from("x")),
+                        Expr::var(Ident::from("y")),
+                    ),
+                    Expr::lambda(
+                        vec![Ident::from("z")],
+                        Expr::block(vec![Stmt::expr(Expr::var(Ident::from("z")), true)]),
+                    ),
+                ],
+            ),
+            Expr::var(Ident::from("x")),
+        );
+        let mut counter = VarCounter(0);
+        counter.visit_expr(&expr);
+        assert_eq!(counter.0, 5);
     }
 }
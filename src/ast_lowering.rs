@@ -1,19 +1,63 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::ast::{BinOp, BuiltinIds, BuiltinKind, Expr, Stmt};
+use crate::ast::{walk_expr, BinOp, BuiltinIds, BuiltinKind, Expr, Ident, Stmt, Visitor};
 use crate::cctx::Id;
 use crate::sir;
 
-pub fn lower(builtin_ids: &BuiltinIds, stmts: &[Stmt]) -> sir::Function {
-    let num_args = 0;
-    let mut num_named_vars = num_args;
-
+/// Lowers the program into the entry function and the lambdas it contains.
+///
+/// The entry function returns the value of the program, which the typechecker
+/// requires to be `()`, and the evaluator discards.
+pub fn lower(builtin_ids: &BuiltinIds, stmts: &[Stmt]) -> sir::ProgramUnit {
+    let mut functions = vec![];
     let mut vars = HashSet::new();
     collect_vars_stmts(stmts, &mut vars);
-    let mut var_ids = vars.into_iter().collect::<Vec<_>>();
-    var_ids.sort_unstable();
+    lower_function(
+        builtin_ids,
+        &mut functions,
+        &[],
+        &[],
+        vars,
+        |fctx, result_var| lower_stmts(fctx, stmts, result_var),
+    );
+    sir::ProgramUnit::new(functions)
+}
 
+/// Lowers a function body into a new entry of `functions` and returns its id.
+///
+/// The first variables of the function are the captured ones, followed by the parameters.
+fn lower_function<F>(
+    builtin_ids: &BuiltinIds,
+    functions: &mut Vec<sir::Function>,
+    captures: &[Id],
+    params: &[Ident],
+    vars: HashSet<Id>,
+    lower_body: F,
+) -> usize
+where
+    F: FnOnce(&mut FunctionContext<'_>, usize),
+{
+    // Reserve the slot first so that the outermost function gets the smallest id
+    let function_id = functions.len();
+    functions.push(sir::Function::new(0, 0, vec![]));
+
+    let num_args = captures.len() + params.len();
     let mut var_id_map = HashMap::new();
+    for (i, &id) in captures
+        .iter()
+        .chain(params.iter().map(|param| &param.id))
+        .enumerate()
+    {
+        debug_assert!(!id.is_dummy());
+        var_id_map.insert(id, i);
+    }
+
+    let mut num_named_vars = num_args;
+    let mut var_ids = vars
+        .into_iter()
+        .filter(|id| !var_id_map.contains_key(id))
+        .collect::<Vec<_>>();
+    var_ids.sort_unstable();
 
     for &id in &var_ids {
         var_id_map.insert(id, num_named_vars);
@@ -24,20 +68,33 @@ pub fn lower(builtin_ids: &BuiltinIds, stmts: &[Stmt]) -> sir::Function {
         sir::Function::new(num_args, num_named_vars, vec![sir::BasicBlock::default()]);
     let mut fctx = FunctionContext {
         builtin_ids,
+        functions,
         function: &mut function,
         var_id_map: &var_id_map,
+        loops: vec![],
     };
     let result_var = fctx.fresh_var();
-    lower_stmts(&mut fctx, stmts, result_var);
+    lower_body(&mut fctx, result_var);
     fctx.push(sir::Inst::return_(result_var));
-    function
+    functions[function_id] = function;
+    function_id
 }
 
 #[derive(Debug)]
 struct FunctionContext<'a> {
     builtin_ids: &'a BuiltinIds,
+    functions: &'a mut Vec<sir::Function>,
     function: &'a mut sir::Function,
     var_id_map: &'a HashMap<Id, usize>,
+    /// Enclosing `loop`s, innermost last
+    loops: Vec<LoopContext>,
+}
+
+#[derive(Debug)]
+struct LoopContext {
+    result_var: usize,
+    /// Blocks ending with a `break`, to be connected to the continuation
+    break_bb_ids: Vec<usize>,
 }
 
 impl FunctionContext<'_> {
@@ -63,6 +120,10 @@ impl FunctionContext<'_> {
 }
 
 fn lower_stmts(fctx: &mut FunctionContext<'_>, stmts: &[Stmt], result_var: usize) {
+    if stmts.is_empty() {
+        // An empty block evaluates to unit
+        fctx.push(sir::Inst::literal(result_var, ()));
+    }
     for (i, stmt) in stmts.iter().enumerate() {
         let is_last = i == stmts.len() - 1;
         let result_var = if is_last { Some(result_var) } else { None };
@@ -72,16 +133,20 @@ fn lower_stmts(fctx: &mut FunctionContext<'_>, stmts: &[Stmt], result_var: usize
 
 fn lower_stmt(fctx: &mut FunctionContext<'_>, stmt: &Stmt, result_var: Option<usize>) {
     match stmt {
-        Stmt::Let { lhs, init } => {
+        Stmt::Let { lhs, init, .. } => {
             debug_assert!(!lhs.id.is_dummy());
 
             let var_id = fctx.var_id_map[&lhs.id];
-            lower_expr(fctx, init, var_id);
+            if let Some(init) = init {
+                lower_expr(fctx, init, var_id);
+            }
             if let Some(result_var) = result_var {
                 fctx.push(sir::Inst::literal(result_var, ()));
             }
         }
-        Stmt::Expr { expr, use_value } => {
+        Stmt::Expr {
+            expr, use_value, ..
+        } => {
             debug_assert!(result_var.is_some() || !*use_value);
             let stmt_result_var = if *use_value {
                 result_var.unwrap()
@@ -90,7 +155,9 @@ fn lower_stmt(fctx: &mut FunctionContext<'_>, stmt: &Stmt, result_var: Option<us
                 fctx.fresh_var()
             };
             lower_expr(fctx, expr, stmt_result_var);
-            if result_var.is_some() && !*use_value {
+            // After `return` or `break`, there is no value to produce
+            let diverges = matches!(expr, Expr::Return { .. } | Expr::Break { .. });
+            if result_var.is_some() && !*use_value && !diverges {
                 // Return unit instead
                 fctx.push(sir::Inst::literal(result_var.unwrap(), ()));
             }
@@ -108,6 +175,20 @@ fn lower_expr(fctx: &mut FunctionContext<'_>, expr: &Expr, result_var: usize) {
                     match builtin {
                         BuiltinKind::Puts => sir::BuiltinKind::Puts,
                         BuiltinKind::Puti => sir::BuiltinKind::Puti,
+                        BuiltinKind::StrEq => sir::BuiltinKind::StrEq,
+                        BuiltinKind::Panic => sir::BuiltinKind::Panic,
+                        BuiltinKind::Len => sir::BuiltinKind::Len,
+                        BuiltinKind::PanicWithInt => sir::BuiltinKind::PanicWithInt,
+                        BuiltinKind::Gets => sir::BuiltinKind::Gets,
+                        BuiltinKind::OptionNone => sir::BuiltinKind::OptionNone,
+                        BuiltinKind::OptionSome => sir::BuiltinKind::OptionSome,
+                        BuiltinKind::IsSome => sir::BuiltinKind::IsSome,
+                        BuiltinKind::Unwrap => sir::BuiltinKind::Unwrap,
+                        BuiltinKind::CharAt => sir::BuiltinKind::CharAt,
+                        BuiltinKind::TermWidth => sir::BuiltinKind::TermWidth,
+                        BuiltinKind::Args => sir::BuiltinKind::Args,
+                        BuiltinKind::Putc => sir::BuiltinKind::Putc,
+                        BuiltinKind::Repeat => sir::BuiltinKind::Repeat,
                     },
                 ));
             } else {
@@ -117,48 +198,82 @@ fn lower_expr(fctx: &mut FunctionContext<'_>, expr: &Expr, result_var: usize) {
         }
         Expr::Branch { cond, then, else_ } => {
             let cond_var = lower_expr2(fctx, cond);
-
-            let branch_bb_id = fctx.current_bb_id();
-
-            let then_bb_id = fctx.new_bb();
-            lower_expr(fctx, then, result_var);
-
-            let else_bb_id = fctx.new_bb();
-            lower_expr(fctx, else_, result_var);
-
-            let cont_bb_id = fctx.new_bb();
-
-            fctx.push_at(
-                branch_bb_id,
-                sir::Inst::branch(cond_var, then_bb_id, else_bb_id),
+            lower_branch(
+                fctx,
+                cond_var,
+                |fctx| lower_expr(fctx, then, result_var),
+                |fctx| {
+                    if let Some(else_) = else_ {
+                        lower_expr(fctx, else_, result_var);
+                    } else {
+                        fctx.push(sir::Inst::literal(result_var, ()));
+                    }
+                },
+            );
+        }
+        Expr::IfSome {
+            binder,
+            scrutinee,
+            then,
+            else_,
+        } => {
+            let scrutinee_var = lower_expr2(fctx, scrutinee);
+            // The scrutinee is only moved out once it is known to have a value
+            let is_some_var = fctx.fresh_var();
+            let cond_var = fctx.fresh_var();
+            fctx.push(sir::Inst::builtin(is_some_var, sir::BuiltinKind::IsSome));
+            fctx.push(sir::Inst::push_arg_copy(scrutinee_var));
+            fctx.push(sir::Inst::call(cond_var, is_some_var));
+            lower_branch(
+                fctx,
+                cond_var,
+                |fctx| {
+                    let unwrap_var = fctx.fresh_var();
+                    let binder_var = fctx.var_id_map[&binder.id];
+                    fctx.push(sir::Inst::builtin(unwrap_var, sir::BuiltinKind::Unwrap));
+                    fctx.push(sir::Inst::push_arg(scrutinee_var));
+                    fctx.push(sir::Inst::call(binder_var, unwrap_var));
+                    lower_expr(fctx, then, result_var);
+                },
+                |fctx| {
+                    if let Some(else_) = else_ {
+                        lower_expr(fctx, else_, result_var);
+                    } else {
+                        fctx.push(sir::Inst::literal(result_var, ()));
+                    }
+                },
             );
-            fctx.push_at(then_bb_id, sir::Inst::jump(cont_bb_id));
-            fctx.push_at(else_bb_id, sir::Inst::jump(cont_bb_id));
         }
         Expr::While { cond, body } => {
             let prev_bb_id = fctx.current_bb_id();
 
             let cond_bb_id = fctx.new_bb();
             let cond_var = lower_expr2(fctx, cond);
+            let cond_end_bb_id = fctx.current_bb_id();
 
             let body_bb_id = fctx.new_bb();
             lower_expr(fctx, body, result_var);
+            let body_end_bb_id = fctx.current_bb_id();
 
             let cont_bb_id = fctx.new_bb();
 
             fctx.push_at(prev_bb_id, sir::Inst::jump(cond_bb_id));
             fctx.push_at(
-                cond_bb_id,
+                cond_end_bb_id,
                 sir::Inst::branch(cond_var, body_bb_id, cont_bb_id),
             );
-            fctx.push_at(body_bb_id, sir::Inst::jump(cond_bb_id));
+            fctx.push_at(body_end_bb_id, sir::Inst::jump(cond_bb_id));
             fctx.push(sir::Inst::literal(result_var, ()));
         }
-        Expr::Block { stmts } => lower_stmts(fctx, stmts, result_var),
+        Expr::Block { stmts, .. } => lower_stmts(fctx, stmts, result_var),
         Expr::Assign { lhs, rhs } => {
             debug_assert!(!lhs.id.is_dummy());
-            let var_id = fctx.var_id_map[&lhs.id];
-            lower_expr(fctx, rhs, var_id);
+            // `x = x` is a no-op; lowering it would move `x` out and back in
+            let is_self_assign = matches!(&**rhs, Expr::Var { ident } if ident.id == lhs.id);
+            if !is_self_assign {
+                let var_id = fctx.var_id_map[&lhs.id];
+                lower_expr(fctx, rhs, var_id);
+            }
             fctx.push(sir::Inst::literal(result_var, ()));
         }
         Expr::Call { callee, args } => {
@@ -171,6 +286,18 @@ fn lower_expr(fctx: &mut FunctionContext<'_>, expr: &Expr, result_var: usize) {
                 fctx.push(sir::Inst::push_arg(arg_var));
             }
             fctx.push(sir::Inst::call(result_var, callee_var));
+            let never_returns = matches!(
+                &**callee,
+                Expr::Var { ident } if matches!(
+                    fctx.builtin_ids.builtins.get(&ident.id),
+                    Some(BuiltinKind::Panic | BuiltinKind::PanicWithInt)
+                )
+            );
+            if never_returns {
+                fctx.push(sir::Inst::unreachable());
+                // Code after the panic goes to a fresh unreachable block
+                fctx.new_bb();
+            }
         }
         Expr::IntegerLiteral { value } => {
             fctx.push(sir::Inst::literal(result_var, *value));
@@ -178,13 +305,53 @@ fn lower_expr(fctx: &mut FunctionContext<'_>, expr: &Expr, result_var: usize) {
         Expr::StringLiteral { value } => {
             fctx.push(sir::Inst::literal(result_var, &**value));
         }
+        Expr::BooleanLiteral { value } => {
+            fctx.push(sir::Inst::literal(result_var, *value));
+        }
+        Expr::BinOp {
+            op: BinOp::And,
+            lhs,
+            rhs,
+        } => {
+            // if <lhs> then <rhs> else false
+            let cond_var = lower_expr2(fctx, lhs);
+            lower_branch(
+                fctx,
+                cond_var,
+                |fctx| lower_expr(fctx, rhs, result_var),
+                |fctx| fctx.push(sir::Inst::literal(result_var, false)),
+            );
+        }
+        Expr::BinOp {
+            op: BinOp::Or,
+            lhs,
+            rhs,
+        } => {
+            // if <lhs> then true else <rhs>
+            let cond_var = lower_expr2(fctx, lhs);
+            lower_branch(
+                fctx,
+                cond_var,
+                |fctx| fctx.push(sir::Inst::literal(result_var, true)),
+                |fctx| lower_expr(fctx, rhs, result_var),
+            );
+        }
         Expr::BinOp { op, lhs, rhs } => {
             let callee_var = fctx.fresh_var();
             fctx.push(sir::Inst::builtin(
                 callee_var,
                 match op {
                     BinOp::Add => sir::BuiltinKind::Add,
+                    BinOp::Sub => sir::BuiltinKind::Sub,
+                    BinOp::Mul => sir::BuiltinKind::Mul,
+                    BinOp::Div => sir::BuiltinKind::Div,
                     BinOp::Lt => sir::BuiltinKind::Lt,
+                    BinOp::Gt => sir::BuiltinKind::Gt,
+                    BinOp::Le => sir::BuiltinKind::Le,
+                    BinOp::Ge => sir::BuiltinKind::Ge,
+                    BinOp::Eq => sir::BuiltinKind::Eq,
+                    BinOp::Ne => sir::BuiltinKind::Ne,
+                    BinOp::And | BinOp::Or => unreachable!("lowered as branches"),
                 },
             ));
 
@@ -195,7 +362,139 @@ fn lower_expr(fctx: &mut FunctionContext<'_>, expr: &Expr, result_var: usize) {
             fctx.push(sir::Inst::push_arg(rhs_var));
             fctx.push(sir::Inst::call(result_var, callee_var));
         }
+        Expr::Lambda { params, body } => {
+            lower_lambda(fctx, params, body, result_var);
+        }
+        Expr::ArrayLiteral { elems } => {
+            let elem_vars = elems
+                .iter()
+                .map(|elem| lower_expr2(fctx, elem))
+                .collect::<Vec<_>>();
+            for &elem_var in &elem_vars {
+                fctx.push(sir::Inst::push_arg(elem_var));
+            }
+            fctx.push(sir::Inst::array(result_var));
+        }
+        Expr::Index { base, index } => {
+            let callee_var = fctx.fresh_var();
+            let arg_vars = if let Expr::Range { start, end } = &**index {
+                fctx.push(sir::Inst::builtin(callee_var, sir::BuiltinKind::Slice));
+                vec![
+                    lower_expr2(fctx, base),
+                    lower_expr2(fctx, start),
+                    lower_expr2(fctx, end),
+                ]
+            } else {
+                fctx.push(sir::Inst::builtin(callee_var, sir::BuiltinKind::Index));
+                vec![lower_expr2(fctx, base), lower_expr2(fctx, index)]
+            };
+            for &arg_var in &arg_vars {
+                fctx.push(sir::Inst::push_arg(arg_var));
+            }
+            fctx.push(sir::Inst::call(result_var, callee_var));
+        }
+        Expr::Range { .. } => unreachable!("range outside of an index"),
+        Expr::Try { body } => {
+            // The body is run as a closure so that its errors are caught at the call boundary
+            let closure_var = fctx.fresh_var();
+            lower_lambda(fctx, &[], body, closure_var);
+            fctx.push(sir::Inst::try_call(result_var, closure_var));
+        }
+        Expr::Return { value } => {
+            let value_var = lower_expr2(fctx, value);
+            fctx.push(sir::Inst::return_(value_var));
+            // Code after `return` goes to a fresh unreachable block
+            fctx.new_bb();
+        }
+        Expr::Loop { body } => {
+            let prev_bb_id = fctx.current_bb_id();
+
+            let body_bb_id = fctx.new_bb();
+            fctx.loops.push(LoopContext {
+                result_var,
+                break_bb_ids: vec![],
+            });
+            let body_var = fctx.fresh_var();
+            lower_expr(fctx, body, body_var);
+            let loop_ctx = fctx.loops.pop().unwrap();
+            let body_end_bb_id = fctx.current_bb_id();
+
+            // Unreachable if there is no `break`
+            let cont_bb_id = fctx.new_bb();
+
+            fctx.push_at(prev_bb_id, sir::Inst::jump(body_bb_id));
+            fctx.push_at(body_end_bb_id, sir::Inst::jump(body_bb_id));
+            for break_bb_id in loop_ctx.break_bb_ids {
+                fctx.push_at(break_bb_id, sir::Inst::jump(cont_bb_id));
+            }
+        }
+        Expr::Break { value } => {
+            let loop_ctx = fctx.loops.last().expect("`break` outside of a loop");
+            let loop_result_var = loop_ctx.result_var;
+            lower_expr(fctx, value, loop_result_var);
+            let break_bb_id = fctx.current_bb_id();
+            fctx.loops
+                .last_mut()
+                .unwrap()
+                .break_bb_ids
+                .push(break_bb_id);
+            // Code after `break` goes to a fresh unreachable block
+            fctx.new_bb();
+        }
+        Expr::Commented { expr, .. } => lower_expr(fctx, expr, result_var),
+    }
+}
+
+fn lower_lambda(fctx: &mut FunctionContext<'_>, params: &[Ident], body: &Expr, result_var: usize) {
+    let captures = lambda_captures(params, body)
+        .into_iter()
+        .filter(|id| !fctx.builtin_ids.builtins.contains_key(id))
+        .collect::<Vec<_>>();
+    let mut vars = HashSet::new();
+    collect_vars_expr(body, &mut vars);
+    let function_id = lower_function(
+        fctx.builtin_ids,
+        fctx.functions,
+        &captures,
+        params,
+        vars,
+        |fctx, result_var| lower_expr(fctx, body, result_var),
+    );
+    // Captured values are passed like arguments
+    for capture in &captures {
+        let var_id = fctx.var_id_map[capture];
+        fctx.push(sir::Inst::push_arg(var_id));
     }
+    fctx.push(sir::Inst::closure(result_var, function_id));
+}
+
+/// Lowers a branch on `cond_var` into the current block, continuing in a new block
+/// where both arms join.
+fn lower_branch(
+    fctx: &mut FunctionContext<'_>,
+    cond_var: usize,
+    lower_then: impl FnOnce(&mut FunctionContext<'_>),
+    lower_else: impl FnOnce(&mut FunctionContext<'_>),
+) {
+    let branch_bb_id = fctx.current_bb_id();
+
+    let then_bb_id = fctx.new_bb();
+    lower_then(fctx);
+    // The arms themselves may span several blocks
+    let then_end_bb_id = fctx.current_bb_id();
+
+    let else_bb_id = fctx.new_bb();
+    lower_else(fctx);
+    let else_end_bb_id = fctx.current_bb_id();
+
+    let cont_bb_id = fctx.new_bb();
+
+    fctx.push_at(
+        branch_bb_id,
+        sir::Inst::branch(cond_var, then_bb_id, else_bb_id),
+    );
+    fctx.push_at(then_end_bb_id, sir::Inst::jump(cont_bb_id));
+    fctx.push_at(else_end_bb_id, sir::Inst::jump(cont_bb_id));
 }
 
 fn lower_expr2(fctx: &mut FunctionContext<'_>, expr: &Expr) -> usize {
@@ -205,58 +504,73 @@ fn lower_expr2(fctx: &mut FunctionContext<'_>, expr: &Expr) -> usize {
 }
 
 fn collect_vars_stmts(stmts: &[Stmt], vars: &mut HashSet<Id>) {
+    let mut collector = VarCollector { vars };
     for stmt in stmts {
-        collect_vars_stmt(stmt, vars);
+        collector.visit_stmt(stmt);
     }
 }
 
-fn collect_vars_stmt(stmt: &Stmt, vars: &mut HashSet<Id>) {
-    match stmt {
-        Stmt::Let { lhs, init } => {
-            debug_assert!(!lhs.id.is_dummy());
-            vars.insert(lhs.id);
-            collect_vars_expr(init, vars);
-        }
-        Stmt::Expr { expr, use_value: _ } => {
-            collect_vars_expr(expr, vars);
+fn collect_vars_expr(expr: &Expr, vars: &mut HashSet<Id>) {
+    VarCollector { vars }.visit_expr(expr);
+}
+
+/// Collects the variables declared or referenced in the current function.
+struct VarCollector<'a> {
+    vars: &'a mut HashSet<Id>,
+}
+
+impl Visitor for VarCollector<'_> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr {
+            // Variables declared inside the lambda belong to its own function
+            Expr::Lambda { params, body } => self.vars.extend(lambda_captures(params, body)),
+            Expr::Try { body } => self.vars.extend(lambda_captures(&[], body)),
+            _ => walk_expr(self, expr),
         }
     }
+    fn visit_decl(&mut self, ident: &Ident) {
+        debug_assert!(!ident.id.is_dummy());
+        self.vars.insert(ident.id);
+    }
+    fn visit_ref(&mut self, ident: &Ident) {
+        debug_assert!(!ident.id.is_dummy());
+        self.vars.insert(ident.id);
+    }
 }
 
-fn collect_vars_expr(expr: &Expr, vars: &mut HashSet<Id>) {
-    match expr {
-        Expr::Var { ident } => {
-            debug_assert!(!ident.id.is_dummy());
-            vars.insert(ident.id);
-        }
-        Expr::Branch { cond, then, else_ } => {
-            collect_vars_expr(cond, vars);
-            collect_vars_expr(then, vars);
-            collect_vars_expr(else_, vars);
-        }
-        Expr::While { cond, body } => {
-            collect_vars_expr(cond, vars);
-            collect_vars_expr(body, vars);
-        }
-        Expr::Block { stmts } => collect_vars_stmts(stmts, vars),
-        Expr::Assign { lhs, rhs } => {
-            debug_assert!(!lhs.id.is_dummy());
-            vars.insert(lhs.id);
-            collect_vars_expr(rhs, vars);
-        }
-        Expr::Call { callee, args } => {
-            collect_vars_expr(callee, vars);
-            for arg in args {
-                collect_vars_expr(arg, vars);
-            }
-        }
-        Expr::IntegerLiteral { value: _ } => {}
-        Expr::StringLiteral { value: _ } => {}
-        Expr::BinOp { op: _, lhs, rhs } => {
-            collect_vars_expr(lhs, vars);
-            collect_vars_expr(rhs, vars);
+/// Variables referenced from the lambda but declared outside of it, in the order of ids.
+///
+/// Builtins are included here; they are excluded when the captures are actually built.
+fn lambda_captures(params: &[Ident], body: &Expr) -> Vec<Id> {
+    let mut vars = HashSet::new();
+    collect_vars_expr(body, &mut vars);
+    let mut decls = params.iter().map(|param| param.id).collect::<HashSet<_>>();
+    collect_decls_expr(body, &mut decls);
+    let mut captures = vars.difference(&decls).copied().collect::<Vec<_>>();
+    captures.sort_unstable();
+    captures
+}
+
+fn collect_decls_expr(expr: &Expr, decls: &mut HashSet<Id>) {
+    DeclCollector { decls }.visit_expr(expr);
+}
+
+/// Collects the variables declared in the current function, except for its parameters.
+struct DeclCollector<'a> {
+    decls: &'a mut HashSet<Id>,
+}
+
+impl Visitor for DeclCollector<'_> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr {
+            // Nested lambdas and `try` bodies are separate functions
+            Expr::Lambda { .. } | Expr::Try { .. } => {}
+            _ => walk_expr(self, expr),
         }
     }
+    fn visit_decl(&mut self, ident: &Ident) {
+        self.decls.insert(ident.id);
+    }
 }
 
 #[cfg(test)]
@@ -268,6 +582,10 @@ mod tests {
     use crate::ast::{assign_id_stmts, Scope};
     use crate::cctx::CCtx;
     use crate::sir::Inst;
+    use crate::sir_compile::compile;
+    use crate::sir_eval::{eval1_value, Value};
+    use crate::sir_validation::SirValidationError;
+    use crate::testing::MockRtCtx;
 
     fn assign_id(cctx: &mut CCtx, builtin_ids: &BuiltinIds, mut stmts: Vec<Stmt>) -> Vec<Stmt> {
         let mut scope = Scope::new(builtin_ids);
@@ -287,22 +605,67 @@ mod tests {
                 vec![exprs::string_literal("Hello, world!")],
             ))],
         );
-        let function = lower(&builtin_ids, &s);
+        let program_unit = lower(&builtin_ids, &s);
         assert_eq!(
-            function,
-            sir::Function::describe(0, |[_tmp1, tmp2, tmp3, puts1, tmp4], [entry]| {
-                vec![(
-                    entry,
-                    sir::BasicBlock::new(vec![
-                        Inst::builtin(puts1, sir::BuiltinKind::Puts),
-                        Inst::literal(tmp4, "Hello, world!"),
-                        Inst::push_arg(tmp4),
-                        Inst::call(tmp3, puts1),
-                        Inst::literal(tmp2, ()),
-                        Inst::return_(tmp2),
-                    ]),
-                )]
-            })
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(0, |[_tmp1, tmp2, tmp3, puts1, tmp4], [entry]| {
+                    vec![(
+                        entry,
+                        sir::BasicBlock::new(vec![
+                            Inst::builtin(puts1, sir::BuiltinKind::Puts),
+                            Inst::literal(tmp4, "Hello, world!"),
+                            Inst::push_arg(tmp4),
+                            Inst::call(tmp3, puts1),
+                            Inst::literal(tmp2, ()),
+                            Inst::return_(tmp2),
+                        ]),
+                    )]
+                })
+                .with_num_named_vars(1)
+            )
+        );
+    }
+
+    #[test]
+    fn test_lower_panic() {
+        let mut cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let s = assign_id(
+            &mut cctx,
+            &builtin_ids,
+            vec![stmts::expr(exprs::call(
+                exprs::var("panic"),
+                vec![exprs::string_literal("boom")],
+            ))],
+        );
+        let program_unit = lower(&builtin_ids, &s);
+        assert_eq!(
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(0, |[_tmp1, tmp2, tmp3, panic1, tmp4], [entry, bb1]| {
+                    vec![
+                        (
+                            entry,
+                            sir::BasicBlock::new(vec![
+                                Inst::builtin(panic1, sir::BuiltinKind::Panic),
+                                Inst::literal(tmp4, "boom"),
+                                Inst::push_arg(tmp4),
+                                Inst::call(tmp3, panic1),
+                                Inst::unreachable(),
+                            ]),
+                        ),
+                        (
+                            bb1,
+                            sir::BasicBlock::new(vec![
+                                Inst::literal(tmp2, ()),
+                                Inst::return_(tmp2),
+                            ]),
+                        ),
+                    ]
+                })
+                .with_num_named_vars(1)
+            )
         );
     }
 
@@ -318,23 +681,26 @@ mod tests {
                 exprs::integer_literal(2),
             ))],
         );
-        let function = lower(&builtin_ids, &s);
+        let program_unit = lower(&builtin_ids, &s);
         assert_eq!(
-            function,
-            sir::Function::describe(0, |[tmp1, add1, tmp2, tmp3], [entry]| {
-                vec![(
-                    entry,
-                    sir::BasicBlock::new(vec![
-                        Inst::builtin(add1, sir::BuiltinKind::Add),
-                        Inst::literal(tmp2, 1),
-                        Inst::literal(tmp3, 2),
-                        Inst::push_arg(tmp2),
-                        Inst::push_arg(tmp3),
-                        Inst::call(tmp1, add1),
-                        Inst::return_(tmp1),
-                    ]),
-                )]
-            })
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(0, |[tmp1, add1, tmp2, tmp3], [entry]| {
+                    vec![(
+                        entry,
+                        sir::BasicBlock::new(vec![
+                            Inst::builtin(add1, sir::BuiltinKind::Add),
+                            Inst::literal(tmp2, 1),
+                            Inst::literal(tmp3, 2),
+                            Inst::push_arg(tmp2),
+                            Inst::push_arg(tmp3),
+                            Inst::call(tmp1, add1),
+                            Inst::return_(tmp1),
+                        ]),
+                    )]
+                })
+                .with_num_named_vars(0)
+            )
         );
     }
 
@@ -350,20 +716,166 @@ mod tests {
                 stmts::then_expr(exprs::var("x")),
             ],
         );
-        let function = lower(&builtin_ids, &s);
+        let program_unit = lower(&builtin_ids, &s);
         assert_eq!(
-            function,
-            sir::Function::describe(0, |[x, tmp1], [entry]| {
-                vec![(
-                    entry,
-                    sir::BasicBlock::new(vec![
-                        Inst::literal(x, 42),
-                        Inst::copy(tmp1, x),
-                        Inst::return_(tmp1),
-                    ]),
-                )]
-            })
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(0, |[x, tmp1], [entry]| {
+                    vec![(
+                        entry,
+                        sir::BasicBlock::new(vec![
+                            Inst::literal(x, 42),
+                            Inst::copy(tmp1, x),
+                            Inst::return_(tmp1),
+                        ]),
+                    )]
+                })
+                .with_num_named_vars(1)
+            )
+        );
+        assert_eq!(program_unit.functions[0].num_named_vars, 1);
+        assert_eq!(program_unit.functions[0].num_temps(), 1);
+    }
+
+    #[test]
+    fn test_lower_let_uninit() {
+        let mut cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let s = assign_id(
+            &mut cctx,
+            &builtin_ids,
+            vec![
+                stmts::let_uninit("x"),
+                stmts::expr(exprs::assign("x", exprs::integer_literal(1))),
+                stmts::then_expr(exprs::var("x")),
+            ],
+        );
+        let program_unit = lower(&builtin_ids, &s);
+        assert_eq!(
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(0, |[x, tmp1, tmp2], [entry]| {
+                    vec![(
+                        entry,
+                        sir::BasicBlock::new(vec![
+                            Inst::literal(x, 1),
+                            Inst::literal(tmp2, ()),
+                            Inst::copy(tmp1, x),
+                            Inst::return_(tmp1),
+                        ]),
+                    )]
+                })
+                .with_num_named_vars(1)
+            )
+        );
+        assert!(program_unit.validate_init().is_ok());
+    }
+
+    #[test]
+    fn test_lower_self_assign() {
+        let mut cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let s = assign_id(
+            &mut cctx,
+            &builtin_ids,
+            vec![
+                stmts::let_("x", exprs::integer_literal(1)),
+                stmts::expr(exprs::assign("x", exprs::var("x"))),
+                stmts::then_expr(exprs::var("x")),
+            ],
+        );
+        let program_unit = lower(&builtin_ids, &s);
+        assert_eq!(
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(0, |[x, tmp1, tmp2], [entry]| {
+                    vec![(
+                        entry,
+                        sir::BasicBlock::new(vec![
+                            Inst::literal(x, 1),
+                            Inst::literal(tmp2, ()),
+                            Inst::copy(tmp1, x),
+                            Inst::return_(tmp1),
+                        ]),
+                    )]
+                })
+                .with_num_named_vars(1)
+            )
         );
+        assert!(program_unit.validate_init().is_ok());
+    }
+
+    #[test]
+    fn test_lower_empty_block() {
+        let mut cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let s = assign_id(
+            &mut cctx,
+            &builtin_ids,
+            vec![
+                stmts::let_("x", exprs::block(vec![])),
+                stmts::then_expr(exprs::var("x")),
+            ],
+        );
+        let program_unit = lower(&builtin_ids, &s);
+        assert_eq!(
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(0, |[x, tmp1], [entry]| {
+                    vec![(
+                        entry,
+                        sir::BasicBlock::new(vec![
+                            Inst::literal(x, ()),
+                            Inst::copy(tmp1, x),
+                            Inst::return_(tmp1),
+                        ]),
+                    )]
+                })
+                .with_num_named_vars(1)
+            )
+        );
+        assert!(program_unit.validate_init().is_ok());
+    }
+
+    #[test]
+    fn test_lower_let_uninit_read() {
+        let mut cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let s = assign_id(
+            &mut cctx,
+            &builtin_ids,
+            vec![stmts::let_uninit("x"), stmts::then_expr(exprs::var("x"))],
+        );
+        let program_unit = lower(&builtin_ids, &s);
+        assert!(matches!(
+            program_unit.validate_init(),
+            Err(SirValidationError::UninitializedVariable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_lower_let_uninit_assigned_in_one_branch() {
+        let mut cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let s = assign_id(
+            &mut cctx,
+            &builtin_ids,
+            vec![
+                stmts::let_("c", exprs::integer_literal(1)),
+                stmts::let_uninit("x"),
+                stmts::expr(exprs::branch(
+                    exprs::var("c"),
+                    exprs::assign("x", exprs::integer_literal(1)),
+                    exprs::block(vec![]),
+                )),
+                stmts::then_expr(exprs::var("x")),
+            ],
+        );
+        let program_unit = lower(&builtin_ids, &s);
+        assert!(matches!(
+            program_unit.validate_init(),
+            Err(SirValidationError::UninitializedVariable { .. })
+        ));
     }
 
     #[test]
@@ -382,34 +894,145 @@ mod tests {
                 )),
             ],
         );
-        let function = lower(&builtin_ids, &s);
+        let program_unit = lower(&builtin_ids, &s);
         assert_eq!(
-            function,
-            sir::Function::describe(
-                0,
-                |[x, tmp1, tmp2], [entry, branch_then, branch_else, cont]| {
-                    vec![
-                        (
-                            entry,
-                            sir::BasicBlock::new(vec![
-                                Inst::literal(x, 42),
-                                Inst::copy(tmp2, x),
-                                Inst::branch(tmp2, branch_then, branch_else),
-                            ]),
-                        ),
-                        (
-                            branch_then,
-                            sir::BasicBlock::new(vec![Inst::literal(tmp1, 1), Inst::jump(cont)]),
-                        ),
-                        (
-                            branch_else,
-                            sir::BasicBlock::new(vec![Inst::literal(tmp1, 2), Inst::jump(cont)]),
-                        ),
-                        (cont, sir::BasicBlock::new(vec![Inst::return_(tmp1)])),
-                    ]
-                }
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(
+                    0,
+                    |[x, tmp1, tmp2], [entry, branch_then, branch_else, cont]| {
+                        vec![
+                            (
+                                entry,
+                                sir::BasicBlock::new(vec![
+                                    Inst::literal(x, 42),
+                                    Inst::copy(tmp2, x),
+                                    Inst::branch(tmp2, branch_then, branch_else),
+                                ]),
+                            ),
+                            (
+                                branch_then,
+                                sir::BasicBlock::new(vec![
+                                    Inst::literal(tmp1, 1),
+                                    Inst::jump(cont),
+                                ]),
+                            ),
+                            (
+                                branch_else,
+                                sir::BasicBlock::new(vec![
+                                    Inst::literal(tmp1, 2),
+                                    Inst::jump(cont),
+                                ]),
+                            ),
+                            (cont, sir::BasicBlock::new(vec![Inst::return_(tmp1)])),
+                        ]
+                    }
+                )
+                .with_num_named_vars(1)
+            )
+        );
+    }
+
+    #[test]
+    fn test_lower_branch_result_initialized() {
+        // The result of a branch must be written on every path into its continuation,
+        // which reads it here through the final `then`.
+        let branches = [
+            // if c { }
+            exprs::branch_without_else(exprs::var("c"), exprs::block(vec![])),
+            // if c then 1 else 2
+            exprs::branch(
+                exprs::var("c"),
+                exprs::integer_literal(1),
+                exprs::integer_literal(2),
+            ),
+            // if c then 1 else return 2
+            exprs::branch(
+                exprs::var("c"),
+                exprs::integer_literal(1),
+                exprs::return_(exprs::integer_literal(2)),
+            ),
+            // if c then panic("x") else 2
+            exprs::branch(
+                exprs::var("c"),
+                exprs::call(exprs::var("panic"), vec![exprs::string_literal("x")]),
+                exprs::integer_literal(2),
+            ),
+            // if c then (if c then 1 else 2) else do { while c {}; then 3; }
+            exprs::branch(
+                exprs::var("c"),
+                exprs::branch(
+                    exprs::var("c"),
+                    exprs::integer_literal(1),
+                    exprs::integer_literal(2),
+                ),
+                exprs::block(vec![
+                    stmts::expr(exprs::while_(exprs::var("c"), exprs::block(vec![]))),
+                    stmts::then_expr(exprs::integer_literal(3)),
+                ]),
+            ),
+        ];
+        for branch in branches {
+            let mut cctx = CCtx::new();
+            let builtin_ids = BuiltinIds::new(&cctx);
+            let s = assign_id(
+                &mut cctx,
+                &builtin_ids,
+                vec![
+                    stmts::let_(
+                        "c",
+                        exprs::lt(exprs::integer_literal(1), exprs::integer_literal(2)),
+                    ),
+                    stmts::then_expr(branch.clone()),
+                ],
+            );
+            let program_unit = lower(&builtin_ids, &s);
+            assert!(
+                program_unit.validate_init().is_ok(),
+                "{:?}\n{:#?}",
+                branch,
+                program_unit
+            );
+        }
+    }
+
+    #[test]
+    fn test_lower_loop_break() {
+        let mut cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let s = assign_id(
+            &mut cctx,
+            &builtin_ids,
+            vec![stmts::then_expr(exprs::loop_(exprs::block(vec![
+                stmts::expr(exprs::break_(exprs::integer_literal(1))),
+            ])))],
+        );
+        let program_unit = lower(&builtin_ids, &s);
+        assert_eq!(
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(
+                    0,
+                    // The values of the body and of the `break` statement are never written
+                    |[tmp1, _tmp2, _tmp3], [entry, body, after_break, cont]| {
+                        vec![
+                            (entry, sir::BasicBlock::new(vec![Inst::jump(body)])),
+                            (
+                                body,
+                                sir::BasicBlock::new(vec![
+                                    Inst::literal(tmp1, 1),
+                                    Inst::jump(cont),
+                                ]),
+                            ),
+                            (after_break, sir::BasicBlock::new(vec![Inst::jump(body)])),
+                            (cont, sir::BasicBlock::new(vec![Inst::return_(tmp1)])),
+                        ]
+                    }
+                )
+                .with_num_named_vars(0)
             )
         );
+        assert!(program_unit.validate_init().is_ok());
     }
 
     #[test]
@@ -430,52 +1053,116 @@ mod tests {
                 )),
             ],
         );
-        let function = lower(&builtin_ids, &s);
+        let program_unit = lower(&builtin_ids, &s);
         assert_eq!(
-            function,
-            sir::Function::describe(
-                0,
-                |[x, tmp1, cond1, lt1, tmp2, tmp3, add1, tmp4, tmp5], [entry, cond, body, cont]| {
-                    vec![
-                        (
-                            entry,
-                            sir::BasicBlock::new(vec![Inst::literal(x, 42), Inst::jump(cond)]),
-                        ),
-                        (
-                            cond,
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(
+                    0,
+                    |[x, tmp1, cond1, lt1, tmp2, tmp3, add1, tmp4, tmp5],
+                     [entry, cond, body, cont]| {
+                        vec![
+                            (
+                                entry,
+                                sir::BasicBlock::new(vec![Inst::literal(x, 42), Inst::jump(cond)]),
+                            ),
+                            (
+                                cond,
+                                sir::BasicBlock::new(vec![
+                                    Inst::builtin(lt1, sir::BuiltinKind::Lt),
+                                    Inst::literal(tmp2, -1),
+                                    Inst::copy(tmp3, x),
+                                    Inst::push_arg(tmp2),
+                                    Inst::push_arg(tmp3),
+                                    Inst::call(cond1, lt1),
+                                    Inst::branch(cond1, body, cont),
+                                ]),
+                            ),
+                            (
+                                body,
+                                sir::BasicBlock::new(vec![
+                                    Inst::builtin(add1, sir::BuiltinKind::Add),
+                                    Inst::copy(tmp4, x),
+                                    Inst::literal(tmp5, -1),
+                                    Inst::push_arg(tmp4),
+                                    Inst::push_arg(tmp5),
+                                    Inst::call(x, add1),
+                                    Inst::literal(tmp1, ()),
+                                    Inst::jump(cond),
+                                ]),
+                            ),
+                            (
+                                cont,
+                                sir::BasicBlock::new(vec![
+                                    Inst::literal(tmp1, ()),
+                                    Inst::return_(tmp1),
+                                ]),
+                            ),
+                        ]
+                    }
+                )
+                .with_num_named_vars(1)
+            )
+        );
+    }
+
+    #[test]
+    fn test_lower_lambda_capture() {
+        let mut cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let s = assign_id(
+            &mut cctx,
+            &builtin_ids,
+            vec![
+                stmts::let_("y", exprs::integer_literal(1)),
+                stmts::let_(
+                    "f",
+                    exprs::lambda(&["x"], exprs::add(exprs::var("x"), exprs::var("y"))),
+                ),
+                stmts::then_expr(exprs::call(
+                    exprs::var("f"),
+                    vec![exprs::integer_literal(2)],
+                )),
+            ],
+        );
+        let program_unit = lower(&builtin_ids, &s);
+        assert_eq!(
+            program_unit,
+            sir::ProgramUnit::describe(|[main, lambda1]| {
+                vec![
+                    (
+                        main,
+                        sir::Function::simple(0, |[y, f, tmp1, tmp2, tmp3]| {
                             sir::BasicBlock::new(vec![
-                                Inst::builtin(lt1, sir::BuiltinKind::Lt),
-                                Inst::literal(tmp2, -1),
-                                Inst::copy(tmp3, x),
-                                Inst::push_arg(tmp2),
+                                Inst::literal(y, 1),
+                                Inst::push_arg(y),
+                                Inst::closure(f, lambda1),
+                                Inst::copy(tmp2, f),
+                                Inst::literal(tmp3, 2),
                                 Inst::push_arg(tmp3),
-                                Inst::call(cond1, lt1),
-                                Inst::branch(cond1, body, cont),
-                            ]),
-                        ),
-                        (
-                            body,
+                                Inst::call(tmp1, tmp2),
+                                Inst::return_(tmp1),
+                            ])
+                        })
+                        .with_num_named_vars(2),
+                    ),
+                    (
+                        lambda1,
+                        sir::Function::simple(2, |[y, x, tmp1, add1, tmp2, tmp3]| {
                             sir::BasicBlock::new(vec![
                                 Inst::builtin(add1, sir::BuiltinKind::Add),
-                                Inst::copy(tmp4, x),
-                                Inst::literal(tmp5, -1),
-                                Inst::push_arg(tmp4),
-                                Inst::push_arg(tmp5),
-                                Inst::call(x, add1),
-                                Inst::literal(tmp1, ()),
-                                Inst::jump(cond),
-                            ]),
-                        ),
-                        (
-                            cont,
-                            sir::BasicBlock::new(vec![
-                                Inst::literal(tmp1, ()),
+                                Inst::copy(tmp2, x),
+                                Inst::copy(tmp3, y),
+                                Inst::push_arg(tmp2),
+                                Inst::push_arg(tmp3),
+                                Inst::call(tmp1, add1),
                                 Inst::return_(tmp1),
-                            ]),
-                        ),
-                    ]
-                }
-            )
+                            ])
+                        })
+                        .with_num_named_vars(2),
+                    ),
+                ]
+            })
         );
     }
 
@@ -491,21 +1178,60 @@ mod tests {
                 vec![exprs::integer_literal(42)],
             ))],
         );
-        let function = lower(&builtin_ids, &s);
+        let program_unit = lower(&builtin_ids, &s);
         assert_eq!(
-            function,
-            sir::Function::describe(0, |[_tmp1, tmp2, puti1, tmp3], [entry]| {
-                vec![(
-                    entry,
-                    sir::BasicBlock::new(vec![
-                        Inst::builtin(puti1, sir::BuiltinKind::Puti),
-                        Inst::literal(tmp3, 42),
-                        Inst::push_arg(tmp3),
-                        Inst::call(tmp2, puti1),
-                        Inst::return_(tmp2),
-                    ]),
-                )]
-            })
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(0, |[_tmp1, tmp2, puti1, tmp3], [entry]| {
+                    vec![(
+                        entry,
+                        sir::BasicBlock::new(vec![
+                            Inst::builtin(puti1, sir::BuiltinKind::Puti),
+                            Inst::literal(tmp3, 42),
+                            Inst::push_arg(tmp3),
+                            Inst::call(tmp2, puti1),
+                            Inst::return_(tmp2),
+                        ]),
+                    )]
+                })
+                .with_num_named_vars(1)
+            )
+        );
+    }
+
+    #[test]
+    fn test_lower_zero_arg_builtin() {
+        let mut cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let s = assign_id(
+            &mut cctx,
+            &builtin_ids,
+            vec![stmts::then_expr(exprs::call(
+                exprs::var("term_width"),
+                vec![],
+            ))],
+        );
+        let program_unit = lower(&builtin_ids, &s);
+        // The call is made with no arguments pushed
+        assert_eq!(
+            program_unit,
+            sir::ProgramUnit::simple(
+                sir::Function::describe(0, |[_tmp1, tmp2, term_width1], [entry]| {
+                    vec![(
+                        entry,
+                        sir::BasicBlock::new(vec![
+                            Inst::builtin(term_width1, sir::BuiltinKind::TermWidth),
+                            Inst::call(tmp2, term_width1),
+                            Inst::return_(tmp2),
+                        ]),
+                    )]
+                })
+                .with_num_named_vars(1)
+            )
         );
+        // The builtin sees no arguments, or it would fail with an arity mismatch
+        let program_unit = compile(&cctx, &program_unit);
+        let ctx = MockRtCtx::new().with_term_width(80);
+        assert_eq!(eval1_value(&ctx, &program_unit), Ok(Value::Integer(80)));
     }
 }
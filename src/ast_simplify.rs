@@ -0,0 +1,211 @@
+// AST simplification -- local rewrites applied after ids are assigned
+
+use crate::ast::{Expr, Stmt};
+
+/// Simplifies the program in place.
+///
+/// The rewrites may drop constraints that the original program imposes
+/// (e.g. that the condition of a removed branch is a `Bool`),
+/// so type checking should see the program before it is simplified.
+pub fn simplify_stmts(stmts: &mut [Stmt]) {
+    for stmt in stmts {
+        simplify_stmt(stmt);
+    }
+}
+
+fn simplify_stmt(stmt: &mut Stmt) {
+    match stmt {
+        Stmt::Let { init, .. } => {
+            if let Some(init) = init {
+                simplify_expr(init);
+            }
+        }
+        Stmt::Expr { expr, .. } => simplify_expr(expr),
+    }
+}
+
+fn simplify_expr(expr: &mut Expr) {
+    match expr {
+        Expr::Var { .. }
+        | Expr::IntegerLiteral { .. }
+        | Expr::StringLiteral { .. }
+        | Expr::BooleanLiteral { .. } => {}
+        Expr::Branch { cond, then, else_ } => {
+            simplify_expr(cond);
+            simplify_expr(then);
+            if let Some(else_) = else_ {
+                simplify_expr(else_);
+            }
+        }
+        Expr::IfSome {
+            scrutinee,
+            then,
+            else_,
+            ..
+        } => {
+            simplify_expr(scrutinee);
+            simplify_expr(then);
+            if let Some(else_) = else_ {
+                simplify_expr(else_);
+            }
+        }
+        Expr::While { cond, body } => {
+            simplify_expr(cond);
+            simplify_expr(body);
+        }
+        Expr::Block { stmts, .. } => simplify_stmts(stmts),
+        Expr::Assign { rhs, .. } => simplify_expr(rhs),
+        Expr::Call { callee, args } => {
+            simplify_expr(callee);
+            for arg in args {
+                simplify_expr(arg);
+            }
+        }
+        Expr::BinOp { lhs, rhs, .. } => {
+            simplify_expr(lhs);
+            simplify_expr(rhs);
+        }
+        Expr::Lambda { body, .. } => simplify_expr(body),
+        Expr::ArrayLiteral { elems } => {
+            for elem in elems {
+                simplify_expr(elem);
+            }
+        }
+        Expr::Index { base, index } => {
+            simplify_expr(base);
+            simplify_expr(index);
+        }
+        Expr::Range { start, end } => {
+            simplify_expr(start);
+            simplify_expr(end);
+        }
+        Expr::Try { body } => simplify_expr(body),
+        Expr::Return { value } => simplify_expr(value),
+        Expr::Loop { body } => simplify_expr(body),
+        Expr::Break { value } => simplify_expr(value),
+        Expr::Commented { expr, .. } => simplify_expr(expr),
+    }
+    simplify_same_branches(expr);
+}
+
+/// Rewrites `if c then e else e` into `do { c; e }`, keeping the side effects of `c`.
+///
+/// The branches are compared including their ids, so branches that declare
+/// variables of their own are never considered identical.
+fn simplify_same_branches(expr: &mut Expr) {
+    let Expr::Branch {
+        cond,
+        then,
+        else_: Some(else_),
+    } = expr
+    else {
+        return;
+    };
+    if then != else_ {
+        return;
+    }
+    let cond = std::mem::replace(&mut **cond, Expr::block(vec![]));
+    let then = std::mem::replace(&mut **then, Expr::block(vec![]));
+    *expr = Expr::block(vec![Stmt::expr(cond, false), Stmt::expr(then, true)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::ast::testing::{exprs, stmts};
+    use crate::ast::{assign_id_stmts, BuiltinIds, Scope};
+    use crate::cctx::CCtx;
+
+    fn assign_id(cctx: &CCtx, builtin_ids: &BuiltinIds, mut stmts: Vec<Stmt>) -> Vec<Stmt> {
+        let mut scope = Scope::new(builtin_ids);
+        assign_id_stmts(cctx, &mut scope, &mut stmts);
+        stmts
+    }
+
+    #[test]
+    fn test_simplify_same_branches() {
+        let cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        // let c = 1 < 2; puti(if c then 1 else 1);
+        let mut program = assign_id(
+            &cctx,
+            &builtin_ids,
+            vec![
+                stmts::let_(
+                    "c",
+                    exprs::lt(exprs::integer_literal(1), exprs::integer_literal(2)),
+                ),
+                stmts::expr(exprs::call(
+                    exprs::var("puti"),
+                    vec![exprs::branch(
+                        exprs::var("c"),
+                        exprs::integer_literal(1),
+                        exprs::integer_literal(1),
+                    )],
+                )),
+            ],
+        );
+        // let c = 1 < 2; puti(do { c; then 1; });
+        // Assigned in a fresh context so that the ids match
+        let cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        let expected = assign_id(
+            &cctx,
+            &builtin_ids,
+            vec![
+                stmts::let_(
+                    "c",
+                    exprs::lt(exprs::integer_literal(1), exprs::integer_literal(2)),
+                ),
+                stmts::expr(exprs::call(
+                    exprs::var("puti"),
+                    vec![exprs::block(vec![
+                        stmts::expr(exprs::var("c")),
+                        stmts::then_expr(exprs::integer_literal(1)),
+                    ])],
+                )),
+            ],
+        );
+        simplify_stmts(&mut program);
+        assert_eq!(program, expected);
+    }
+
+    #[test]
+    fn test_simplify_different_branches() {
+        let cctx = CCtx::new();
+        let builtin_ids = BuiltinIds::new(&cctx);
+        // let c = 1 < 2; puti(if c then 1 else 2);
+        // puti(if c then do { let x = 1; then x; } else do { let x = 1; then x; });
+        let branch = exprs::block(vec![
+            stmts::let_("x", exprs::integer_literal(1)),
+            stmts::then_expr(exprs::var("x")),
+        ]);
+        let mut program = assign_id(
+            &cctx,
+            &builtin_ids,
+            vec![
+                stmts::let_(
+                    "c",
+                    exprs::lt(exprs::integer_literal(1), exprs::integer_literal(2)),
+                ),
+                stmts::expr(exprs::call(
+                    exprs::var("puti"),
+                    vec![exprs::branch(
+                        exprs::var("c"),
+                        exprs::integer_literal(1),
+                        exprs::integer_literal(2),
+                    )],
+                )),
+                stmts::expr(exprs::call(
+                    exprs::var("puti"),
+                    vec![exprs::branch(exprs::var("c"), branch.clone(), branch)],
+                )),
+            ],
+        );
+        let expected = program.clone();
+        simplify_stmts(&mut program);
+        assert_eq!(program, expected);
+    }
+}
@@ -1,17 +1,60 @@
 use std::collections::HashMap;
 
-use crate::ast::{Expr, Ident, Stmt};
+use thiserror::Error;
+
+use crate::ast::{BuiltinIds, BuiltinKind, Expr, Ident, Stmt};
 use crate::cctx::Id;
 use crate::ntype::{TyCtx, Type, UnificationFailure};
 
-pub fn typecheck(program: &[Stmt], ty_ctx: &mut TyCtx) -> Result<(), UnificationFailure> {
+#[derive(Debug, Error)]
+pub enum TypeError {
+    #[error(transparent)]
+    Unification(#[from] UnificationFailure),
+    #[error("`if` without `else` cannot be used as a value")]
+    MissingElse,
+    #[error("the program must evaluate to `()`, got `{ty}`")]
+    NonUnitProgram { ty: String },
+}
+
+pub fn typecheck(program: &[Stmt], ty_ctx: &mut TyCtx) -> Result<(), TypeError> {
     TypeChecker::new(ty_ctx).typecheck_program(program)
 }
 
+/// Typechecks the program and returns the inferred type of every variable,
+/// including builtins. The types may refer to metavariables in `ty_ctx`.
+pub fn typecheck_collect(
+    program: &[Stmt],
+    builtin_ids: &BuiltinIds,
+    ty_ctx: &mut TyCtx,
+) -> Result<HashMap<Id, Type>, TypeError> {
+    let mut typechecker = TypeChecker::new(ty_ctx);
+    typechecker.declare_builtins(builtin_ids);
+    typechecker.typecheck_program(program)?;
+    Ok(typechecker.var_types)
+}
+
+/// Same as `typecheck_collect`, but the program may evaluate to any value,
+/// whose type is returned.
+pub fn typecheck_value(
+    program: &[Stmt],
+    builtin_ids: &BuiltinIds,
+    ty_ctx: &mut TyCtx,
+) -> Result<Type, TypeError> {
+    let mut typechecker = TypeChecker::new(ty_ctx);
+    typechecker.declare_builtins(builtin_ids);
+    typechecker.typecheck_stmts(program)
+}
+
 #[derive(Debug)]
 struct TypeChecker<'a> {
     ty_ctx: &'a mut TyCtx,
     var_types: HashMap<Id, Type>,
+    /// Builtins are typed afresh at each use, as some of them are polymorphic
+    builtins: HashMap<Id, BuiltinKind>,
+    /// Return type of the innermost function, for `return`
+    return_type: Type,
+    /// Type of the values given to `break` in the innermost `loop`, once one is seen
+    break_type: Option<Type>,
 }
 
 impl<'a> TypeChecker<'a> {
@@ -19,33 +62,91 @@ impl<'a> TypeChecker<'a> {
         Self {
             ty_ctx,
             var_types: HashMap::new(),
+            builtins: HashMap::new(),
+            return_type: Type::Unit,
+            break_type: None,
         }
     }
-    fn typecheck_program(&mut self, program: &[Stmt]) -> Result<(), UnificationFailure> {
+    fn declare_builtins(&mut self, builtin_ids: &BuiltinIds) {
+        self.builtins = builtin_ids.builtins.clone();
+    }
+    /// The program as a whole must evaluate to `()`; its value is never printed.
+    fn typecheck_program(&mut self, program: &[Stmt]) -> Result<(), TypeError> {
         let ty = self.typecheck_stmts(program)?;
-        ty.unify(&Type::Unit, self.ty_ctx)?;
+        self.join(&ty, &Type::Unit)
+            .map_err(|_| TypeError::NonUnitProgram {
+                ty: ty.view(self.ty_ctx).to_string(),
+            })?;
         Ok(())
     }
-    fn typecheck_stmts(&mut self, stmts: &[Stmt]) -> Result<Type, UnificationFailure> {
+    /// Typechecks the body of a lambda or `try`, in which `return` refers to the body itself.
+    ///
+    /// Scope resolution has already rejected `return` directly inside a `try` body.
+    fn typecheck_function_body(&mut self, body: &Expr) -> Result<Type, TypeError> {
+        let body_ty = Type::fresh(self.ty_ctx);
+        let outer_return_type = std::mem::replace(&mut self.return_type, body_ty.clone());
+        // `break` does not cross function boundaries
+        let outer_break_type = self.break_type.take();
+        let result = self.typecheck_expr(body);
+        self.return_type = outer_return_type;
+        self.break_type = outer_break_type;
+        self.join(&result?, &body_ty)?;
+        Ok(body_ty)
+    }
+    /// Type of a value that comes from either of the two places control can join from.
+    ///
+    /// A diverging place, of type `!`, never gives the value, so the other one decides the type.
+    fn join(&mut self, ty1: &Type, ty2: &Type) -> Result<Type, UnificationFailure> {
+        if ty1.resolve(self.ty_ctx) == &Type::Never {
+            return Ok(ty2.clone());
+        }
+        if ty2.resolve(self.ty_ctx) != &Type::Never {
+            ty1.unify(ty2, self.ty_ctx)?;
+        }
+        Ok(ty1.clone())
+    }
+    fn typecheck_stmts(&mut self, stmts: &[Stmt]) -> Result<Type, TypeError> {
         let mut final_type = Type::Unit;
+        // The parser ensures that only the last statement has `then`
         for stmt in stmts {
             final_type = self.typecheck_stmt(stmt)?;
         }
         Ok(final_type)
     }
 
-    fn typecheck_stmt(&mut self, stmt: &Stmt) -> Result<Type, UnificationFailure> {
+    fn typecheck_stmt(&mut self, stmt: &Stmt) -> Result<Type, TypeError> {
         match stmt {
-            Stmt::Expr { expr, use_value } => {
-                let ty = self.typecheck_expr(expr)?;
-                if *use_value {
+            Stmt::Expr {
+                expr, use_value, ..
+            } => {
+                let ty = match expr {
+                    // An else-less `if` is only allowed when its value is discarded
+                    Expr::Branch { cond, then, else_ } if !*use_value => {
+                        self.typecheck_branch(cond, then, else_.as_deref(), false)?
+                    }
+                    Expr::IfSome {
+                        binder,
+                        scrutinee,
+                        then,
+                        else_,
+                    } if !*use_value => {
+                        self.typecheck_if_some(binder, scrutinee, then, else_.as_deref(), false)?
+                    }
+                    _ => self.typecheck_expr(expr)?,
+                };
+                // `return` and `break` diverge, so their statements can have any type
+                if *use_value || matches!(expr, Expr::Return { .. } | Expr::Break { .. }) {
                     Ok(ty)
                 } else {
                     Ok(Type::Unit)
                 }
             }
-            Stmt::Let { lhs, init } => {
-                let init_ty = self.typecheck_expr(init)?;
+            Stmt::Let { lhs, init, .. } => {
+                let init_ty = if let Some(init) = init {
+                    self.typecheck_expr(init)?
+                } else {
+                    Type::fresh(self.ty_ctx)
+                };
                 debug_assert!(!self.var_types.contains_key(&lhs.id));
                 self.var_types.insert(lhs.id, init_ty);
                 Ok(Type::Unit)
@@ -53,28 +154,29 @@ impl<'a> TypeChecker<'a> {
         }
     }
 
-    fn typecheck_expr(&mut self, expr: &Expr) -> Result<Type, UnificationFailure> {
+    fn typecheck_expr(&mut self, expr: &Expr) -> Result<Type, TypeError> {
         match expr {
             Expr::Var { ident } => {
                 let ty = self.typecheck_ident(ident)?;
                 Ok(ty)
             }
             Expr::Branch { cond, then, else_ } => {
-                let cond_ty = self.typecheck_expr(cond)?;
-                cond_ty.unify(&Type::Bool, self.ty_ctx)?;
-                let then_ty = self.typecheck_expr(then)?;
-                let else_ty = self.typecheck_expr(else_)?;
-                then_ty.unify(&else_ty, self.ty_ctx)?;
-                Ok(then_ty)
+                self.typecheck_branch(cond, then, else_.as_deref(), true)
             }
+            Expr::IfSome {
+                binder,
+                scrutinee,
+                then,
+                else_,
+            } => self.typecheck_if_some(binder, scrutinee, then, else_.as_deref(), true),
             Expr::While { cond, body } => {
                 let cond_ty = self.typecheck_expr(cond)?;
                 cond_ty.unify(&Type::Bool, self.ty_ctx)?;
                 let body_ty = self.typecheck_expr(body)?;
-                body_ty.unify(&Type::Unit, self.ty_ctx)?;
+                self.join(&body_ty, &Type::Unit)?;
                 Ok(Type::Unit)
             }
-            Expr::Block { stmts } => self.typecheck_stmts(stmts),
+            Expr::Block { stmts, .. } => self.typecheck_stmts(stmts),
             Expr::Assign { lhs, rhs } => {
                 let lhs_ty = self.typecheck_ident(lhs)?;
                 let rhs_ty = self.typecheck_expr(rhs)?;
@@ -91,18 +193,36 @@ impl<'a> TypeChecker<'a> {
                 let ret_ty = Type::fresh(&mut self.ty_ctx);
                 let func_ty = Type::function(arg_tys, ret_ty.clone());
                 callee_ty.unify(&func_ty, self.ty_ctx)?;
+                // A call to a diverging function, such as `panic`, diverges as well
+                if let Type::Function { ret, .. } = callee_ty.resolve(self.ty_ctx) {
+                    if ret.resolve(self.ty_ctx) == &Type::Never {
+                        return Ok(Type::Never);
+                    }
+                }
                 Ok(ret_ty)
             }
             Expr::IntegerLiteral { value: _ } => Ok(Type::Integer),
             Expr::StringLiteral { value: _ } => Ok(Type::String),
+            Expr::BooleanLiteral { value: _ } => Ok(Type::Bool),
             Expr::BinOp { op, lhs, rhs } => {
                 let op_ty = match op {
-                    crate::ast::BinOp::Add => {
+                    crate::ast::BinOp::Add
+                    | crate::ast::BinOp::Sub
+                    | crate::ast::BinOp::Mul
+                    | crate::ast::BinOp::Div => {
                         Type::function(vec![Type::Integer, Type::Integer], Type::Integer)
                     }
-                    crate::ast::BinOp::Lt => {
+                    crate::ast::BinOp::Lt
+                    | crate::ast::BinOp::Gt
+                    | crate::ast::BinOp::Le
+                    | crate::ast::BinOp::Ge
+                    | crate::ast::BinOp::Eq
+                    | crate::ast::BinOp::Ne => {
                         Type::function(vec![Type::Integer, Type::Integer], Type::Bool)
                     }
+                    crate::ast::BinOp::And | crate::ast::BinOp::Or => {
+                        Type::function(vec![Type::Bool, Type::Bool], Type::Bool)
+                    }
                 };
                 let lhs_ty = self.typecheck_expr(lhs)?;
                 let rhs_ty = self.typecheck_expr(rhs)?;
@@ -113,21 +233,169 @@ impl<'a> TypeChecker<'a> {
                 )?;
                 Ok(ret_ty)
             }
+            Expr::Lambda { params, body } => {
+                let mut param_tys = Vec::new();
+                for param in params {
+                    let param_ty = Type::fresh(self.ty_ctx);
+                    debug_assert!(!self.var_types.contains_key(&param.id));
+                    self.var_types.insert(param.id, param_ty.clone());
+                    param_tys.push(param_ty);
+                }
+                let body_ty = self.typecheck_function_body(body)?;
+                Ok(Type::function(param_tys, body_ty))
+            }
+            Expr::ArrayLiteral { elems } => {
+                let elem_ty = Type::fresh(self.ty_ctx);
+                for elem in elems {
+                    let ty = self.typecheck_expr(elem)?;
+                    ty.unify(&elem_ty, self.ty_ctx)?;
+                }
+                Ok(Type::array(elem_ty))
+            }
+            Expr::Index { base, index } => {
+                let base_ty = self.typecheck_expr(base)?;
+                if let Expr::Range { start, end } = &**index {
+                    let start_ty = self.typecheck_expr(start)?;
+                    start_ty.unify(&Type::Integer, self.ty_ctx)?;
+                    let end_ty = self.typecheck_expr(end)?;
+                    end_ty.unify(&Type::Integer, self.ty_ctx)?;
+                    // Slicing a string yields a string; anything else must be an array
+                    if base_ty.resolve(self.ty_ctx) != &Type::String {
+                        let elem_ty = Type::fresh(self.ty_ctx);
+                        base_ty.unify(&Type::array(elem_ty), self.ty_ctx)?;
+                    }
+                    return Ok(base_ty);
+                }
+                let elem_ty = Type::fresh(self.ty_ctx);
+                base_ty.unify(&Type::array(elem_ty.clone()), self.ty_ctx)?;
+                let index_ty = self.typecheck_expr(index)?;
+                index_ty.unify(&Type::Integer, self.ty_ctx)?;
+                Ok(elem_ty)
+            }
+            Expr::Range { .. } => unreachable!("range outside of an index"),
+            Expr::Try { body } => {
+                let body_ty = self.typecheck_function_body(body)?;
+                Ok(Type::optional(body_ty))
+            }
+            Expr::Return { value } => {
+                let value_ty = self.typecheck_expr(value)?;
+                value_ty.unify(&self.return_type, self.ty_ctx)?;
+                // Control does not reach the continuation
+                Ok(Type::Never)
+            }
+            Expr::Loop { body } => {
+                let outer_break_type = self.break_type.take();
+                let result = self.typecheck_expr(body);
+                // Without `break`, the loop never finishes
+                let loop_ty = std::mem::replace(&mut self.break_type, outer_break_type)
+                    .unwrap_or(Type::Never);
+                self.join(&result?, &Type::Unit)?;
+                Ok(loop_ty)
+            }
+            Expr::Break { value } => {
+                let value_ty = self.typecheck_expr(value)?;
+                let break_ty = match self.break_type.take() {
+                    Some(break_ty) => self.join(&break_ty, &value_ty)?,
+                    None => value_ty,
+                };
+                self.break_type = Some(break_ty);
+                Ok(Type::Never)
+            }
+            Expr::Commented { expr, .. } => self.typecheck_expr(expr),
         }
     }
 
-    fn typecheck_ident(&mut self, ident: &Ident) -> Result<Type, UnificationFailure> {
+    fn typecheck_branch(
+        &mut self,
+        cond: &Expr,
+        then: &Expr,
+        else_: Option<&Expr>,
+        use_value: bool,
+    ) -> Result<Type, TypeError> {
+        let cond_ty = self.typecheck_expr(cond)?;
+        cond_ty.unify(&Type::Bool, self.ty_ctx)?;
+        self.typecheck_arms(then, else_, use_value)
+    }
+
+    fn typecheck_if_some(
+        &mut self,
+        binder: &Ident,
+        scrutinee: &Expr,
+        then: &Expr,
+        else_: Option<&Expr>,
+        use_value: bool,
+    ) -> Result<Type, TypeError> {
+        let scrutinee_ty = self.typecheck_expr(scrutinee)?;
+        let value_ty = Type::fresh(self.ty_ctx);
+        scrutinee_ty.unify(&Type::optional(value_ty.clone()), self.ty_ctx)?;
+        debug_assert!(!self.var_types.contains_key(&binder.id));
+        self.var_types.insert(binder.id, value_ty);
+        self.typecheck_arms(then, else_, use_value)
+    }
+
+    /// Type of an `if` with the arms, once its condition is checked
+    fn typecheck_arms(
+        &mut self,
+        then: &Expr,
+        else_: Option<&Expr>,
+        use_value: bool,
+    ) -> Result<Type, TypeError> {
+        let then_ty = self.typecheck_expr(then)?;
+        let else_ty = if let Some(else_) = else_ {
+            self.typecheck_expr(else_)?
+        } else if use_value {
+            return Err(TypeError::MissingElse);
+        } else {
+            Type::Unit
+        };
+        Ok(self.join(&then_ty, &else_ty)?)
+    }
+
+    fn typecheck_ident(&mut self, ident: &Ident) -> Result<Type, TypeError> {
         debug_assert!(!ident.id.is_dummy());
+        if let Some(&builtin_kind) = self.builtins.get(&ident.id) {
+            return Ok(builtin_type(builtin_kind, self.ty_ctx));
+        }
         let ty = self.var_types.get(&ident.id).unwrap();
         Ok(ty.clone())
     }
 }
 
+fn builtin_type(f: BuiltinKind, ty_ctx: &mut TyCtx) -> Type {
+    match f {
+        BuiltinKind::Puts => Type::function(vec![Type::String], Type::Unit),
+        BuiltinKind::Puti => Type::function(vec![Type::Integer], Type::Unit),
+        BuiltinKind::StrEq => Type::function(vec![Type::String, Type::String], Type::Bool),
+        BuiltinKind::Panic => Type::function(vec![Type::String], Type::Never),
+        BuiltinKind::PanicWithInt => Type::function(vec![Type::String, Type::Integer], Type::Never),
+        // Works on both strings and arrays; the evaluator rejects other values.
+        BuiltinKind::Len => Type::function(vec![Type::fresh(ty_ctx)], Type::Integer),
+        BuiltinKind::Gets => Type::function(vec![], Type::optional(Type::String)),
+        BuiltinKind::OptionNone => Type::function(vec![], Type::optional(Type::fresh(ty_ctx))),
+        BuiltinKind::OptionSome => {
+            let value_ty = Type::fresh(ty_ctx);
+            Type::function(vec![value_ty.clone()], Type::optional(value_ty))
+        }
+        BuiltinKind::IsSome => {
+            Type::function(vec![Type::optional(Type::fresh(ty_ctx))], Type::Bool)
+        }
+        BuiltinKind::Unwrap => {
+            let value_ty = Type::fresh(ty_ctx);
+            Type::function(vec![Type::optional(value_ty.clone())], value_ty)
+        }
+        BuiltinKind::CharAt => Type::function(vec![Type::String, Type::Integer], Type::Integer),
+        BuiltinKind::TermWidth => Type::function(vec![], Type::Integer),
+        BuiltinKind::Args => Type::function(vec![], Type::array(Type::String)),
+        BuiltinKind::Putc => Type::function(vec![Type::Integer], Type::Unit),
+        BuiltinKind::Repeat => Type::function(vec![Type::String, Type::Integer], Type::String),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use crate::ast::{assign_id_stmt, BinOp, BuiltinIds, Expr, Scope, Stmt};
+    use crate::ast::{assign_id_expr, assign_id_stmt, BinOp, BuiltinIds, Expr, Ident, Scope, Stmt};
     use crate::cctx::CCtx;
     use crate::ntype::Type;
 
@@ -140,6 +408,7 @@ mod tests {
         let mut scope = Scope::new(&builtin_ids);
         let mut ty_ctx = TyCtx::default();
         let mut typechecker = TypeChecker::new(&mut ty_ctx);
+        typechecker.declare_builtins(&builtin_ids);
         f(&cctx, &mut scope, &mut typechecker)
     }
 
@@ -160,6 +429,16 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_typecheck_boolean_literal() {
+        with_typechecker(|_, _, typechecker| {
+            let ty = typechecker
+                .typecheck_expr(&Expr::boolean_literal(true))
+                .unwrap();
+            assert_eq!(ty, Type::Bool);
+        });
+    }
+
     #[test]
     fn test_typecheck_stmts() {
         with_typechecker(|cctx, scope, typechecker| {
@@ -187,4 +466,254 @@ mod tests {
             assert_eq!(ty, Type::Unit);
         });
     }
+
+    #[test]
+    fn test_typecheck_if_without_else_as_value() {
+        with_typechecker(|cctx, scope, typechecker| {
+            let mut stmts = vec![
+                Stmt::let_(
+                    Ident::from("c"),
+                    Expr::bin_op(
+                        BinOp::Lt,
+                        Expr::integer_literal(1),
+                        Expr::integer_literal(2),
+                    ),
+                ),
+                Stmt::let_(
+                    Ident::from("x"),
+                    Expr::branch_without_else(
+                        Expr::var(Ident::from("c")),
+                        Expr::block(vec![Stmt::expr(Expr::integer_literal(1), true)]),
+                    ),
+                ),
+            ];
+            for stmt in &mut stmts {
+                assign_id_stmt(cctx, scope, stmt);
+            }
+            let result = typechecker.typecheck_stmts(&stmts);
+            assert!(matches!(result, Err(TypeError::MissingElse)));
+        });
+    }
+
+    #[test]
+    fn test_typecheck_program_value() {
+        with_typechecker(|cctx, scope, typechecker| {
+            // then do {};
+            let mut stmts = vec![Stmt::expr(Expr::block(vec![]), true)];
+            for stmt in &mut stmts {
+                assign_id_stmt(cctx, scope, stmt);
+            }
+            assert!(typechecker.typecheck_program(&stmts).is_ok());
+        });
+        with_typechecker(|cctx, scope, typechecker| {
+            // then 5;
+            let mut stmts = vec![Stmt::expr(Expr::integer_literal(5), true)];
+            for stmt in &mut stmts {
+                assign_id_stmt(cctx, scope, stmt);
+            }
+            let result = typechecker.typecheck_program(&stmts);
+            assert!(matches!(
+                result,
+                Err(TypeError::NonUnitProgram { ty }) if ty == "Integer"
+            ));
+        });
+    }
+
+    #[test]
+    fn test_typecheck_if_without_else_as_stmt() {
+        with_typechecker(|cctx, scope, typechecker| {
+            let mut stmts = vec![
+                Stmt::let_(
+                    Ident::from("c"),
+                    Expr::bin_op(
+                        BinOp::Lt,
+                        Expr::integer_literal(1),
+                        Expr::integer_literal(2),
+                    ),
+                ),
+                Stmt::let_(Ident::from("f"), Expr::lambda(vec![], Expr::block(vec![]))),
+                Stmt::expr(
+                    Expr::branch_without_else(
+                        Expr::var(Ident::from("c")),
+                        Expr::block(vec![Stmt::expr(
+                            Expr::call(Expr::var(Ident::from("f")), vec![]),
+                            false,
+                        )]),
+                    ),
+                    false,
+                ),
+            ];
+            for stmt in &mut stmts {
+                assign_id_stmt(cctx, scope, stmt);
+            }
+            let ty = typechecker.typecheck_stmts(&stmts).unwrap();
+            assert_eq!(ty, Type::Unit);
+        });
+    }
+
+    #[test]
+    fn test_typecheck_while_integer_cond() {
+        with_typechecker(|cctx, scope, typechecker| {
+            // while 5 {}
+            let mut expr = Expr::while_(Expr::integer_literal(5), Expr::block(vec![]));
+            assign_id_expr(cctx, scope, &mut expr);
+            assert!(typechecker.typecheck_expr(&expr).is_err());
+        });
+    }
+
+    #[test]
+    fn test_typecheck_diverging_branch() {
+        with_typechecker(|cctx, scope, typechecker| {
+            // if 1 < 2 then 1 else panic("x")
+            let mut expr = Expr::branch(
+                Expr::bin_op(
+                    BinOp::Lt,
+                    Expr::integer_literal(1),
+                    Expr::integer_literal(2),
+                ),
+                Expr::integer_literal(1),
+                Expr::call(
+                    Expr::var(Ident::from("panic")),
+                    vec![Expr::string_literal("x".to_owned())],
+                ),
+            );
+            assign_id_expr(cctx, scope, &mut expr);
+            let ty = typechecker.typecheck_expr(&expr).unwrap();
+            assert_eq!(ty.view(typechecker.ty_ctx).to_string(), "Integer");
+        });
+        with_typechecker(|cctx, scope, typechecker| {
+            // if 1 < 2 then panic("x") else "y"
+            let mut expr = Expr::branch(
+                Expr::bin_op(
+                    BinOp::Lt,
+                    Expr::integer_literal(1),
+                    Expr::integer_literal(2),
+                ),
+                Expr::call(
+                    Expr::var(Ident::from("panic")),
+                    vec![Expr::string_literal("x".to_owned())],
+                ),
+                Expr::string_literal("y".to_owned()),
+            );
+            assign_id_expr(cctx, scope, &mut expr);
+            let ty = typechecker.typecheck_expr(&expr).unwrap();
+            assert_eq!(ty.view(typechecker.ty_ctx).to_string(), "String");
+        });
+        with_typechecker(|cctx, scope, typechecker| {
+            // 1 + panic("x")
+            let mut expr = Expr::bin_op(
+                BinOp::Add,
+                Expr::integer_literal(1),
+                Expr::call(
+                    Expr::var(Ident::from("panic")),
+                    vec![Expr::string_literal("x".to_owned())],
+                ),
+            );
+            assign_id_expr(cctx, scope, &mut expr);
+            // Only the join points of control flow let a diverging expression take any type
+            assert!(typechecker.typecheck_expr(&expr).is_err());
+        });
+    }
+
+    #[test]
+    fn test_typecheck_if_some() {
+        with_typechecker(|cctx, scope, typechecker| {
+            // if some(x) = gets() then x else "eof"
+            let mut expr = Expr::if_some(
+                Ident::from("x"),
+                Expr::call(Expr::var(Ident::from("gets")), vec![]),
+                Expr::var(Ident::from("x")),
+                Expr::string_literal("eof".to_owned()),
+            );
+            assign_id_expr(cctx, scope, &mut expr);
+            let ty = typechecker.typecheck_expr(&expr).unwrap();
+            assert_eq!(ty.view(typechecker.ty_ctx).to_string(), "String");
+        });
+        with_typechecker(|cctx, scope, typechecker| {
+            // if some(x) = 1 then x else 0
+            let mut expr = Expr::if_some(
+                Ident::from("x"),
+                Expr::integer_literal(1),
+                Expr::var(Ident::from("x")),
+                Expr::integer_literal(0),
+            );
+            assign_id_expr(cctx, scope, &mut expr);
+            assert!(matches!(
+                typechecker.typecheck_expr(&expr),
+                Err(TypeError::Unification(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_typecheck_loop() {
+        with_typechecker(|cctx, scope, typechecker| {
+            // loop { if 1 < 2 { break 1; }; break 2; }
+            let mut expr = Expr::loop_(Expr::block(vec![
+                Stmt::expr(
+                    Expr::branch_without_else(
+                        Expr::bin_op(
+                            BinOp::Lt,
+                            Expr::integer_literal(1),
+                            Expr::integer_literal(2),
+                        ),
+                        Expr::block(vec![Stmt::expr(
+                            Expr::break_(Expr::integer_literal(1)),
+                            false,
+                        )]),
+                    ),
+                    false,
+                ),
+                Stmt::expr(Expr::break_(Expr::integer_literal(2)), false),
+            ]));
+            assign_id_expr(cctx, scope, &mut expr);
+            let ty = typechecker.typecheck_expr(&expr).unwrap();
+            assert_eq!(ty.view(typechecker.ty_ctx).to_string(), "Integer");
+        });
+        with_typechecker(|cctx, scope, typechecker| {
+            // loop { break 1; break "x"; }
+            let mut expr = Expr::loop_(Expr::block(vec![
+                Stmt::expr(Expr::break_(Expr::integer_literal(1)), false),
+                Stmt::expr(Expr::break_(Expr::string_literal("x".to_owned())), false),
+            ]));
+            assign_id_expr(cctx, scope, &mut expr);
+            assert!(typechecker.typecheck_expr(&expr).is_err());
+        });
+        with_typechecker(|cctx, scope, typechecker| {
+            // loop {}
+            let mut expr = Expr::loop_(Expr::block(vec![]));
+            assign_id_expr(cctx, scope, &mut expr);
+            let ty = typechecker.typecheck_expr(&expr).unwrap();
+            assert_eq!(ty.view(typechecker.ty_ctx).to_string(), "!");
+        });
+    }
+
+    #[test]
+    fn test_typecheck_return_type_mismatch() {
+        with_typechecker(|cctx, scope, typechecker| {
+            // fn(x) { if x < 1 { return "small"; }; then x; }
+            let mut expr = Expr::lambda(
+                vec![Ident::from("x")],
+                Expr::block(vec![
+                    Stmt::expr(
+                        Expr::branch_without_else(
+                            Expr::bin_op(
+                                BinOp::Lt,
+                                Expr::var(Ident::from("x")),
+                                Expr::integer_literal(1),
+                            ),
+                            Expr::block(vec![Stmt::expr(
+                                Expr::return_(Expr::string_literal("small".to_owned())),
+                                false,
+                            )]),
+                        ),
+                        false,
+                    ),
+                    Stmt::expr(Expr::var(Ident::from("x")), true),
+                ]),
+            );
+            assign_id_expr(cctx, scope, &mut expr);
+            assert!(typechecker.typecheck_expr(&expr).is_err());
+        });
+    }
 }
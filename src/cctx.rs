@@ -1,20 +1,33 @@
 // Compiler Context
 
+use std::collections::HashSet;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::sync::atomic::{self, AtomicUsize};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+use crate::CompileOptions;
 
 #[derive(Debug)]
 pub struct CCtx {
     pub id_gen: IdGen,
+    pub options: CompileOptions,
+    pub interner: Interner,
 }
 
 impl CCtx {
     pub fn new() -> Self {
         Self {
             id_gen: IdGen::new(),
+            options: CompileOptions::default(),
+            interner: Interner::default(),
         }
     }
+
+    pub fn with_options(mut self, options: CompileOptions) -> Self {
+        self.options = options;
+        self
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -61,6 +74,73 @@ impl fmt::Debug for Id {
     }
 }
 
+/// Identifier name, cheap to clone and compare.
+///
+/// Symbols from the same `Interner` share their string, so that most comparisons
+/// are those of pointers. The interner keeps the string for as long as it lives,
+/// i.e. for the compilation owning the `CCtx`; a symbol outliving it keeps its own string.
+#[derive(Clone, Eq)]
+pub struct Symbol(Arc<str>);
+
+impl Symbol {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Symbols not from an interner, as in hand-written ASTs, are equal to interned ones by content.
+impl From<&str> for Symbol {
+    fn from(s: &str) -> Self {
+        Symbol(Arc::from(s))
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+/// Deduplicates the strings of the symbols for a compilation.
+///
+/// Clones share the same set of strings, as with `IdGen`.
+#[derive(Debug, Clone, Default)]
+pub struct Interner {
+    strings: Arc<Mutex<HashSet<Arc<str>>>>,
+}
+
+impl Interner {
+    pub fn intern(&self, s: &str) -> Symbol {
+        let mut strings = self.strings.lock().unwrap();
+        if let Some(string) = strings.get(s) {
+            return Symbol(string.clone());
+        }
+        let string = Arc::<str>::from(s);
+        strings.insert(string.clone());
+        Symbol(string)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Symbol::intern")
+            .field(&self.as_str())
+            .finish()
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -72,4 +152,18 @@ mod tests {
         assert_eq!(id_gen.fresh(), Id { number: 2 });
         assert_eq!(id_gen.fresh(), Id { number: 3 });
     }
+
+    #[test]
+    fn test_symbol_intern() {
+        let interner = Interner::default();
+        let foo = interner.intern("foo");
+        let foo2 = interner.clone().intern(&String::from("foo"));
+        assert_eq!(foo2, foo);
+        assert!(std::ptr::eq(foo2.as_str(), foo.as_str()));
+        assert_ne!(interner.intern("bar"), foo);
+        assert_eq!(foo.as_str(), "foo");
+        assert_eq!(foo.to_string(), "foo");
+        // Not interned, but with the same name
+        assert_eq!(Symbol::from("foo"), foo);
+    }
 }
@@ -1,15 +1,46 @@
 use crate::cctx::CCtx;
 use crate::rt_ctx::RtCtx;
 use crate::sir::ProgramUnit;
-use crate::sir_compile::compile;
-use crate::sir_eval::eval1;
+use crate::sir_compile::{allocate_registers, compile};
+use crate::sir_eval::{eval1, eval1_value, Value};
 use crate::sir_typecheck::typecheck;
+use crate::{CheckError, CompileOptions, RunError};
 
-pub fn eval(ctx: &dyn RtCtx, program_unit: &ProgramUnit) {
-    let cctx = CCtx::new();
-    typecheck(&cctx, program_unit).unwrap();
+/// Same as `eval_with_options`, with the default options.
+#[cfg(test)]
+pub fn eval<C: RtCtx + ?Sized>(ctx: &C, program_unit: &ProgramUnit) -> Result<(), RunError> {
+    eval_with_options(ctx, program_unit, &CompileOptions::default())
+}
+
+/// Evaluates the program, specialized to the type of the context,
+/// after checking it with the limits in the options.
+pub fn eval_with_options<C: RtCtx + ?Sized>(
+    ctx: &C,
+    program_unit: &ProgramUnit,
+    options: &CompileOptions,
+) -> Result<(), RunError> {
+    eval1(ctx, &prepare(program_unit, options)?)?;
+    Ok(())
+}
+
+/// Same as `eval_with_options` with the default options, but returns the value of the program.
+pub fn eval_value(ctx: &dyn RtCtx, program_unit: &ProgramUnit) -> Result<Value, RunError> {
+    Ok(eval1_value(
+        ctx,
+        &prepare(program_unit, &CompileOptions::default())?,
+    )?)
+}
+
+/// Checks the lowered program and compiles it for evaluation.
+fn prepare(
+    program_unit: &ProgramUnit,
+    options: &CompileOptions,
+) -> Result<ProgramUnit, CheckError> {
+    let cctx = CCtx::new().with_options(options.clone());
+    typecheck(&cctx, program_unit).map_err(|_| CheckError::LoweredType)?;
+    program_unit.validate(options)?;
     let program_unit = compile(&cctx, program_unit);
-    eval1(ctx, &program_unit)
+    Ok(allocate_registers(&cctx, &program_unit))
 }
 
 #[cfg(test)]
@@ -17,7 +48,8 @@ mod tests {
     use super::*;
 
     use crate::sir::{BasicBlock, BuiltinKind, Function, Inst, ProgramUnit};
-    use crate::testing::MockRtCtx;
+    use crate::sir_eval::RuntimeError;
+    use crate::testing::{fib_program, MockRtCtx};
 
     #[test]
     fn test_puts() {
@@ -34,7 +66,8 @@ mod tests {
                     Inst::return_(tmp1),
                 ])
             })),
-        );
+        )
+        .unwrap();
         assert_eq!(ctx.stdout.lock().unwrap().as_str(), "Hello, world!\n");
     }
 
@@ -67,7 +100,8 @@ mod tests {
                     ]
                 },
             )),
-        );
+        )
+        .unwrap();
         assert_eq!(ctx.stdout.lock().unwrap().as_str(), "Hello, world!\n");
     }
 
@@ -94,7 +128,8 @@ mod tests {
                     ])
                 },
             )),
-        );
+        )
+        .unwrap();
         assert_eq!(ctx.stdout.lock().unwrap().as_str(), "2\n");
     }
 
@@ -139,7 +174,8 @@ mod tests {
                     ]
                 },
             )),
-        );
+        )
+        .unwrap();
         assert_eq!(ctx.stdout.lock().unwrap().as_str(), "x is true\n");
     }
 
@@ -184,10 +220,115 @@ mod tests {
                     ]
                 },
             )),
-        );
+        )
+        .unwrap();
         assert_eq!(ctx.stdout.lock().unwrap().as_str(), "x is false\n");
     }
 
+    #[test]
+    fn test_str_eq_true() {
+        let ctx = MockRtCtx::new();
+        eval(
+            &ctx,
+            &ProgramUnit::simple(Function::describe(
+                0,
+                |[s, t, cond, str_eq1, msg, tmp1, puts1, tmp2],
+                 [entry, branch_then, branch_else]| {
+                    vec![
+                        (
+                            entry,
+                            BasicBlock::new(vec![
+                                Inst::literal(s, "a"),
+                                Inst::literal(t, "a"),
+                                Inst::builtin(str_eq1, BuiltinKind::StrEq),
+                                Inst::push_arg(s),
+                                Inst::push_arg(t),
+                                Inst::call(cond, str_eq1),
+                                Inst::branch(cond, branch_then, branch_else),
+                            ]),
+                        ),
+                        (
+                            branch_then,
+                            BasicBlock::new(vec![
+                                Inst::literal(msg, "eq"),
+                                Inst::builtin(puts1, BuiltinKind::Puts),
+                                Inst::push_arg(msg),
+                                Inst::call(tmp2, puts1),
+                                Inst::literal(tmp1, ()),
+                                Inst::return_(tmp1),
+                            ]),
+                        ),
+                        (
+                            branch_else,
+                            BasicBlock::new(vec![
+                                Inst::literal(msg, "ne"),
+                                Inst::builtin(puts1, BuiltinKind::Puts),
+                                Inst::push_arg(msg),
+                                Inst::call(tmp2, puts1),
+                                Inst::literal(tmp1, ()),
+                                Inst::return_(tmp1),
+                            ]),
+                        ),
+                    ]
+                },
+            )),
+        )
+        .unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "eq\n");
+    }
+
+    #[test]
+    fn test_str_eq_false() {
+        let ctx = MockRtCtx::new();
+        eval(
+            &ctx,
+            &ProgramUnit::simple(Function::describe(
+                0,
+                |[s, t, cond, str_eq1, msg, tmp1, puts1, tmp2],
+                 [entry, branch_then, branch_else]| {
+                    vec![
+                        (
+                            entry,
+                            BasicBlock::new(vec![
+                                Inst::literal(s, "a"),
+                                Inst::literal(t, "b"),
+                                Inst::builtin(str_eq1, BuiltinKind::StrEq),
+                                Inst::push_arg(s),
+                                Inst::push_arg(t),
+                                Inst::call(cond, str_eq1),
+                                Inst::branch(cond, branch_then, branch_else),
+                            ]),
+                        ),
+                        (
+                            branch_then,
+                            BasicBlock::new(vec![
+                                Inst::literal(msg, "eq"),
+                                Inst::builtin(puts1, BuiltinKind::Puts),
+                                Inst::push_arg(msg),
+                                Inst::call(tmp2, puts1),
+                                Inst::literal(tmp1, ()),
+                                Inst::return_(tmp1),
+                            ]),
+                        ),
+                        (
+                            branch_else,
+                            BasicBlock::new(vec![
+                                Inst::literal(msg, "ne"),
+                                Inst::builtin(puts1, BuiltinKind::Puts),
+                                Inst::push_arg(msg),
+                                Inst::call(tmp2, puts1),
+                                Inst::literal(tmp1, ()),
+                                Inst::return_(tmp1),
+                            ]),
+                        ),
+                    ]
+                },
+            )),
+        )
+        .unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "ne\n");
+    }
+
     #[test]
     fn test_sum() {
         // let mut sum = 0;
@@ -264,112 +405,229 @@ mod tests {
                     ]
                 },
             )),
-        );
+        )
+        .unwrap();
         assert_eq!(ctx.stdout.lock().unwrap().as_str(), "45\n");
     }
 
     #[test]
-    fn test_fib() {
-        // let fib = fn(n) {
-        //     if n < 2 {
-        //         n
-        //     } else {
-        //         fib(n - 1) + fib(n - 2)
-        //     }
-        // };
-        // puti(fib(10));
+    fn test_array_index() {
+        // puti([10, 20, 30][1]);
+        let ctx = MockRtCtx::new();
+        eval(
+            &ctx,
+            &ProgramUnit::simple(Function::simple(
+                0,
+                |[tmp1, index1, arr1, elem1, elem2, elem3, idx1, tmp2, puti1, tmp3]| {
+                    BasicBlock::new(vec![
+                        Inst::builtin(index1, BuiltinKind::Index),
+                        Inst::literal(elem1, 10),
+                        Inst::literal(elem2, 20),
+                        Inst::literal(elem3, 30),
+                        Inst::push_arg(elem1),
+                        Inst::push_arg(elem2),
+                        Inst::push_arg(elem3),
+                        Inst::array(arr1),
+                        Inst::literal(idx1, 1),
+                        Inst::push_arg(arr1),
+                        Inst::push_arg(idx1),
+                        Inst::call(tmp2, index1),
+                        Inst::builtin(puti1, BuiltinKind::Puti),
+                        Inst::push_arg(tmp2),
+                        Inst::call(tmp3, puti1),
+                        Inst::literal(tmp1, ()),
+                        Inst::return_(tmp1),
+                    ])
+                },
+            )),
+        )
+        .unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "20\n");
+    }
 
+    #[test]
+    fn test_string_slice() {
+        // puts("hello"[1..3]);
         let ctx = MockRtCtx::new();
         eval(
             &ctx,
-            &ProgramUnit::describe(|[entry, fib]| {
-                vec![
-                    (
-                        entry,
-                        Function::simple(0, |[tmp1, fib1, tmp2, tmp3, puti1, tmp4]| {
-                            BasicBlock::new(vec![
-                                // tmp1 = 10;
-                                Inst::literal(tmp1, 10),
-                                // tmp2 = fib(tmp1);
-                                Inst::closure(fib1, fib),
-                                Inst::push_arg(tmp1),
-                                Inst::call(tmp2, fib1),
-                                // puti(tmp2);
-                                Inst::builtin(puti1, BuiltinKind::Puti),
-                                Inst::push_arg(tmp2),
-                                Inst::call(tmp4, puti1),
-                                // return;
-                                Inst::literal(tmp3, ()),
-                                Inst::return_(tmp3),
-                            ])
-                        }),
-                    ),
-                    (
-                        fib,
-                        Function::describe(
-                            1,
-                            |[n, tmp1, lt1, tmp2, tmp3, tmp4, add1, tmp5, fib1, tmp6, tmp7, tmp8, tmp9],
-                             [entry, branch_then, branch_else]| {
-                                vec![
-                                    (
-                                        entry,
-                                        BasicBlock::new(vec![
-                                            // tmp1 = n < 2;
-                                            Inst::literal(tmp2, 2),
-                                            Inst::builtin(lt1, BuiltinKind::Lt),
-                                            Inst::push_arg(n),
-                                            Inst::push_arg(tmp2),
-                                            Inst::call(tmp1, lt1),
-                                            // if tmp1 { goto branch_then; } else { goto branch_else; };
-                                            Inst::branch(tmp1, branch_then, branch_else),
-                                        ]),
-                                    ),
-                                    (
-                                        branch_then,
-                                        BasicBlock::new(vec![
-                                            // return n;
-                                            Inst::return_(n),
-                                        ]),
-                                    ),
-                                    (
-                                        branch_else,
-                                        BasicBlock::new(vec![
-                                            // tmp4 = n - 1;
-                                            Inst::literal(tmp5, -1),
-                                            Inst::builtin(add1, BuiltinKind::Add),
-                                            Inst::push_arg(n),
-                                            Inst::push_arg(tmp5),
-                                            Inst::call(tmp4, add1),
-                                            // tmp6 = fib(tmp4);
-                                            Inst::closure(fib1, fib),
-                                            Inst::push_arg(tmp4),
-                                            Inst::call(tmp6, fib1),
-                                            // tmp7 = n - 2;
-                                            Inst::literal(tmp8, -2),
-                                            Inst::builtin(add1, BuiltinKind::Add),
-                                            Inst::push_arg(n),
-                                            Inst::push_arg(tmp8),
-                                            Inst::call(tmp7, add1),
-                                            // tmp9 = fib(tmp7);
-                                            Inst::closure(fib1, fib),
-                                            Inst::push_arg(tmp7),
-                                            Inst::call(tmp9, fib1),
-                                            // tmp3 = tmp6 + tmp9;
-                                            Inst::builtin(add1, BuiltinKind::Add),
-                                            Inst::push_arg(tmp6),
-                                            Inst::push_arg(tmp9),
-                                            Inst::call(tmp3, add1),
-                                            // return tmp3;
-                                            Inst::return_(tmp3),
-                                        ]),
-                                    ),
-                                ]
-                            },
-                        ),
-                    ),
-                ]
-            }),
+            &ProgramUnit::simple(Function::simple(
+                0,
+                |[tmp1, slice1, s, start, end, tmp2, puts1, tmp3]| {
+                    BasicBlock::new(vec![
+                        Inst::builtin(slice1, BuiltinKind::Slice),
+                        Inst::literal(s, "hello"),
+                        Inst::literal(start, 1),
+                        Inst::literal(end, 3),
+                        Inst::push_arg(s),
+                        Inst::push_arg(start),
+                        Inst::push_arg(end),
+                        Inst::call(tmp2, slice1),
+                        Inst::builtin(puts1, BuiltinKind::Puts),
+                        Inst::push_arg(tmp2),
+                        Inst::call(tmp3, puts1),
+                        Inst::literal(tmp1, ()),
+                        Inst::return_(tmp1),
+                    ])
+                },
+            )),
+        )
+        .unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "el\n");
+    }
+
+    #[test]
+    fn test_array_slice() {
+        // puti([10, 20, 30][1..3][1]);
+        let ctx = MockRtCtx::new();
+        eval(
+            &ctx,
+            &ProgramUnit::simple(Function::simple(
+                0,
+                |[tmp1, slice1, index1, arr1, elem1, elem2, elem3, start, end, arr2, idx1, tmp2, puti1, tmp3]| {
+                    BasicBlock::new(vec![
+                        Inst::builtin(slice1, BuiltinKind::Slice),
+                        Inst::builtin(index1, BuiltinKind::Index),
+                        Inst::literal(elem1, 10),
+                        Inst::literal(elem2, 20),
+                        Inst::literal(elem3, 30),
+                        Inst::push_arg(elem1),
+                        Inst::push_arg(elem2),
+                        Inst::push_arg(elem3),
+                        Inst::array(arr1),
+                        Inst::literal(start, 1),
+                        Inst::literal(end, 3),
+                        Inst::push_arg(arr1),
+                        Inst::push_arg(start),
+                        Inst::push_arg(end),
+                        Inst::call(arr2, slice1),
+                        Inst::literal(idx1, 1),
+                        Inst::push_arg(arr2),
+                        Inst::push_arg(idx1),
+                        Inst::call(tmp2, index1),
+                        Inst::builtin(puti1, BuiltinKind::Puti),
+                        Inst::push_arg(tmp2),
+                        Inst::call(tmp3, puti1),
+                        Inst::literal(tmp1, ()),
+                        Inst::return_(tmp1),
+                    ])
+                },
+            )),
+        )
+        .unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "30\n");
+    }
+
+    #[test]
+    fn test_slice_out_of_bounds() {
+        // puts("hello"[3..6]);
+        let ctx = MockRtCtx::new();
+        let result = eval(
+            &ctx,
+            &ProgramUnit::simple(Function::simple(
+                0,
+                |[tmp1, slice1, s, start, end, tmp2, puts1, tmp3]| {
+                    BasicBlock::new(vec![
+                        Inst::builtin(slice1, BuiltinKind::Slice),
+                        Inst::literal(s, "hello"),
+                        Inst::literal(start, 3),
+                        Inst::literal(end, 6),
+                        Inst::push_arg(s),
+                        Inst::push_arg(start),
+                        Inst::push_arg(end),
+                        Inst::call(tmp2, slice1),
+                        Inst::builtin(puts1, BuiltinKind::Puts),
+                        Inst::push_arg(tmp2),
+                        Inst::call(tmp3, puts1),
+                        Inst::literal(tmp1, ()),
+                        Inst::return_(tmp1),
+                    ])
+                },
+            )),
         );
+        assert!(matches!(
+            result,
+            Err(RunError::Runtime(RuntimeError::IndexOutOfBounds {
+                index: 6,
+                len: 5
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_panic() {
+        // panic("boom");
+        // puts("unreachable");
+        let ctx = MockRtCtx::new();
+        let result = eval(
+            &ctx,
+            &ProgramUnit::simple(Function::simple(
+                0,
+                |[tmp1, panic1, msg1, tmp2, puts1, msg2, tmp3]| {
+                    BasicBlock::new(vec![
+                        Inst::builtin(panic1, BuiltinKind::Panic),
+                        Inst::literal(msg1, "boom"),
+                        Inst::push_arg(msg1),
+                        Inst::call(tmp2, panic1),
+                        Inst::builtin(puts1, BuiltinKind::Puts),
+                        Inst::literal(msg2, "unreachable"),
+                        Inst::push_arg(msg2),
+                        Inst::call(tmp3, puts1),
+                        Inst::literal(tmp1, ()),
+                        Inst::return_(tmp1),
+                    ])
+                },
+            )),
+        );
+        assert!(matches!(
+            result,
+            Err(RunError::Runtime(RuntimeError::Panic(msg))) if msg == "boom"
+        ));
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "");
+    }
+
+    #[test]
+    fn test_fib_size() {
+        let cctx = CCtx::new();
+        let program_unit = fib_program();
+        assert_eq!(program_unit.instruction_count(), 37);
+        assert_eq!(program_unit.estimated_cost(), 72);
+        let program_unit = compile(&cctx, &program_unit);
+        assert_eq!(program_unit.instruction_count(), 38);
+        assert_eq!(program_unit.estimated_cost(), 73);
+    }
+
+    #[test]
+    fn test_fib() {
+        let ctx = MockRtCtx::new();
+        eval(&ctx, &fib_program()).unwrap();
         assert_eq!(ctx.stdout.lock().unwrap().as_str(), "55\n");
     }
+
+    #[test]
+    fn test_fib_allocate_registers() {
+        let cctx = CCtx::new();
+        let compiled = compile(&cctx, &fib_program());
+        let allocated = allocate_registers(&cctx, &compiled);
+        assert!(allocated.functions[1].num_vars < compiled.functions[1].num_vars);
+        // Every variable of `fib_program` is named, and so is every slot
+        assert_eq!(allocated.functions[1].num_temps(), 0);
+        // Temporaries are counted apart from the named variables after allocation
+        let mut with_temps = compiled.clone();
+        let num_args = with_temps.functions[1].num_args;
+        with_temps.functions[1].num_named_vars = num_args;
+        let allocated_with_temps = allocate_registers(&cctx, &with_temps);
+        assert_eq!(allocated_with_temps.functions[1].num_named_vars, num_args);
+        assert_eq!(
+            allocated_with_temps.functions[1].num_vars,
+            allocated.functions[1].num_vars
+        );
+
+        let ctx1 = MockRtCtx::new();
+        eval1(&ctx1, &compiled).unwrap();
+        let ctx2 = MockRtCtx::new();
+        eval1(&ctx2, &allocated).unwrap();
+        assert_eq!(ctx1.stdout.lock().unwrap().as_str(), "55\n");
+        assert_eq!(ctx2.stdout.lock().unwrap().as_str(), "55\n");
+    }
 }
@@ -0,0 +1,491 @@
+// Formatter -- prints the AST back as umo source code
+
+use std::fmt::Write;
+
+use crate::ast::{BinOp, Expr, Stmt};
+
+const INDENT: &str = "  ";
+
+/// Prints the program in the canonical layout, including the preamble.
+///
+/// Comments are printed only if the program was parsed with comments kept;
+/// `trailing_comments` are those after the last statement.
+pub fn format_program(stmts: &[Stmt], trailing_comments: &[String]) -> String {
+    let mut formatter = Formatter::default();
+    formatter.out.push_str("use lang::\"0.0.1\";\n");
+    for stmt in stmts {
+        formatter.format_stmt(stmt);
+    }
+    formatter.format_dangling_comments(trailing_comments);
+    formatter.out
+}
+
+/// Precedence of an expression; an operand is parenthesized when its precedence is too low.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Prec {
+    /// Assignments and `return`, which extend as far to the right as possible
+    Open,
+    LogicalOr,
+    LogicalAnd,
+    Comparison,
+    Additive,
+    Multiplicative,
+    /// Calls, indexing, and primary expressions
+    Postfix,
+}
+
+#[derive(Debug, Default)]
+struct Formatter {
+    out: String,
+    indent: usize,
+}
+
+impl Formatter {
+    fn format_stmt(&mut self, stmt: &Stmt) {
+        for comment in stmt.leading_comments() {
+            self.write_indent();
+            self.out.push_str(comment);
+            self.out.push('\n');
+        }
+        self.write_indent();
+        match stmt {
+            Stmt::Let { lhs, init, .. } => {
+                write!(self.out, "let {}", lhs.name).unwrap();
+                if let Some(init) = init {
+                    self.out.push_str(" = ");
+                    self.format_expr(init, Prec::Open);
+                }
+            }
+            Stmt::Expr {
+                expr, use_value, ..
+            } => {
+                if *use_value {
+                    self.out.push_str("then ");
+                }
+                self.format_expr(expr, Prec::Open);
+            }
+        }
+        self.out.push(';');
+        for comment in stmt.trailing_comments() {
+            self.out.push(' ');
+            self.out.push_str(comment);
+        }
+        self.out.push('\n');
+    }
+
+    /// Formats comments on their own lines, e.g. those at the end of a block
+    fn format_dangling_comments(&mut self, comments: &[String]) {
+        for comment in comments {
+            self.write_indent();
+            self.out.push_str(comment);
+            self.out.push('\n');
+        }
+    }
+
+    fn format_expr(&mut self, expr: &Expr, min_prec: Prec) {
+        if prec_of(expr) < min_prec {
+            self.out.push('(');
+            self.format_expr(expr, Prec::Open);
+            self.out.push(')');
+            return;
+        }
+        match expr {
+            Expr::Var { ident } => self.out.push_str(ident.name.as_str()),
+            Expr::Branch { cond, then, else_ } => {
+                self.out.push_str("if ");
+                self.format_expr(cond, Prec::Open);
+                self.format_if_arms(then, else_.as_deref());
+            }
+            Expr::IfSome {
+                binder,
+                scrutinee,
+                then,
+                else_,
+            } => {
+                write!(self.out, "if some({}) = ", binder.name).unwrap();
+                self.format_expr(scrutinee, Prec::Open);
+                self.format_if_arms(then, else_.as_deref());
+            }
+            Expr::While { cond, body } => {
+                self.out.push_str("while ");
+                self.format_expr(cond, Prec::Open);
+                self.out.push(' ');
+                self.format_expr(body, Prec::Open);
+            }
+            Expr::Block {
+                stmts,
+                trailing_comments,
+            } => {
+                if stmts.is_empty() && trailing_comments.is_empty() {
+                    self.out.push_str("{}");
+                    return;
+                }
+                self.out.push_str("{\n");
+                self.indent += 1;
+                for stmt in stmts {
+                    self.format_stmt(stmt);
+                }
+                self.format_dangling_comments(trailing_comments);
+                self.indent -= 1;
+                self.write_indent();
+                self.out.push('}');
+            }
+            Expr::Assign { lhs, rhs } => {
+                write!(self.out, "{} = ", lhs.name).unwrap();
+                self.format_expr(rhs, Prec::Open);
+            }
+            Expr::Call { callee, args } => {
+                self.format_postfix_operand(callee);
+                self.out.push('(');
+                self.format_exprs(args);
+                self.out.push(')');
+            }
+            Expr::IntegerLiteral { value } => write!(self.out, "{}", value).unwrap(),
+            Expr::StringLiteral { value } => {
+                self.out.push('"');
+                for c in value.chars() {
+                    match c {
+                        '\n' => self.out.push_str("\\n"),
+                        '\t' => self.out.push_str("\\t"),
+                        '\0' => self.out.push_str("\\0"),
+                        '\\' | '"' => write!(self.out, "\\{}", c).unwrap(),
+                        _ => self.out.push(c),
+                    }
+                }
+                self.out.push('"');
+            }
+            Expr::BooleanLiteral { value } => write!(self.out, "{}", value).unwrap(),
+            Expr::BinOp { op, lhs, rhs } => {
+                let (op_str, prec) = match op {
+                    BinOp::Add => ("+", Prec::Additive),
+                    BinOp::Sub => ("-", Prec::Additive),
+                    BinOp::Mul => ("*", Prec::Multiplicative),
+                    BinOp::Div => ("/", Prec::Multiplicative),
+                    BinOp::Lt => ("<", Prec::Comparison),
+                    BinOp::Gt => (">", Prec::Comparison),
+                    BinOp::Le => ("<=", Prec::Comparison),
+                    BinOp::Ge => (">=", Prec::Comparison),
+                    BinOp::Eq => ("==", Prec::Comparison),
+                    BinOp::Ne => ("!=", Prec::Comparison),
+                    BinOp::And => ("&&", Prec::LogicalAnd),
+                    BinOp::Or => ("||", Prec::LogicalOr),
+                };
+                // Binary operators are left-associative
+                self.format_expr(lhs, prec);
+                write!(self.out, " {} ", op_str).unwrap();
+                self.format_expr(rhs, next_prec(prec));
+            }
+            Expr::Lambda { params, body } => {
+                self.out.push_str("fn(");
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.out.push_str(param.name.as_str());
+                }
+                self.out.push_str(") ");
+                self.format_expr(body, Prec::Open);
+            }
+            Expr::ArrayLiteral { elems } => {
+                self.out.push('[');
+                self.format_exprs(elems);
+                self.out.push(']');
+            }
+            Expr::Index { base, index } => {
+                self.format_postfix_operand(base);
+                self.out.push('[');
+                self.format_expr(index, Prec::Open);
+                self.out.push(']');
+            }
+            Expr::Range { start, end } => {
+                self.format_expr(start, Prec::Open);
+                self.out.push_str("..");
+                self.format_expr(end, Prec::Open);
+            }
+            Expr::Try { body } => {
+                self.out.push_str("try ");
+                self.format_expr(body, Prec::Open);
+            }
+            Expr::Return { value } => {
+                self.out.push_str("return ");
+                self.format_expr(value, Prec::Open);
+            }
+            Expr::Loop { body } => {
+                self.out.push_str("loop ");
+                self.format_expr(body, Prec::Open);
+            }
+            Expr::Break { value } => {
+                self.out.push_str("break ");
+                self.format_expr(value, Prec::Open);
+            }
+            Expr::Commented { comments, expr } => {
+                for comment in comments {
+                    self.out.push_str(comment);
+                    if comment.starts_with("//") {
+                        // A line comment extends to the end of the line
+                        self.out.push('\n');
+                        self.indent += 1;
+                        self.write_indent();
+                        self.indent -= 1;
+                    } else {
+                        self.out.push(' ');
+                    }
+                }
+                self.format_expr(expr, min_prec);
+            }
+        }
+    }
+
+    /// Formats what follows the condition of an `if`
+    /// Formats the callee or the indexed expression.
+    ///
+    /// The else arm of `if .. then .. else ..` would take the postfix operator,
+    /// so such an `if` is parenthesized.
+    fn format_postfix_operand(&mut self, expr: &Expr) {
+        if is_then_else(expr) {
+            self.out.push('(');
+            self.format_expr(expr, Prec::Open);
+            self.out.push(')');
+        } else {
+            self.format_expr(expr, Prec::Postfix);
+        }
+    }
+
+    fn format_if_arms(&mut self, then: &Expr, else_: Option<&Expr>) {
+        match (then, else_) {
+            (Expr::Block { .. }, None) => {
+                self.out.push(' ');
+                self.format_expr(then, Prec::Open);
+            }
+            (Expr::Block { .. }, Some(else_)) if is_block_style(else_) => {
+                self.out.push(' ');
+                self.format_expr(then, Prec::Open);
+                self.out.push_str(" else ");
+                self.format_expr(else_, Prec::Open);
+            }
+            (_, Some(else_)) => {
+                self.out.push_str(" then ");
+                self.format_expr(then, Prec::Open);
+                self.out.push_str(" else ");
+                // Only a unary or postfix expression may follow `else` here
+                self.format_expr(else_, Prec::Postfix);
+            }
+            (_, None) => {
+                self.out.push_str(" { then ");
+                self.format_expr(then, Prec::Open);
+                self.out.push_str("; }");
+            }
+        }
+    }
+
+    fn format_exprs(&mut self, exprs: &[Expr]) {
+        for (i, expr) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.format_expr(expr, Prec::Open);
+        }
+    }
+
+    fn write_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+}
+
+fn prec_of(expr: &Expr) -> Prec {
+    match expr {
+        Expr::Assign { .. } | Expr::Return { .. } | Expr::Break { .. } => Prec::Open,
+        Expr::BinOp { op: BinOp::Or, .. } => Prec::LogicalOr,
+        Expr::BinOp { op: BinOp::And, .. } => Prec::LogicalAnd,
+        Expr::BinOp {
+            op: BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge | BinOp::Eq | BinOp::Ne,
+            ..
+        } => Prec::Comparison,
+        Expr::BinOp {
+            op: BinOp::Add | BinOp::Sub,
+            ..
+        } => Prec::Additive,
+        Expr::BinOp {
+            op: BinOp::Mul | BinOp::Div,
+            ..
+        } => Prec::Multiplicative,
+        Expr::Commented { expr, .. } => prec_of(expr),
+        _ => Prec::Postfix,
+    }
+}
+
+fn next_prec(prec: Prec) -> Prec {
+    match prec {
+        Prec::Open => Prec::LogicalOr,
+        Prec::LogicalOr => Prec::LogicalAnd,
+        Prec::LogicalAnd => Prec::Comparison,
+        Prec::Comparison => Prec::Additive,
+        Prec::Additive => Prec::Multiplicative,
+        Prec::Multiplicative | Prec::Postfix => Prec::Postfix,
+    }
+}
+
+/// Whether the expression may follow `else` in a block-style `if`
+fn is_block_style(expr: &Expr) -> bool {
+    match expr {
+        Expr::Block { .. } => true,
+        Expr::Branch {
+            then, else_: None, ..
+        } => matches!(**then, Expr::Block { .. }),
+        Expr::Branch {
+            then,
+            else_: Some(else_),
+            ..
+        } => matches!(**then, Expr::Block { .. }) && is_block_style(else_),
+        Expr::IfSome {
+            then, else_: None, ..
+        } => matches!(**then, Expr::Block { .. }),
+        Expr::IfSome {
+            then,
+            else_: Some(else_),
+            ..
+        } => matches!(**then, Expr::Block { .. }) && is_block_style(else_),
+        Expr::Commented { expr, .. } => is_block_style(expr),
+        _ => false,
+    }
+}
+
+/// Whether the expression is formatted as `if .. then .. else ..`
+fn is_then_else(expr: &Expr) -> bool {
+    match expr {
+        Expr::Branch {
+            then,
+            else_: Some(else_),
+            ..
+        }
+        | Expr::IfSome {
+            then,
+            else_: Some(else_),
+            ..
+        } => !(matches!(**then, Expr::Block { .. }) && is_block_style(else_)),
+        Expr::Commented { expr, .. } => is_then_else(expr),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::parser::{parse, parse_with_comments};
+
+    #[test]
+    fn test_format_program_comments() {
+        let source = concat!(
+            "use lang::\"0.0.1\";\n",
+            "// The answer\n",
+            "let x = 42;\n",
+            "let f = fn(y) {\n",
+            "  // Inside a block\n",
+            "  then x + y;\n",
+            "};\n",
+        );
+        let (stmts, trailing_comments) = parse_with_comments(source).unwrap();
+        assert_eq!(format_program(&stmts, &trailing_comments), source);
+        // Without the option, comments are not kept
+        let stmts = parse(source).unwrap();
+        assert!(!format_program(&stmts, &[]).contains("//"));
+    }
+
+    fn format_with_comments(source: &str) -> String {
+        let (stmts, trailing_comments) = parse_with_comments(source).unwrap();
+        format_program(&stmts, &trailing_comments)
+    }
+
+    #[test]
+    fn test_format_program_trailing_comments() {
+        let source = concat!(
+            "use lang::\"0.0.1\";\n",
+            "let x = 42; // The answer\n",
+            "let f = fn(y) {\n",
+            "  then x + y; /* sum */ // of two\n",
+            "  // End of block\n",
+            "};\n",
+            "if true {\n",
+            "  // Empty block\n",
+            "};\n",
+            "// End of file\n",
+        );
+        assert_eq!(format_with_comments(source), source);
+    }
+
+    #[test]
+    fn test_format_program_inline_comments() {
+        let source = "use lang::\"0.0.1\";\nputi(1 /* mid */ + 2);\nputi(/* arg */ 3 /* end */);\n";
+        let formatted = format_with_comments(source);
+        assert_eq!(
+            formatted,
+            "use lang::\"0.0.1\";\nputi(1 + /* mid */ 2);\nputi(/* arg */ 3); /* end */\n"
+        );
+        assert_eq!(format_with_comments(&formatted), formatted);
+
+        let source = "use lang::\"0.0.1\";\nputi(1 + // next\n  2);\n";
+        assert_eq!(format_with_comments(source), source);
+    }
+
+    #[test]
+    fn test_format_program_round_trip() {
+        let source = concat!(
+            "use lang::\"0.0.1\";\n",
+            "let a = (1 + 2) + (3 - 4) * (5 / 6);\n",
+            "let b = a < (a + 1);\nlet g = [a > 1, a <= 2, (a - 1) >= 3, a == 1, a != 2];\nlet t = if true then false else b;\n",
+            "let h = (b || b) && (b && b || b);\n",
+            "let c = [fn(x) { then x; }(1), if b then 1 else (a + 1)];\n",
+            "while a < 10 { a = a + 1; };\n",
+            "let d = try { then c[0] + c[1..2][0]; };\n",
+            "if b { puti(1); } else if a < 5 { puti(2); } else { puti(3); };\n",
+            "let e = loop { if b { break a; }; };\n",
+            "if some(s) = gets() { puts(s); } else if some(n) = some(a) { puti(n); };\n",
+            "puts(\"tab\\there\\n\\\"quoted\\\" \\\\\");\n",
+            "let n = [-5, - 2147483648, -a];\n",
+            "let k = [if b then 1 else c[0], (if b then puti else puts)(1)];\n",
+        );
+        let formatted = format_program(&parse(source).unwrap(), &[]);
+        assert_eq!(
+            formatted,
+            concat!(
+                "use lang::\"0.0.1\";\n",
+                "let a = 1 + 2 + (3 - 4) * (5 / 6);\n",
+                "let b = a < a + 1;\nlet g = [a > 1, a <= 2, a - 1 >= 3, a == 1, a != 2];\nlet t = if true then false else b;\n",
+                "let h = (b || b) && (b && b || b);\n",
+                "let c = [fn(x) {\n",
+                "  then x;\n",
+                "}(1), if b then 1 else (a + 1)];\n",
+                "while a < 10 {\n",
+                "  a = a + 1;\n",
+                "};\n",
+                "let d = try {\n",
+                "  then c[0] + c[1..2][0];\n",
+                "};\n",
+                "if b {\n",
+                "  puti(1);\n",
+                "} else if a < 5 {\n",
+                "  puti(2);\n",
+                "} else {\n",
+                "  puti(3);\n",
+                "};\n",
+                "let e = loop {\n",
+                "  if b {\n",
+                "    break a;\n",
+                "  };\n",
+                "};\n",
+                "if some(s) = gets() {\n",
+                "  puts(s);\n",
+                "} else if some(n) = some(a) {\n",
+                "  puti(n);\n",
+                "};\n",
+                "puts(\"tab\\there\\n\\\"quoted\\\" \\\\\");\n",
+                "let n = [-5, -2147483648, 0 - a];\n",
+                "let k = [if b then 1 else c[0], (if b then puti else puts)(1)];\n",
+            )
+        );
+        assert_eq!(parse(&formatted).unwrap(), parse(source).unwrap());
+    }
+}
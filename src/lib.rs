@@ -1,35 +1,433 @@
+use std::fmt::Write;
 use std::fs;
 use std::path::Path;
 
-use ast::BuiltinIds;
+use thiserror::Error;
+
+use ast::{BuiltinIds, ScopeError, Stmt};
+use ast_typecheck::TypeError;
 use cctx::CCtx;
+use ntype::TyCtx;
+use parser::ParseError;
+use sir_validation::SirValidationError;
+
+pub use ast::Warning;
+pub use sir_eval::{RuntimeError, Value};
 
 mod ast;
 mod ast_lowering;
+mod ast_simplify;
 pub mod ast_typecheck;
 mod cctx;
 mod eval_;
+mod format;
 pub mod ntype;
 mod parser;
+mod prelude;
 pub mod rt_ctx;
 mod sir;
 mod sir_compile;
+mod sir_dom;
 mod sir_eval;
 mod sir_typecheck;
 mod sir_validation;
+pub mod source_map;
 pub mod testing;
 mod util;
 
-pub fn run(ctx: &dyn rt_ctx::RtCtx, source_path: &Path) {
+/// Options for compiling a program
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// Whether to fail the compilation when there are warnings
+    deny_warnings: bool,
+    /// Number of columns a `\t` advances in the reported positions
+    tab_width: usize,
+    /// How deeply expressions may nest, counting each operator of a chain such as `1 + 1 + 1`
+    max_depth: usize,
+    /// Limit of the number of arguments to a call, if any
+    max_args: Option<usize>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            deny_warnings: false,
+            tab_width: 1,
+            max_depth: parser::DEFAULT_MAX_DEPTH,
+            max_args: None,
+        }
+    }
+}
+
+impl CompileOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_deny_warnings(mut self, deny_warnings: bool) -> Self {
+        self.deny_warnings = deny_warnings;
+        self
+    }
+
+    pub fn with_tab_width(mut self, tab_width: usize) -> Self {
+        self.tab_width = tab_width;
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn with_max_args(mut self, max_args: usize) -> Self {
+        self.max_args = Some(max_args);
+        self
+    }
+}
+
+/// Parses the program with the prelude prepended, and assigns ids to it.
+///
+/// Also returns the number of leading statements that come from the prelude.
+fn load_program(
+    cctx: &CCtx,
+    builtin_ids: &BuiltinIds,
+    source: &str,
+    warnings: &mut Vec<Warning>,
+) -> Result<(Vec<Stmt>, usize), CheckError> {
+    let stmts = crate::parser::parse_with_cctx(source, cctx)?;
+    prepare_program(cctx, builtin_ids, stmts, warnings)
+}
+
+/// Prepends the prelude to the statements, and assigns ids to them.
+///
+/// Also returns the number of leading statements that come from the prelude.
+/// Warnings are appended to `warnings`, and fail the preparation if the options deny them.
+/// Undefined names always fail it.
+fn prepare_program(
+    cctx: &CCtx,
+    builtin_ids: &BuiltinIds,
+    stmts: Vec<Stmt>,
+    warnings: &mut Vec<Warning>,
+) -> Result<(Vec<Stmt>, usize), CheckError> {
+    let mut program_ast = prelude::prelude_stmts();
+    let num_prelude_stmts = program_ast.len();
+    program_ast.extend(stmts);
+    let mut scope = crate::ast::Scope::new(builtin_ids);
+    crate::ast::assign_id_stmts(cctx, &mut scope, &mut program_ast);
+    if let Some(error) = scope.errors().first() {
+        return Err(CheckError::Scope(error.clone()));
+    }
+    let mut new_warnings = scope.warnings().to_vec();
+    // The prelude may well leave its bindings unused
+    new_warnings.extend(crate::ast::unused_bindings(
+        &program_ast[num_prelude_stmts..],
+    ));
+    if cctx.options.deny_warnings && !new_warnings.is_empty() {
+        return Err(CheckError::WarningsDenied {
+            warnings: new_warnings,
+        });
+    }
+    warnings.extend(new_warnings);
+    Ok((program_ast, num_prelude_stmts))
+}
+
+/// Runs the program in the file, and returns the warnings found while compiling it.
+///
+/// The evaluator is specialized to the type of the context,
+/// so that a concrete one avoids dynamic dispatch for each builtin call.
+pub fn run<C: rt_ctx::RtCtx + ?Sized>(
+    ctx: &C,
+    source_path: &Path,
+) -> Result<Vec<Warning>, RunError> {
     let source = fs::read_to_string(source_path).unwrap();
+    run_source(ctx, &source)
+}
+
+/// Same as `run`, but compiles the program with the options,
+/// reporting the errors they lead to instead of panicking.
+pub fn run_with_options<C: rt_ctx::RtCtx + ?Sized>(
+    ctx: &C,
+    source_path: &Path,
+    options: &CompileOptions,
+) -> Result<Vec<Warning>, RunError> {
+    let source = fs::read_to_string(source_path).unwrap();
+    run_source_with_options(ctx, &source, options)
+}
+
+/// Runs the program given as a string, from parsing through evaluation.
+pub fn run_source<C: rt_ctx::RtCtx + ?Sized>(
+    ctx: &C,
+    source: &str,
+) -> Result<Vec<Warning>, RunError> {
+    run_source_with_options(ctx, source, &CompileOptions::default())
+}
+
+fn run_source_with_options<C: rt_ctx::RtCtx + ?Sized>(
+    ctx: &C,
+    source: &str,
+    options: &CompileOptions,
+) -> Result<Vec<Warning>, RunError> {
+    let cctx = CCtx::new().with_options(options.clone());
+    let builtin_ids = BuiltinIds::new(&cctx);
+    let mut warnings = vec![];
+    let (mut program_ast, _) = load_program(&cctx, &builtin_ids, source, &mut warnings)?;
+    let mut ty_ctx = TyCtx::default();
+    ast_typecheck::typecheck_collect(&program_ast, &builtin_ids, &mut ty_ctx)
+        .map_err(CheckError::from)?;
+    ast_simplify::simplify_stmts(&mut program_ast);
+    let program_unit = ast_lowering::lower(&builtin_ids, &program_ast);
+    crate::eval_::eval_with_options(ctx, &program_unit, options)?;
+    Ok(warnings)
+}
+
+/// Evaluates a bare expression, such as `1 + 2 * 3`, and returns its value.
+///
+/// The expression is written without the preamble and may use the prelude.
+/// Builtins with side effects use the standard input and output of the process.
+pub fn eval_expr(source: &str) -> Result<Value, RunError> {
     let cctx = CCtx::new();
     let builtin_ids = BuiltinIds::new(&cctx);
-    let mut program_ast = crate::parser::parse(&source).unwrap();
-    let mut scope = crate::ast::Scope::new(&builtin_ids);
-    crate::ast::assign_id_stmts(&cctx, &mut scope, &mut program_ast);
-    let program_sir = ast_lowering::lower(&builtin_ids, &program_ast);
-    let program_unit = sir::ProgramUnit::new(vec![program_sir]);
-    crate::eval_::eval(ctx, &program_unit);
+    let expr = crate::parser::parse_expr(source, &cctx).map_err(CheckError::from)?;
+    let (mut program_ast, _) = prepare_program(
+        &cctx,
+        &builtin_ids,
+        vec![Stmt::expr(expr, true)],
+        &mut vec![],
+    )?;
+    let mut ty_ctx = TyCtx::default();
+    ast_typecheck::typecheck_value(&program_ast, &builtin_ids, &mut ty_ctx)
+        .map_err(CheckError::from)?;
+    ast_simplify::simplify_stmts(&mut program_ast);
+    let program_unit = ast_lowering::lower(&builtin_ids, &program_ast);
+    crate::eval_::eval_value(&rt_ctx::RtCtxImpl::new(), &program_unit)
+}
+
+/// Reformats the program, keeping its comments.
+pub fn format_source(source: &str) -> Result<String, CheckError> {
+    let (program_ast, trailing_comments) = crate::parser::parse_with_comments(source)?;
+    Ok(format::format_program(&program_ast, &trailing_comments))
+}
+
+/// Prints the inferred type of each top-level `let` binding, one per line.
+///
+/// Warnings are appended to `warnings`.
+pub fn explain_types(
+    source_path: &Path,
+    options: &CompileOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<String, CheckError> {
+    let source = fs::read_to_string(source_path).unwrap();
+    explain_types_source(&source, options, warnings)
+}
+
+fn explain_types_source(
+    source: &str,
+    options: &CompileOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<String, CheckError> {
+    let cctx = CCtx::new().with_options(options.clone());
+    let builtin_ids = BuiltinIds::new(&cctx);
+    let (program_ast, num_prelude_stmts) = load_program(&cctx, &builtin_ids, source, warnings)?;
+    let mut ty_ctx = TyCtx::default();
+    let var_types = ast_typecheck::typecheck_collect(&program_ast, &builtin_ids, &mut ty_ctx)?;
+    let mut out = String::new();
+    for stmt in &program_ast[num_prelude_stmts..] {
+        if let Stmt::Let { lhs, .. } = stmt {
+            writeln!(out, "{} : {}", lhs.name, var_types[&lhs.id].view(&ty_ctx)).unwrap();
+        }
+    }
+    Ok(out)
+}
+
+#[derive(Debug, Error)]
+pub enum CheckError {
+    #[error("parse error at {line}:{column}")]
+    Parse { line: usize, column: usize },
+    #[error("expression nested too deeply at {line}:{column}")]
+    NestingTooDeep { line: usize, column: usize },
+    #[error("`then` is only allowed in the last statement of a block at {line}:{column}")]
+    ThenNotLast { line: usize, column: usize },
+    #[error("{0}")]
+    Scope(#[from] ScopeError),
+    #[error("type error: {0}")]
+    Type(#[from] TypeError),
+    #[error("type error in the lowered program")]
+    LoweredType,
+    #[error("invalid lowered program: {0}")]
+    LoweredInvalid(#[from] SirValidationError),
+    #[error("{} warning(s) treated as errors", warnings.len())]
+    WarningsDenied { warnings: Vec<Warning> },
+}
+
+#[derive(Debug, Error)]
+pub enum RunError {
+    #[error(transparent)]
+    Check(#[from] CheckError),
+    #[error("runtime error: {0}")]
+    Runtime(#[from] RuntimeError),
+}
+
+impl From<ParseError> for CheckError {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::Syntax { line, column } => CheckError::Parse { line, column },
+            ParseError::NestingTooDeep { line, column } => {
+                CheckError::NestingTooDeep { line, column }
+            }
+            ParseError::ThenNotLast { line, column } => CheckError::ThenNotLast { line, column },
+        }
+    }
+}
+
+/// Parses and type-checks the program, at both the AST and SIR levels, without running it.
+///
+/// Returns the warnings found on success.
+pub fn check(source_path: &Path) -> Result<Vec<Warning>, CheckError> {
+    check_with_options(source_path, &CompileOptions::default())
+}
+
+/// Same as `check`, but with the options.
+pub fn check_with_options(
+    source_path: &Path,
+    options: &CompileOptions,
+) -> Result<Vec<Warning>, CheckError> {
+    let source = fs::read_to_string(source_path).unwrap();
+    check_source_with_options(&source, options)
+}
+
+/// Same as `check`, but with the program given as a string.
+pub fn check_source(source: &str) -> Result<Vec<Warning>, CheckError> {
+    check_source_with_options(source, &CompileOptions::default())
+}
+
+fn check_source_with_options(
+    source: &str,
+    options: &CompileOptions,
+) -> Result<Vec<Warning>, CheckError> {
+    let cctx = CCtx::new().with_options(options.clone());
+    let builtin_ids = BuiltinIds::new(&cctx);
+    let mut warnings = vec![];
+    let (program_ast, _) = load_program(&cctx, &builtin_ids, source, &mut warnings)?;
+    let mut ty_ctx = TyCtx::default();
+    ast_typecheck::typecheck_collect(&program_ast, &builtin_ids, &mut ty_ctx)?;
+    let program_unit = ast_lowering::lower(&builtin_ids, &program_ast);
+    sir_typecheck::typecheck(&cctx, &program_unit).map_err(|_| CheckError::LoweredType)?;
+    program_unit.validate(options)?;
+    Ok(warnings)
+}
+
+/// Prints the liveness information of each compiled function.
+///
+/// Warnings are appended to `warnings`.
+pub fn explain_liveness(
+    source_path: &Path,
+    options: &CompileOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<String, CheckError> {
+    let source = fs::read_to_string(source_path).unwrap();
+    explain_liveness_source(&source, options, warnings)
+}
+
+fn explain_liveness_source(
+    source: &str,
+    options: &CompileOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<String, CheckError> {
+    let program_unit = compile_source(source, options, warnings)?;
+    let mut out = String::new();
+    for (i, function) in program_unit.functions.iter().enumerate() {
+        writeln!(out, "f{}:", i).unwrap();
+        out.push_str(&function.liveness_report());
+    }
+    Ok(out)
+}
+
+/// Prints the instruction count and the estimated cost of the compiled program.
+///
+/// Warnings are appended to `warnings`.
+pub fn explain_size(
+    source_path: &Path,
+    options: &CompileOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<String, CheckError> {
+    let source = fs::read_to_string(source_path).unwrap();
+    explain_size_source(&source, options, warnings)
+}
+
+fn explain_size_source(
+    source: &str,
+    options: &CompileOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<String, CheckError> {
+    let program_unit = compile_source(source, options, warnings)?;
+    Ok(format!(
+        "instructions: {}\nestimated cost: {}\n",
+        program_unit.instruction_count(),
+        program_unit.estimated_cost()
+    ))
+}
+
+/// Prints the program after each compilation pass, ending with the register allocation
+/// that the evaluator runs on the compiled program.
+///
+/// Warnings are appended to `warnings`.
+pub fn explain_passes(
+    source_path: &Path,
+    options: &CompileOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<String, CheckError> {
+    let source = fs::read_to_string(source_path).unwrap();
+    explain_passes_source(&source, options, warnings)
+}
+
+fn explain_passes_source(
+    source: &str,
+    options: &CompileOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<String, CheckError> {
+    let (cctx, program_unit) = lower_source(source, options, warnings)?;
+    let (compiled, mut snapshots) = sir_compile::compile_with_snapshots(&cctx, &program_unit);
+    snapshots.push((
+        "allocate_registers".to_owned(),
+        sir_compile::allocate_registers(&cctx, &compiled),
+    ));
+    let mut out = String::new();
+    for (name, program_unit) in snapshots {
+        writeln!(out, "== {}", name).unwrap();
+        for (i, function) in program_unit.functions.iter().enumerate() {
+            writeln!(out, "f{}:", i).unwrap();
+            out.push_str(&function.liveness_report());
+        }
+    }
+    Ok(out)
+}
+
+/// Compiles the program, after checking it as `check` does.
+fn compile_source(
+    source: &str,
+    options: &CompileOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<sir::ProgramUnit, CheckError> {
+    let (cctx, program_unit) = lower_source(source, options, warnings)?;
+    Ok(sir_compile::compile(&cctx, &program_unit))
+}
+
+/// Checks the program as `check` does, and returns it lowered but not compiled yet.
+fn lower_source(
+    source: &str,
+    options: &CompileOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<(CCtx, sir::ProgramUnit), CheckError> {
+    let cctx = CCtx::new().with_options(options.clone());
+    let builtin_ids = BuiltinIds::new(&cctx);
+    let (mut program_ast, _) = load_program(&cctx, &builtin_ids, source, warnings)?;
+    let mut ty_ctx = TyCtx::default();
+    ast_typecheck::typecheck_collect(&program_ast, &builtin_ids, &mut ty_ctx)?;
+    ast_simplify::simplify_stmts(&mut program_ast);
+    let program_unit = ast_lowering::lower(&builtin_ids, &program_ast);
+    sir_typecheck::typecheck(&cctx, &program_unit).map_err(|_| CheckError::LoweredType)?;
+    program_unit.validate(options)?;
+    Ok((cctx, program_unit))
 }
 
 #[cfg(test)]
@@ -42,7 +440,841 @@ mod tests {
     fn test_run_hello() {
         let source_path = std::path::Path::new("examples/hello.umo");
         let ctx = MockRtCtx::new();
-        run(&ctx, source_path);
+        run(&ctx, source_path).unwrap();
         assert_eq!(ctx.stdout.lock().unwrap().as_str(), "Hello, world!\n");
     }
+
+    #[test]
+    fn test_run_try() {
+        let source = r#"use lang::"0.0.1";
+let ok = try { then 42; };
+puti(unwrap(ok));
+let oob = try { then [1][5]; };
+let panicked = try { panic("boom"); };
+puts("survived");
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "42\nsurvived\n");
+    }
+
+    #[test]
+    fn test_run_try_errors() {
+        let source = r#"use lang::"0.0.1";
+puts(if is_some(try { then 1 / 0; }) then "some" else "none");
+puts(if is_some(try { then [1, 2][2]; }) then "some" else "none");
+if some(x) = try { let x = 1; x = 2; then x; } { puti(x); };
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "none\nnone\n2\n");
+    }
+
+    #[test]
+    fn test_run_try_assign_outer_variable() {
+        let source = r#"use lang::"0.0.1";
+let x = 0;
+let _ = try { x = 1; };
+puti(x);
+"#;
+        assert!(matches!(
+            check_source(source),
+            Err(CheckError::Scope(ScopeError::AssignToCapturedInTry { .. }))
+        ));
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source(&ctx, source),
+            Err(RunError::Check(CheckError::Scope(_)))
+        ));
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "");
+    }
+
+    #[test]
+    fn test_run_lambda_assign_captured_variable() {
+        let source = r#"use lang::"0.0.1";
+let x = 1;
+let f = fn() { x = 2; };
+f();
+puti(x);
+"#;
+        assert!(matches!(
+            check_source(source),
+            Err(CheckError::Scope(
+                ScopeError::AssignToCapturedInLambda { .. }
+            ))
+        ));
+        // The function's own variables and parameters may be assigned
+        let source = r#"use lang::"0.0.1";
+let f = fn(n) { let y = 1; y = y + n; n = 0; then y; };
+puti(f(2));
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "3\n");
+    }
+
+    #[test]
+    fn test_run_try_return() {
+        let source = r#"use lang::"0.0.1";
+let f = fn(n) {
+  let r = try { return 5; };
+  puti(unwrap(r));
+  then 7;
+};
+puti(f(1));
+"#;
+        assert!(matches!(
+            check_source(source),
+            Err(CheckError::Scope(ScopeError::ReturnInTry))
+        ));
+        // A function inside the `try` body may still return from itself
+        let source = r#"use lang::"0.0.1";
+let r = try { let g = fn() { return 5; }; then g(); };
+puti(unwrap(r));
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "5\n");
+    }
+
+    #[test]
+    fn test_run_panic_with_int() {
+        let source = r#"use lang::"0.0.1";
+let n = 40 + 2;
+panic_with_int("unexpected count: ", n);
+puts("unreachable");
+"#;
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source(&ctx, source),
+            Err(RunError::Runtime(RuntimeError::Panic(msg))) if msg == "unexpected count: 42"
+        ));
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "");
+    }
+
+    #[test]
+    fn test_run_trailing_expr() {
+        let source = r#"use lang::"0.0.1";
+let f = fn(x) { x + 1 };
+puti(f(1));
+puti(do { 1; 2 })
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "2\n2\n");
+    }
+
+    #[test]
+    fn test_run_gets() {
+        let source = r#"use lang::"0.0.1";
+let a = gets();
+let b = gets();
+let c = gets();
+puts(unwrap(a));
+puti(len(unwrap(b)));
+if is_some(c) { puts("some"); } else { puts("none"); };
+puti(unwrap(some(1)) + 1);
+"#;
+        let ctx = MockRtCtx::new().with_stdin("x\n\n");
+        run_source(&ctx, source).unwrap();
+        // The empty line is `some("")`, unlike the end of input
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "x\n0\nnone\n2\n");
+    }
+
+    #[test]
+    fn test_run_unwrap_none() {
+        let source = r#"use lang::"0.0.1";
+let n = none();
+puti(unwrap(n));
+"#;
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source(&ctx, source),
+            Err(RunError::Runtime(RuntimeError::UnwrapNone))
+        ));
+    }
+
+    #[test]
+    fn test_run_block_like_args() {
+        let source = r#"use lang::"0.0.1";
+puti(do { let a = 1; then a + 1; });
+let c = 0 < 1;
+puti(if c then 1 else 2);
+puti(10 + if c { then 3; } else { then 4; });
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "2\n1\n13\n");
+    }
+
+    #[test]
+    fn test_run_prelude() {
+        let source = r#"use lang::"0.0.1";
+puti(clamp(5, 1, 3));
+puti(clamp(0, 1, 3));
+puti(clamp(2, 1, 3));
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "3\n1\n2\n");
+    }
+
+    #[test]
+    fn test_run_early_return() {
+        let source = r#"use lang::"0.0.1";
+let f = fn(n) {
+  if n < 10 { return 1; };
+  then 2;
+};
+puti(f(5));
+puti(f(15));
+let g = fn(n) {
+  let i = 0;
+  while 0 < 1 { if n < i + 1 { return i; }; i = i + 1; };
+  then 0;
+};
+puti(g(3));
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "1\n2\n3\n");
+    }
+
+    #[test]
+    fn test_run_nested_branch() {
+        let source = r#"use lang::"0.0.1";
+let c = 0 < 1;
+if c { if c { puti(1); } else { puti(2); }; } else { puti(3); };
+puti(4);
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "1\n4\n");
+    }
+
+    #[test]
+    fn test_run_common_builtin_calls() {
+        let source = r#"use lang::"0.0.1";
+let x = 4;
+puti((x + 2) + (x + 2));
+x = 1;
+puti((x + 2) + (x + 2));
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "12\n6\n");
+    }
+
+    #[test]
+    fn test_run_beyond_i32() {
+        let source = r#"use lang::"0.0.1";
+let x = 2000000000;
+let y = x + x;
+puti(y);
+puti(y + y);
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(
+            ctx.stdout.lock().unwrap().as_str(),
+            "4000000000\n8000000000\n"
+        );
+    }
+
+    #[test]
+    fn test_run_empty_block() {
+        let source = "use lang::\"0.0.1\";\nlet x = do {};\nthen x;\n";
+        assert_eq!(
+            explain_types_source(source, &CompileOptions::default(), &mut vec![]).unwrap(),
+            "x : ()\n"
+        );
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+    }
+
+    #[test]
+    fn test_run_len() {
+        let source = r#"use lang::"0.0.1";
+let s = "abc";
+let n = len(s);
+puti(n);
+puts(s);
+puti(len([1, 2]));
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "3\nabc\n2\n");
+    }
+
+    #[test]
+    fn test_run_same_branches() {
+        let source = r#"use lang::"0.0.1";
+let x = 1;
+if do { puts("cond"); then x < 2; } { puti(x); } else { puti(x); };
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        // The branch is removed, but the condition is still evaluated
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "cond\n1\n");
+    }
+
+    #[test]
+    fn test_run_multibyte_string() {
+        let source = r#"use lang::"0.0.1";
+let s = "héllo";
+puti(len(s));
+puti(char_at(s, 1));
+puts(s[1..3]);
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "5\n233\nél\n");
+
+        let source = r#"use lang::"0.0.1";
+puti(char_at("héllo", 5));
+"#;
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source(&ctx, source),
+            Err(RunError::Runtime(RuntimeError::IndexOutOfBounds {
+                index: 5,
+                len: 5
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_run_diverging_branch() {
+        let source = r#"use lang::"0.0.1";
+let x = 1;
+puti(if x < 2 { then x; } else { then panic("too large"); });
+puti(if x < 1 { then panic_with_int("too small: ", x); } else { then x + 1; });
+puti(if 1 < 2 then 1 else panic("x"));
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "1\n2\n1\n");
+    }
+
+    #[test]
+    fn test_run_sub() {
+        let source = r#"use lang::"0.0.1";
+puti(1 - 2 - 3);
+let x = 10;
+puti(x - 3 + 1);
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "-4\n8\n");
+    }
+
+    #[test]
+    fn test_run_mul_div() {
+        let source = r#"use lang::"0.0.1";
+puti(2 + 3 * 4);
+puti(10 / 3);
+puti(-7 / 2);
+puti(-(2 - 5) * -3);
+puti(-2147483648);
+let x = 4;
+puti(- -x);
+puti(2 * 3 - 8 / 2 / 2);
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(
+            ctx.stdout.lock().unwrap().as_str(),
+            "14\n3\n-3\n-9\n-2147483648\n4\n4\n"
+        );
+    }
+
+    #[test]
+    fn test_run_comparisons() {
+        let source = r#"use lang::"0.0.1";
+let i = 0;
+while i <= 3 {
+  if i > 1 { puti(i); };
+  if i >= 3 { puts("last"); };
+  i = i + 1;
+};
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "2\n3\nlast\n");
+    }
+
+    #[test]
+    fn test_run_equality() {
+        let source = r#"use lang::"0.0.1";
+if 1 == 1 { puts("eq"); };
+if 1 == 2 { puts("wrong"); };
+let x = 3;
+if x != 1 + 1 { puti(x); };
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "eq\n3\n");
+    }
+
+    #[test]
+    fn test_run_short_circuit() {
+        let source = r#"use lang::"0.0.1";
+let hit = fn(x) { puti(x); then true; };
+if false && hit(1) { puts("no"); };
+if true || hit(2) { puts("a"); };
+if true && hit(3) { puts("b"); };
+if false || hit(4) { puts("c"); };
+let x = 5;
+if 0 < x && x < 10 || hit(6) { puts("d"); };
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "a\n3\nb\n4\nc\nd\n");
+        let source = "use lang::\"0.0.1\";\nif 1 && true { puts(\"x\"); };\n";
+        assert!(matches!(check_source(source), Err(CheckError::Type(_))));
+    }
+
+    #[test]
+    fn test_run_boolean_literal() {
+        let source = r#"use lang::"0.0.1";
+let done = false;
+if true { puts("yes"); } else { puts("no"); };
+loop {
+  if done { break 0; };
+  done = true;
+};
+if done { puts("done"); };
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "yes\ndone\n");
+    }
+
+    #[test]
+    fn test_run_loop() {
+        let source = r#"use lang::"0.0.1";
+let i = 0;
+let x = loop {
+  if 3 < i { break i + 10; };
+  i = i + 1;
+};
+puti(x);
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "14\n");
+
+        // Without `break`, the loop only ends by leaving the program
+        let source = r#"use lang::"0.0.1";
+let i = 0;
+loop {
+  puti(i);
+  if 1 < i { panic("done"); };
+  i = i + 1;
+};
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source(&ctx, source),
+            Err(RunError::Runtime(RuntimeError::Panic(msg))) if msg == "done"
+        ));
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "0\n1\n2\n");
+    }
+
+    #[test]
+    fn test_run_putc() {
+        let source = r#"use lang::"0.0.1";
+putc(65);
+putc(66);
+puts("");
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "AB\n");
+    }
+
+    #[test]
+    fn test_run_repeat() {
+        let source = r#"use lang::"0.0.1";
+puts(repeat("ab", 3));
+puts(repeat("-", 0));
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "ababab\n\n");
+    }
+
+    #[test]
+    fn test_run_returned_closure() {
+        let source = r#"use lang::"0.0.1";
+let add = fn(x) { then fn(y) { then x + y; }; };
+let twice = fn(f) { then fn(x) { then f(f(x)); }; };
+let g = add(2);
+puti(g(3));
+puti(add(4)(5));
+puti(twice(add(10))(1));
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "5\n9\n21\n");
+    }
+
+    #[test]
+    fn test_run_string_escapes() {
+        let source = r#"use lang::"0.0.1";
+puts("a\tb\n\"c\" \\");
+"#;
+        let ctx = MockRtCtx::new();
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "a\tb\n\"c\" \\\n");
+    }
+
+    #[test]
+    fn test_run_deny_warnings() {
+        let source = r#"use lang::"0.0.1";
+let puti = 1;
+puts(repeat("ran", puti));
+"#;
+        let ctx = MockRtCtx::new();
+        let warnings = run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "ran\n");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].to_string(), "`puti` shadows builtin");
+        assert_eq!(check_source(source).unwrap(), warnings);
+
+        let options = CompileOptions::new().with_deny_warnings(true);
+        assert!(matches!(
+            check_source_with_options(source, &options),
+            Err(CheckError::WarningsDenied { warnings: denied }) if denied == warnings
+        ));
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source_with_options(&ctx, source, &options),
+            Err(RunError::Check(CheckError::WarningsDenied { .. }))
+        ));
+        // The program is not run
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "");
+    }
+
+    #[test]
+    fn test_run_if_some() {
+        let source = r#"use lang::"0.0.1";
+let count = 0;
+loop {
+  if some(line) = gets() {
+    puts(line);
+    count = count + 1;
+  } else {
+    break 0;
+  };
+};
+puti(count);
+puti(if some(x) = some(5) then x + 1 else 0);
+puti(if some(x) = none() then x else 7);
+"#;
+        assert!(check_source(source).is_ok());
+        let ctx = MockRtCtx::new().with_stdin("a\n\nb\n");
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "a\n\nb\n3\n6\n7\n");
+    }
+
+    #[test]
+    fn test_run_args() {
+        let source = r#"use lang::"0.0.1";
+let args = args();
+puti(len(args));
+puts(args[1]);
+"#;
+        let ctx = MockRtCtx::new().with_args(&["foo", "bar"]);
+        run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "2\nbar\n");
+    }
+
+    #[test]
+    fn test_check() {
+        let source = "use lang::\"0.0.1\";\nputi(1 + 2);\n";
+        assert!(check_source(source).is_ok());
+        let source = "use lang::\"0.0.1\";\nputi(\"three\");\n";
+        assert!(matches!(check_source(source), Err(CheckError::Type(_))));
+        let source = "use lang::\"0.0.1\";\nthen puts(\"done\");\n";
+        assert!(check_source(source).is_ok());
+        let source = "use lang::\"0.0.1\";\nthen 5;\n";
+        assert!(matches!(
+            check_source(source),
+            Err(CheckError::Type(TypeError::NonUnitProgram { .. }))
+        ));
+        // Running the program rejects it in the same way
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source(&ctx, source),
+            Err(RunError::Check(CheckError::Type(
+                TypeError::NonUnitProgram { .. }
+            )))
+        ));
+        let source = "use lang::\"0.0.1\";\nputi(1 +);\n";
+        assert!(matches!(
+            check_source(source),
+            Err(CheckError::Parse { line: 2, column: 9 })
+        ));
+        let source = "use lang::\"0.0.1\";\nthen puts(\"a\");\nputs(\"b\");\n";
+        assert!(matches!(
+            check_source(source),
+            Err(CheckError::ThenNotLast { line: 2, column: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_eval_expr() {
+        assert_eq!(eval_expr("1 + 2 * 3").unwrap(), Value::Integer(7));
+        assert_eq!(eval_expr("0xff == 255").unwrap(), Value::Bool(true));
+        assert_eq!(eval_expr("0b1010 == 10").unwrap(), Value::Bool(true));
+        assert_eq!(
+            eval_expr("max(2, clamp(9, 0, 5))").unwrap(),
+            Value::Integer(5)
+        );
+        assert_eq!(
+            eval_expr("if 1 < 2 then \"yes\" else \"no\"")
+                .unwrap()
+                .to_string(),
+            "\"yes\""
+        );
+        assert!(matches!(
+            eval_expr("1 + 2;"),
+            Err(RunError::Check(CheckError::Parse { line: 1, column: 6 }))
+        ));
+        assert!(matches!(
+            eval_expr("1 + \"2\""),
+            Err(RunError::Check(CheckError::Type(_)))
+        ));
+        assert!(matches!(
+            eval_expr("1 / 0"),
+            Err(RunError::Runtime(RuntimeError::DivisionByZero))
+        ));
+        assert!(matches!(
+            eval_expr("x + 1"),
+            Err(RunError::Check(CheckError::Scope(
+                ScopeError::UndefinedVariable { .. }
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_run_uninitialized_variable() {
+        let source = r#"use lang::"0.0.1";
+let x;
+puti(x);
+"#;
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source(&ctx, source),
+            Err(RunError::Check(CheckError::LoweredInvalid(
+                SirValidationError::UninitializedVariable { .. }
+            )))
+        ));
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "");
+    }
+
+    #[test]
+    fn test_run_type_error() {
+        let source = r#"use lang::"0.0.1";
+if 5 { puti(1); } else { puti(1); };
+"#;
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source(&ctx, source),
+            Err(RunError::Check(CheckError::Type(_)))
+        ));
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "");
+    }
+
+    #[test]
+    fn test_check_undefined_variable() {
+        let source = r#"use lang::"0.0.1";
+puti(x);
+"#;
+        assert!(matches!(
+            check_source(source),
+            Err(CheckError::Scope(ScopeError::UndefinedVariable { .. }))
+        ));
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source(&ctx, source),
+            Err(RunError::Check(CheckError::Scope(_)))
+        ));
+    }
+
+    #[test]
+    fn test_check_tab_width() {
+        let source = "use lang::\"0.0.1\";\n\tputi(;\n";
+        assert!(matches!(
+            check_source(source),
+            Err(CheckError::Parse { line: 2, column: 7 })
+        ));
+        let options = CompileOptions::new().with_tab_width(4);
+        assert!(matches!(
+            check_source_with_options(source, &options),
+            Err(CheckError::Parse {
+                line: 2,
+                column: 10
+            })
+        ));
+    }
+
+    #[test]
+    fn test_check_max_args() {
+        let source =
+            "use lang::\"0.0.1\";\nlet f = fn(a, b, c) { puti(a + b + c); };\nf(1, 2, 3);\n";
+        assert!(check_source(source).is_ok());
+        let options = CompileOptions::new().with_max_args(2);
+        assert!(matches!(
+            check_source_with_options(source, &options),
+            Err(CheckError::LoweredInvalid(
+                SirValidationError::TooManyArgs { .. }
+            ))
+        ));
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source_with_options(&ctx, source, &options),
+            Err(RunError::Check(CheckError::LoweredInvalid(
+                SirValidationError::TooManyArgs { .. }
+            )))
+        ));
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "");
+    }
+
+    #[test]
+    fn test_check_max_depth() {
+        let source = "use lang::\"0.0.1\";\nputi(1 + 1 + 1 + 1);\n";
+        assert!(check_source(source).is_ok());
+        let options = CompileOptions::new().with_max_depth(4);
+        assert!(matches!(
+            check_source_with_options(source, &options),
+            Err(CheckError::NestingTooDeep {
+                line: 2,
+                column: 12
+            })
+        ));
+        // Rejected before the later passes recurse into it
+        let source = format!("use lang::\"0.0.1\";\nputi(1{});\n", " + 1".repeat(5000));
+        let ctx = MockRtCtx::new();
+        assert!(matches!(
+            run_source(&ctx, &source),
+            Err(RunError::Check(CheckError::NestingTooDeep { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_check_uninitialized_variable() {
+        let source = r#"use lang::"0.0.1";
+let x;
+puti(x);
+"#;
+        assert!(matches!(
+            check_source(source),
+            Err(CheckError::LoweredInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_explain_liveness_invalid() {
+        let options = CompileOptions::default();
+        let source = "use lang::\"0.0.1\";\nputi(1 +);\n";
+        assert!(matches!(
+            explain_liveness_source(source, &options, &mut vec![]),
+            Err(CheckError::Parse { .. })
+        ));
+        let source = "use lang::\"0.0.1\";\nputi(x);\n";
+        assert!(matches!(
+            explain_liveness_source(source, &options, &mut vec![]),
+            Err(CheckError::Scope(ScopeError::UndefinedVariable { .. }))
+        ));
+        let source = "use lang::\"0.0.1\";\nputi(\"a\");\n";
+        assert!(matches!(
+            explain_liveness_source(source, &options, &mut vec![]),
+            Err(CheckError::Type(_))
+        ));
+        let source = "use lang::\"0.0.1\";\nlet puti = 1;\nputs(repeat(\"-\", puti));\n";
+        let mut warnings = vec![];
+        assert!(explain_liveness_source(source, &options, &mut warnings).is_ok());
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_explain_size() {
+        let options = CompileOptions::default();
+        let source = "use lang::\"0.0.1\";\nputi(1);\n";
+        let out = explain_size_source(source, &options, &mut vec![]).unwrap();
+        assert!(out.starts_with("instructions: "));
+        let source = "use lang::\"0.0.1\";\nputi(x);\n";
+        assert!(matches!(
+            explain_size_source(source, &options, &mut vec![]),
+            Err(CheckError::Scope(_))
+        ));
+    }
+
+    #[test]
+    fn test_explain_passes() {
+        let source = "use lang::\"0.0.1\";\nlet puti = 1;\nputs(repeat(\"-\", puti));\n";
+        let mut warnings = vec![];
+        let out = explain_passes_source(source, &CompileOptions::default(), &mut warnings).unwrap();
+        assert!(out.starts_with("== hoist_loop_invariants\n"));
+        assert!(out.contains("\n== insert_copy\n"));
+        assert!(out.contains("\n== allocate_registers\n"));
+        assert_eq!(warnings.len(), 1);
+
+        let options = CompileOptions::new().with_deny_warnings(true);
+        assert!(matches!(
+            explain_passes_source(source, &options, &mut vec![]),
+            Err(CheckError::WarningsDenied { .. })
+        ));
+        let options = CompileOptions::new().with_tab_width(4);
+        assert!(matches!(
+            explain_passes_source("use lang::\"0.0.1\";\n\tputi(;\n", &options, &mut vec![]),
+            Err(CheckError::Parse {
+                line: 2,
+                column: 10
+            })
+        ));
+    }
+
+    #[test]
+    fn test_explain_types() {
+        let source = "use lang::\"0.0.1\";\nlet f = fn(x) { then x + 1; };\n";
+        let out = explain_types_source(source, &CompileOptions::default(), &mut vec![]).unwrap();
+        assert_eq!(out, "f : (Integer) -> Integer\n");
+        let source = "use lang::\"0.0.1\";\nlet r = try { then 42; };\nputi(unwrap(r));\n";
+        let out = explain_types_source(source, &CompileOptions::default(), &mut vec![]).unwrap();
+        assert_eq!(out, "r : Option<Integer>\n");
+    }
+
+    #[test]
+    fn test_explain_types_invalid() {
+        let options = CompileOptions::default();
+        let source = "use lang::\"0.0.1\";\nlet x = 1 +;\n";
+        assert!(matches!(
+            explain_types_source(source, &options, &mut vec![]),
+            Err(CheckError::Parse { .. })
+        ));
+        let source = "use lang::\"0.0.1\";\nputi(x);\n";
+        assert!(matches!(
+            explain_types_source(source, &options, &mut vec![]),
+            Err(CheckError::Scope(ScopeError::UndefinedVariable { .. }))
+        ));
+        let source = "use lang::\"0.0.1\";\nlet x = puti(\"a\");\n";
+        assert!(matches!(
+            explain_types_source(source, &options, &mut vec![]),
+            Err(CheckError::Type(_))
+        ));
+    }
 }
@@ -1,16 +1,124 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 use umo::rt_ctx::RtCtxImpl;
+use umo::{CheckError, CompileOptions, RunError, Warning};
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     source: PathBuf,
+    /// Arguments passed to the program; put them after `--` if they start with a hyphen
+    #[arg(trailing_var_arg = true)]
+    program_args: Vec<String>,
+    /// Print intermediate information instead of running the program
+    #[arg(long, value_enum)]
+    emit: Option<Emit>,
+    /// Only parse and type-check the program; exit with non-zero status on errors
+    #[arg(long, conflicts_with = "emit")]
+    check: bool,
+    /// Print the program after each compilation pass instead of running it
+    #[arg(long, conflicts_with_all = ["emit", "check"])]
+    dump_passes: bool,
+    /// Treat warnings as errors when checking or running the program
+    #[arg(long)]
+    deny_warnings: bool,
+    /// Number of columns a tab advances in the reported error positions
+    #[arg(long, default_value_t = 1)]
+    tab_width: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// Inferred types of top-level bindings
+    Types,
+    /// Liveness of variables in the compiled functions
+    Liveness,
+    /// Instruction count and estimated cost of the compiled program
+    Size,
 }
 
 fn main() {
     let args = Args::parse();
-    umo::run(&RtCtxImpl, &args.source);
+    let options = CompileOptions::new()
+        .with_deny_warnings(args.deny_warnings)
+        .with_tab_width(args.tab_width);
+    if args.check {
+        match umo::check_with_options(&args.source, &options) {
+            Ok(warnings) => print_warnings(&warnings),
+            Err(e) => exit_with_check_error(e),
+        }
+        return;
+    }
+    if args.dump_passes {
+        let mut warnings = vec![];
+        match umo::explain_passes(&args.source, &options, &mut warnings) {
+            Ok(out) => {
+                print_warnings(&warnings);
+                print!("{}", out);
+            }
+            Err(e) => exit_with_check_error(e),
+        }
+        return;
+    }
+    match args.emit {
+        Some(Emit::Types) => {
+            let mut warnings = vec![];
+            match umo::explain_types(&args.source, &options, &mut warnings) {
+                Ok(out) => {
+                    print_warnings(&warnings);
+                    print!("{}", out);
+                }
+                Err(e) => exit_with_check_error(e),
+            }
+            return;
+        }
+        Some(Emit::Liveness) => {
+            let mut warnings = vec![];
+            match umo::explain_liveness(&args.source, &options, &mut warnings) {
+                Ok(out) => {
+                    print_warnings(&warnings);
+                    print!("{}", out);
+                }
+                Err(e) => exit_with_check_error(e),
+            }
+            return;
+        }
+        Some(Emit::Size) => {
+            let mut warnings = vec![];
+            match umo::explain_size(&args.source, &options, &mut warnings) {
+                Ok(out) => {
+                    print_warnings(&warnings);
+                    print!("{}", out);
+                }
+                Err(e) => exit_with_check_error(e),
+            }
+            return;
+        }
+        None => {}
+    }
+    let ctx = RtCtxImpl::new().with_args(args.program_args);
+    match umo::run_with_options(&ctx, &args.source, &options) {
+        Ok(warnings) => print_warnings(&warnings),
+        Err(RunError::Check(e)) => exit_with_check_error(e),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn print_warnings(warnings: &[Warning]) {
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+fn exit_with_check_error(e: CheckError) -> ! {
+    if let CheckError::WarningsDenied { warnings } = &e {
+        print_warnings(warnings);
+    }
+    eprintln!("error: {}", e);
+    std::process::exit(1);
 }
@@ -15,12 +15,23 @@ pub struct TyCtx {
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Type {
-    MetaVar { var_id: usize },
+    MetaVar {
+        var_id: usize,
+    },
     Unit,
     String,
     Integer,
     Bool,
-    Function { args: Vec<Type>, ret: Box<Type> },
+    Function {
+        args: Vec<Type>,
+        ret: Box<Type>,
+    },
+    Array(Box<Type>),
+    /// A value that may be absent, as returned by `gets`
+    Optional(Box<Type>),
+    /// Type of expressions that never produce a value, such as `return` and `panic(..)`.
+    /// It unifies with any type without constraining it.
+    Never,
 }
 
 impl Type {
@@ -47,6 +58,15 @@ impl Type {
             ret: Box::new(ret),
         }
     }
+    pub fn array(elem: Type) -> Self {
+        Type::Array(Box::new(elem))
+    }
+    pub fn optional(value: Type) -> Self {
+        Type::Optional(Box::new(value))
+    }
+    pub fn never() -> Self {
+        Type::Never
+    }
 
     pub fn view<'a>(&'a self, ctx: &'a TyCtx) -> TypeView<'a> {
         TypeView { type_: self, ctx }
@@ -118,6 +138,7 @@ impl Type {
             (Type::String, Type::String) => Ok(()),
             (Type::Integer, Type::Integer) => Ok(()),
             (Type::Bool, Type::Bool) => Ok(()),
+            (Type::Never, Type::Never) => Ok(()),
             (
                 Type::Function {
                     args: args1,
@@ -137,6 +158,8 @@ impl Type {
                 ret1.unify_impl(ret2, vars)?;
                 Ok(())
             }
+            (Type::Array(elem1), Type::Array(elem2)) => elem1.unify_impl(elem2, vars),
+            (Type::Optional(value1), Type::Optional(value2)) => value1.unify_impl(value2, vars),
             _ => Err(UnificationFailure),
         }
     }
@@ -152,6 +175,9 @@ impl Type {
             Type::Function { args, ret } => {
                 args.iter().any(|ty| ty.has_fv(var_id, vars)) || ret.has_fv(var_id, vars)
             }
+            Type::Array(elem) => elem.has_fv(var_id, vars),
+            Type::Optional(value) => value.has_fv(var_id, vars),
+            Type::Never => false,
         }
     }
 }
@@ -172,6 +198,7 @@ impl PartialEq for TypeView<'_> {
             (Type::String, Type::String) => true,
             (Type::Integer, Type::Integer) => true,
             (Type::Bool, Type::Bool) => true,
+            (Type::Never, Type::Never) => true,
             (
                 Type::Function {
                     args: args1,
@@ -200,6 +227,24 @@ impl PartialEq for TypeView<'_> {
                         ctx: other.ctx,
                     }
             }
+            (Type::Array(elem1), Type::Array(elem2)) => {
+                TypeView {
+                    type_: elem1,
+                    ctx: self.ctx,
+                } == TypeView {
+                    type_: elem2,
+                    ctx: other.ctx,
+                }
+            }
+            (Type::Optional(value1), Type::Optional(value2)) => {
+                TypeView {
+                    type_: value1,
+                    ctx: self.ctx,
+                } == TypeView {
+                    type_: value2,
+                    ctx: other.ctx,
+                }
+            }
             _ => false,
         }
     }
@@ -216,6 +261,7 @@ impl fmt::Debug for TypeView<'_> {
             Type::String => write!(f, "Type::string()"),
             Type::Integer => write!(f, "Type::integer()"),
             Type::Bool => write!(f, "Type::bool()"),
+            Type::Never => write!(f, "Type::never()"),
             Type::Function { args, ret } => f
                 .debug_tuple("Type::function")
                 .field(
@@ -232,6 +278,46 @@ impl fmt::Debug for TypeView<'_> {
                     ctx: self.ctx,
                 })
                 .finish(),
+            Type::Array(elem) => f
+                .debug_tuple("Type::array")
+                .field(&TypeView {
+                    type_: elem,
+                    ctx: self.ctx,
+                })
+                .finish(),
+            Type::Optional(value) => f
+                .debug_tuple("Type::optional")
+                .field(&TypeView {
+                    type_: value,
+                    ctx: self.ctx,
+                })
+                .finish(),
+        }
+    }
+}
+
+impl fmt::Display for TypeView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ty = self.type_.resolve(self.ctx);
+        match ty {
+            Type::MetaVar { var_id } => write!(f, "?{}", var_id),
+            Type::Unit => write!(f, "()"),
+            Type::String => write!(f, "String"),
+            Type::Integer => write!(f, "Integer"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Never => write!(f, "!"),
+            Type::Function { args, ret } => {
+                write!(f, "(")?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg.view(self.ctx))?;
+                }
+                write!(f, ") -> {}", ret.view(self.ctx))
+            }
+            Type::Array(elem) => write!(f, "[{}]", elem.view(self.ctx)),
+            Type::Optional(value) => write!(f, "Option<{}>", value.view(self.ctx)),
         }
     }
 }
@@ -325,6 +411,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_display() {
+        let mut ctx = TyCtx::default();
+
+        let var1 = Type::fresh(&mut ctx);
+        let ty1 = Type::function(
+            vec![Type::integer(), var1.clone()],
+            Type::function(vec![], Type::unit()),
+        );
+        assert_eq!(ty1.view(&ctx).to_string(), "(Integer, ?0) -> () -> ()");
+        var1.unify(&Type::string(), &mut ctx).unwrap();
+        assert_eq!(ty1.view(&ctx).to_string(), "(Integer, String) -> () -> ()");
+    }
+
+    #[test]
+    fn test_unify_optional() {
+        let mut ctx = TyCtx::default();
+
+        let var1 = Type::fresh(&mut ctx);
+        let ty1 = Type::optional(var1.clone());
+        assert!(ty1.unify(&Type::optional(Type::string()), &mut ctx).is_ok());
+        assert_eq!(var1.view(&ctx), Type::string().view(&ctx));
+        assert_eq!(ty1.view(&ctx).to_string(), "Option<String>");
+        assert!(ty1.unify(&Type::string(), &mut ctx).is_err());
+    }
+
+    #[test]
+    fn test_unify_never() {
+        let mut ctx = TyCtx::default();
+
+        assert!(Type::never().unify(&Type::integer(), &mut ctx).is_err());
+        assert!(Type::string().unify(&Type::never(), &mut ctx).is_err());
+        assert!(Type::never().unify(&Type::never(), &mut ctx).is_ok());
+        // `!` is an ordinary type; the typechecker handles divergence where control joins
+        let var1 = Type::fresh(&mut ctx);
+        assert!(var1.unify(&Type::never(), &mut ctx).is_ok());
+        assert_eq!(var1.view(&ctx), Type::never().view(&ctx));
+        assert!(var1.unify(&Type::integer(), &mut ctx).is_err());
+        assert_eq!(Type::never().view(&ctx).to_string(), "!");
+    }
+
     #[test]
     fn test_unify_arg() {
         let mut ctx = TyCtx::default();
@@ -1,48 +1,248 @@
+use std::mem;
+
+use thiserror::Error;
+
 use crate::ast::{BinOp, Expr, Ident, Stmt};
+use crate::cctx::{CCtx, Interner};
+use crate::source_map::SourceMap;
 
-#[derive(Debug)]
-pub struct ParseError;
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("parse error at {line}:{column}")]
+    Syntax { line: usize, column: usize },
+    #[error("expression nested too deeply at {line}:{column}")]
+    NestingTooDeep { line: usize, column: usize },
+    #[error("`then` is only allowed in the last statement of a block at {line}:{column}")]
+    ThenNotLast { line: usize, column: usize },
+}
+
+/// Default limit of `Parser::max_depth`
+pub const DEFAULT_MAX_DEPTH: usize = 256;
 
 pub fn parse(source: &str) -> Result<Vec<Stmt>, ParseError> {
     let mut parser = Parser::new(source);
+    let (stmts, _) = parser.parse_program()?;
+    Ok(stmts)
+}
+
+/// Same as `parse`, but interns the names with the compiler context,
+/// and reports the positions and limits the nesting as its options say.
+pub fn parse_with_cctx(source: &str, cctx: &CCtx) -> Result<Vec<Stmt>, ParseError> {
+    let mut parser = Parser::new(source)
+        .with_interner(cctx.interner.clone())
+        .with_tab_width(cctx.options.tab_width)
+        .with_max_depth(cctx.options.max_depth);
+    let (stmts, _) = parser.parse_program()?;
+    Ok(stmts)
+}
+
+/// Parses a bare expression, without the preamble, interning the names with the compiler context.
+pub fn parse_expr(source: &str, cctx: &CCtx) -> Result<Expr, ParseError> {
+    let mut parser = Parser::new(source).with_interner(cctx.interner.clone());
+    let expr = parser.parse_expr()?;
+    parser.expect_eof()?;
+    Ok(expr)
+}
+
+/// Same as `parse`, but also keeps the comments in the AST.
+///
+/// A comment is attached to the statement that follows it, or to the statement
+/// it follows on the same line. One inside a statement is attached to the operand
+/// that follows it, or to the statement if none does.
+/// Also returns the comments after the last statement.
+pub fn parse_with_comments(source: &str) -> Result<(Vec<Stmt>, Vec<String>), ParseError> {
+    let mut parser = Parser::new(source).with_keep_comments(true);
     parser.parse_program()
 }
 
+/// Resolves the escape sequences in the body of a string literal,
+/// which the lexer has already checked.
+fn unescape(s: &str) -> String {
+    let mut value = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        value.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '"')) => c,
+            _ => unreachable!("invalid escape sequence"),
+        });
+    }
+    value
+}
+
 #[derive(Debug)]
-struct Parser {
-    buf: Vec<u8>,
+struct Parser<'a> {
+    buf: &'a [u8],
     pos: usize,
     next_token_cache: Option<Token>,
+    source_map: SourceMap<'a>,
+    /// Interner for the names of identifiers
+    interner: Interner,
+    /// Whether to collect comments as trivia
+    keep_comments: bool,
+    /// Comments seen but not attached to the AST yet, with their byte offsets
+    pending_comments: Vec<(usize, String)>,
+    /// Byte offset of the end of the last token consumed
+    prev_token_end: usize,
+    /// Number of expressions currently being parsed recursively
+    depth: usize,
+    /// Limit of `depth`, so that deeply nested input does not overflow the stack
+    /// here or in the later passes
+    max_depth: usize,
+    /// Number of `loop`s around the current position in the innermost function,
+    /// so that a `break` outside of them is rejected
+    loop_depth: usize,
 }
 
-impl Parser {
-    fn new(source: &str) -> Self {
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
         Self {
-            buf: source.as_bytes().to_vec(),
+            buf: source.as_bytes(),
             pos: 0,
             next_token_cache: None,
+            source_map: SourceMap::new(source),
+            interner: Interner::default(),
+            keep_comments: false,
+            pending_comments: vec![],
+            prev_token_end: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            loop_depth: 0,
         }
     }
-    fn parse_program(&mut self) -> Result<Vec<Stmt>, ParseError> {
-        // Preliminary preamble
-        if self.buf[self.pos..].starts_with(b"use lang::\"0.0.1\";\n") {
-            self.pos += b"use lang::\"0.0.1\";\n".len();
-        } else {
-            return Err(ParseError);
+    fn with_interner(mut self, interner: Interner) -> Self {
+        self.interner = interner;
+        self
+    }
+    fn with_keep_comments(mut self, keep_comments: bool) -> Self {
+        self.keep_comments = keep_comments;
+        self
+    }
+    fn with_tab_width(mut self, tab_width: usize) -> Self {
+        self.source_map = self.source_map.with_tab_width(tab_width);
+        self
+    }
+    fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+    /// Identifier with the name of the token, interned
+    fn ident(&self, tok: Token) -> Ident {
+        let name = std::str::from_utf8(&self.buf[tok.begin..tok.end]).unwrap();
+        Ident::from(self.interner.intern(name))
+    }
+    /// Line and column of the current token
+    fn position(&self) -> (usize, usize) {
+        let offset = self
+            .next_token_cache
+            .as_ref()
+            .map_or(self.pos, |tok| tok.begin);
+        self.source_map.line_col(offset)
+    }
+    /// Error located at the current token
+    fn error(&self) -> ParseError {
+        let (line, column) = self.position();
+        ParseError::Syntax { line, column }
+    }
+    /// Runs `f` one nesting level deeper, failing if the limit is exceeded.
+    fn nested<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        self.deepen()?;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+    /// Goes one level deeper, for each operator in a left-associative chain
+    /// as well as in `nested`. The caller restores `depth` afterwards.
+    fn deepen(&mut self) -> Result<(), ParseError> {
+        if self.depth >= self.max_depth {
+            let (line, column) = self.position();
+            return Err(ParseError::NestingTooDeep { line, column });
         }
+        self.depth += 1;
+        Ok(())
+    }
+    /// Parses the program, returning also the comments after its last statement.
+    fn parse_program(&mut self) -> Result<(Vec<Stmt>, Vec<String>), ParseError> {
+        self.parse_preamble()?;
         let stmts = self.parse_stmts()?;
         self.expect_eof()?;
         Ok(stmts)
     }
-    fn parse_stmts(&mut self) -> Result<Vec<Stmt>, ParseError> {
+    /// Parses the preamble `use lang::"0.0.1";`, which is the only version supported for now.
+    fn parse_preamble(&mut self) -> Result<(), ParseError> {
+        for expected in ["use", "lang", "::", "\"0.0.1\"", ";"] {
+            let tok = self.next_token()?;
+            if &self.buf[tok.begin..tok.end] != expected.as_bytes() {
+                return Err(self.error());
+            }
+            self.bump();
+        }
+        Ok(())
+    }
+    /// Parses the statements up to a closing delimiter,
+    /// returning also the comments after the last statement.
+    fn parse_stmts(&mut self) -> Result<(Vec<Stmt>, Vec<String>), ParseError> {
         let mut stmts = vec![];
         loop {
             if self.lookahead_delim()? {
                 break;
             }
-            stmts.push(self.parse_stmt()?);
+            // The lookahead has already collected the comments before the statement
+            let leading_comments = self.take_comments(self.pending_comments.len());
+            let (line, column) = self.position();
+            let stmt = self.parse_stmt()?;
+            let stmt_end = self.prev_token_end;
+            let at_delim = self.lookahead_delim()?;
+            // Only the last statement gives the value of the block,
+            // so the value of an earlier `then` would be silently lost
+            if matches!(
+                stmt,
+                Stmt::Expr {
+                    use_value: true,
+                    ..
+                }
+            ) && !at_delim
+            {
+                return Err(ParseError::ThenNotLast { line, column });
+            }
+            let trailing_comments = self.take_trailing_comments(stmt_end);
+            stmts.push(
+                stmt.with_leading_comments(leading_comments)
+                    .with_trailing_comments(trailing_comments),
+            );
         }
-        Ok(stmts)
+        let trailing_comments = self.take_comments(self.pending_comments.len());
+        Ok((stmts, trailing_comments))
+    }
+    /// Takes the first `n` pending comments.
+    fn take_comments(&mut self, n: usize) -> Vec<String> {
+        self.pending_comments
+            .drain(..n)
+            .map(|(_, comment)| comment)
+            .collect()
+    }
+    /// Takes the pending comments inside the statement ending at `stmt_end`,
+    /// and those after it up to the end of the line.
+    fn take_trailing_comments(&mut self, stmt_end: usize) -> Vec<String> {
+        let mut end = stmt_end;
+        let mut n = 0;
+        for (begin, comment) in &self.pending_comments {
+            if *begin > end && self.buf[end..*begin].contains(&b'\n') {
+                break;
+            }
+            end = end.max(begin + comment.len());
+            n += 1;
+        }
+        self.take_comments(n)
     }
     fn parse_stmt(&mut self) -> Result<Stmt, ParseError> {
         let tok = self.next_token()?;
@@ -50,43 +250,50 @@ impl Parser {
             TokenKind::KeywordLet => {
                 self.bump();
                 let id_token = self.next_token()?;
-                let name = match id_token.kind {
+                let lhs = match id_token.kind {
                     TokenKind::Identifier => {
                         self.bump();
-                        std::str::from_utf8(&self.buf[id_token.begin..id_token.end])
-                            .unwrap()
-                            .to_owned()
+                        self.ident(id_token)
                     }
-                    _ => return Err(ParseError),
+                    _ => return Err(self.error()),
                 };
                 let tok = self.next_token()?;
+                if tok.kind == TokenKind::Semicolon {
+                    // let <name>;
+                    self.bump();
+                    return Ok(Stmt::let_uninit(lhs));
+                }
                 if tok.kind != TokenKind::Equal {
-                    return Err(ParseError);
+                    return Err(self.error());
                 }
                 self.bump();
                 let init = self.parse_expr()?;
                 let tok = self.next_token()?;
                 if tok.kind != TokenKind::Semicolon {
-                    return Err(ParseError);
+                    return Err(self.error());
                 }
                 self.bump();
-                Ok(Stmt::let_(Ident::from(name), init))
+                Ok(Stmt::let_(lhs, init))
             }
             TokenKind::KeywordThen => {
                 self.bump();
                 let expr = self.parse_expr()?;
                 let tok = self.next_token()?;
                 if tok.kind != TokenKind::Semicolon {
-                    return Err(ParseError);
+                    return Err(self.error());
                 }
                 self.bump();
                 Ok(Stmt::expr(expr, true))
             }
             _ => {
                 let expr = self.parse_expr()?;
+                if self.lookahead_delim()? {
+                    // <expr> without `;` at the end of a block or program yields its value
+                    return Ok(Stmt::expr(expr, true));
+                }
                 let tok = self.next_token()?;
                 if tok.kind != TokenKind::Semicolon {
-                    return Err(ParseError);
+                    return Err(self.error());
                 }
                 self.bump();
                 Ok(Stmt::expr(expr, false))
@@ -109,26 +316,55 @@ impl Parser {
                 // Non-empty list without trailing comma
                 break;
             } else {
-                return Err(ParseError);
+                return Err(self.error());
             }
         }
         Ok(exprs)
     }
+    fn parse_params(&mut self) -> Result<Vec<Ident>, ParseError> {
+        let mut params = vec![];
+        loop {
+            if self.lookahead_delim()? {
+                // Empty list or trailing comma
+                break;
+            }
+            let tok = self.next_token()?;
+            if tok.kind != TokenKind::Identifier {
+                return Err(self.error());
+            }
+            self.bump();
+            params.push(self.ident(tok));
+
+            let tok = self.next_token()?;
+            if matches!(tok.kind, TokenKind::Comma) {
+                self.bump();
+            } else if self.lookahead_delim()? {
+                // Non-empty list without trailing comma
+                break;
+            } else {
+                return Err(self.error());
+            }
+        }
+        Ok(params)
+    }
     fn lookahead_delim(&mut self) -> Result<bool, ParseError> {
         let tok = self.next_token()?;
         Ok(matches!(
             tok.kind,
-            TokenKind::RParen | TokenKind::RBrace | TokenKind::Eof
+            TokenKind::RParen | TokenKind::RBracket | TokenKind::RBrace | TokenKind::Eof
         ))
     }
     fn parse_expr(&mut self) -> Result<Expr, ParseError> {
-        let e = self.parse_expr_comparison()?;
+        self.nested(Self::parse_expr_assign)
+    }
+    fn parse_expr_assign(&mut self) -> Result<Expr, ParseError> {
+        let e = self.parse_expr_logical_or()?;
         let tok = self.next_token()?;
         match tok.kind {
             TokenKind::Equal => {
                 self.bump();
                 let Expr::Var { ident } = e else {
-                    return Err(ParseError);
+                    return Err(self.error());
                 };
                 let rhs = self.parse_expr()?;
                 return Ok(Expr::assign(ident, rhs));
@@ -137,57 +373,190 @@ impl Parser {
         }
         Ok(e)
     }
+    fn parse_expr_logical_or(&mut self) -> Result<Expr, ParseError> {
+        let mut e = self.parse_expr_logical_and()?;
+        let depth = self.depth;
+        loop {
+            let tok = self.next_token()?;
+            if tok.kind != TokenKind::OrOr {
+                break;
+            }
+            self.deepen()?;
+            self.bump();
+            let rhs = self.parse_expr_logical_and()?;
+            e = Expr::bin_op(BinOp::Or, e, rhs);
+        }
+        self.depth = depth;
+        Ok(e)
+    }
+    fn parse_expr_logical_and(&mut self) -> Result<Expr, ParseError> {
+        let mut e = self.parse_expr_comparison()?;
+        let depth = self.depth;
+        loop {
+            let tok = self.next_token()?;
+            if tok.kind != TokenKind::AndAnd {
+                break;
+            }
+            self.deepen()?;
+            self.bump();
+            let rhs = self.parse_expr_comparison()?;
+            e = Expr::bin_op(BinOp::And, e, rhs);
+        }
+        self.depth = depth;
+        Ok(e)
+    }
     fn parse_expr_comparison(&mut self) -> Result<Expr, ParseError> {
         let mut e = self.parse_expr_additive()?;
+        let depth = self.depth;
         loop {
             let tok = self.next_token()?;
             let bin_op = match tok.kind {
                 TokenKind::LessThan => BinOp::Lt,
+                TokenKind::LessEqual => BinOp::Le,
+                TokenKind::GreaterThan => BinOp::Gt,
+                TokenKind::GreaterEqual => BinOp::Ge,
+                TokenKind::EqualEqual => BinOp::Eq,
+                TokenKind::NotEqual => BinOp::Ne,
                 _ => break,
             };
+            self.deepen()?;
             self.bump();
             let rhs = self.parse_expr_additive()?;
             e = Expr::bin_op(bin_op, e, rhs);
         }
+        self.depth = depth;
         Ok(e)
     }
     fn parse_expr_additive(&mut self) -> Result<Expr, ParseError> {
-        let mut e = self.parse_expr_call()?;
+        let mut e = self.parse_expr_multiplicative()?;
+        let depth = self.depth;
         loop {
             let tok = self.next_token()?;
             let bin_op = match tok.kind {
                 TokenKind::Plus => BinOp::Add,
+                TokenKind::Minus => BinOp::Sub,
+                _ => break,
+            };
+            self.deepen()?;
+            self.bump();
+            let rhs = self.parse_expr_multiplicative()?;
+            e = Expr::bin_op(bin_op, e, rhs);
+        }
+        self.depth = depth;
+        Ok(e)
+    }
+    fn parse_expr_multiplicative(&mut self) -> Result<Expr, ParseError> {
+        let mut e = self.parse_expr_unary()?;
+        let depth = self.depth;
+        loop {
+            let tok = self.next_token()?;
+            let bin_op = match tok.kind {
+                TokenKind::Star => BinOp::Mul,
+                TokenKind::Slash => BinOp::Div,
                 _ => break,
             };
+            self.deepen()?;
             self.bump();
-            let rhs = self.parse_expr_call()?;
+            let rhs = self.parse_expr_unary()?;
             e = Expr::bin_op(bin_op, e, rhs);
         }
+        self.depth = depth;
         Ok(e)
     }
+    fn parse_expr_unary(&mut self) -> Result<Expr, ParseError> {
+        let tok = self.next_token()?;
+        if tok.kind == TokenKind::Minus {
+            self.bump();
+            let tok = self.next_token()?;
+            if tok.kind == TokenKind::Integer {
+                // A negative literal is a literal by itself, so that the smallest integer can be written
+                let e = self.parse_integer_literal(true)?;
+                return self.parse_expr_postfix(e);
+            }
+            // `-e` is sugar for `0 - e`
+            let operand = self.nested(Self::parse_expr_unary)?;
+            return Ok(Expr::bin_op(BinOp::Sub, Expr::integer_literal(0), operand));
+        }
+        self.parse_expr_call()
+    }
     fn parse_expr_call(&mut self) -> Result<Expr, ParseError> {
-        let mut e = self.parse_expr_primary()?;
+        let e = self.parse_expr_primary()?;
+        self.parse_expr_postfix(e)
+    }
+    /// Parses the calls and indexing that follow the expression.
+    fn parse_expr_postfix(&mut self, mut e: Expr) -> Result<Expr, ParseError> {
+        let depth = self.depth;
         loop {
             let tok = self.next_token()?;
             match tok.kind {
                 TokenKind::LParen => {
+                    self.deepen()?;
                     self.bump();
                     let args = self.parse_exprs()?;
                     let tok = self.next_token()?;
                     if tok.kind != TokenKind::RParen {
-                        return Err(ParseError);
+                        return Err(self.error());
                     }
                     self.bump();
                     e = Expr::call(e, args);
                 }
+                TokenKind::LBracket => {
+                    // <base>[<index>] or <base>[<start>..<end>]
+                    self.deepen()?;
+                    self.bump();
+                    let mut index = self.parse_expr()?;
+                    let tok = self.next_token()?;
+                    if tok.kind == TokenKind::DotDot {
+                        self.bump();
+                        let end = self.parse_expr()?;
+                        index = Expr::range(index, end);
+                    }
+                    let tok = self.next_token()?;
+                    if tok.kind != TokenKind::RBracket {
+                        return Err(self.error());
+                    }
+                    self.bump();
+                    e = Expr::index(e, index);
+                }
                 _ => {
                     break;
                 }
             }
         }
+        self.depth = depth;
         Ok(e)
     }
     fn parse_expr_primary(&mut self) -> Result<Expr, ParseError> {
+        // The lookahead has already collected the comments before the operand
+        self.next_token()?;
+        let comments = self.take_comments(self.pending_comments.len());
+        let e = self.parse_expr_primary_uncommented()?;
+        if comments.is_empty() {
+            return Ok(e);
+        }
+        Ok(Expr::commented(comments, e))
+    }
+    /// Parses the integer literal at the next token, negated if `negative`.
+    fn parse_integer_literal(&mut self, negative: bool) -> Result<Expr, ParseError> {
+        let tok = self.next_token()?;
+        let s = std::str::from_utf8(&self.buf[tok.begin..tok.end])
+            .unwrap()
+            .replace('_', "");
+        let (radix, digits) = match s.get(..2) {
+            Some("0x") => (16, &s[2..]),
+            Some("0o") => (8, &s[2..]),
+            Some("0b") => (2, &s[2..]),
+            _ => (10, &s[..]),
+        };
+        // The sign is parsed together with the digits, as `-2147483648` has no positive counterpart
+        let sign = if negative { "-" } else { "" };
+        let value = i32::from_str_radix(&format!("{}{}", sign, digits), radix)
+            // Out of range
+            .map_err(|_| self.error())?;
+        self.bump();
+        Ok(Expr::integer_literal(value))
+    }
+    fn parse_expr_primary_uncommented(&mut self) -> Result<Expr, ParseError> {
         let tok = self.next_token()?;
         match tok.kind {
             TokenKind::LParen => {
@@ -195,14 +564,25 @@ impl Parser {
                 let e = self.parse_expr()?;
                 let tok = self.next_token()?;
                 if tok.kind != TokenKind::RParen {
-                    return Err(ParseError);
+                    return Err(self.error());
                 }
+                self.bump();
                 Ok(e)
             }
+            TokenKind::LBracket => {
+                // [<elems>]
+                self.bump();
+                let elems = self.parse_exprs()?;
+                let tok = self.next_token()?;
+                if tok.kind != TokenKind::RBracket {
+                    return Err(self.error());
+                }
+                self.bump();
+                Ok(Expr::array_literal(elems))
+            }
             TokenKind::Identifier => {
                 self.bump();
-                let name = std::str::from_utf8(&self.buf[tok.begin..tok.end]).unwrap();
-                Ok(Expr::var(Ident::from(name)))
+                Ok(Expr::var(self.ident(tok)))
             }
             TokenKind::KeywordDo => {
                 // do { <stmts> }
@@ -211,21 +591,23 @@ impl Parser {
             }
             TokenKind::KeywordIf => {
                 self.bump();
+                let binder = self.parse_some_pattern();
                 let cond = self.parse_expr()?;
                 let tok = self.next_token()?;
-                match tok.kind {
+                let (then, else_) = match tok.kind {
                     TokenKind::KeywordThen => {
                         // if <cond> then <then> else <else>
                         self.bump();
                         let then = self.parse_expr()?;
                         let tok = self.next_token()?;
                         if tok.kind != TokenKind::KeywordElse {
-                            return Err(ParseError);
+                            return Err(self.error());
                         }
                         self.bump();
-                        // TODO: primary should not be right-open
-                        let else_ = self.parse_expr_primary()?;
-                        Ok(Expr::branch(cond, then, else_))
+                        // Calls and indexing belong to the else arm, as in `else panic("x")`
+                        // TODO: binary operators after the else arm still apply to the whole `if`
+                        let else_ = self.nested(Self::parse_expr_unary)?;
+                        (then, Some(else_))
                     }
                     TokenKind::LBrace => {
                         let then = self.parse_block_expr()?;
@@ -236,16 +618,73 @@ impl Parser {
                             // TODO: deal with ambiguous cases like
                             // if <cond1> then if <cond2> { <then2> } else { <else1> }
                             self.bump();
-                            // TODO: also handle else-if exceptions
-                            let else_ = self.parse_block_expr()?;
-                            Ok(Expr::branch(cond, then, else_))
+                            let tok = self.next_token()?;
+                            let else_ = if tok.kind == TokenKind::KeywordIf {
+                                // if <cond> { <then> } else if ...
+                                self.nested(Self::parse_expr_primary)?
+                            } else {
+                                self.parse_block_expr()?
+                            };
+                            (then, Some(else_))
                         } else {
                             // if <cond> { <then> }
-                            Ok(Expr::branch(cond, then, Expr::block(vec![])))
+                            (then, None)
                         }
                     }
-                    _ => return Err(ParseError),
+                    _ => return Err(self.error()),
+                };
+                Ok(match (binder, else_) {
+                    (None, Some(else_)) => Expr::branch(cond, then, else_),
+                    (None, None) => Expr::branch_without_else(cond, then),
+                    (Some(binder), Some(else_)) => Expr::if_some(binder, cond, then, else_),
+                    (Some(binder), None) => Expr::if_some_without_else(binder, cond, then),
+                })
+            }
+            TokenKind::KeywordFn => {
+                // fn(<params>) { <body> }
+                self.bump();
+                let tok = self.next_token()?;
+                if tok.kind != TokenKind::LParen {
+                    return Err(self.error());
+                }
+                self.bump();
+                let params = self.parse_params()?;
+                let tok = self.next_token()?;
+                if tok.kind != TokenKind::RParen {
+                    return Err(self.error());
                 }
+                self.bump();
+                let body = self.in_function_body(Self::parse_block_expr)?;
+                Ok(Expr::lambda(params, body))
+            }
+            TokenKind::KeywordReturn => {
+                // return <value>
+                self.bump();
+                let value = self.parse_expr()?;
+                Ok(Expr::return_(value))
+            }
+            TokenKind::KeywordTry => {
+                // try { <body> }
+                self.bump();
+                let body = self.in_function_body(Self::parse_block_expr)?;
+                Ok(Expr::try_(body))
+            }
+            TokenKind::KeywordLoop => {
+                // loop { <body> }
+                self.bump();
+                self.loop_depth += 1;
+                let body = self.parse_block_expr();
+                self.loop_depth -= 1;
+                Ok(Expr::loop_(body?))
+            }
+            TokenKind::KeywordBreak => {
+                // break <value>
+                if self.loop_depth == 0 {
+                    return Err(self.error());
+                }
+                self.bump();
+                let value = self.parse_expr()?;
+                Ok(Expr::break_(value))
             }
             TokenKind::KeywordWhile => {
                 // while <cond> { <body> }
@@ -253,92 +692,150 @@ impl Parser {
                 let cond = self.parse_expr()?;
                 let tok = self.next_token()?;
                 if tok.kind != TokenKind::LBrace {
-                    return Err(ParseError);
+                    return Err(self.error());
                 }
                 let body = self.parse_block_expr()?;
                 Ok(Expr::while_(cond, body))
             }
-            TokenKind::Integer => {
-                self.bump();
-                let s = std::str::from_utf8(&self.buf[tok.begin..tok.end]).unwrap();
-                let value = s.parse::<i32>().unwrap();
-                Ok(Expr::integer_literal(value))
-            }
+            TokenKind::Integer => self.parse_integer_literal(false),
             TokenKind::String => {
                 self.bump();
                 let s = std::str::from_utf8(&self.buf[tok.begin + 1..tok.end - 1]).unwrap();
-                Ok(Expr::string_literal(s.to_owned()))
+                Ok(Expr::string_literal(unescape(s)))
+            }
+            TokenKind::KeywordTrue | TokenKind::KeywordFalse => {
+                self.bump();
+                Ok(Expr::boolean_literal(tok.kind == TokenKind::KeywordTrue))
+            }
+            _ => Err(self.error()),
+        }
+    }
+    /// Parses the `some(<binder>) =` of `if some(<binder>) = <scrutinee> ...`, if it comes next.
+    ///
+    /// Nothing is consumed otherwise, as a plain condition starts there instead.
+    fn parse_some_pattern(&mut self) -> Option<Ident> {
+        let pos = self.pos;
+        let next_token_cache = self.next_token_cache;
+        let num_pending_comments = self.pending_comments.len();
+        let binder = self.try_parse_some_pattern();
+        if binder.is_none() {
+            self.pos = pos;
+            self.next_token_cache = next_token_cache;
+            self.pending_comments.truncate(num_pending_comments);
+        }
+        binder
+    }
+    fn try_parse_some_pattern(&mut self) -> Option<Ident> {
+        let tok = self.next_token().ok()?;
+        if tok.kind != TokenKind::Identifier || &self.buf[tok.begin..tok.end] != b"some" {
+            return None;
+        }
+        self.bump();
+        if self.next_token().ok()?.kind != TokenKind::LParen {
+            return None;
+        }
+        self.bump();
+        let tok = self.next_token().ok()?;
+        if tok.kind != TokenKind::Identifier {
+            return None;
+        }
+        self.bump();
+        let binder = self.ident(tok);
+        for kind in [TokenKind::RParen, TokenKind::Equal] {
+            if self.next_token().ok()?.kind != kind {
+                return None;
             }
-            _ => Err(ParseError),
+            self.bump();
         }
+        Some(binder)
+    }
+    /// Runs `f` for the body of a lambda or `try`, which `break` cannot leave.
+    fn in_function_body<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        let outer_loop_depth = mem::replace(&mut self.loop_depth, 0);
+        let result = f(self);
+        self.loop_depth = outer_loop_depth;
+        result
     }
     fn parse_block_expr(&mut self) -> Result<Expr, ParseError> {
         let tok = self.next_token()?;
         if tok.kind != TokenKind::LBrace {
-            return Err(ParseError);
+            return Err(self.error());
         }
         self.bump();
-        let stmts = self.parse_stmts()?;
+        let (stmts, trailing_comments) = self.parse_stmts()?;
         let tok = self.next_token()?;
         if tok.kind != TokenKind::RBrace {
-            return Err(ParseError);
+            return Err(self.error());
         }
         self.bump();
-        Ok(Expr::block(stmts))
+        Ok(Expr::Block {
+            stmts,
+            trailing_comments,
+        })
     }
     fn expect_eof(&mut self) -> Result<(), ParseError> {
         let tok = self.next_token()?;
         if tok.kind != TokenKind::Eof {
-            return Err(ParseError);
+            return Err(self.error());
         }
         Ok(())
     }
     fn bump(&mut self) {
-        assert!(self.next_token_cache.is_some());
-        self.next_token_cache = None;
+        let tok = self.next_token_cache.take().unwrap();
+        self.prev_token_end = tok.end;
     }
     fn next_token(&mut self) -> Result<Token, ParseError> {
-        if let Some(tok) = self.next_token_cache.clone() {
+        if let Some(tok) = self.next_token_cache {
             return Ok(tok);
         }
-        self.skip_whitespace();
+        self.skip_whitespace()?;
         let begin = self.pos;
+        // Fast path for the most frequent tokens
+        if let Some(kind) = self.buf.get(self.pos).copied().and_then(single_byte_token) {
+            self.pos += 1;
+            let tok = Token {
+                kind,
+                begin,
+                end: self.pos,
+            };
+            self.next_token_cache = Some(tok);
+            return Ok(tok);
+        }
         let kind = match self.buf.get(self.pos).copied() {
-            Some(b'(') => {
-                self.pos += 1;
-                TokenKind::LParen
+            Some(b'.') if self.buf.get(self.pos + 1) == Some(&b'.') => {
+                self.pos += 2;
+                TokenKind::DotDot
             }
-            Some(b')') => {
-                self.pos += 1;
-                TokenKind::RParen
+            Some(b':') if self.buf.get(self.pos + 1) == Some(&b':') => {
+                self.pos += 2;
+                TokenKind::ColonColon
             }
-            Some(b'+') => {
-                self.pos += 1;
-                TokenKind::Plus
+            Some(b'&') if self.buf.get(self.pos + 1) == Some(&b'&') => {
+                self.pos += 2;
+                TokenKind::AndAnd
             }
-            Some(b',') => {
-                self.pos += 1;
-                TokenKind::Comma
-            }
-            Some(b';') => {
-                self.pos += 1;
-                TokenKind::Semicolon
+            Some(b'|') if self.buf.get(self.pos + 1) == Some(&b'|') => {
+                self.pos += 2;
+                TokenKind::OrOr
             }
-            Some(b'<') => {
-                self.pos += 1;
-                TokenKind::LessThan
-            }
-            Some(b'=') => {
-                self.pos += 1;
-                TokenKind::Equal
-            }
-            Some(b'{') => {
-                self.pos += 1;
-                TokenKind::LBrace
-            }
-            Some(b'}') => {
-                self.pos += 1;
-                TokenKind::RBrace
+            Some(b @ (b'<' | b'>' | b'=' | b'!')) => {
+                let or_equal = self.buf.get(self.pos + 1) == Some(&b'=');
+                let kind = match (b, or_equal) {
+                    (b'<', false) => TokenKind::LessThan,
+                    (b'<', true) => TokenKind::LessEqual,
+                    (b'>', false) => TokenKind::GreaterThan,
+                    (b'>', true) => TokenKind::GreaterEqual,
+                    (b'=', false) => TokenKind::Equal,
+                    (b'=', true) => TokenKind::EqualEqual,
+                    (_, true) => TokenKind::NotEqual,
+                    // `!` alone is not an operator yet
+                    (_, false) => return Err(self.error()),
+                };
+                self.pos += if or_equal { 2 } else { 1 };
+                kind
             }
             Some(b'a'..=b'z') | Some(b'A'..=b'Z') | Some(b'_') => {
                 while self.pos < self.buf.len()
@@ -348,62 +845,179 @@ impl Parser {
                 }
                 match &self.buf[begin..self.pos] {
                     // TODO: other reserved identifiers
-                    b"true" | b"false" => todo!(),
+                    b"break" => TokenKind::KeywordBreak,
                     b"do" => TokenKind::KeywordDo,
                     b"else" => TokenKind::KeywordElse,
+                    b"false" => TokenKind::KeywordFalse,
+                    b"fn" => TokenKind::KeywordFn,
                     b"if" => TokenKind::KeywordIf,
                     b"let" => TokenKind::KeywordLet,
+                    b"loop" => TokenKind::KeywordLoop,
+                    b"return" => TokenKind::KeywordReturn,
                     b"then" => TokenKind::KeywordThen,
+                    b"true" => TokenKind::KeywordTrue,
+                    b"try" => TokenKind::KeywordTry,
                     b"while" => TokenKind::KeywordWhile,
                     _ => TokenKind::Identifier,
                 }
             }
             Some(b'0'..=b'9') => {
-                // TODO: check leading zero
-                while self.pos < self.buf.len() && self.buf[self.pos].is_ascii_digit() {
-                    self.pos += 1;
+                let radix = match &self.buf[self.pos..] {
+                    [b'0', b'x', ..] => Some(16),
+                    [b'0', b'o', ..] => Some(8),
+                    [b'0', b'b', ..] => Some(2),
+                    _ => None,
+                };
+                if let Some(radix) = radix {
+                    self.pos += 2;
+                    self.scan_digits(radix)?;
+                } else {
+                    // TODO: check leading zero
+                    self.scan_digits(10)?;
                 }
                 TokenKind::Integer
             }
             Some(b'"') => {
                 self.pos += 1;
-                while self.pos < self.buf.len() && self.buf[self.pos] != b'"' {
-                    // TODO: handle escapes etc.
-                    self.pos += 1;
-                }
-                if self.pos == self.buf.len() {
-                    return Err(ParseError);
+                loop {
+                    match self.buf.get(self.pos) {
+                        None => return Err(self.error()),
+                        Some(b'"') => break,
+                        Some(b'\\') => {
+                            // Reported at the backslash
+                            if !matches!(
+                                self.buf.get(self.pos + 1),
+                                Some(b'n' | b't' | b'\\' | b'"' | b'0')
+                            ) {
+                                return Err(self.error());
+                            }
+                            self.pos += 2;
+                        }
+                        Some(_) => self.pos += 1,
+                    }
                 }
                 self.pos += 1;
                 TokenKind::String
             }
             None => TokenKind::Eof,
-            _ => return Err(ParseError),
+            _ => return Err(self.error()),
         };
         let end = self.pos;
         let tok = Token { kind, begin, end };
-        self.next_token_cache = Some(tok.clone());
+        self.next_token_cache = Some(tok);
         Ok(tok)
     }
 
-    fn skip_whitespace(&mut self) {
+    /// Scans a nonempty run of digits, which may be separated by single underscores.
+    ///
+    /// Errors are reported at the offending character.
+    fn scan_digits(&mut self, radix: u32) -> Result<(), ParseError> {
+        let begin = self.pos;
+        while let Some(&b) = self.buf.get(self.pos) {
+            if b == b'_' {
+                let next_is_digit = self
+                    .buf
+                    .get(self.pos + 1)
+                    .is_some_and(|&next| char::from(next).is_digit(radix));
+                if self.pos == begin || !next_is_digit {
+                    return Err(self.error());
+                }
+            } else if !char::from(b).is_digit(radix) {
+                // Letters out of the radix are rejected, except in decimal literals
+                // where they start the next token
+                if radix != 10 && b.is_ascii_alphanumeric() {
+                    return Err(self.error());
+                }
+                break;
+            }
+            self.pos += 1;
+        }
+        if self.pos == begin {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) -> Result<(), ParseError> {
         while self.pos < self.buf.len() {
             match self.buf[self.pos] {
                 b' ' | b'\n' | b'\r' | b'\t' => self.pos += 1,
+                b'/' if self.buf.get(self.pos + 1) == Some(&b'/') => {
+                    // Line comment
+                    let begin = self.pos;
+                    while self.pos < self.buf.len() && self.buf[self.pos] != b'\n' {
+                        self.pos += 1;
+                    }
+                    if self.keep_comments {
+                        let comment = std::str::from_utf8(&self.buf[begin..self.pos]).unwrap();
+                        self.pending_comments
+                            .push((begin, comment.trim_end().to_owned()));
+                    }
+                }
+                b'/' if self.buf.get(self.pos + 1) == Some(&b'*') => {
+                    // Block comment, which may contain other block comments
+                    let begin = self.pos;
+                    self.pos += 2;
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match &self.buf[self.pos..] {
+                            [] => {
+                                let (line, column) = self.source_map.line_col(begin);
+                                return Err(ParseError::Syntax { line, column });
+                            }
+                            [b'/', b'*', ..] => {
+                                depth += 1;
+                                self.pos += 2;
+                            }
+                            [b'*', b'/', ..] => {
+                                depth -= 1;
+                                self.pos += 2;
+                            }
+                            _ => self.pos += 1,
+                        }
+                    }
+                    if self.keep_comments {
+                        let comment = std::str::from_utf8(&self.buf[begin..self.pos]).unwrap();
+                        self.pending_comments.push((begin, comment.to_owned()));
+                    }
+                }
                 _ => break,
             }
         }
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Punctuation consisting of a single byte
+fn single_byte_token(b: u8) -> Option<TokenKind> {
+    let kind = match b {
+        b'(' => TokenKind::LParen,
+        b')' => TokenKind::RParen,
+        b'+' => TokenKind::Plus,
+        b'-' => TokenKind::Minus,
+        b'*' => TokenKind::Star,
+        // `//` has been skipped as a comment by now
+        b'/' => TokenKind::Slash,
+        b',' => TokenKind::Comma,
+        b';' => TokenKind::Semicolon,
+        // `<`, `>`, and `=` may begin `<=`, `>=`, and `==`
+        b'[' => TokenKind::LBracket,
+        b']' => TokenKind::RBracket,
+        b'{' => TokenKind::LBrace,
+        b'}' => TokenKind::RBrace,
+        _ => return None,
+    };
+    Some(kind)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct Token {
     kind: TokenKind,
     begin: usize,
     end: usize,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum TokenKind {
     /// `(`
     LParen,
@@ -411,23 +1025,58 @@ enum TokenKind {
     RParen,
     /// `+`
     Plus,
+    /// `-`
+    Minus,
+    /// `*`
+    Star,
+    /// `/`
+    Slash,
     /// `,`
     Comma,
+    /// `..`
+    DotDot,
+    /// `::`
+    ColonColon,
     /// `;`
     Semicolon,
     /// `<`
     LessThan,
+    /// `<=`
+    LessEqual,
+    /// `>`
+    GreaterThan,
+    /// `>=`
+    GreaterEqual,
     /// `=`
     Equal,
+    /// `==`
+    EqualEqual,
+    /// `!=`
+    NotEqual,
+    /// `&&`
+    AndAnd,
+    /// `||`
+    OrOr,
+    /// `[`
+    LBracket,
+    /// `]`
+    RBracket,
     /// `{`
     LBrace,
     /// `}`
     RBrace,
+    KeywordBreak,
     KeywordDo,
     KeywordElse,
+    KeywordFalse,
+    KeywordFn,
     KeywordIf,
     KeywordLet,
+    KeywordLoop,
+    KeywordReturn,
     KeywordThen,
+    KeywordTrue,
+    KeywordTry,
     KeywordWhile,
     Identifier,
     Integer,
@@ -439,6 +1088,230 @@ enum TokenKind {
 mod tests {
     use super::*;
 
+    use crate::cctx::Symbol;
+
+    fn tokenize(source: &str) -> Vec<Token> {
+        let mut parser = Parser::new(source);
+        let mut tokens = vec![];
+        loop {
+            let tok = parser.next_token().unwrap();
+            tokens.push(tok);
+            if tok.kind == TokenKind::Eof {
+                return tokens;
+            }
+            parser.bump();
+        }
+    }
+
+    #[test]
+    fn test_tokenize_large_program() {
+        use TokenKind::*;
+        let unit = "let x_1 = f(a, [1, 2][0..1]) + \"s\" < y; // c\nwhile c { x = 2; }\n";
+        let unit_tokens = tokenize(unit);
+        assert_eq!(
+            unit_tokens.iter().map(|tok| tok.kind).collect::<Vec<_>>(),
+            vec![
+                KeywordLet,
+                Identifier,
+                Equal,
+                Identifier,
+                LParen,
+                Identifier,
+                Comma,
+                LBracket,
+                Integer,
+                Comma,
+                Integer,
+                RBracket,
+                LBracket,
+                Integer,
+                DotDot,
+                Integer,
+                RBracket,
+                RParen,
+                Plus,
+                String,
+                LessThan,
+                Identifier,
+                Semicolon,
+                KeywordWhile,
+                Identifier,
+                LBrace,
+                Identifier,
+                Equal,
+                Integer,
+                Semicolon,
+                RBrace,
+                Eof,
+            ]
+        );
+
+        // The tokens of a repeated program are those of the unit, shifted
+        let n = 10000;
+        let tokens = tokenize(&unit.repeat(n));
+        let unit_tokens = &unit_tokens[..unit_tokens.len() - 1];
+        assert_eq!(tokens.len(), unit_tokens.len() * n + 1);
+        for (i, chunk) in tokens.chunks(unit_tokens.len()).take(n).enumerate() {
+            let offset = unit.len() * i;
+            let expected = unit_tokens
+                .iter()
+                .map(|tok| Token {
+                    kind: tok.kind,
+                    begin: tok.begin + offset,
+                    end: tok.end + offset,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(chunk, expected);
+        }
+        assert_eq!(tokens.last().unwrap().kind, Eof);
+    }
+
+    #[test]
+    fn test_tokenize_comparisons() {
+        use TokenKind::*;
+        let kinds = tokenize("a<b <= c>d >= e < = f>= g==h = = i != j=k")
+            .iter()
+            .map(|tok| tok.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                Identifier,
+                LessThan,
+                Identifier,
+                LessEqual,
+                Identifier,
+                GreaterThan,
+                Identifier,
+                GreaterEqual,
+                Identifier,
+                LessThan,
+                Equal,
+                Identifier,
+                GreaterEqual,
+                Identifier,
+                EqualEqual,
+                Identifier,
+                Equal,
+                Equal,
+                Identifier,
+                NotEqual,
+                Identifier,
+                Equal,
+                Identifier,
+                Eof,
+            ]
+        );
+        assert!(Parser::new("! = a").next_token().is_err());
+    }
+
+    #[test]
+    fn test_next_token_without_bump() {
+        let mut parser = Parser::new("foo bar");
+        let tok = parser.next_token().unwrap();
+        assert_eq!(
+            tok,
+            Token {
+                kind: TokenKind::Identifier,
+                begin: 0,
+                end: 3,
+            }
+        );
+        // Peeking again neither consumes the token nor reads another one
+        assert_eq!(parser.next_token().unwrap(), tok);
+        assert_eq!(parser.pos, 3);
+        assert_eq!(parser.position(), (1, 1));
+        parser.bump();
+        assert_eq!(parser.position(), (1, 4));
+        let tok = parser.next_token().unwrap();
+        assert_eq!(
+            tok,
+            Token {
+                kind: TokenKind::Identifier,
+                begin: 4,
+                end: 7,
+            }
+        );
+        assert_eq!(parser.position(), (1, 5));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_bump_without_next_token() {
+        let mut parser = Parser::new("foo");
+        parser.bump();
+    }
+
+    #[test]
+    fn test_parse_expr_leaves_next_token() {
+        // Each expression is followed by `;`, which must be the next token after parsing
+        let sources = [
+            "x;",
+            "(x);",
+            "[x, y];",
+            "f(x)(y);",
+            "x[0..1];",
+            "x + y < z;",
+            "x = y;",
+            "do { x; };",
+            "if c then x else y;",
+            "if c { x };",
+            "if c { x } else { y };",
+            "if c { x } else if d { y } else { z };",
+            "while c { x; };",
+            "fn(x, y) { x };",
+            "try { x };",
+            "return x;",
+            "42;",
+            "\"s\";",
+        ];
+        for source in sources {
+            let mut parser = Parser::new(source);
+            parser.parse_expr().unwrap();
+            assert_eq!(
+                parser.next_token().unwrap(),
+                Token {
+                    kind: TokenKind::Semicolon,
+                    begin: source.len() - 1,
+                    end: source.len(),
+                },
+                "{}",
+                source
+            );
+            parser.bump();
+            assert_eq!(parser.next_token().unwrap().kind, TokenKind::Eof);
+        }
+    }
+
+    #[test]
+    fn test_parse_large_program() {
+        let n = 200000;
+        let mut source = String::from("use lang::\"0.0.1\";\n");
+        for i in 0..n {
+            source.push_str(&format!("let x{} = f(x, \"s\") + {};\n", i, i));
+        }
+        assert!(source.len() > 1 << 20);
+        let stmts = parse(&source).unwrap();
+        assert_eq!(stmts.len(), n);
+        assert_eq!(
+            stmts[n - 1],
+            Stmt::let_(
+                Ident::from(format!("x{}", n - 1)),
+                Expr::bin_op(
+                    BinOp::Add,
+                    Expr::call(
+                        Expr::var(Ident::from("f")),
+                        vec![
+                            Expr::var(Ident::from("x")),
+                            Expr::string_literal("s".to_owned())
+                        ]
+                    ),
+                    Expr::integer_literal(n as i32 - 1)
+                )
+            )
+        );
+    }
+
     #[test]
     fn test_parse_var_ref() {
         assert_eq!(
@@ -453,6 +1326,14 @@ mod tests {
             Parser::new("(x)").parse_expr().unwrap(),
             Expr::var(Ident::from("x"))
         );
+        assert_eq!(
+            Parser::new("(x) + (y)").parse_expr().unwrap(),
+            Expr::bin_op(
+                BinOp::Add,
+                Expr::var(Ident::from("x")),
+                Expr::var(Ident::from("y"))
+            )
+        );
     }
 
     #[test]
@@ -467,12 +1348,101 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_radix_integer_literal() {
+        for (source, value) in [
+            ("0xff", 255),
+            ("0x7FFFFFFF", i32::MAX),
+            ("0o17", 15),
+            ("0b1010", 10),
+            ("0b0", 0),
+        ] {
+            assert_eq!(
+                Parser::new(source).parse_expr().unwrap(),
+                Expr::integer_literal(value),
+                "{}",
+                source
+            );
+        }
+        for (source, column) in [
+            ("0x", 3),
+            ("0x + 1", 3),
+            ("0b102", 5),
+            ("0o8", 3),
+            ("0xfg", 4),
+            ("0x80000000", 1),
+        ] {
+            let e = Parser::new(source).parse_expr().unwrap_err();
+            assert!(
+                matches!(e, ParseError::Syntax { line: 1, column: c } if c == column),
+                "{}: {:?}",
+                source,
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_integer_literal_overflow() {
+        let e = parse("use lang::\"0.0.1\";\n3000000000;\n").unwrap_err();
+        assert!(matches!(e, ParseError::Syntax { line: 2, column: 1 }));
+        assert!(parse("use lang::\"0.0.1\";\n99999999999;\n").is_err());
+        assert_eq!(
+            Parser::new("2147483647").parse_expr().unwrap(),
+            Expr::integer_literal(i32::MAX)
+        );
+    }
+
+    #[test]
+    fn test_parse_integer_literal_separators() {
+        for (source, value) in [
+            ("1_000_000", 1_000_000),
+            ("1_2_3", 123),
+            ("0xff_ff", 0xffff),
+            ("0b1010_1010", 0b1010_1010),
+        ] {
+            assert_eq!(
+                Parser::new(source).parse_expr().unwrap(),
+                Expr::integer_literal(value),
+                "{}",
+                source
+            );
+        }
+        for (source, column) in [("1_", 2), ("1__0", 2), ("1_ + 2", 2), ("0x_ff", 3)] {
+            let e = Parser::new(source).parse_expr().unwrap_err();
+            assert!(
+                matches!(e, ParseError::Syntax { line: 1, column: c } if c == column),
+                "{}: {:?}",
+                source,
+                e
+            );
+        }
+        // A leading underscore starts an identifier
+        assert_eq!(
+            Parser::new("_1").parse_expr().unwrap(),
+            Expr::var(Ident::from("_1"))
+        );
+    }
+
     #[test]
     fn test_parse_string_literal() {
         assert_eq!(
             Parser::new("\"hello\"").parse_expr().unwrap(),
             Expr::string_literal("hello".to_string())
         );
+        assert_eq!(
+            Parser::new(r#""a\nb\t\\\"\0""#).parse_expr().unwrap(),
+            Expr::string_literal("a\nb\t\\\"\0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_string_literal_invalid_escape() {
+        let e = Parser::new(r#""ab\q""#).parse_expr().unwrap_err();
+        assert!(matches!(e, ParseError::Syntax { line: 1, column: 4 }));
+        // The backslash escapes the closing quote
+        let e = Parser::new(r#""ab\""#).parse_expr().unwrap_err();
+        assert!(matches!(e, ParseError::Syntax { .. }));
     }
 
     #[test]
@@ -509,14 +1479,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_else_if() {
+        let block = |name: &str| Expr::block(vec![Stmt::expr(Expr::var(Ident::from(name)), false)]);
+        assert_eq!(
+            Parser::new("if x { a; } else if y { b; } else { c; }")
+                .parse_expr()
+                .unwrap(),
+            Expr::branch(
+                Expr::var(Ident::from("x")),
+                block("a"),
+                Expr::branch(Expr::var(Ident::from("y")), block("b"), block("c"))
+            )
+        );
+    }
+
     #[test]
     fn test_parse_if_without_else_in_block_style() {
         assert_eq!(
             Parser::new("if x { y; }").parse_expr().unwrap(),
-            Expr::branch(
+            Expr::branch_without_else(
                 Expr::var(Ident::from("x")),
-                Expr::block(vec![Stmt::expr(Expr::var(Ident::from("y")), false)]),
-                Expr::block(vec![])
+                Expr::block(vec![Stmt::expr(Expr::var(Ident::from("y")), false)])
             )
         );
     }
@@ -531,6 +1515,17 @@ mod tests {
                 Expr::var(Ident::from("z"))
             )
         );
+        assert_eq!(
+            Parser::new("if x then y else f(z)").parse_expr().unwrap(),
+            Expr::branch(
+                Expr::var(Ident::from("x")),
+                Expr::var(Ident::from("y")),
+                Expr::call(
+                    Expr::var(Ident::from("f")),
+                    vec![Expr::var(Ident::from("z"))]
+                )
+            )
+        );
     }
 
     #[test]
@@ -552,6 +1547,398 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_trailing_expr() {
+        assert_eq!(
+            Parser::new("do { 1; 2 }").parse_expr().unwrap(),
+            Expr::block(vec![
+                Stmt::expr(Expr::integer_literal(1), false),
+                Stmt::expr(Expr::integer_literal(2), true),
+            ])
+        );
+        assert_eq!(
+            Parser::new("do { 1; 2; }").parse_expr().unwrap(),
+            Expr::block(vec![
+                Stmt::expr(Expr::integer_literal(1), false),
+                Stmt::expr(Expr::integer_literal(2), false),
+            ])
+        );
+        assert_eq!(
+            parse("use lang::\"0.0.1\";\nputs(x)").unwrap(),
+            vec![Stmt::expr(
+                Expr::call(
+                    Expr::var(Ident::from("puts")),
+                    vec![Expr::var(Ident::from("x"))]
+                ),
+                true
+            )]
+        );
+        // Only the last statement may omit `;`
+        assert!(Parser::new("do { 1 2 }").parse_expr().is_err());
+    }
+
+    #[test]
+    fn test_parse_lambda() {
+        assert_eq!(
+            Parser::new("fn(x, y) { then x; }").parse_expr().unwrap(),
+            Expr::lambda(
+                vec![Ident::from("x"), Ident::from("y")],
+                Expr::block(vec![Stmt::expr(Expr::var(Ident::from("x")), true)])
+            )
+        );
+        assert_eq!(
+            Parser::new("fn() { }").parse_expr().unwrap(),
+            Expr::lambda(vec![], Expr::block(vec![]))
+        );
+    }
+
+    #[test]
+    fn test_parse_array_literal() {
+        assert_eq!(
+            Parser::new("[]").parse_expr().unwrap(),
+            Expr::array_literal(vec![])
+        );
+        assert_eq!(
+            Parser::new("[1]").parse_expr().unwrap(),
+            Expr::array_literal(vec![Expr::integer_literal(1)])
+        );
+        assert_eq!(
+            Parser::new("[1, 2,]").parse_expr().unwrap(),
+            Expr::array_literal(vec![Expr::integer_literal(1), Expr::integer_literal(2)])
+        );
+    }
+
+    #[test]
+    fn test_parse_index() {
+        assert_eq!(
+            Parser::new("a[1]").parse_expr().unwrap(),
+            Expr::index(Expr::var(Ident::from("a")), Expr::integer_literal(1))
+        );
+        assert_eq!(
+            Parser::new("a[1..3]").parse_expr().unwrap(),
+            Expr::index(
+                Expr::var(Ident::from("a")),
+                Expr::range(Expr::integer_literal(1), Expr::integer_literal(3))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_try() {
+        assert_eq!(
+            Parser::new("try { then x; }").parse_expr().unwrap(),
+            Expr::try_(Expr::block(vec![Stmt::expr(
+                Expr::var(Ident::from("x")),
+                true
+            )]))
+        );
+    }
+
+    #[test]
+    fn test_parse_loop() {
+        assert_eq!(
+            Parser::new("loop { break x; }").parse_expr().unwrap(),
+            Expr::loop_(Expr::block(vec![Stmt::expr(
+                Expr::break_(Expr::var(Ident::from("x"))),
+                false
+            )]))
+        );
+        // `break` must be in a loop of the same function
+        assert!(Parser::new("break x").parse_expr().is_err());
+        assert!(Parser::new("loop { fn() { break x; }; }")
+            .parse_expr()
+            .is_err());
+        assert!(Parser::new("loop { try { break x; }; }")
+            .parse_expr()
+            .is_err());
+        assert!(Parser::new("loop { fn() { loop { break x; }; }; }")
+            .parse_expr()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_parse_if_some() {
+        let then = Expr::block(vec![Stmt::expr(Expr::var(Ident::from("x")), true)]);
+        assert_eq!(
+            Parser::new("if some(x) = opt { then x; } else { then 0; }")
+                .parse_expr()
+                .unwrap(),
+            Expr::if_some(
+                Ident::from("x"),
+                Expr::var(Ident::from("opt")),
+                then.clone(),
+                Expr::block(vec![Stmt::expr(Expr::integer_literal(0), true)]),
+            )
+        );
+        assert_eq!(
+            Parser::new("if some(x) = f(y) { then x; }")
+                .parse_expr()
+                .unwrap(),
+            Expr::if_some_without_else(
+                Ident::from("x"),
+                Expr::call(
+                    Expr::var(Ident::from("f")),
+                    vec![Expr::var(Ident::from("y"))]
+                ),
+                then.clone(),
+            )
+        );
+        // Not a pattern, so `some(x)` is the condition itself
+        assert_eq!(
+            Parser::new("if some(x) == y { then x; }")
+                .parse_expr()
+                .unwrap(),
+            Expr::branch_without_else(
+                Expr::bin_op(
+                    BinOp::Eq,
+                    Expr::call(
+                        Expr::var(Ident::from("some")),
+                        vec![Expr::var(Ident::from("x"))]
+                    ),
+                    Expr::var(Ident::from("y"))
+                ),
+                then,
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_boolean_literal() {
+        assert_eq!(
+            Parser::new("if true { false } else { x }")
+                .parse_expr()
+                .unwrap(),
+            Expr::branch(
+                Expr::boolean_literal(true),
+                Expr::block(vec![Stmt::expr(Expr::boolean_literal(false), true)]),
+                Expr::block(vec![Stmt::expr(Expr::var(Ident::from("x")), true)]),
+            )
+        );
+        // Not identifiers
+        assert!(Parser::new("let true = 1;").parse_stmt().is_err());
+        assert_eq!(
+            Parser::new("trueish").parse_expr().unwrap(),
+            Expr::var(Ident::from("trueish"))
+        );
+    }
+
+    #[test]
+    fn test_parse_same_ident_interned() {
+        let cctx = CCtx::new();
+        let Expr::BinOp { lhs, rhs, .. } = parse_expr("foo + foo", &cctx).unwrap() else {
+            panic!("expected a binary operation");
+        };
+        let (Expr::Var { ident: lhs }, Expr::Var { ident: rhs }) = (*lhs, *rhs) else {
+            panic!("expected variables");
+        };
+        assert_eq!(lhs.name, rhs.name);
+        // The name is shared with the other uses in the compiler context
+        assert!(std::ptr::eq(lhs.name.as_str(), rhs.name.as_str()));
+        let foo = cctx.interner.intern("foo");
+        assert!(std::ptr::eq(lhs.name.as_str(), foo.as_str()));
+        assert_eq!(lhs.name, Symbol::from("foo"));
+    }
+
+    #[test]
+    fn test_parse_return() {
+        assert_eq!(
+            Parser::new("return x + 1").parse_expr().unwrap(),
+            Expr::return_(Expr::bin_op(
+                BinOp::Add,
+                Expr::var(Ident::from("x")),
+                Expr::integer_literal(1)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_comments() {
+        let source = "use lang::\"0.0.1\";\n// greeting\nputs(x); // trailing\n";
+        let stmt = Stmt::expr(
+            Expr::call(
+                Expr::var(Ident::from("puts")),
+                vec![Expr::var(Ident::from("x"))],
+            ),
+            false,
+        );
+        assert_eq!(parse(source).unwrap(), vec![stmt.clone()]);
+        assert_eq!(
+            parse_with_comments(source).unwrap(),
+            (
+                vec![stmt
+                    .clone()
+                    .with_leading_comments(vec!["// greeting".to_owned()])
+                    .with_trailing_comments(vec!["// trailing".to_owned()])],
+                vec![]
+            )
+        );
+        // Comments on the next line or at the end of a block are not trailing
+        let source = "use lang::\"0.0.1\";\nwhile true {\n  puts(x); /* a */\n  // b\n};\n// c\n";
+        assert_eq!(
+            parse_with_comments(source).unwrap(),
+            (
+                vec![Stmt::expr(
+                    Expr::while_(
+                        Expr::boolean_literal(true),
+                        Expr::Block {
+                            stmts: vec![stmt.with_trailing_comments(vec!["/* a */".to_owned()])],
+                            trailing_comments: vec!["// b".to_owned()],
+                        }
+                    ),
+                    false
+                )],
+                vec!["// c".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_trailing_comment_line() {
+        // The comment ends the source without a newline, next to a `/` operator
+        let source = "use lang::\"0.0.1\";\nputi(6 / 2);\n// the end";
+        assert_eq!(
+            parse(source).unwrap(),
+            vec![Stmt::expr(
+                Expr::call(
+                    Expr::var(Ident::from("puti")),
+                    vec![Expr::bin_op(
+                        BinOp::Div,
+                        Expr::integer_literal(6),
+                        Expr::integer_literal(2)
+                    )],
+                ),
+                false,
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_block_comments() {
+        let source = "use lang::\"0.0.1\";\n/* a /* nested */ comment */\nputs(/* inline */ x);\n";
+        let stmt = Stmt::expr(
+            Expr::call(
+                Expr::var(Ident::from("puts")),
+                vec![Expr::var(Ident::from("x"))],
+            ),
+            false,
+        );
+        assert_eq!(parse(source).unwrap(), vec![stmt]);
+        assert_eq!(
+            parse_with_comments(source).unwrap().0,
+            vec![Stmt::expr(
+                Expr::call(
+                    Expr::var(Ident::from("puts")),
+                    vec![Expr::commented(
+                        vec!["/* inline */".to_owned()],
+                        Expr::var(Ident::from("x"))
+                    )],
+                ),
+                false,
+            )
+            .with_leading_comments(vec!["/* a /* nested */ comment */".to_owned()])]
+        );
+        assert_eq!(
+            Parser::new("1 /* / */ / 2 /**/").parse_expr().unwrap(),
+            Expr::bin_op(
+                BinOp::Div,
+                Expr::integer_literal(1),
+                Expr::integer_literal(2)
+            )
+        );
+        // The outer comment is left open, and reported where it starts
+        let e = Parser::new("x +\n  /* /* */").parse_expr().unwrap_err();
+        assert!(matches!(e, ParseError::Syntax { line: 2, column: 3 }));
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let e = Parser::new("x +\n  ;").parse_expr().unwrap_err();
+        assert!(matches!(e, ParseError::Syntax { line: 2, column: 3 }));
+        assert_eq!(e.to_string(), "parse error at 2:3");
+    }
+
+    #[test]
+    fn test_parse_then_not_last() {
+        let e = parse("use lang::\"0.0.1\";\nthen 1;\nlet y = 1;\n").unwrap_err();
+        assert!(matches!(e, ParseError::ThenNotLast { line: 2, column: 1 }));
+        assert_eq!(
+            e.to_string(),
+            "`then` is only allowed in the last statement of a block at 2:1"
+        );
+        let e = Parser::new("do { let x = 1; then x; puti(x); }")
+            .parse_expr()
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            ParseError::ThenNotLast {
+                line: 1,
+                column: 17
+            }
+        ));
+        // Still fine as the last statement
+        assert!(Parser::new("do { let x = 1; then x; }")
+            .parse_expr()
+            .is_ok());
+    }
+
+    /// Runs `f` with the stack size of the main thread rather than the smaller one of test
+    /// threads, as unoptimized builds need it for the default nesting limit.
+    fn with_main_stack<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(f)
+            .unwrap()
+            .join()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_nesting_too_deep() {
+        let source = format!("{}x{}", "(".repeat(10000), ")".repeat(10000));
+        let e = with_main_stack(move || Parser::new(&source).parse_expr().unwrap_err());
+        assert!(matches!(
+            e,
+            ParseError::NestingTooDeep {
+                line: 1,
+                column: 257
+            }
+        ));
+
+        // A flat chain of operators nests as deeply as parentheses do
+        let source = format!("x{}", " + x".repeat(5000));
+        assert!(matches!(
+            Parser::new(&source).parse_expr(),
+            Err(ParseError::NestingTooDeep { .. })
+        ));
+        let source = format!("f{}", "(x)".repeat(5000));
+        assert!(matches!(
+            Parser::new(&source).parse_expr(),
+            Err(ParseError::NestingTooDeep { .. })
+        ));
+
+        let source = format!("{}x", "-".repeat(10000));
+        assert!(matches!(
+            with_main_stack(move || Parser::new(&source).parse_expr()),
+            Err(ParseError::NestingTooDeep { .. })
+        ));
+
+        let source = format!("{}x{}", "(".repeat(10), ")".repeat(10));
+        assert!(Parser::new(&source).with_max_depth(11).parse_expr().is_ok());
+        assert!(matches!(
+            Parser::new(&source).with_max_depth(10).parse_expr(),
+            Err(ParseError::NestingTooDeep { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_nesting_too_deep_else_if() {
+        let source = format!("{}x", "if c then x else ".repeat(10000));
+        assert!(matches!(
+            with_main_stack(move || Parser::new(&source).parse_expr()),
+            Err(ParseError::NestingTooDeep { .. })
+        ));
+    }
+
     #[test]
     fn test_parse_additive() {
         assert_eq!(
@@ -576,6 +1963,179 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_precedence() {
+        fn v(name: &str) -> Expr {
+            Expr::var(Ident::from(name))
+        }
+        fn i(value: i32) -> Expr {
+            Expr::integer_literal(value)
+        }
+        fn add(lhs: Expr, rhs: Expr) -> Expr {
+            Expr::bin_op(BinOp::Add, lhs, rhs)
+        }
+        fn sub(lhs: Expr, rhs: Expr) -> Expr {
+            Expr::bin_op(BinOp::Sub, lhs, rhs)
+        }
+        fn mul(lhs: Expr, rhs: Expr) -> Expr {
+            Expr::bin_op(BinOp::Mul, lhs, rhs)
+        }
+        fn div(lhs: Expr, rhs: Expr) -> Expr {
+            Expr::bin_op(BinOp::Div, lhs, rhs)
+        }
+        fn lt(lhs: Expr, rhs: Expr) -> Expr {
+            Expr::bin_op(BinOp::Lt, lhs, rhs)
+        }
+        fn and(lhs: Expr, rhs: Expr) -> Expr {
+            Expr::bin_op(BinOp::And, lhs, rhs)
+        }
+        fn or(lhs: Expr, rhs: Expr) -> Expr {
+            Expr::bin_op(BinOp::Or, lhs, rhs)
+        }
+        // Operators not in the language yet (`!`, ...)
+        // should be added here as they are introduced.
+        let cases = [
+            ("a + b + c", add(add(v("a"), v("b")), v("c"))),
+            ("a + (b + c)", add(v("a"), add(v("b"), v("c")))),
+            ("a - b - c", sub(sub(v("a"), v("b")), v("c"))),
+            ("a + b - c", sub(add(v("a"), v("b")), v("c"))),
+            ("a - (b + c)", sub(v("a"), add(v("b"), v("c")))),
+            ("a - b < c", lt(sub(v("a"), v("b")), v("c"))),
+            (
+                "a + 1 >= b * 2",
+                Expr::bin_op(BinOp::Ge, add(v("a"), i(1)), mul(v("b"), i(2))),
+            ),
+            ("a || b && c", or(v("a"), and(v("b"), v("c")))),
+            ("a && b || c", or(and(v("a"), v("b")), v("c"))),
+            ("a && b && c", and(and(v("a"), v("b")), v("c"))),
+            (
+                "a < b && b < c",
+                and(lt(v("a"), v("b")), lt(v("b"), v("c"))),
+            ),
+            ("(a || b) && c", and(or(v("a"), v("b")), v("c"))),
+            (
+                "a = b || c",
+                Expr::assign(Ident::from("a"), or(v("b"), v("c"))),
+            ),
+            (
+                "a + 1 == b",
+                Expr::bin_op(BinOp::Eq, add(v("a"), i(1)), v("b")),
+            ),
+            (
+                "a != b < c",
+                lt(Expr::bin_op(BinOp::Ne, v("a"), v("b")), v("c")),
+            ),
+            (
+                "a = b == c",
+                Expr::assign(Ident::from("a"), Expr::bin_op(BinOp::Eq, v("b"), v("c"))),
+            ),
+            (
+                "a <= b > c",
+                Expr::bin_op(BinOp::Gt, Expr::bin_op(BinOp::Le, v("a"), v("b")), v("c")),
+            ),
+            ("a + b * c", add(v("a"), mul(v("b"), v("c")))),
+            (
+                "a * b - c / d",
+                sub(mul(v("a"), v("b")), div(v("c"), v("d"))),
+            ),
+            ("a / b * c", mul(div(v("a"), v("b")), v("c"))),
+            ("(a + b) * c", mul(add(v("a"), v("b")), v("c"))),
+            (
+                "a * f(b)[c]",
+                mul(
+                    v("a"),
+                    Expr::index(Expr::call(v("f"), vec![v("b")]), v("c")),
+                ),
+            ),
+            ("a / b // c", div(v("a"), v("b"))),
+            ("-5", i(-5)),
+            ("-2147483648", i(i32::MIN)),
+            ("-0x10", i(-16)),
+            ("-(1 + 2)", sub(i(0), add(i(1), i(2)))),
+            ("- -3", sub(i(0), i(-3))),
+            ("-2 * 3", mul(i(-2), i(3))),
+            ("1 - -2", sub(i(1), i(-2))),
+            ("a - -b", sub(v("a"), sub(i(0), v("b")))),
+            ("-f(a)", sub(i(0), Expr::call(v("f"), vec![v("a")]))),
+            ("a < b < c", lt(lt(v("a"), v("b")), v("c"))),
+            (
+                "a + b < c + d",
+                lt(add(v("a"), v("b")), add(v("c"), v("d"))),
+            ),
+            ("a < (b < c)", lt(v("a"), lt(v("b"), v("c")))),
+            (
+                "f(a) + b[c]",
+                add(
+                    Expr::call(v("f"), vec![v("a")]),
+                    Expr::index(v("b"), v("c")),
+                ),
+            ),
+            (
+                "f(a)(b)[c]",
+                Expr::index(
+                    Expr::call(Expr::call(v("f"), vec![v("a")]), vec![v("b")]),
+                    v("c"),
+                ),
+            ),
+            (
+                "a[b + c..d]",
+                Expr::index(v("a"), Expr::range(add(v("b"), v("c")), v("d"))),
+            ),
+            (
+                "a = b = c + d",
+                Expr::assign(
+                    Ident::from("a"),
+                    Expr::assign(Ident::from("b"), add(v("c"), v("d"))),
+                ),
+            ),
+            (
+                "a = b < c",
+                Expr::assign(Ident::from("a"), lt(v("b"), v("c"))),
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(
+                Parser::new(source).parse_expr().unwrap(),
+                expected,
+                "source: {}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_block_like_operands() {
+        let x = || Expr::var(Ident::from("x"));
+        let f = || Expr::var(Ident::from("f"));
+        assert_eq!(
+            Parser::new("f(do { then x; }, x)").parse_expr().unwrap(),
+            Expr::call(f(), vec![Expr::block(vec![Stmt::expr(x(), true)]), x()])
+        );
+        assert_eq!(
+            Parser::new("f(if x then 1 else 2)").parse_expr().unwrap(),
+            Expr::call(
+                f(),
+                vec![Expr::branch(
+                    x(),
+                    Expr::integer_literal(1),
+                    Expr::integer_literal(2)
+                )]
+            )
+        );
+        assert_eq!(
+            Parser::new("f(while x { })").parse_expr().unwrap(),
+            Expr::call(f(), vec![Expr::while_(x(), Expr::block(vec![]))])
+        );
+        assert_eq!(
+            Parser::new("1 + do { then x; }").parse_expr().unwrap(),
+            Expr::bin_op(
+                BinOp::Add,
+                Expr::integer_literal(1),
+                Expr::block(vec![Stmt::expr(x(), true)])
+            )
+        );
+    }
+
     #[test]
     fn test_parse_assignment() {
         assert_eq!(
@@ -592,6 +2152,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_let_uninit_stmt() {
+        assert_eq!(
+            Parser::new("let x;").parse_stmt().unwrap(),
+            Stmt::let_uninit(Ident::from("x"))
+        );
+    }
+
     #[test]
     fn test_parse_then_stmt() {
         assert_eq!(
@@ -611,7 +2179,7 @@ mod tests {
     #[test]
     fn test_parse_stmts() {
         assert_eq!(
-            Parser::new("let x = 1; then x;").parse_stmts().unwrap(),
+            Parser::new("let x = 1; then x;").parse_stmts().unwrap().0,
             vec![
                 Stmt::let_(Ident::from("x"), Expr::integer_literal(1)),
                 Stmt::expr(Expr::var(Ident::from("x")), true)
@@ -624,11 +2192,35 @@ mod tests {
         assert_eq!(
             Parser::new("use lang::\"0.0.1\";\nlet x = 1;\nthen x;\n")
                 .parse_program()
-                .unwrap(),
+                .unwrap()
+                .0,
             vec![
                 Stmt::let_(Ident::from("x"), Expr::integer_literal(1)),
                 Stmt::expr(Expr::var(Ident::from("x")), true)
             ]
         );
     }
+
+    #[test]
+    fn test_parse_program_preamble() {
+        assert_eq!(
+            parse("// header\n\nuse  lang :: \"0.0.1\" ;puti(1);\n").unwrap(),
+            vec![Stmt::expr(
+                Expr::call(
+                    Expr::var(Ident::from("puti")),
+                    vec![Expr::integer_literal(1)]
+                ),
+                false
+            )]
+        );
+        let e = parse("// header\nuse lang::\"0.0.2\";\n").unwrap_err();
+        assert!(matches!(
+            e,
+            ParseError::Syntax {
+                line: 2,
+                column: 11
+            }
+        ));
+        assert!(parse("puti(1);\n").is_err());
+    }
 }
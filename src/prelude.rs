@@ -0,0 +1,32 @@
+// Prelude -- functions available to every program, written in umo itself
+
+use crate::ast::Stmt;
+
+const PRELUDE_SOURCE: &str = r#"use lang::"0.0.1";
+let max = fn(a, b) { then if a < b then b else a; };
+let min = fn(a, b) { then if b < a then b else a; };
+let clamp = fn(x, lo, hi) { then max(lo, min(x, hi)); };
+"#;
+
+/// The prelude definitions, to be prepended to the program before ids are assigned.
+pub fn prelude_stmts() -> Vec<Stmt> {
+    crate::parser::parse(PRELUDE_SOURCE).expect("the prelude should parse")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prelude_stmts() {
+        let stmts = prelude_stmts();
+        let names = stmts
+            .iter()
+            .filter_map(|stmt| match stmt {
+                Stmt::Let { lhs, .. } => Some(lhs.name.as_str()),
+                Stmt::Expr { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["max", "min", "clamp"]);
+    }
+}
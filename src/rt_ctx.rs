@@ -1,12 +1,110 @@
+use std::io::Write;
+
 pub trait RtCtx {
     fn puts(&self, s: &str);
+    /// Writes a single character, without a line ending.
+    fn putc(&self, c: char);
+    /// Reads a line without its terminator, or returns `None` at the end of input.
+    fn gets(&self) -> Option<String>;
+    /// Width of the terminal in columns, or `None` if the output is not a terminal.
+    fn term_width(&self) -> Option<usize>;
+    /// Arguments given to the program, not including the interpreter or the source path.
+    fn args(&self) -> Vec<String>;
+}
+
+/// Line terminator appended by `puts`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct RtCtxImpl;
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct RtCtxImpl {
+    line_ending: LineEnding,
+    args: Vec<String>,
+}
+
+impl RtCtxImpl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_line_ending(self, line_ending: LineEnding) -> Self {
+        Self {
+            line_ending,
+            ..self
+        }
+    }
+
+    pub fn with_args(self, args: Vec<String>) -> Self {
+        Self { args, ..self }
+    }
+
+    fn write_line(&self, out: &mut impl Write, s: &str) -> std::io::Result<()> {
+        write!(out, "{}{}", s, self.line_ending.as_str())
+    }
+}
 
 impl RtCtx for RtCtxImpl {
     fn puts(&self, s: &str) {
-        println!("{}", s);
+        // Panics on write errors, as `println!` does
+        self.write_line(&mut std::io::stdout().lock(), s)
+            .expect("failed printing to stdout");
+    }
+    fn putc(&self, c: char) {
+        write!(std::io::stdout().lock(), "{}", c).expect("failed printing to stdout");
+    }
+    fn gets(&self) -> Option<String> {
+        let mut line = String::new();
+        // Read errors are treated as the end of input
+        if std::io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+            return None;
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Some(line)
+    }
+    fn term_width(&self) -> Option<usize> {
+        terminal_size::terminal_size().map(|(terminal_size::Width(width), _)| width as usize)
+    }
+    fn args(&self) -> Vec<String> {
+        self.args.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_line_default() {
+        let mut out = vec![];
+        RtCtxImpl::new().write_line(&mut out, "foo").unwrap();
+        assert_eq!(out, b"foo\n");
+    }
+
+    #[test]
+    fn test_write_line_crlf() {
+        let mut out = vec![];
+        RtCtxImpl::new()
+            .with_line_ending(LineEnding::CrLf)
+            .write_line(&mut out, "foo")
+            .unwrap();
+        assert_eq!(out, b"foo\r\n");
     }
 }
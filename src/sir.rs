@@ -1,6 +1,6 @@
 // SIR -- Sequential Intermediate Representation
 
-use std::fmt;
+use std::fmt::{self, Write};
 use std::sync::Arc;
 
 use bit_set::BitSet;
@@ -18,6 +18,25 @@ impl ProgramUnit {
         Self { functions }
     }
 
+    /// Total number of instructions in all functions.
+    pub fn instruction_count(&self) -> usize {
+        self.functions
+            .iter()
+            .flat_map(|function| &function.body)
+            .map(|bb| bb.insts.len())
+            .sum()
+    }
+
+    /// Rough static cost of the program; see `InstKind::cost`.
+    pub fn estimated_cost(&self) -> usize {
+        self.functions
+            .iter()
+            .flat_map(|function| &function.body)
+            .flat_map(|bb| &bb.insts)
+            .map(|inst| inst.kind.cost())
+            .sum()
+    }
+
     pub fn describe<const NF: usize, F>(f: F) -> Self
     where
         F: FnOnce([usize; NF]) -> Vec<(usize, Function)>,
@@ -84,10 +103,16 @@ impl fmt::Debug for ProgramUnit {
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Function {
-    /// Number of arguments, Must be <= num_vars
+    /// Number of arguments, Must be <= num_vars.
+    /// The arguments are received in the variables `0..num_args`.
     pub num_args: usize,
     /// Number of local variables, including args
     pub num_vars: usize,
+    /// Number of variables that originate from source-level names, including args.
+    /// Must be <= num_vars; the rest are temporaries.
+    /// After register allocation, the variables are slots, and this counts those
+    /// holding at least one named variable.
+    pub num_named_vars: usize,
     pub body: Vec<BasicBlock>,
 }
 
@@ -96,10 +121,82 @@ impl Function {
         Self {
             num_args,
             num_vars,
+            num_named_vars: num_vars,
             body,
         }
     }
 
+    #[cfg(test)]
+    pub fn with_num_named_vars(mut self, num_named_vars: usize) -> Self {
+        assert!(num_named_vars <= self.num_vars);
+        self.num_named_vars = num_named_vars;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn num_temps(&self) -> usize {
+        self.num_vars - self.num_named_vars
+    }
+
+    /// Predecessors of each block, in the order of the blocks.
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut predecessors = vec![vec![]; self.body.len()];
+        for (bb_id, bb) in self.body.iter().enumerate() {
+            for succ in bb.successors() {
+                predecessors[succ].push(bb_id);
+            }
+        }
+        predecessors
+    }
+
+    fn var_names(&self) -> Vec<String> {
+        (0..self.num_vars)
+            .map(|i| {
+                if i < self.num_named_vars {
+                    format!("v{}", i)
+                } else {
+                    format!("tmp{}", i - self.num_named_vars)
+                }
+            })
+            .collect()
+    }
+
+    /// Lists the liveness information computed by `compile`, for debugging:
+    /// the live-in set of each block and the live-out set of each instruction.
+    pub fn liveness_report(&self) -> String {
+        let vars = self.var_names();
+        let blocks = (0..self.body.len())
+            .map(|i| format!("bb{}", i))
+            .collect::<Vec<_>>();
+        let params = InstDebugParams {
+            vars: &vars,
+            blocks: &blocks,
+            functions: &[],
+        };
+        let mut out = String::new();
+        for (bb, block_name) in self.body.iter().zip(&blocks) {
+            writeln!(
+                out,
+                "{}: live_in = {:?}",
+                block_name,
+                debug_live_set(bb.live_in.as_ref(), &vars)
+            )
+            .unwrap();
+            for inst in &bb.insts {
+                // Print the instruction alone; its live_out goes to the right
+                let inst_only = Inst::new(inst.kind.clone());
+                writeln!(
+                    out,
+                    "    {:?}  // live_out = {:?}",
+                    inst_only.debug_with(params),
+                    debug_live_set(inst.live_out.as_ref(), &vars)
+                )
+                .unwrap();
+            }
+        }
+        out
+    }
+
     pub fn describe<const NV: usize, const NB: usize, F>(num_args: usize, f: F) -> Self
     where
         F: FnOnce([usize; NV], [usize; NB]) -> Vec<(usize, BasicBlock)>,
@@ -143,9 +240,7 @@ impl<'a> PDebug<FunctionDebugParams<'a>> for Function {
             f.debug_tuple("Function::simple")
                 .field(&self.num_args)
                 .field(&debug_with(|f| {
-                    let vars = (0..self.num_vars)
-                        .map(|i| format!("v{}", i))
-                        .collect::<Vec<_>>();
+                    let vars = self.var_names();
                     f.write_str("|")?;
                     f.debug_list()
                         .entries(vars.iter().map(|v| debug_with_display(v)))
@@ -161,14 +256,12 @@ impl<'a> PDebug<FunctionDebugParams<'a>> for Function {
                     )?;
                     Ok(())
                 }))
-                .finish()
+                .finish()?;
         } else {
             f.debug_tuple("Function::describe")
                 .field(&self.num_args)
                 .field(&debug_with(|f| {
-                    let vars = (0..self.num_vars)
-                        .map(|i| format!("v{}", i))
-                        .collect::<Vec<_>>();
+                    let vars = self.var_names();
                     let blocks = (0..self.body.len())
                         .map(|i| format!("bb{}", i))
                         .collect::<Vec<_>>();
@@ -196,8 +289,14 @@ impl<'a> PDebug<FunctionDebugParams<'a>> for Function {
                         .finish()?;
                     Ok(())
                 }))
-                .finish()
+                .finish()?;
+        }
+        if self.num_named_vars != self.num_vars {
+            f.debug_tuple(".with_num_named_vars")
+                .field(&self.num_named_vars)
+                .finish()?;
         }
+        Ok(())
     }
 }
 
@@ -229,6 +328,18 @@ impl BasicBlock {
         self.live_in = Some(live_in);
         self
     }
+    /// Blocks that the tail instruction may transfer control to.
+    pub fn successors(&self) -> Vec<usize> {
+        match self.insts.last().map(|inst| &inst.kind) {
+            Some(InstKind::Jump { target }) => vec![*target],
+            Some(InstKind::Branch {
+                branch_then,
+                branch_else,
+                ..
+            }) => vec![*branch_then, *branch_else],
+            _ => vec![],
+        }
+    }
 }
 
 impl<'a> PDebug<InstDebugParams<'a>> for BasicBlock {
@@ -303,6 +414,9 @@ impl Inst {
     pub fn return_(rhs: usize) -> Self {
         Self::new(InstKind::Return { rhs })
     }
+    pub fn unreachable() -> Self {
+        Self::new(InstKind::Unreachable)
+    }
     pub fn copy(lhs: usize, rhs: usize) -> Self {
         Self::new(InstKind::Copy { lhs, rhs })
     }
@@ -321,12 +435,21 @@ impl Inst {
     pub fn builtin(lhs: usize, builtin: BuiltinKind) -> Self {
         Self::new(InstKind::Builtin { lhs, builtin })
     }
+    pub fn array(lhs: usize) -> Self {
+        Self::new(InstKind::Array { lhs })
+    }
     pub fn push_arg(value_ref: usize) -> Self {
         Self::new(InstKind::PushArg { value_ref })
     }
+    pub fn push_arg_copy(value_ref: usize) -> Self {
+        Self::new(InstKind::PushArgCopy { value_ref })
+    }
     pub fn call(lhs: usize, callee: usize) -> Self {
         Self::new(InstKind::Call { lhs, callee })
     }
+    pub fn try_call(lhs: usize, callee: usize) -> Self {
+        Self::new(InstKind::TryCall { lhs, callee })
+    }
 }
 
 impl<'a> PDebug<InstDebugParams<'a>> for Inst {
@@ -358,6 +481,7 @@ impl<'a> PDebug<InstDebugParams<'a>> for Inst {
                 .debug_tuple("Inst::return_")
                 .field(&debug_var(*rhs, vars))
                 .finish()?,
+            InstKind::Unreachable => f.write_str("Inst::unreachable()")?,
             InstKind::Copy { lhs, rhs } => f
                 .debug_tuple("Inst::copy")
                 .field(&debug_var(*lhs, vars))
@@ -382,15 +506,28 @@ impl<'a> PDebug<InstDebugParams<'a>> for Inst {
                 .field(&debug_var(*lhs, vars))
                 .field(builtin)
                 .finish()?,
+            InstKind::Array { lhs } => f
+                .debug_tuple("Inst::array")
+                .field(&debug_var(*lhs, vars))
+                .finish()?,
             InstKind::PushArg { value_ref } => f
                 .debug_tuple("Inst::push_arg")
                 .field(&debug_var(*value_ref, vars))
                 .finish()?,
+            InstKind::PushArgCopy { value_ref } => f
+                .debug_tuple("Inst::push_arg_copy")
+                .field(&debug_var(*value_ref, vars))
+                .finish()?,
             InstKind::Call { lhs, callee } => f
                 .debug_tuple("Inst::call")
                 .field(&debug_var(*lhs, vars))
                 .field(&debug_var(*callee, vars))
                 .finish()?,
+            InstKind::TryCall { lhs, callee } => f
+                .debug_tuple("Inst::try_call")
+                .field(&debug_var(*lhs, vars))
+                .field(&debug_var(*callee, vars))
+                .finish()?,
         }
         if let Some(live_out) = &self.live_out {
             f.debug_tuple(".with_live_out")
@@ -432,6 +569,21 @@ fn debug_bit_set<'a>(bit_set: &'a BitSet<usize>, vars: &'a [String]) -> impl fmt
     })
 }
 
+fn debug_live_set<'a>(
+    bit_set: Option<&'a BitSet<usize>>,
+    vars: &'a [String],
+) -> impl fmt::Debug + 'a {
+    debug_with(move |f| {
+        if let Some(bit_set) = bit_set {
+            f.debug_set()
+                .entries(bit_set.iter().map(|v| debug_var(v, vars)))
+                .finish()
+        } else {
+            f.write_str("(not computed)")
+        }
+    })
+}
+
 fn debug_var<'a>(var: usize, vars: &'a [String]) -> impl fmt::Debug + 'a {
     debug_with(move |f| {
         if let Some(var_name) = vars.get(var) {
@@ -475,6 +627,9 @@ pub enum InstKind {
     Return {
         rhs: usize,
     },
+    /// Marks the end of a block that control never reaches.
+    /// Executing it is a runtime error.
+    Unreachable,
     Copy {
         lhs: usize,
         rhs: usize,
@@ -486,6 +641,8 @@ pub enum InstKind {
         lhs: usize,
         value: Literal,
     },
+    /// Creates a closure. The captured values are the pending arguments pushed by `PushArg`,
+    /// so they are moved (or copied if still live) just like call arguments.
     Closure {
         lhs: usize,
         function_id: usize,
@@ -494,31 +651,100 @@ pub enum InstKind {
         lhs: usize,
         builtin: BuiltinKind,
     },
+    /// Creates an array from the pending arguments pushed by `PushArg`.
+    Array {
+        lhs: usize,
+    },
     PushArg {
         value_ref: usize,
     },
+    /// Same as `PushArg`, but the variable keeps its value.
+    /// Inserted by `compile` for arguments of builtins that only read them.
+    PushArgCopy {
+        value_ref: usize,
+    },
     Call {
         lhs: usize,
         callee: usize,
     },
+    /// Same as `Call`, but a runtime error in the callee is caught.
+    /// The result is `some(value)` on success and `none()` on error.
+    TryCall {
+        lhs: usize,
+        callee: usize,
+    },
 }
 
 impl InstKind {
+    /// Rough relative cost of executing the instruction once.
+    ///
+    /// Allocations and calls are weighted higher than plain moves of values.
+    pub fn cost(&self) -> usize {
+        match self {
+            InstKind::Jump { .. }
+            | InstKind::Branch { .. }
+            | InstKind::Return { .. }
+            | InstKind::Unreachable
+            | InstKind::Copy { .. }
+            | InstKind::Drop { .. }
+            | InstKind::Literal { .. }
+            | InstKind::PushArg { .. }
+            | InstKind::PushArgCopy { .. }
+            | InstKind::Builtin { .. } => 1,
+            InstKind::Closure { .. } | InstKind::Array { .. } => 2,
+            InstKind::Call { .. } | InstKind::TryCall { .. } => 5,
+        }
+    }
     pub fn is_tail(&self) -> bool {
         match self {
-            InstKind::Jump { .. } | InstKind::Branch { .. } | InstKind::Return { .. } => true,
+            InstKind::Jump { .. }
+            | InstKind::Branch { .. }
+            | InstKind::Return { .. }
+            | InstKind::Unreachable => true,
             InstKind::Copy { .. }
             | InstKind::Drop { .. }
             | InstKind::Literal { .. }
             | InstKind::PushArg { .. }
+            | InstKind::PushArgCopy { .. }
             | InstKind::Closure { .. }
             | InstKind::Builtin { .. }
-            | InstKind::Call { .. } => false,
+            | InstKind::Array { .. }
+            | InstKind::Call { .. }
+            | InstKind::TryCall { .. } => false,
         }
     }
     pub fn is_middle(&self) -> bool {
         !self.is_tail()
     }
+
+    /// Whether the instruction has no effect other than defining its `lhs`,
+    /// so that it may be removed when the result is unused.
+    ///
+    /// `Closure` and `Array` consume the pending arguments,
+    /// so removing one of them also requires removing the `PushArg`s before it.
+    /// Calls are impure since the callee is not known here;
+    /// for a call to a builtin, see [`BuiltinKind::is_pure`].
+    pub fn is_pure(&self) -> bool {
+        match self {
+            InstKind::Copy { .. }
+            | InstKind::Literal { .. }
+            | InstKind::Closure { .. }
+            | InstKind::Builtin { .. }
+            | InstKind::Array { .. } => true,
+            InstKind::Jump { .. }
+            | InstKind::Branch { .. }
+            | InstKind::Return { .. }
+            | InstKind::Unreachable
+            | InstKind::Drop { .. }
+            | InstKind::PushArg { .. }
+            | InstKind::PushArgCopy { .. }
+            | InstKind::Call { .. }
+            | InstKind::TryCall { .. } => false,
+        }
+    }
+    pub fn has_side_effects(&self) -> bool {
+        !self.is_pure()
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash)]
@@ -576,7 +802,258 @@ impl fmt::Debug for Literal {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum BuiltinKind {
     Add,
+    Sub,
+    Mul,
+    Div,
     Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
     Puts,
     Puti,
+    StrEq,
+    Index,
+    Slice,
+    Panic,
+    Len,
+    PanicWithInt,
+    Gets,
+    OptionNone,
+    OptionSome,
+    IsSome,
+    Unwrap,
+    CharAt,
+    TermWidth,
+    Args,
+    Putc,
+    Repeat,
+}
+
+impl BuiltinKind {
+    /// The name of the builtin as written in the source.
+    pub fn name(self) -> &'static str {
+        match self {
+            BuiltinKind::Add => "add",
+            BuiltinKind::Sub => "sub",
+            BuiltinKind::Mul => "mul",
+            BuiltinKind::Div => "div",
+            BuiltinKind::Lt => "lt",
+            BuiltinKind::Gt => "gt",
+            BuiltinKind::Le => "le",
+            BuiltinKind::Ge => "ge",
+            BuiltinKind::Eq => "eq",
+            BuiltinKind::Ne => "ne",
+            BuiltinKind::Puts => "puts",
+            BuiltinKind::Puti => "puti",
+            BuiltinKind::StrEq => "str_eq",
+            BuiltinKind::Index => "index",
+            BuiltinKind::Slice => "slice",
+            BuiltinKind::Panic => "panic",
+            BuiltinKind::Len => "len",
+            BuiltinKind::PanicWithInt => "panic_with_int",
+            BuiltinKind::Gets => "gets",
+            BuiltinKind::OptionNone => "none",
+            BuiltinKind::OptionSome => "some",
+            BuiltinKind::IsSome => "is_some",
+            BuiltinKind::Unwrap => "unwrap",
+            BuiltinKind::CharAt => "char_at",
+            BuiltinKind::TermWidth => "term_width",
+            BuiltinKind::Args => "args",
+            BuiltinKind::Putc => "putc",
+            BuiltinKind::Repeat => "repeat",
+        }
+    }
+
+    /// Whether a call has no effect other than producing its result,
+    /// so that calls with the same arguments can share the result.
+    pub fn is_pure(self) -> bool {
+        match self {
+            BuiltinKind::Add
+            | BuiltinKind::Sub
+            | BuiltinKind::Mul
+            | BuiltinKind::Div
+            | BuiltinKind::Lt
+            | BuiltinKind::Gt
+            | BuiltinKind::Le
+            | BuiltinKind::Ge
+            | BuiltinKind::Eq
+            | BuiltinKind::Ne
+            | BuiltinKind::StrEq
+            | BuiltinKind::Index
+            | BuiltinKind::Slice
+            | BuiltinKind::Len
+            | BuiltinKind::OptionNone
+            | BuiltinKind::OptionSome
+            | BuiltinKind::IsSome
+            | BuiltinKind::Unwrap
+            | BuiltinKind::CharAt
+            | BuiltinKind::Args
+            | BuiltinKind::Repeat => true,
+            BuiltinKind::Puts
+            | BuiltinKind::Puti
+            | BuiltinKind::Panic
+            | BuiltinKind::PanicWithInt
+            | BuiltinKind::Gets
+            | BuiltinKind::TermWidth
+            | BuiltinKind::Putc => false,
+        }
+    }
+
+    /// Whether the builtin only reads its arguments, rather than consuming them,
+    /// so that the caller may keep using the variables it passes.
+    ///
+    /// Those returning an argument or a part of it, such as `some` and `unwrap`, consume them.
+    pub fn borrows_args(self) -> bool {
+        match self {
+            BuiltinKind::OptionSome | BuiltinKind::Unwrap | BuiltinKind::Index => false,
+            BuiltinKind::Add
+            | BuiltinKind::Sub
+            | BuiltinKind::Mul
+            | BuiltinKind::Div
+            | BuiltinKind::Lt
+            | BuiltinKind::Gt
+            | BuiltinKind::Le
+            | BuiltinKind::Ge
+            | BuiltinKind::Eq
+            | BuiltinKind::Ne
+            | BuiltinKind::Puts
+            | BuiltinKind::Puti
+            | BuiltinKind::StrEq
+            | BuiltinKind::Slice
+            | BuiltinKind::Panic
+            | BuiltinKind::Len
+            | BuiltinKind::PanicWithInt
+            | BuiltinKind::Gets
+            | BuiltinKind::OptionNone
+            | BuiltinKind::IsSome
+            | BuiltinKind::CharAt
+            | BuiltinKind::TermWidth
+            | BuiltinKind::Args
+            | BuiltinKind::Putc
+            | BuiltinKind::Repeat => true,
+        }
+    }
+}
+
+impl fmt::Display for BuiltinKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_kind_name() {
+        assert_eq!(BuiltinKind::Add.name(), "add");
+        assert_eq!(BuiltinKind::Sub.name(), "sub");
+        assert_eq!(BuiltinKind::Mul.name(), "mul");
+        assert_eq!(BuiltinKind::Div.name(), "div");
+        assert_eq!(BuiltinKind::Lt.name(), "lt");
+        assert_eq!(BuiltinKind::Gt.name(), "gt");
+        assert_eq!(BuiltinKind::Le.name(), "le");
+        assert_eq!(BuiltinKind::Ge.name(), "ge");
+        assert_eq!(BuiltinKind::Eq.name(), "eq");
+        assert_eq!(BuiltinKind::Ne.name(), "ne");
+        assert_eq!(BuiltinKind::Puts.name(), "puts");
+        assert_eq!(BuiltinKind::Puti.name(), "puti");
+        assert_eq!(BuiltinKind::StrEq.name(), "str_eq");
+        assert_eq!(BuiltinKind::Index.name(), "index");
+        assert_eq!(BuiltinKind::Slice.name(), "slice");
+        assert_eq!(BuiltinKind::Panic.name(), "panic");
+        assert_eq!(BuiltinKind::Len.name(), "len");
+        assert_eq!(BuiltinKind::PanicWithInt.name(), "panic_with_int");
+        assert_eq!(BuiltinKind::OptionSome.name(), "some");
+        assert_eq!(BuiltinKind::CharAt.name(), "char_at");
+        assert_eq!(BuiltinKind::TermWidth.name(), "term_width");
+        assert_eq!(BuiltinKind::Args.name(), "args");
+        assert_eq!(BuiltinKind::Putc.name(), "putc");
+        assert_eq!(BuiltinKind::Repeat.name(), "repeat");
+        assert_eq!(BuiltinKind::StrEq.to_string(), "str_eq");
+    }
+
+    #[test]
+    fn test_inst_kind_is_pure() {
+        let pure = [
+            Inst::copy(0, 1),
+            Inst::literal(0, 42),
+            Inst::closure(0, 1),
+            Inst::builtin(0, BuiltinKind::Puts),
+            Inst::array(0),
+        ];
+        for inst in pure {
+            assert!(inst.kind.is_pure(), "{:?}", inst);
+            assert!(!inst.kind.has_side_effects(), "{:?}", inst);
+        }
+        let impure = [
+            Inst::jump(0),
+            Inst::branch(0, 1, 2),
+            Inst::return_(0),
+            Inst::unreachable(),
+            Inst::drop(0),
+            Inst::push_arg(0),
+            Inst::push_arg_copy(0),
+            Inst::call(0, 1),
+            Inst::try_call(0, 1),
+        ];
+        for inst in impure {
+            assert!(!inst.kind.is_pure(), "{:?}", inst);
+            assert!(inst.kind.has_side_effects(), "{:?}", inst);
+        }
+    }
+
+    #[test]
+    fn test_builtin_kind_is_pure() {
+        let pure = [
+            BuiltinKind::Add,
+            BuiltinKind::Sub,
+            BuiltinKind::Mul,
+            BuiltinKind::Div,
+            BuiltinKind::Lt,
+            BuiltinKind::Gt,
+            BuiltinKind::Le,
+            BuiltinKind::Ge,
+            BuiltinKind::Eq,
+            BuiltinKind::Ne,
+            BuiltinKind::StrEq,
+            BuiltinKind::Index,
+            BuiltinKind::Slice,
+            BuiltinKind::Len,
+            BuiltinKind::OptionNone,
+            BuiltinKind::OptionSome,
+            BuiltinKind::IsSome,
+            BuiltinKind::Unwrap,
+            BuiltinKind::CharAt,
+            BuiltinKind::Args,
+            BuiltinKind::Repeat,
+        ];
+        for builtin in pure {
+            assert!(builtin.is_pure(), "{:?}", builtin);
+        }
+        let impure = [
+            BuiltinKind::Puts,
+            BuiltinKind::Puti,
+            BuiltinKind::Panic,
+            BuiltinKind::PanicWithInt,
+            BuiltinKind::Gets,
+            BuiltinKind::TermWidth,
+            BuiltinKind::Putc,
+        ];
+        for builtin in impure {
+            assert!(!builtin.is_pure(), "{:?}", builtin);
+        }
+    }
+    #[test]
+    fn test_borrows_args() {
+        assert!(BuiltinKind::Puts.borrows_args());
+        assert!(BuiltinKind::Len.borrows_args());
+        assert!(BuiltinKind::Slice.borrows_args());
+        assert!(!BuiltinKind::OptionSome.borrows_args());
+        assert!(!BuiltinKind::Unwrap.borrows_args());
+        assert!(!BuiltinKind::Index.borrows_args());
+    }
 }
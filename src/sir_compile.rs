@@ -1,26 +1,524 @@
+use std::collections::HashMap;
 use std::mem;
 
 use bit_set::BitSet;
 
 use crate::cctx::CCtx;
-use crate::sir::{BasicBlock, Function, Inst, InstKind, ProgramUnit};
+use crate::sir::{BasicBlock, BuiltinKind, Function, Inst, InstKind, Literal, ProgramUnit};
+use crate::sir_dom::dominates;
+
+/// A pass of `compile`, applied to each function in turn
+type Pass = fn(&CCtx, &mut Function);
+
+/// The passes of `compile`, in the order they run
+///
+/// Those before `liveness` run where reading a variable does not move it yet,
+/// so they may add reads of a variable, such as a copy of an earlier result.
+/// From `insert_copy` on, a read moves the variable unless a copy is made for it.
+const PASSES: &[(&str, Pass)] = &[
+    ("hoist_loop_invariants", |_, function| {
+        while hoist_loop_invariants(function) {}
+    }),
+    ("fold_constants", |_, function| fold_constants(function)),
+    ("eliminate_common_builtin_calls", |_, function| {
+        eliminate_common_builtin_calls(function)
+    }),
+    ("liveness", liveness),
+    ("eliminate_dead_code", |cctx, function| {
+        while eliminate_dead_code(function) {
+            clear_liveness(function);
+            liveness(cctx, function);
+        }
+    }),
+    ("eliminate_copy", |cctx, function| {
+        if eliminate_copy(function) {
+            clear_liveness(function);
+            liveness(cctx, function);
+        }
+    }),
+    ("insert_copy", insert_copy),
+];
 
 pub fn compile(cctx: &CCtx, program_unit: &ProgramUnit) -> ProgramUnit {
-    if cfg!(debug_assert) {
+    compile_impl(cctx, program_unit, None)
+}
+
+/// Same as `compile`, but also returns the program as it was after each pass,
+/// paired with the name of the pass, for debugging the passes.
+pub fn compile_with_snapshots(
+    cctx: &CCtx,
+    program_unit: &ProgramUnit,
+) -> (ProgramUnit, Vec<(String, ProgramUnit)>) {
+    let mut snapshots = vec![];
+    let program_unit = compile_impl(cctx, program_unit, Some(&mut snapshots));
+    (program_unit, snapshots)
+}
+
+fn compile_impl(
+    cctx: &CCtx,
+    program_unit: &ProgramUnit,
+    mut snapshots: Option<&mut Vec<(String, ProgramUnit)>>,
+) -> ProgramUnit {
+    if cfg!(debug_assertions) {
         program_unit.validate_insts().unwrap();
     }
     let mut program_unit = program_unit.clone();
-    for function in &mut program_unit.functions {
-        *function = compile_function(cctx, function);
+    for &(name, pass) in PASSES {
+        for function in &mut program_unit.functions {
+            pass(cctx, function);
+            // Once computed, the liveness must be kept up to date by each pass,
+            // except that `insert_copy` leaves the drops it inserts out of it
+            if cfg!(debug_assertions) && name != "insert_copy" && function.body[0].live_in.is_some()
+            {
+                if let Err(e) = function.verify_liveness() {
+                    panic!("stale liveness after {}: {}", name, e);
+                }
+            }
+        }
+        if let Some(snapshots) = &mut snapshots {
+            snapshots.push((name.to_owned(), program_unit.clone()));
+        }
     }
     program_unit
 }
 
-fn compile_function(cctx: &CCtx, function: &Function) -> Function {
-    let mut function = function.clone();
-    liveness(cctx, &mut function);
-    insert_copy(cctx, &mut function);
-    function
+/// Rewrites `Literal(t, v); Copy(x, t)` into `Literal(x, v)` when `t` is dead after the copy.
+/// The same applies to `Builtin` and `Closure`.
+fn eliminate_copy(function: &mut Function) -> bool {
+    let mut updated = false;
+    for bb in &mut function.body {
+        let mut i = 0;
+        while i + 1 < bb.insts.len() {
+            let InstKind::Copy {
+                lhs: dest,
+                rhs: src,
+            } = bb.insts[i + 1].kind
+            else {
+                i += 1;
+                continue;
+            };
+            let is_fresh_def = matches!(
+                bb.insts[i].kind,
+                InstKind::Literal { .. }
+                    | InstKind::Builtin { .. }
+                    | InstKind::Closure { .. }
+                    | InstKind::Array { .. }
+            );
+            let src_dead = !bb.insts[i + 1].live_out.as_ref().unwrap().contains(src);
+            if is_fresh_def && lhs_of(&bb.insts[i]) == Some(src) && src != dest && src_dead {
+                match &mut bb.insts[i].kind {
+                    InstKind::Literal { lhs, .. }
+                    | InstKind::Builtin { lhs, .. }
+                    | InstKind::Closure { lhs, .. }
+                    | InstKind::Array { lhs } => *lhs = dest,
+                    _ => unreachable!(),
+                }
+                bb.insts.remove(i + 1);
+                updated = true;
+            }
+            i += 1;
+        }
+    }
+    updated
+}
+
+/// Removes pure instructions whose results are dead, using the liveness computed beforehand.
+/// `Closure`, `Array`, and calls to builtins that cannot fail are removed
+/// together with the `PushArg`s for them.
+///
+/// Returns whether anything was removed, in which case the liveness must be recomputed
+/// and more instructions may become dead.
+fn eliminate_dead_code(function: &mut Function) -> bool {
+    let mut updated = false;
+    for bb in &mut function.body {
+        let mut scan = BlockScan::default();
+        let mut dead = vec![false; bb.insts.len()];
+        for (i, inst) in bb.insts.iter().enumerate() {
+            let lhs_dead =
+                lhs_of(inst).is_some_and(|lhs| !inst.live_out.as_ref().unwrap().contains(lhs));
+            let args = scan.take_args(i, inst);
+            let removable = match &inst.kind {
+                InstKind::Call { .. } => scan.callee_builtin(inst).is_some_and(|builtin| {
+                    let operands = args
+                        .iter()
+                        .map(|&j| scan.literal_arg(&bb.insts[j]))
+                        .collect::<Vec<_>>();
+                    builtin_call_never_fails(builtin, &operands)
+                }),
+                InstKind::TryCall { .. } => false,
+                InstKind::Closure { .. } | InstKind::Array { .. } => true,
+                _ => !inst.kind.has_side_effects(),
+            };
+            if lhs_dead && removable {
+                dead[i] = true;
+                for j in args {
+                    dead[j] = true;
+                }
+            }
+            scan.record(inst);
+        }
+        if dead.contains(&true) {
+            let mut dead = dead.into_iter();
+            bb.insts.retain(|_| !dead.next().unwrap());
+            updated = true;
+        }
+    }
+    updated
+}
+
+/// Whether a call to the pure builtin can be removed without losing a runtime error.
+/// Operands are given as literals where known.
+///
+/// The arithmetic is checked as the evaluator does it, in `i64`.
+fn builtin_call_never_fails(builtin: BuiltinKind, operands: &[Option<&Literal>]) -> bool {
+    if !builtin.is_pure() {
+        return false;
+    }
+    let integer_operands = match operands {
+        [Some(Literal::Integer(i)), Some(Literal::Integer(j))] => {
+            Some((i64::from(*i), i64::from(*j)))
+        }
+        _ => None,
+    };
+    match builtin {
+        BuiltinKind::Add => integer_operands.is_some_and(|(i, j)| i.checked_add(j).is_some()),
+        BuiltinKind::Sub => integer_operands.is_some_and(|(i, j)| i.checked_sub(j).is_some()),
+        BuiltinKind::Mul => integer_operands.is_some_and(|(i, j)| i.checked_mul(j).is_some()),
+        // `checked_div` also rejects division by zero
+        BuiltinKind::Div => integer_operands.is_some_and(|(i, j)| i.checked_div(j).is_some()),
+        BuiltinKind::Lt
+        | BuiltinKind::Gt
+        | BuiltinKind::Le
+        | BuiltinKind::Ge
+        | BuiltinKind::Eq
+        | BuiltinKind::Ne
+        | BuiltinKind::StrEq
+        | BuiltinKind::OptionNone
+        | BuiltinKind::OptionSome
+        | BuiltinKind::IsSome
+        | BuiltinKind::Args => true,
+        // Any other builtin, including one added later, is kept unless known to be safe
+        _ => false,
+    }
+}
+
+/// Replaces a call to an arithmetic or comparison builtin with a literal of its result
+/// when the operands are literals defined earlier in the same block.
+/// Calls that would fail, or whose result does not fit in a literal, are kept.
+///
+/// The literals and the builtin left unused are removed by `eliminate_dead_code`.
+fn fold_constants(function: &mut Function) {
+    for bb in &mut function.body {
+        let mut scan = BlockScan::default();
+        let mut insts = Vec::<Inst>::with_capacity(bb.insts.len());
+        for mut inst in mem::take(&mut bb.insts) {
+            let args = scan.take_args(insts.len(), &inst);
+            if let InstKind::Call { lhs, .. } = inst.kind {
+                let operands = args
+                    .iter()
+                    .map(|&i| scan.literal_arg(&insts[i]))
+                    .collect::<Option<Vec<_>>>();
+                let folded = scan
+                    .callee_builtin(&inst)
+                    .zip(operands)
+                    .and_then(|(builtin, operands)| fold_builtin_call(builtin, &operands));
+                if let Some(value) = folded {
+                    for &i in args.iter().rev() {
+                        insts.remove(i);
+                    }
+                    inst = Inst::literal(lhs, value);
+                }
+            }
+            scan.record(&inst);
+            insts.push(inst);
+        }
+        bb.insts = insts;
+    }
+}
+
+/// Result of a call to the builtin with the literal operands, if it can be computed here.
+fn fold_builtin_call(builtin: BuiltinKind, operands: &[&Literal]) -> Option<Literal> {
+    let [Literal::Integer(i), Literal::Integer(j)] = operands else {
+        return None;
+    };
+    match builtin {
+        // Results beyond the range of literals are left to the runtime
+        BuiltinKind::Add => i.checked_add(*j).map(Literal::Integer),
+        BuiltinKind::Sub => i.checked_sub(*j).map(Literal::Integer),
+        BuiltinKind::Mul => i.checked_mul(*j).map(Literal::Integer),
+        BuiltinKind::Div => i.checked_div(*j).map(Literal::Integer),
+        BuiltinKind::Lt => Some(Literal::Bool(i < j)),
+        BuiltinKind::Gt => Some(Literal::Bool(i > j)),
+        BuiltinKind::Le => Some(Literal::Bool(i <= j)),
+        BuiltinKind::Ge => Some(Literal::Bool(i >= j)),
+        BuiltinKind::Eq => Some(Literal::Bool(i == j)),
+        BuiltinKind::Ne => Some(Literal::Bool(i != j)),
+        _ => None,
+    }
+}
+
+/// Operand of a builtin call, as compared by `eliminate_common_builtin_calls`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Operand {
+    Var(usize),
+    Literal(Literal),
+}
+
+/// Replaces a call to a pure builtin with a copy of an earlier result in the same block
+/// when the operands are the same and none of them has been written since.
+/// Operands defined by literals are compared by value, so `1 + 2` computed twice is shared,
+/// and copies are traced back to their source variable.
+fn eliminate_common_builtin_calls(function: &mut Function) {
+    for bb in &mut function.body {
+        let mut scan = BlockScan::default();
+        let mut copies = HashMap::<usize, usize>::new();
+        let mut available = HashMap::<(BuiltinKind, Vec<Operand>), usize>::new();
+        let mut insts = Vec::<Inst>::with_capacity(bb.insts.len());
+        for mut inst in mem::take(&mut bb.insts) {
+            let mut new_entry = None;
+            let args = scan.take_args(insts.len(), &inst);
+            if let InstKind::Call { lhs, .. } = inst.kind {
+                if let Some(builtin) = scan.callee_builtin(&inst).filter(|b| b.is_pure()) {
+                    let operands = args
+                        .iter()
+                        .map(|&i| {
+                            let value_ref = pushed_var(&insts[i]);
+                            let var = copies.get(&value_ref).copied().unwrap_or(value_ref);
+                            match scan.literals.get(&var) {
+                                Some(value) => Operand::Literal(value.clone()),
+                                None => Operand::Var(var),
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    let key = (builtin, operands);
+                    if let Some(&prev) = available.get(&key) {
+                        for &i in args.iter().rev() {
+                            insts.remove(i);
+                        }
+                        inst = Inst::copy(lhs, prev);
+                    } else if !key.1.contains(&Operand::Var(lhs)) {
+                        new_entry = Some((key, lhs));
+                    }
+                }
+            }
+            scan.record(&inst);
+            if let Some(written) = written_var(&inst) {
+                copies.retain(|&copy, &mut src| copy != written && src != written);
+                available.retain(|(_, operands), result| {
+                    *result != written && !operands.contains(&Operand::Var(written))
+                });
+            }
+            match &inst.kind {
+                InstKind::Copy { lhs, rhs } if lhs != rhs => {
+                    let src = copies.get(rhs).copied().unwrap_or(*rhs);
+                    copies.insert(*lhs, src);
+                }
+                _ => {}
+            }
+            if let Some((key, result)) = new_entry {
+                available.insert(key, result);
+            }
+            insts.push(inst);
+        }
+        bb.insts = insts;
+    }
+}
+
+/// Moves instructions that compute the same value in every iteration of a loop
+/// to the block from which the loop is entered, called the preheader.
+///
+/// Only `Literal`, `Builtin`, and `Copy` from a variable not written in the loop are moved,
+/// when their destination is written nowhere else in the loop, is read in the loop
+/// only after them, and holds their value at every exit of the loop.
+///
+/// Returns whether anything was moved. One loop is handled at a time,
+/// as the dominators change when a preheader is added.
+fn hoist_loop_invariants(function: &mut Function) -> bool {
+    function.ensure_preheaders();
+    let idom = function.dominators();
+    for (header, blocks) in function.natural_loops(&idom) {
+        let Some(preheader) = function.preheader(header, &blocks) else {
+            continue;
+        };
+        let invariants = loop_invariants(function, &idom, &blocks);
+        if !invariants.is_empty() {
+            insert_into_preheader(function, preheader, &invariants);
+            return true;
+        }
+    }
+    false
+}
+
+/// Positions of the instructions that `hoist_loop_invariants` may move out of the loop
+fn loop_invariants(
+    function: &Function,
+    idom: &[Option<usize>],
+    blocks: &BitSet<usize>,
+) -> Vec<(usize, usize)> {
+    let mut num_writes = vec![0; function.num_vars];
+    let mut reads = vec![vec![]; function.num_vars];
+    let mut exits = vec![];
+    for bb_id in blocks {
+        let bb = &function.body[bb_id];
+        if bb.successors().iter().any(|&succ| !blocks.contains(succ)) {
+            exits.push(bb_id);
+        }
+        for (i, inst) in bb.insts.iter().enumerate() {
+            let written = match &inst.kind {
+                InstKind::Drop { rhs } => Some(*rhs),
+                _ => lhs_of(inst),
+            };
+            if let Some(written) = written {
+                num_writes[written] += 1;
+            }
+            let mut read = BitSet::<usize>::default();
+            update_alive(inst, &mut read);
+            for var in &read {
+                reads[var].push((bb_id, i));
+            }
+        }
+    }
+    let mut invariants = vec![];
+    for bb_id in blocks {
+        if !exits.iter().all(|&exit| dominates(idom, bb_id, exit)) {
+            continue;
+        }
+        for (i, inst) in function.body[bb_id].insts.iter().enumerate() {
+            let lhs = match inst.kind {
+                InstKind::Literal { lhs, .. } | InstKind::Builtin { lhs, .. } => lhs,
+                InstKind::Copy { lhs, rhs } if num_writes[rhs] == 0 => lhs,
+                _ => continue,
+            };
+            let read_after = reads[lhs].iter().all(|&(read_bb_id, j)| {
+                if read_bb_id == bb_id {
+                    j > i
+                } else {
+                    dominates(idom, bb_id, read_bb_id)
+                }
+            });
+            if num_writes[lhs] == 1 && read_after {
+                invariants.push((bb_id, i));
+            }
+        }
+    }
+    invariants
+}
+
+/// Moves the instructions at the positions, given in order, to the end of the preheader.
+fn insert_into_preheader(function: &mut Function, preheader: usize, positions: &[(usize, usize)]) {
+    let mut hoisted = positions
+        .iter()
+        .rev()
+        .map(|&(bb_id, i)| function.body[bb_id].insts.remove(i))
+        .collect::<Vec<_>>();
+    hoisted.reverse();
+    let insts = &mut function.body[preheader].insts;
+    let end = insts.len() - 1;
+    insts.splice(end..end, hoisted);
+}
+
+impl Function {
+    /// Gives each natural loop a preheader: a block that always jumps to the header
+    /// and is its only predecessor from outside the loop.
+    /// New preheaders are appended to the body.
+    ///
+    /// Loops headed by the entry block are left alone, as nothing can precede it.
+    pub fn ensure_preheaders(&mut self) {
+        loop {
+            let idom = self.dominators();
+            let missing = self
+                .natural_loops(&idom)
+                .into_iter()
+                .find(|(header, blocks)| *header != 0 && self.preheader(*header, blocks).is_none());
+            let Some((header, blocks)) = missing else {
+                break;
+            };
+            let entries = self.loop_entries(header, &blocks);
+            let preheader = self.body.len();
+            self.body.push(BasicBlock::new(vec![Inst::jump(header)]));
+            for entry in entries {
+                match &mut self.body[entry].insts.last_mut().unwrap().kind {
+                    InstKind::Jump { target } => *target = preheader,
+                    InstKind::Branch {
+                        branch_then,
+                        branch_else,
+                        ..
+                    } => {
+                        for target in [branch_then, branch_else] {
+                            if *target == header {
+                                *target = preheader;
+                            }
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// The preheader of the loop, if it has one
+    fn preheader(&self, header: usize, blocks: &BitSet<usize>) -> Option<usize> {
+        match self.loop_entries(header, blocks)[..] {
+            [entry]
+                if matches!(
+                    self.body[entry].insts.last().map(|inst| &inst.kind),
+                    Some(InstKind::Jump { .. })
+                ) =>
+            {
+                Some(entry)
+            }
+            _ => None,
+        }
+    }
+
+    /// Blocks outside the loop that branch to its header
+    fn loop_entries(&self, header: usize, blocks: &BitSet<usize>) -> Vec<usize> {
+        let mut entries = self.predecessors()[header]
+            .iter()
+            .copied()
+            .filter(|&pred| !blocks.contains(pred))
+            .collect::<Vec<_>>();
+        entries.dedup();
+        entries
+    }
+
+    /// Checks the liveness annotations against a fresh liveness analysis,
+    /// reporting the first block or instruction where they differ.
+    ///
+    /// Catches passes that rewrite instructions without keeping the liveness up to date.
+    pub fn verify_liveness(&self) -> Result<(), String> {
+        let mut expected = self.clone();
+        clear_liveness(&mut expected);
+        liveness(&CCtx::new(), &mut expected);
+        for (bb_id, (bb, expected_bb)) in self.body.iter().zip(&expected.body).enumerate() {
+            if bb.live_in != expected_bb.live_in {
+                return Err(format!(
+                    "bb{}: live_in is {:?}, but should be {:?}",
+                    bb_id, bb.live_in, expected_bb.live_in
+                ));
+            }
+            for (inst_id, (inst, expected_inst)) in
+                bb.insts.iter().zip(&expected_bb.insts).enumerate()
+            {
+                if inst.live_out != expected_inst.live_out {
+                    return Err(format!(
+                        "bb{}[{}]: live_out is {:?}, but should be {:?}",
+                        bb_id, inst_id, inst.live_out, expected_inst.live_out
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn clear_liveness(function: &mut Function) {
+    for bb in &mut function.body {
+        bb.live_in = None;
+        for inst in &mut bb.insts {
+            inst.live_out = None;
+        }
+    }
 }
 
 fn liveness(cctx: &CCtx, function: &mut Function) {
@@ -72,6 +570,7 @@ fn update_alive(inst: &Inst, alive: &mut BitSet<usize>) {
         InstKind::Return { rhs } => {
             alive.insert(*rhs);
         }
+        InstKind::Unreachable => {}
         InstKind::Copy { lhs, rhs } => {
             alive.remove(*lhs);
             alive.insert(*rhs);
@@ -82,17 +581,22 @@ fn update_alive(inst: &Inst, alive: &mut BitSet<usize>) {
         InstKind::Literal { lhs, .. } => {
             alive.remove(*lhs);
         }
-        InstKind::Closure { lhs, function_id } => {
+        InstKind::Closure {
+            lhs,
+            function_id: _,
+        } => {
             alive.remove(*lhs);
-            alive.insert(*function_id);
         }
         InstKind::Builtin { lhs, builtin: _ } => {
             alive.remove(*lhs);
         }
-        InstKind::PushArg { value_ref } => {
+        InstKind::Array { lhs } => {
+            alive.remove(*lhs);
+        }
+        InstKind::PushArg { value_ref } | InstKind::PushArgCopy { value_ref } => {
             alive.insert(*value_ref);
         }
-        InstKind::Call { lhs, callee } => {
+        InstKind::Call { lhs, callee } | InstKind::TryCall { lhs, callee } => {
             alive.remove(*lhs);
             alive.insert(*callee);
         }
@@ -120,7 +624,7 @@ fn block_live_out_to_be(function: &Function, bb: &BasicBlock) -> BitSet<usize> {
             }
             live_out
         }
-        InstKind::Return { rhs: _ } => BitSet::default(),
+        InstKind::Return { rhs: _ } | InstKind::Unreachable => BitSet::default(),
         _ => unreachable!(),
     }
 }
@@ -161,7 +665,7 @@ fn insert_copy(cctx: &CCtx, function: &mut Function) {
                 carried_over[*branch_then].union_with(block_live_out(bb));
                 carried_over[*branch_else].union_with(block_live_out(bb));
             }
-            InstKind::Return { rhs: _ } => {}
+            InstKind::Return { rhs: _ } | InstKind::Unreachable => {}
             _ => unreachable!(),
         }
     }
@@ -178,6 +682,7 @@ fn insert_copy_bb(
     mut carried_over: BitSet<usize>,
 ) {
     let old_insts = mem::replace(&mut bb.insts, Vec::new());
+    let borrowed_args = borrowed_push_args(&old_insts);
 
     // Drop unused variables carried over from the last block (caused by branch instructions)
     let mut unused_carried_over = carried_over.clone();
@@ -189,10 +694,15 @@ fn insert_copy_bb(
     }
 
     // Process block body
-    for mut inst in old_insts {
+    for (i, mut inst) in old_insts.into_iter().enumerate() {
         // Insert copy before the instruction, if necessary
         if let Some(moved_rhs) = moved_rhs_of(&inst) {
-            if inst.live_out.as_ref().unwrap().contains(moved_rhs) {
+            if inst.live_out.as_ref().unwrap().contains(moved_rhs) && borrowed_args.contains(i) {
+                // The callee only reads the argument, so no temporary is needed
+                inst.kind = InstKind::PushArgCopy {
+                    value_ref: moved_rhs,
+                };
+            } else if inst.live_out.as_ref().unwrap().contains(moved_rhs) {
                 let new_rhs = fresh_var(num_vars);
                 let mut alive = inst_live_in(&inst);
                 alive.insert(new_rhs);
@@ -222,6 +732,219 @@ fn insert_copy_bb(
     }
 }
 
+/// Positions of the `PushArg`s whose values are passed to a builtin that only reads them.
+///
+/// Only builtins loaded in the same block are recognized.
+fn borrowed_push_args(insts: &[Inst]) -> BitSet<usize> {
+    let mut scan = BlockScan::default();
+    let mut borrowed_args = BitSet::<usize>::default();
+    for (i, inst) in insts.iter().enumerate() {
+        let args = scan.take_args(i, inst);
+        if scan.callee_builtin(inst).is_some_and(|b| b.borrows_args()) {
+            borrowed_args.extend(args);
+        }
+        scan.record(inst);
+    }
+    borrowed_args
+}
+
+/// What the instructions so far in a basic block tell about the next one:
+/// the literals and builtins held in variables, and the `PushArg`s waiting for a call.
+#[derive(Debug, Default)]
+struct BlockScan {
+    literals: HashMap<usize, Literal>,
+    builtins: HashMap<usize, BuiltinKind>,
+    /// Positions of the `PushArg`s for the next call
+    pending_args: Vec<usize>,
+}
+
+impl BlockScan {
+    /// Notes the instruction at `pos` if it pushes an argument, and returns the positions
+    /// of the `PushArg`s it consumes if it is a `Call`, `Closure`, or `Array`.
+    fn take_args(&mut self, pos: usize, inst: &Inst) -> Vec<usize> {
+        match &inst.kind {
+            InstKind::PushArg { .. } | InstKind::PushArgCopy { .. } => {
+                self.pending_args.push(pos);
+                vec![]
+            }
+            InstKind::Call { .. } | InstKind::Closure { .. } | InstKind::Array { .. } => {
+                mem::take(&mut self.pending_args)
+            }
+            InstKind::TryCall { .. } => {
+                self.pending_args.clear();
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// The builtin called by the instruction, if it is loaded earlier in the block.
+    fn callee_builtin(&self, inst: &Inst) -> Option<BuiltinKind> {
+        match &inst.kind {
+            InstKind::Call { callee, .. } => self.builtins.get(callee).copied(),
+            _ => None,
+        }
+    }
+
+    /// The literal pushed by the `PushArg`, if it is defined earlier in the block.
+    fn literal_arg(&self, push_arg: &Inst) -> Option<&Literal> {
+        self.literals.get(&pushed_var(push_arg))
+    }
+
+    /// Updates what is known after the instruction runs.
+    fn record(&mut self, inst: &Inst) {
+        if let Some(written) = written_var(inst) {
+            self.literals.remove(&written);
+            self.builtins.remove(&written);
+        }
+        match &inst.kind {
+            InstKind::Literal { lhs, value } => {
+                self.literals.insert(*lhs, value.clone());
+            }
+            InstKind::Builtin { lhs, builtin } => {
+                self.builtins.insert(*lhs, *builtin);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn pushed_var(push_arg: &Inst) -> usize {
+    match push_arg.kind {
+        InstKind::PushArg { value_ref } | InstKind::PushArgCopy { value_ref } => value_ref,
+        _ => unreachable!(),
+    }
+}
+
+/// The variable whose value the instruction replaces or discards
+fn written_var(inst: &Inst) -> Option<usize> {
+    match &inst.kind {
+        InstKind::Drop { rhs } => Some(*rhs),
+        _ => lhs_of(inst),
+    }
+}
+
+/// Coalesces non-interfering variables onto shared slots to reduce `num_vars`.
+///
+/// Must be run on the output of `compile`, as it relies on the liveness information.
+/// After the allocation, variables no longer correspond to the source-level names.
+pub fn allocate_registers(cctx: &CCtx, program_unit: &ProgramUnit) -> ProgramUnit {
+    let mut program_unit = program_unit.clone();
+    for function in &mut program_unit.functions {
+        allocate_registers_function(cctx, function);
+    }
+    program_unit
+}
+
+fn allocate_registers_function(_cctx: &CCtx, function: &mut Function) {
+    // Two variables interfere if they are live at the same time.
+    let mut interference = vec![BitSet::<usize>::default(); function.num_vars];
+    for bb in &function.body {
+        let live_sets = bb
+            .live_in
+            .iter()
+            .chain(bb.insts.iter().map(|inst| inst.live_out.as_ref().unwrap()));
+        for live in live_sets {
+            for var in live.iter() {
+                interference[var].union_with(live);
+            }
+        }
+    }
+
+    // Greedy coloring. Arguments keep their positions as they are passed by the caller.
+    let mut slots = vec![0; function.num_vars];
+    let mut num_slots = function.num_args;
+    for var in 0..function.num_vars {
+        if var < function.num_args {
+            slots[var] = var;
+            continue;
+        }
+        let used = interference[var]
+            .iter()
+            .filter(|&other| other < var)
+            .map(|other| slots[other])
+            .collect::<BitSet<usize>>();
+        let slot = (0..).find(|&slot| !used.contains(slot)).unwrap();
+        slots[var] = slot;
+        num_slots = num_slots.max(slot + 1);
+    }
+
+    // Slots holding a named variable are numbered first, so that `num_named_vars`
+    // still separates them from temporaries. The arguments are named and stay in place.
+    let named_slots = slots[..function.num_named_vars]
+        .iter()
+        .copied()
+        .collect::<BitSet<usize>>();
+    let mut renumbered = vec![0; num_slots];
+    let order = (0..num_slots)
+        .filter(|&slot| named_slots.contains(slot))
+        .chain((0..num_slots).filter(|&slot| !named_slots.contains(slot)));
+    for (new_slot, slot) in order.enumerate() {
+        renumbered[slot] = new_slot;
+    }
+    for slot in &mut slots {
+        *slot = renumbered[*slot];
+    }
+
+    let rename_set =
+        |set: &BitSet<usize>| set.iter().map(|var| slots[var]).collect::<BitSet<usize>>();
+    for bb in &mut function.body {
+        if let Some(live_in) = &mut bb.live_in {
+            *live_in = rename_set(live_in);
+        }
+        for inst in &mut bb.insts {
+            rename_vars(inst, |var| slots[var]);
+            if let Some(live_out) = &mut inst.live_out {
+                *live_out = rename_set(live_out);
+            }
+        }
+    }
+    function.num_vars = num_slots;
+    function.num_named_vars = named_slots.len();
+}
+
+fn rename_vars<F>(inst: &mut Inst, f: F)
+where
+    F: Fn(usize) -> usize,
+{
+    match &mut inst.kind {
+        InstKind::Jump { .. } => {}
+        InstKind::Branch { cond, .. } => {
+            *cond = f(*cond);
+        }
+        InstKind::Return { rhs } => {
+            *rhs = f(*rhs);
+        }
+        InstKind::Unreachable => {}
+        InstKind::Copy { lhs, rhs } => {
+            *lhs = f(*lhs);
+            *rhs = f(*rhs);
+        }
+        InstKind::Drop { rhs } => {
+            *rhs = f(*rhs);
+        }
+        InstKind::Literal { lhs, .. } => {
+            *lhs = f(*lhs);
+        }
+        InstKind::Closure { lhs, .. } => {
+            *lhs = f(*lhs);
+        }
+        InstKind::Builtin { lhs, .. } => {
+            *lhs = f(*lhs);
+        }
+        InstKind::Array { lhs } => {
+            *lhs = f(*lhs);
+        }
+        InstKind::PushArg { value_ref } | InstKind::PushArgCopy { value_ref } => {
+            *value_ref = f(*value_ref);
+        }
+        InstKind::Call { lhs, callee } | InstKind::TryCall { lhs, callee } => {
+            *lhs = f(*lhs);
+            *callee = f(*callee);
+        }
+    }
+}
+
 fn fresh_var(num_vars: &mut usize) -> usize {
     let var = *num_vars;
     *num_vars += 1;
@@ -233,6 +956,7 @@ fn moved_rhs_of(inst: &Inst) -> Option<usize> {
         InstKind::Jump { .. } => None,
         InstKind::Branch { cond, .. } => Some(*cond),
         InstKind::Return { rhs } => Some(*rhs),
+        InstKind::Unreachable => None,
         InstKind::Copy { .. } => None,
         InstKind::Drop { rhs } => Some(*rhs),
         InstKind::Literal { .. } => None,
@@ -241,8 +965,11 @@ fn moved_rhs_of(inst: &Inst) -> Option<usize> {
             function_id: _,
         } => None,
         InstKind::Builtin { lhs: _, builtin: _ } => None,
+        InstKind::Array { lhs: _ } => None,
         InstKind::PushArg { value_ref } => Some(*value_ref),
+        InstKind::PushArgCopy { value_ref: _ } => None,
         InstKind::Call { lhs: _, callee } => Some(*callee),
+        InstKind::TryCall { lhs: _, callee } => Some(*callee),
     }
 }
 
@@ -257,6 +984,9 @@ fn replace_moved_rhs(inst: &mut Inst, to: usize) {
         InstKind::Return { rhs } => {
             *rhs = to;
         }
+        InstKind::Unreachable => {
+            unreachable!();
+        }
         InstKind::Copy { .. } => {
             unreachable!();
         }
@@ -272,10 +1002,16 @@ fn replace_moved_rhs(inst: &mut Inst, to: usize) {
         InstKind::Builtin { .. } => {
             unreachable!();
         }
+        InstKind::Array { .. } => {
+            unreachable!();
+        }
         InstKind::PushArg { value_ref } => {
             *value_ref = to;
         }
-        InstKind::Call { callee, .. } => {
+        InstKind::PushArgCopy { .. } => {
+            unreachable!();
+        }
+        InstKind::Call { callee, .. } | InstKind::TryCall { callee, .. } => {
             *callee = to;
         }
     }
@@ -286,13 +1022,17 @@ fn lhs_of(inst: &Inst) -> Option<usize> {
         InstKind::Jump { .. } => None,
         InstKind::Branch { .. } => None,
         InstKind::Return { .. } => None,
+        InstKind::Unreachable => None,
         InstKind::Copy { lhs, .. } => Some(*lhs),
         InstKind::Drop { .. } => None,
         InstKind::Literal { lhs, .. } => Some(*lhs),
         InstKind::Closure { lhs, .. } => Some(*lhs),
         InstKind::Builtin { lhs, .. } => Some(*lhs),
+        InstKind::Array { lhs } => Some(*lhs),
         InstKind::PushArg { .. } => None,
+        InstKind::PushArgCopy { .. } => None,
         InstKind::Call { lhs, .. } => Some(*lhs),
+        InstKind::TryCall { lhs, .. } => Some(*lhs),
     }
 }
 
@@ -301,6 +1041,8 @@ mod tests {
     use pretty_assertions::assert_eq;
 
     use crate::sir::{BasicBlock, BuiltinKind, Inst, ProgramUnit};
+    use crate::sir_eval::eval1;
+    use crate::testing::{fib_program, MockRtCtx};
 
     use super::*;
 
@@ -327,13 +1069,13 @@ mod tests {
         let program_unit = compile(&cctx, &program_unit);
         assert_eq!(
             program_unit,
-            ProgramUnit::simple(Function::simple(0, |[x, puts1, tmp1, tmp2, tmp3]| {
+            ProgramUnit::simple(Function::simple(0, |[x, puts1, tmp1, tmp2]| {
                 BasicBlock::new(vec![
                     Inst::literal(x, "Hello, world!").with_live_out([x].into_iter().collect()),
                     Inst::builtin(puts1, BuiltinKind::Puts)
                         .with_live_out([x, puts1].into_iter().collect()),
-                    Inst::copy(tmp3, x).with_live_out([x, puts1, tmp3].into_iter().collect()),
-                    Inst::push_arg(tmp3).with_live_out([x, puts1].into_iter().collect()),
+                    // puts only reads x, so x is pushed without a temporary
+                    Inst::push_arg_copy(x).with_live_out([x, puts1].into_iter().collect()),
                     Inst::call(tmp2, puts1).with_live_out([x, tmp2].into_iter().collect()),
                     Inst::drop(tmp2).with_live_out([x].into_iter().collect()),
                     Inst::builtin(puts1, BuiltinKind::Puts)
@@ -355,6 +1097,672 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_compile_eliminate_dead_code() {
+        let cctx = CCtx::new();
+        // let x = 1 + 2; puts("hi");
+        let program_unit = ProgramUnit::simple(Function::describe(
+            0,
+            |[add1, a, b, x, puts1, s, tmp1, tmp2], [bb0]| {
+                vec![(
+                    bb0,
+                    BasicBlock::new(vec![
+                        Inst::builtin(add1, BuiltinKind::Add),
+                        Inst::literal(a, 1),
+                        Inst::literal(b, 2),
+                        Inst::push_arg(a),
+                        Inst::push_arg(b),
+                        Inst::call(x, add1),
+                        Inst::builtin(puts1, BuiltinKind::Puts),
+                        Inst::literal(s, "hi"),
+                        Inst::push_arg(s),
+                        Inst::call(tmp2, puts1),
+                        Inst::literal(tmp1, ()),
+                        Inst::return_(tmp1),
+                    ]),
+                )]
+            },
+        ));
+        let program_unit = compile(&cctx, &program_unit);
+        // The result of puts is unused too, but the call is kept
+        assert_eq!(
+            program_unit.functions[0].liveness_report(),
+            concat!(
+                "bb0: live_in = {}\n",
+                "    Inst::builtin(v4, Puts)  // live_out = {v4}\n",
+                "    Inst::literal(v5, \"hi\")  // live_out = {v4, v5}\n",
+                "    Inst::push_arg(v5)  // live_out = {v4}\n",
+                "    Inst::call(v7, v4)  // live_out = {v7}\n",
+                "    Inst::drop(v7)  // live_out = {}\n",
+                "    Inst::literal(v6, ())  // live_out = {v6}\n",
+                "    Inst::return_(v6)  // live_out = {}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_eliminate_dead_code_keeps_failing_builtin_calls() {
+        // let x = 1 / 0; let y = unwrap(none());
+        let mut function = Function::simple(0, |[div1, a, b, x, unwrap1, none1, n, y, tmp1]| {
+            BasicBlock::new(vec![
+                Inst::builtin(div1, BuiltinKind::Div),
+                Inst::literal(a, 1),
+                Inst::literal(b, 0),
+                Inst::push_arg(a),
+                Inst::push_arg(b),
+                Inst::call(x, div1),
+                Inst::builtin(unwrap1, BuiltinKind::Unwrap),
+                Inst::builtin(none1, BuiltinKind::OptionNone),
+                Inst::call(n, none1),
+                Inst::push_arg(n),
+                Inst::call(y, unwrap1),
+                Inst::literal(tmp1, ()),
+                Inst::return_(tmp1),
+            ])
+        });
+        liveness(&CCtx::new(), &mut function);
+        assert!(!eliminate_dead_code(&mut function));
+
+        // let x = 2147483647 + 1; does not overflow, as integers are 64-bit at runtime
+        let mut function = Function::simple(0, |[add1, a, b, x, tmp1]| {
+            BasicBlock::new(vec![
+                Inst::builtin(add1, BuiltinKind::Add),
+                Inst::literal(a, i32::MAX),
+                Inst::literal(b, 1),
+                Inst::push_arg(a),
+                Inst::push_arg(b),
+                Inst::call(x, add1),
+                Inst::literal(tmp1, ()),
+                Inst::return_(tmp1),
+            ])
+        });
+        liveness(&CCtx::new(), &mut function);
+        assert!(eliminate_dead_code(&mut function));
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let cctx = CCtx::new();
+        // puti(1 + 2 * 3); puti(2147483647 + 1);
+        let program_unit = ProgramUnit::simple(Function::simple(
+            0,
+            |[tmp1, puti1, a, b, c, mul1, t1, add1, t2, tmp2, d, add2, t3, tmp3]| {
+                BasicBlock::new(vec![
+                    Inst::builtin(puti1, BuiltinKind::Puti),
+                    Inst::literal(a, 1),
+                    Inst::literal(b, 2),
+                    Inst::literal(c, 3),
+                    Inst::builtin(mul1, BuiltinKind::Mul),
+                    Inst::push_arg(b),
+                    Inst::push_arg(c),
+                    Inst::call(t1, mul1),
+                    Inst::builtin(add1, BuiltinKind::Add),
+                    Inst::push_arg(a),
+                    Inst::push_arg(t1),
+                    Inst::call(t2, add1),
+                    Inst::push_arg(t2),
+                    Inst::call(tmp2, puti1),
+                    Inst::literal(d, i32::MAX),
+                    Inst::builtin(add2, BuiltinKind::Add),
+                    Inst::push_arg(d),
+                    Inst::push_arg(a),
+                    Inst::call(t3, add2),
+                    Inst::push_arg(t3),
+                    Inst::call(tmp3, puti1),
+                    Inst::literal(tmp1, ()),
+                    Inst::return_(tmp1),
+                ])
+            },
+        ));
+        let compiled = compile(&cctx, &program_unit);
+        assert_eq!(program_unit.instruction_count(), 23);
+        assert_eq!(compiled.instruction_count(), 17);
+        let ctx = MockRtCtx::new();
+        eval1(&ctx, &allocate_registers(&cctx, &compiled)).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "7\n2147483648\n");
+    }
+
+    #[test]
+    fn test_compile_with_snapshots() {
+        let cctx = CCtx::new();
+        let program_unit = fib_program();
+        let (compiled, snapshots) = compile_with_snapshots(&cctx, &program_unit);
+        let names = snapshots
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "hoist_loop_invariants",
+                "fold_constants",
+                "eliminate_common_builtin_calls",
+                "liveness",
+                "eliminate_dead_code",
+                "eliminate_copy",
+                "insert_copy"
+            ]
+        );
+        // Liveness is not known before the pass computing it
+        assert!(snapshots[2].1.functions[1].body[0].live_in.is_none());
+        assert!(snapshots[3].1.functions[1].body[0].live_in.is_some());
+        assert_eq!(snapshots.last().unwrap().1, compiled);
+        assert_eq!(compiled, compile(&cctx, &program_unit));
+    }
+
+    #[test]
+    fn test_hoist_loop_invariants() {
+        // let sum = 0; let i = 0; while i < 10 { sum = sum + i; i = i + 1; } puti(sum);
+        let sum_loop = |hoisted: bool| {
+            Function::describe(
+                0,
+                |[sum, i, tmp1, lt1, add1, puti1, tmp2, tmp3, tmp4, tmp5],
+                 [entry, cond, body, end]| {
+                    let invariants =
+                        vec![Inst::literal(tmp1, 10), Inst::builtin(lt1, BuiltinKind::Lt)];
+                    let (entry_invariants, cond_invariants) = if hoisted {
+                        (invariants, vec![])
+                    } else {
+                        (vec![], invariants)
+                    };
+                    vec![
+                        (
+                            entry,
+                            BasicBlock::new(
+                                [Inst::literal(sum, 0), Inst::literal(i, 0)]
+                                    .into_iter()
+                                    .chain(entry_invariants)
+                                    .chain([Inst::jump(cond)])
+                                    .collect::<Vec<_>>(),
+                            ),
+                        ),
+                        (
+                            cond,
+                            BasicBlock::new(
+                                cond_invariants
+                                    .into_iter()
+                                    .chain([
+                                        Inst::push_arg(i),
+                                        Inst::push_arg(tmp1),
+                                        Inst::call(tmp2, lt1),
+                                        Inst::branch(tmp2, body, end),
+                                    ])
+                                    .collect::<Vec<_>>(),
+                            ),
+                        ),
+                        (
+                            body,
+                            BasicBlock::new(vec![
+                                Inst::builtin(add1, BuiltinKind::Add),
+                                Inst::push_arg(sum),
+                                Inst::push_arg(i),
+                                Inst::call(sum, add1),
+                                // Only run when the loop continues, so it stays
+                                Inst::literal(tmp3, 1),
+                                // `add1` is written twice in the loop
+                                Inst::builtin(add1, BuiltinKind::Add),
+                                Inst::push_arg(i),
+                                Inst::push_arg(tmp3),
+                                Inst::call(i, add1),
+                                Inst::jump(cond),
+                            ]),
+                        ),
+                        (
+                            end,
+                            BasicBlock::new(vec![
+                                Inst::builtin(puti1, BuiltinKind::Puti),
+                                Inst::push_arg(sum),
+                                Inst::call(tmp5, puti1),
+                                Inst::literal(tmp4, ()),
+                                Inst::return_(tmp4),
+                            ]),
+                        ),
+                    ]
+                },
+            )
+        };
+        let mut function = sum_loop(false);
+        assert!(hoist_loop_invariants(&mut function));
+        assert!(!hoist_loop_invariants(&mut function));
+        assert_eq!(function, sum_loop(true));
+    }
+
+    #[test]
+    fn test_hoist_loop_invariants_new_preheader() {
+        let before =
+            Function::describe(0, |[c, x, tmp1, tmp2, tmp3], [entry, header, body, end]| {
+                vec![
+                    (
+                        entry,
+                        BasicBlock::new(vec![
+                            Inst::literal(c, true),
+                            Inst::literal(x, 0),
+                            Inst::branch(c, header, end),
+                        ]),
+                    ),
+                    (
+                        header,
+                        BasicBlock::new(vec![
+                            // `x` is read before it is written in the next iteration
+                            Inst::copy(tmp2, x),
+                            Inst::literal(x, 1),
+                            Inst::literal(tmp1, 2),
+                            Inst::branch(c, body, end),
+                        ]),
+                    ),
+                    (body, BasicBlock::new(vec![Inst::jump(header)])),
+                    (
+                        end,
+                        BasicBlock::new(vec![Inst::literal(tmp3, ()), Inst::return_(tmp3)]),
+                    ),
+                ]
+            });
+        let after = Function::describe(
+            0,
+            |[c, x, tmp1, tmp2, tmp3], [entry, header, body, end, preheader]| {
+                vec![
+                    (
+                        entry,
+                        BasicBlock::new(vec![
+                            Inst::literal(c, true),
+                            Inst::literal(x, 0),
+                            Inst::branch(c, preheader, end),
+                        ]),
+                    ),
+                    (
+                        header,
+                        BasicBlock::new(vec![
+                            Inst::copy(tmp2, x),
+                            Inst::literal(x, 1),
+                            Inst::branch(c, body, end),
+                        ]),
+                    ),
+                    (body, BasicBlock::new(vec![Inst::jump(header)])),
+                    (
+                        end,
+                        BasicBlock::new(vec![Inst::literal(tmp3, ()), Inst::return_(tmp3)]),
+                    ),
+                    (
+                        preheader,
+                        BasicBlock::new(vec![Inst::literal(tmp1, 2), Inst::jump(header)]),
+                    ),
+                ]
+            },
+        );
+        let mut function = before;
+        while hoist_loop_invariants(&mut function) {}
+        assert_eq!(function, after);
+    }
+
+    #[test]
+    fn test_ensure_preheaders() {
+        // let sum = 0; let i = 0; if 0 < n { while i < n { sum = sum + i; i = i + 1; } }
+        // The loop is entered from a branch, so it has no preheader yet
+        let sum_loop = |[n, sum, i, tmp1, lt1, add1, tmp2, tmp3, tmp4]: [usize; 9],
+                        [entry, cond, body, end]: [usize; 4],
+                        loop_entry: usize| {
+            vec![
+                (
+                    entry,
+                    BasicBlock::new(vec![
+                        Inst::literal(sum, 0),
+                        Inst::literal(i, 0),
+                        Inst::builtin(lt1, BuiltinKind::Lt),
+                        Inst::push_arg(sum),
+                        Inst::push_arg(n),
+                        Inst::call(tmp1, lt1),
+                        Inst::branch(tmp1, loop_entry, end),
+                    ]),
+                ),
+                (
+                    cond,
+                    BasicBlock::new(vec![
+                        Inst::builtin(lt1, BuiltinKind::Lt),
+                        Inst::push_arg(i),
+                        Inst::push_arg(n),
+                        Inst::call(tmp2, lt1),
+                        Inst::branch(tmp2, body, end),
+                    ]),
+                ),
+                (
+                    body,
+                    BasicBlock::new(vec![
+                        Inst::builtin(add1, BuiltinKind::Add),
+                        Inst::push_arg(sum),
+                        Inst::push_arg(i),
+                        Inst::call(sum, add1),
+                        Inst::literal(tmp3, 1),
+                        Inst::push_arg(i),
+                        Inst::push_arg(tmp3),
+                        Inst::call(i, add1),
+                        Inst::jump(cond),
+                    ]),
+                ),
+                (
+                    end,
+                    BasicBlock::new(vec![Inst::literal(tmp4, ()), Inst::return_(tmp4)]),
+                ),
+            ]
+        };
+        let before = Function::describe(1, |vars, [entry, cond, body, end]| {
+            sum_loop(vars, [entry, cond, body, end], cond)
+        });
+        let after = Function::describe(1, |vars, [entry, cond, body, end, preheader]| {
+            let mut blocks = sum_loop(vars, [entry, cond, body, end], preheader);
+            blocks.push((preheader, BasicBlock::new(vec![Inst::jump(cond)])));
+            blocks
+        });
+        let mut function = before;
+        function.ensure_preheaders();
+        assert_eq!(function, after);
+        // The new block is recognized as the preheader
+        function.ensure_preheaders();
+        assert_eq!(function, after);
+    }
+
+    #[test]
+    fn test_verify_liveness() {
+        let cctx = CCtx::new();
+        let program_unit = compile(&cctx, &fib_program());
+        for function in &program_unit.functions {
+            assert_eq!(function.verify_liveness(), Ok(()));
+        }
+
+        let mut function = program_unit.functions[1].clone();
+        let live_out = function.body[1].insts[0].live_out.as_mut().unwrap();
+        live_out.insert(function.num_vars - 1);
+        let e = function.verify_liveness().unwrap_err();
+        assert!(e.starts_with("bb1[0]: live_out is "), "{}", e);
+
+        // Missing liveness is a divergence too
+        let mut function = program_unit.functions[1].clone();
+        function.body[2].live_in = None;
+        let e = function.verify_liveness().unwrap_err();
+        assert!(e.starts_with("bb2: live_in is None"), "{}", e);
+    }
+
+    #[test]
+    fn test_eliminate_common_builtin_calls() {
+        // puti((1 + 2) + (1 + 2))
+        let mut function = Function::simple(
+            0,
+            |[add1, a1, b1, x, add2, a2, b2, y, add3, z, puti1, tmp1, tmp2]| {
+                BasicBlock::new(vec![
+                    Inst::builtin(add1, BuiltinKind::Add),
+                    Inst::literal(a1, 1),
+                    Inst::literal(b1, 2),
+                    Inst::push_arg(a1),
+                    Inst::push_arg(b1),
+                    Inst::call(x, add1),
+                    Inst::builtin(add2, BuiltinKind::Add),
+                    Inst::literal(a2, 1),
+                    Inst::literal(b2, 2),
+                    Inst::push_arg(a2),
+                    Inst::push_arg(b2),
+                    Inst::call(y, add2),
+                    Inst::builtin(add3, BuiltinKind::Add),
+                    Inst::push_arg(x),
+                    Inst::push_arg(y),
+                    Inst::call(z, add3),
+                    Inst::builtin(puti1, BuiltinKind::Puti),
+                    Inst::push_arg(z),
+                    Inst::call(tmp2, puti1),
+                    Inst::literal(tmp1, ()),
+                    Inst::return_(tmp1),
+                ])
+            },
+        );
+        eliminate_common_builtin_calls(&mut function);
+        assert_eq!(
+            function,
+            Function::simple(
+                0,
+                |[add1, a1, b1, x, add2, a2, b2, y, add3, z, puti1, tmp1, tmp2]| {
+                    BasicBlock::new(vec![
+                        Inst::builtin(add1, BuiltinKind::Add),
+                        Inst::literal(a1, 1),
+                        Inst::literal(b1, 2),
+                        Inst::push_arg(a1),
+                        Inst::push_arg(b1),
+                        Inst::call(x, add1),
+                        Inst::builtin(add2, BuiltinKind::Add),
+                        Inst::literal(a2, 1),
+                        Inst::literal(b2, 2),
+                        Inst::copy(y, x),
+                        Inst::builtin(add3, BuiltinKind::Add),
+                        Inst::push_arg(x),
+                        Inst::push_arg(y),
+                        Inst::call(z, add3),
+                        Inst::builtin(puti1, BuiltinKind::Puti),
+                        Inst::push_arg(z),
+                        Inst::call(tmp2, puti1),
+                        Inst::literal(tmp1, ()),
+                        Inst::return_(tmp1),
+                    ])
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_eliminate_common_builtin_calls_after_write() {
+        // x + 1; x = 5; x + 1; puti(1); puti(1)
+        let mut function = Function::simple(0, |[x, add1, one, y, puti1, tmp1, tmp2]| {
+            BasicBlock::new(vec![
+                Inst::literal(x, 3),
+                Inst::builtin(add1, BuiltinKind::Add),
+                Inst::literal(one, 1),
+                Inst::push_arg(x),
+                Inst::push_arg(one),
+                Inst::call(y, add1),
+                Inst::copy(x, y),
+                Inst::builtin(add1, BuiltinKind::Add),
+                Inst::push_arg(x),
+                Inst::push_arg(one),
+                Inst::call(y, add1),
+                Inst::builtin(puti1, BuiltinKind::Puti),
+                Inst::push_arg(one),
+                Inst::call(tmp2, puti1),
+                Inst::builtin(puti1, BuiltinKind::Puti),
+                Inst::push_arg(one),
+                Inst::call(tmp2, puti1),
+                Inst::literal(tmp1, ()),
+                Inst::return_(tmp1),
+            ])
+        });
+        let expected = function.clone();
+        eliminate_common_builtin_calls(&mut function);
+        assert_eq!(function, expected);
+    }
+
+    #[test]
+    fn test_compile_liveness_report() {
+        let cctx = CCtx::new();
+        let program_unit = ProgramUnit::simple(Function::simple(0, |[x, puts1, tmp1, tmp2]| {
+            BasicBlock::new(vec![
+                Inst::literal(x, "Hello, world!"),
+                Inst::builtin(puts1, BuiltinKind::Puts),
+                Inst::push_arg(x),
+                Inst::call(tmp2, puts1),
+                Inst::builtin(puts1, BuiltinKind::Puts),
+                Inst::push_arg(x),
+                Inst::call(tmp2, puts1),
+                Inst::literal(tmp1, ()),
+                Inst::return_(tmp1),
+            ])
+        }));
+        let program_unit = compile(&cctx, &program_unit);
+        assert_eq!(
+            program_unit.functions[0].liveness_report(),
+            concat!(
+                "bb0: live_in = {}\n",
+                "    Inst::literal(v0, \"Hello, world!\")  // live_out = {v0}\n",
+                "    Inst::builtin(v1, Puts)  // live_out = {v0, v1}\n",
+                "    Inst::push_arg_copy(v0)  // live_out = {v0, v1}\n",
+                "    Inst::call(v3, v1)  // live_out = {v0, v3}\n",
+                "    Inst::drop(v3)  // live_out = {v0}\n",
+                "    Inst::builtin(v1, Puts)  // live_out = {v0, v1}\n",
+                "    Inst::push_arg(v0)  // live_out = {v1}\n",
+                "    Inst::call(v3, v1)  // live_out = {v3}\n",
+                "    Inst::drop(v3)  // live_out = {}\n",
+                "    Inst::literal(v2, ())  // live_out = {v2}\n",
+                "    Inst::return_(v2)  // live_out = {}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_compile_len_keeps_arg_alive() {
+        let cctx = CCtx::new();
+        let program_unit =
+            ProgramUnit::simple(Function::simple(0, |[s, len1, n, puts1, tmp1, tmp2]| {
+                BasicBlock::new(vec![
+                    Inst::literal(s, "abc"),
+                    Inst::builtin(len1, BuiltinKind::Len),
+                    Inst::push_arg(s),
+                    Inst::call(n, len1),
+                    Inst::builtin(puts1, BuiltinKind::Puti),
+                    Inst::push_arg(n),
+                    Inst::call(tmp2, puts1),
+                    Inst::builtin(puts1, BuiltinKind::Puts),
+                    Inst::push_arg(s),
+                    Inst::call(tmp2, puts1),
+                    Inst::literal(tmp1, ()),
+                    Inst::return_(tmp1),
+                ])
+            }));
+        let program_unit = compile(&cctx, &program_unit);
+        // s is still used by puts, so it is not dropped early.
+        // len only reads its argument, so no temporary copy is made either.
+        assert_eq!(
+            program_unit.functions[0].liveness_report(),
+            concat!(
+                "bb0: live_in = {}\n",
+                "    Inst::literal(v0, \"abc\")  // live_out = {v0}\n",
+                "    Inst::builtin(v1, Len)  // live_out = {v0, v1}\n",
+                "    Inst::push_arg_copy(v0)  // live_out = {v0, v1}\n",
+                "    Inst::call(v2, v1)  // live_out = {v0, v2}\n",
+                "    Inst::builtin(v3, Puti)  // live_out = {v0, v2, v3}\n",
+                "    Inst::push_arg(v2)  // live_out = {v0, v3}\n",
+                "    Inst::call(v5, v3)  // live_out = {v0, v5}\n",
+                "    Inst::drop(v5)  // live_out = {v0}\n",
+                "    Inst::builtin(v3, Puts)  // live_out = {v0, v3}\n",
+                "    Inst::push_arg(v0)  // live_out = {v3}\n",
+                "    Inst::call(v5, v3)  // live_out = {v5}\n",
+                "    Inst::drop(v5)  // live_out = {}\n",
+                "    Inst::literal(v4, ())  // live_out = {v4}\n",
+                "    Inst::return_(v4)  // live_out = {}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_compile_block_local_string_dropped_early() {
+        let cctx = CCtx::new();
+        // do { let s = "big"; if c { puts(s); } }; puti(1);
+        let program_unit = ProgramUnit::simple(Function::describe(
+            0,
+            |[s, c, puts1, tmp1, puti1, n, tmp2, tmp3], [bb0, bb1, bb2, bb3]| {
+                vec![
+                    (
+                        bb0,
+                        BasicBlock::new(vec![
+                            Inst::literal(s, "big"),
+                            Inst::literal(c, true),
+                            Inst::branch(c, bb1, bb2),
+                        ]),
+                    ),
+                    (
+                        bb1,
+                        BasicBlock::new(vec![
+                            Inst::builtin(puts1, BuiltinKind::Puts),
+                            Inst::push_arg(s),
+                            Inst::call(tmp1, puts1),
+                            Inst::jump(bb3),
+                        ]),
+                    ),
+                    (bb2, BasicBlock::new(vec![Inst::jump(bb3)])),
+                    (
+                        bb3,
+                        BasicBlock::new(vec![
+                            Inst::builtin(puti1, BuiltinKind::Puti),
+                            Inst::literal(n, 1),
+                            Inst::push_arg(n),
+                            Inst::call(tmp2, puti1),
+                            Inst::literal(tmp3, ()),
+                            Inst::return_(tmp3),
+                        ]),
+                    ),
+                ]
+            },
+        ));
+        let program_unit = compile(&cctx, &program_unit);
+        // s is consumed by puts on one path and dropped on entry to the other,
+        // so it is no longer alive once the block is left.
+        assert_eq!(
+            program_unit.functions[0].liveness_report(),
+            concat!(
+                "bb0: live_in = {}\n",
+                "    Inst::literal(v0, \"big\")  // live_out = {v0}\n",
+                "    Inst::literal(v1, true)  // live_out = {v0, v1}\n",
+                "    Inst::branch(v1, bb1, bb2)  // live_out = {v0}\n",
+                "bb1: live_in = {v0}\n",
+                "    Inst::builtin(v2, Puts)  // live_out = {v0, v2}\n",
+                "    Inst::push_arg(v0)  // live_out = {v2}\n",
+                "    Inst::call(v3, v2)  // live_out = {v3}\n",
+                "    Inst::drop(v3)  // live_out = {}\n",
+                "    Inst::jump(bb3)  // live_out = {}\n",
+                "bb2: live_in = {}\n",
+                "    Inst::drop(v0)  // live_out = {}\n",
+                "    Inst::jump(bb3)  // live_out = {}\n",
+                "bb3: live_in = {}\n",
+                "    Inst::builtin(v4, Puti)  // live_out = {v4}\n",
+                "    Inst::literal(v5, 1)  // live_out = {v4, v5}\n",
+                "    Inst::push_arg(v5)  // live_out = {v4}\n",
+                "    Inst::call(v6, v4)  // live_out = {v6}\n",
+                "    Inst::drop(v6)  // live_out = {}\n",
+                "    Inst::literal(v7, ())  // live_out = {v7}\n",
+                "    Inst::return_(v7)  // live_out = {}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_compile_unreachable() {
+        let cctx = CCtx::new();
+        let program_unit =
+            ProgramUnit::simple(Function::describe(0, |[x, cond], [bb0, bb1, bb2]| {
+                vec![
+                    (
+                        bb0,
+                        BasicBlock::new(vec![
+                            Inst::literal(x, 1),
+                            Inst::literal(cond, true),
+                            Inst::branch(cond, bb1, bb2),
+                        ]),
+                    ),
+                    (bb1, BasicBlock::new(vec![Inst::return_(x)])),
+                    (bb2, BasicBlock::new(vec![Inst::unreachable()])),
+                ]
+            }));
+        let program_unit = compile(&cctx, &program_unit);
+        // Nothing is live out of the unreachable tail, so x is dropped before it.
+        assert_eq!(
+            program_unit.functions[0].liveness_report(),
+            concat!(
+                "bb0: live_in = {}\n",
+                "    Inst::literal(v0, 1)  // live_out = {v0}\n",
+                "    Inst::literal(v1, true)  // live_out = {v0, v1}\n",
+                "    Inst::branch(v1, bb1, bb2)  // live_out = {v0}\n",
+                "bb1: live_in = {v0}\n",
+                "    Inst::return_(v0)  // live_out = {}\n",
+                "bb2: live_in = {}\n",
+                "    Inst::drop(v0)  // live_out = {}\n",
+                "    Inst::unreachable()  // live_out = {}\n",
+            )
+        );
+    }
+
     #[test]
     fn test_compile_drop() {
         let cctx = CCtx::new();
@@ -370,12 +1778,11 @@ mod tests {
             ])
         }));
         let program_unit = compile(&cctx, &program_unit);
+        // The overwritten literal is dead and removed; the unused result of puts is dropped.
         assert_eq!(
             program_unit,
             ProgramUnit::simple(Function::simple(0, |[x, puts1, tmp1, tmp2]| {
                 BasicBlock::new(vec![
-                    Inst::literal(x, "dummy").with_live_out([x].into_iter().collect()),
-                    Inst::drop(x).with_live_out([].into_iter().collect()),
                     Inst::literal(x, "Hello, world!").with_live_out([x].into_iter().collect()),
                     Inst::builtin(puts1, BuiltinKind::Puts)
                         .with_live_out([x, puts1].into_iter().collect()),
@@ -409,4 +1816,95 @@ mod tests {
             }))
         );
     }
+
+    #[test]
+    fn test_borrowed_push_args() {
+        let [s, puts1, f, tmp1, some1] = [0, 1, 2, 3, 4];
+        let insts = vec![
+            Inst::builtin(puts1, BuiltinKind::Puts),
+            Inst::push_arg(s),
+            Inst::call(tmp1, puts1),
+            // The argument becomes part of the result
+            Inst::builtin(some1, BuiltinKind::OptionSome),
+            Inst::push_arg(s),
+            Inst::call(tmp1, some1),
+            // Unknown callee; the argument may be consumed
+            Inst::push_arg(s),
+            Inst::call(tmp1, f),
+            // puts1 no longer holds the builtin
+            Inst::copy(puts1, f),
+            Inst::push_arg(s),
+            Inst::call(tmp1, puts1),
+        ];
+        assert_eq!(borrowed_push_args(&insts), [1].into_iter().collect());
+    }
+
+    #[test]
+    fn test_compile_closure_capture() {
+        let cctx = CCtx::new();
+        let program_unit = ProgramUnit::describe(|[main, lambda1]| {
+            vec![
+                (
+                    main,
+                    Function::simple(0, |[y, f, tmp1]| {
+                        BasicBlock::new(vec![
+                            Inst::literal(y, 1),
+                            Inst::push_arg(y),
+                            Inst::closure(f, lambda1),
+                            Inst::push_arg(y),
+                            Inst::call(tmp1, f),
+                            Inst::return_(tmp1),
+                        ])
+                    }),
+                ),
+                (
+                    lambda1,
+                    Function::simple(2, |[_y, x]| BasicBlock::new(vec![Inst::return_(x)])),
+                ),
+            ]
+        });
+        let program_unit = compile(&cctx, &program_unit);
+        // y is still used after the closure is built, so the capture receives a copy.
+        assert_eq!(
+            program_unit.functions[0],
+            Function::simple(0, |[y, f, tmp1, tmp2]| {
+                BasicBlock::new(vec![
+                    Inst::literal(y, 1).with_live_out([y].into_iter().collect()),
+                    Inst::copy(tmp2, y).with_live_out([y, tmp2].into_iter().collect()),
+                    Inst::push_arg(tmp2).with_live_out([y].into_iter().collect()),
+                    Inst::closure(f, 1).with_live_out([y, f].into_iter().collect()),
+                    Inst::push_arg(y).with_live_out([f].into_iter().collect()),
+                    Inst::call(tmp1, f).with_live_out([tmp1].into_iter().collect()),
+                    Inst::return_(tmp1).with_live_out([].into_iter().collect()),
+                ])
+                .with_live_in([].into_iter().collect())
+            })
+            .with_num_named_vars(3)
+        );
+    }
+
+    #[test]
+    fn test_compile_eliminate_copy() {
+        let cctx = CCtx::new();
+        // let x = 42; then x;
+        let program_unit = ProgramUnit::simple(Function::simple(0, |[x, tmp1]| {
+            BasicBlock::new(vec![
+                Inst::literal(x, 42),
+                Inst::copy(tmp1, x),
+                Inst::return_(tmp1),
+            ])
+        }));
+        let program_unit = compile(&cctx, &program_unit);
+        assert_eq!(program_unit.functions[0].body[0].insts.len(), 2);
+        assert_eq!(
+            program_unit,
+            ProgramUnit::simple(Function::simple(0, |[_x, tmp1]| {
+                BasicBlock::new(vec![
+                    Inst::literal(tmp1, 42).with_live_out([tmp1].into_iter().collect()),
+                    Inst::return_(tmp1).with_live_out([].into_iter().collect()),
+                ])
+                .with_live_in([].into_iter().collect())
+            }))
+        );
+    }
 }
@@ -0,0 +1,196 @@
+// Dominator tree of the SIR control flow graph
+
+use std::collections::BTreeMap;
+
+use bit_set::BitSet;
+
+use crate::sir::Function;
+
+impl Function {
+    /// Immediate dominator of each block.
+    ///
+    /// The entry block and unreachable blocks have none.
+    /// Computed with the iterative algorithm of Cooper, Harvey, and Kennedy.
+    pub fn dominators(&self) -> Vec<Option<usize>> {
+        let mut idom = vec![None; self.body.len()];
+        if self.body.is_empty() {
+            return idom;
+        }
+        let postorder = self.postorder();
+        let mut postorder_index = vec![usize::MAX; self.body.len()];
+        for (i, &bb_id) in postorder.iter().enumerate() {
+            postorder_index[bb_id] = i;
+        }
+        let predecessors = self.predecessors();
+
+        // The entry temporarily dominates itself so that `intersect` stops there
+        idom[0] = Some(0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &bb_id in postorder.iter().rev().filter(|&&bb_id| bb_id != 0) {
+                let mut new_idom = None;
+                for &pred in &predecessors[bb_id] {
+                    // Predecessors not processed yet, or unreachable
+                    if idom[pred].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(other) => intersect(&idom, &postorder_index, pred, other),
+                    });
+                }
+                if new_idom != idom[bb_id] {
+                    idom[bb_id] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom[0] = None;
+        idom
+    }
+
+    /// Blocks of each natural loop, keyed by its header, given the immediate dominators.
+    ///
+    /// Loops sharing a header are merged into one. Unreachable blocks belong to no loop.
+    pub fn natural_loops(&self, idom: &[Option<usize>]) -> BTreeMap<usize, BitSet<usize>> {
+        let predecessors = self.predecessors();
+        let reachable = |bb_id: usize| bb_id == 0 || idom[bb_id].is_some();
+        let mut loops = BTreeMap::<usize, BitSet<usize>>::new();
+        for (bb_id, bb) in self.body.iter().enumerate() {
+            if !reachable(bb_id) {
+                continue;
+            }
+            for header in bb.successors() {
+                if !dominates(idom, header, bb_id) {
+                    continue;
+                }
+                // A back edge: the loop has the blocks reaching it without passing the header
+                let blocks = loops
+                    .entry(header)
+                    .or_insert_with(|| [header].into_iter().collect());
+                let mut stack = vec![bb_id];
+                while let Some(member) = stack.pop() {
+                    if blocks.insert(member) {
+                        stack.extend(
+                            predecessors[member]
+                                .iter()
+                                .copied()
+                                .filter(|&p| reachable(p)),
+                        );
+                    }
+                }
+            }
+        }
+        loops
+    }
+
+    /// Blocks reachable from the entry, in depth-first postorder.
+    fn postorder(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.body.len()];
+        let mut postorder = vec![];
+        // Blocks being visited, paired with the index of the next successor to look at
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((bb_id, next)) = stack.last_mut() {
+            if let Some(&succ) = self.body[*bb_id].successors().get(*next) {
+                *next += 1;
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                postorder.push(*bb_id);
+                stack.pop();
+            }
+        }
+        postorder
+    }
+}
+
+/// Whether `dominator` dominates `bb_id`, given the immediate dominators.
+/// Every block dominates itself.
+pub fn dominates(idom: &[Option<usize>], dominator: usize, mut bb_id: usize) -> bool {
+    loop {
+        if bb_id == dominator {
+            return true;
+        }
+        match idom[bb_id] {
+            Some(parent) => bb_id = parent,
+            None => return false,
+        }
+    }
+}
+
+/// Nearest common dominator of the two blocks
+fn intersect(
+    idom: &[Option<usize>],
+    postorder_index: &[usize],
+    mut bb1: usize,
+    mut bb2: usize,
+) -> usize {
+    while bb1 != bb2 {
+        while postorder_index[bb1] < postorder_index[bb2] {
+            bb1 = idom[bb1].unwrap();
+        }
+        while postorder_index[bb2] < postorder_index[bb1] {
+            bb2 = idom[bb2].unwrap();
+        }
+    }
+    bb1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dominates;
+    use crate::sir::{BasicBlock, Function, Inst};
+    use crate::testing::fib_program;
+
+    #[test]
+    fn test_dominators_fib() {
+        let program_unit = fib_program();
+        assert_eq!(program_unit.functions[0].dominators(), vec![None]);
+        // Both arms of `if n < 2` are dominated by the entry only
+        assert_eq!(
+            program_unit.functions[1].dominators(),
+            vec![None, Some(0), Some(0)]
+        );
+    }
+
+    #[test]
+    fn test_dominators_loop_and_join() {
+        // bb0 -> bb1 -> bb2 | bb5; bb2 -> bb3 | bb4; bb3, bb4 -> bb1; bb6 is unreachable
+        let function = Function::describe(0, |[c, tmp1], [bb0, bb1, bb2, bb3, bb4, bb5, bb6]| {
+            vec![
+                (
+                    bb0,
+                    BasicBlock::new(vec![Inst::literal(c, true), Inst::jump(bb1)]),
+                ),
+                (bb1, BasicBlock::new(vec![Inst::branch(c, bb2, bb5)])),
+                (bb2, BasicBlock::new(vec![Inst::branch(c, bb3, bb4)])),
+                (bb3, BasicBlock::new(vec![Inst::jump(bb1)])),
+                (bb4, BasicBlock::new(vec![Inst::jump(bb1)])),
+                (
+                    bb5,
+                    BasicBlock::new(vec![Inst::literal(tmp1, ()), Inst::return_(tmp1)]),
+                ),
+                (bb6, BasicBlock::new(vec![Inst::jump(bb5)])),
+            ]
+        });
+        let idom = function.dominators();
+        assert_eq!(
+            idom,
+            vec![None, Some(0), Some(1), Some(2), Some(2), Some(1), None]
+        );
+        assert!(dominates(&idom, 1, 4));
+        assert!(dominates(&idom, 4, 4));
+        assert!(!dominates(&idom, 3, 4));
+        assert!(!dominates(&idom, 0, 6));
+        // Both back edges, from bb3 and bb4, go to bb1
+        let loops = function.natural_loops(&idom);
+        assert_eq!(
+            loops.into_iter().collect::<Vec<_>>(),
+            vec![(1, [1, 2, 3, 4].into_iter().collect())]
+        );
+    }
+}
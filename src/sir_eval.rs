@@ -1,25 +1,75 @@
+use std::fmt;
 use std::mem;
+use std::ops::Range;
 use std::sync::Arc;
 
+use thiserror::Error;
+
 use crate::rt_ctx::RtCtx;
 use crate::sir::{BasicBlock, BuiltinKind, Function, InstKind, Literal, ProgramUnit};
 
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RuntimeError {
+    #[error("type mismatch: expected {expected}, got {got}")]
+    TypeMismatch {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("wrong number of arguments: expected {expected}, got {got}")]
+    ArityMismatch { expected: usize, got: usize },
+    #[error("wrong number of arguments to `{builtin}`: expected {expected}, got {got}")]
+    BuiltinArityMismatch {
+        builtin: BuiltinKind,
+        expected: usize,
+        got: usize,
+    },
+    #[error("index out of bounds: the len is {len} but the index is {index}")]
+    IndexOutOfBounds { index: i64, len: usize },
+    #[error("integer overflow")]
+    IntegerOverflow,
+    #[error("division by zero")]
+    DivisionByZero,
+    #[error("reached unreachable code")]
+    ReachedUnreachable,
+    #[error("called `unwrap` on `none`")]
+    UnwrapNone,
+    #[error("panicked: {0}")]
+    Panic(String),
+    #[error("value of type {got} is not callable")]
+    NotCallable { got: &'static str },
+    #[error("invalid character code: {code}")]
+    InvalidChar { code: i64 },
+    #[error("out of memory")]
+    OutOfMemory,
+}
+
+/// Maximum length in bytes of a string built at runtime
+const MAX_STRING_LEN: usize = 1 << 30;
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct State {
     vars: Vec<Option<Value>>,
     args: Vec<Value>,
 }
 
-pub fn eval1(ctx: &dyn RtCtx, program_unit: &ProgramUnit) {
-    eval1_function(ctx, program_unit, &program_unit.functions[0], vec![]);
+/// Evaluates the program, specialized to the type of the context
+/// so that calls into a concrete one need no dynamic dispatch.
+pub fn eval1<C: RtCtx + ?Sized>(ctx: &C, program_unit: &ProgramUnit) -> Result<(), RuntimeError> {
+    eval1_function(ctx, program_unit, &program_unit.functions[0], vec![])?;
+    Ok(())
+}
+/// Same as `eval1`, but returns the value of the entry function.
+pub fn eval1_value(ctx: &dyn RtCtx, program_unit: &ProgramUnit) -> Result<Value, RuntimeError> {
+    eval1_function(ctx, program_unit, &program_unit.functions[0], vec![])
 }
-fn eval1_function(
-    ctx: &dyn RtCtx,
+fn eval1_function<C: RtCtx + ?Sized>(
+    ctx: &C,
     program_unit: &ProgramUnit,
     function: &Function,
     received_args: Vec<Value>,
-) -> Value {
+) -> Result<Value, RuntimeError> {
     assert!(function.num_args <= function.num_vars);
+    check_arity(&received_args, function.num_args)?;
     let mut state = State {
         vars: vec![None; function.num_vars],
         args: vec![],
@@ -30,9 +80,9 @@ fn eval1_function(
     let mut current_bb_id = 0;
     loop {
         let bb = &function.body[current_bb_id];
-        match eval1_bb(ctx, &mut state, program_unit, bb) {
+        match eval1_bb(ctx, &mut state, program_unit, bb)? {
             BlockResult::Return(value) => {
-                return value;
+                return Ok(value);
             }
             BlockResult::Jump(next_bb_id) => {
                 current_bb_id = next_bb_id;
@@ -45,32 +95,36 @@ enum BlockResult {
     Return(Value),
     Jump(usize),
 }
-fn eval1_bb(
-    ctx: &dyn RtCtx,
+fn eval1_bb<C: RtCtx + ?Sized>(
+    ctx: &C,
     state: &mut State,
     program_unit: &ProgramUnit,
     bb: &BasicBlock,
-) -> BlockResult {
+) -> Result<BlockResult, RuntimeError> {
     for inst in &bb.insts {
         match &inst.kind {
             InstKind::Jump { target } => {
-                return BlockResult::Jump(*target);
+                return Ok(BlockResult::Jump(*target));
             }
             InstKind::Branch {
                 cond,
                 branch_then,
                 branch_else,
             } => {
-                let cond = state.vars[*cond].as_ref().unwrap();
-                let cond = if let Value::Integer(i) = cond {
-                    *i != 0
+                let cond = expect_bool(state.vars[*cond].as_ref().unwrap())?;
+                return Ok(BlockResult::Jump(if cond {
+                    *branch_then
                 } else {
-                    panic!("Expected integer");
-                };
-                return BlockResult::Jump(if cond { *branch_then } else { *branch_else });
+                    *branch_else
+                }));
             }
             InstKind::Return { rhs } => {
-                return BlockResult::Return(state.vars[*rhs].as_ref().unwrap().clone());
+                return Ok(BlockResult::Return(
+                    state.vars[*rhs].as_ref().unwrap().clone(),
+                ));
+            }
+            InstKind::Unreachable => {
+                return Err(RuntimeError::ReachedUnreachable);
             }
             InstKind::Copy { lhs, rhs } => {
                 state.vars[*lhs] = Some(state.vars[*rhs].as_ref().unwrap().clone());
@@ -82,101 +136,978 @@ fn eval1_bb(
                 state.vars[*lhs] = Some(Value::from(value.clone()));
             }
             InstKind::Closure { lhs, function_id } => {
+                let captured_vars = mem::take(&mut state.args);
                 state.vars[*lhs] = Some(Value::Closure {
                     function_id: *function_id,
+                    captured_vars: captured_vars.into(),
                 });
             }
             InstKind::Builtin { lhs, builtin } => {
                 state.vars[*lhs] = Some(Value::Builtin(*builtin));
             }
+            InstKind::Array { lhs } => {
+                let elems = mem::take(&mut state.args);
+                state.vars[*lhs] = Some(Value::Array(elems.into()));
+            }
             InstKind::PushArg { value_ref } => {
                 let value = state.vars[*value_ref].take().unwrap();
                 state.args.push(value);
             }
+            InstKind::PushArgCopy { value_ref } => {
+                let value = state.vars[*value_ref].as_ref().unwrap().clone();
+                state.args.push(value);
+            }
             InstKind::Call {
                 lhs,
                 callee: callee_rhs,
             } => {
                 let callee = state.vars[*callee_rhs].take().unwrap();
                 let args = mem::replace(&mut state.args, vec![]);
-                let return_value = match callee {
-                    Value::Closure { function_id } => eval1_function(
-                        ctx,
-                        program_unit,
-                        &program_unit.functions[function_id],
-                        args,
-                    ),
-                    Value::Builtin(f) => eval_builtin(ctx, f, args),
-                    _ => panic!("Expected closure or builtin"),
-                };
+                let return_value = call_value(ctx, program_unit, callee, args)?;
                 state.vars[*lhs] = Some(return_value);
             }
+            InstKind::TryCall {
+                lhs,
+                callee: callee_rhs,
+            } => {
+                let callee = state.vars[*callee_rhs].take().unwrap();
+                let args = mem::take(&mut state.args);
+                let return_value = call_value(ctx, program_unit, callee, args).ok();
+                state.vars[*lhs] = Some(Value::Option(return_value.map(Arc::new)));
+            }
         }
     }
     unreachable!("Missing tail instruction");
 }
 
-fn eval_builtin(ctx: &dyn RtCtx, f: BuiltinKind, args: Vec<Value>) -> Value {
+fn call_value<C: RtCtx + ?Sized>(
+    ctx: &C,
+    program_unit: &ProgramUnit,
+    callee: Value,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    match callee {
+        Value::Closure {
+            function_id,
+            captured_vars,
+        } => {
+            // Captured variables come first, followed by the actual arguments
+            let received_args = captured_vars.iter().cloned().chain(args).collect();
+            eval1_function(
+                ctx,
+                program_unit,
+                &program_unit.functions[function_id],
+                received_args,
+            )
+        }
+        Value::Builtin(f) => eval_builtin(ctx, f, args).map_err(|e| match e {
+            // Name the builtin, as its call site is no longer known here
+            RuntimeError::ArityMismatch { expected, got } => RuntimeError::BuiltinArityMismatch {
+                builtin: f,
+                expected,
+                got,
+            },
+            e => e,
+        }),
+        _ => Err(RuntimeError::NotCallable {
+            got: callee.type_name(),
+        }),
+    }
+}
+
+fn eval_builtin<C: RtCtx + ?Sized>(
+    ctx: &C,
+    f: BuiltinKind,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
     match f {
         BuiltinKind::Add => {
-            assert_eq!(args.len(), 2);
-            let Value::Integer(i) = &args[0]  else {
-                panic!("Expected integer");
-            };
-            let Value::Integer(j) = &args[1] else {
-                panic!("Expected integer");
-            };
-            Value::Integer(i + j)
+            check_arity(&args, 2)?;
+            let i = expect_integer(&args[0])?;
+            let j = expect_integer(&args[1])?;
+            i.checked_add(j)
+                .map(Value::Integer)
+                .ok_or(RuntimeError::IntegerOverflow)
+        }
+        BuiltinKind::Sub => {
+            check_arity(&args, 2)?;
+            let i = expect_integer(&args[0])?;
+            let j = expect_integer(&args[1])?;
+            i.checked_sub(j)
+                .map(Value::Integer)
+                .ok_or(RuntimeError::IntegerOverflow)
+        }
+        BuiltinKind::Mul => {
+            check_arity(&args, 2)?;
+            let i = expect_integer(&args[0])?;
+            let j = expect_integer(&args[1])?;
+            i.checked_mul(j)
+                .map(Value::Integer)
+                .ok_or(RuntimeError::IntegerOverflow)
+        }
+        BuiltinKind::Div => {
+            check_arity(&args, 2)?;
+            let i = expect_integer(&args[0])?;
+            let j = expect_integer(&args[1])?;
+            if j == 0 {
+                return Err(RuntimeError::DivisionByZero);
+            }
+            // Truncates toward zero; only `i64::MIN / -1` overflows
+            i.checked_div(j)
+                .map(Value::Integer)
+                .ok_or(RuntimeError::IntegerOverflow)
         }
         BuiltinKind::Lt => {
-            assert_eq!(args.len(), 2);
-            let Value::Integer(i) = &args[0]  else {
-                panic!("Expected integer");
-            };
-            let Value::Integer(j) = &args[1] else {
-                panic!("Expected integer");
-            };
-            Value::Integer((i < j) as i32)
+            check_arity(&args, 2)?;
+            let i = expect_integer(&args[0])?;
+            let j = expect_integer(&args[1])?;
+            Ok(Value::Bool(i < j))
+        }
+        BuiltinKind::Gt => {
+            check_arity(&args, 2)?;
+            let i = expect_integer(&args[0])?;
+            let j = expect_integer(&args[1])?;
+            Ok(Value::Bool(i > j))
+        }
+        BuiltinKind::Le => {
+            check_arity(&args, 2)?;
+            let i = expect_integer(&args[0])?;
+            let j = expect_integer(&args[1])?;
+            Ok(Value::Bool(i <= j))
+        }
+        BuiltinKind::Ge => {
+            check_arity(&args, 2)?;
+            let i = expect_integer(&args[0])?;
+            let j = expect_integer(&args[1])?;
+            Ok(Value::Bool(i >= j))
+        }
+        BuiltinKind::Eq => {
+            check_arity(&args, 2)?;
+            let i = expect_integer(&args[0])?;
+            let j = expect_integer(&args[1])?;
+            Ok(Value::Bool(i == j))
+        }
+        BuiltinKind::Ne => {
+            check_arity(&args, 2)?;
+            let i = expect_integer(&args[0])?;
+            let j = expect_integer(&args[1])?;
+            Ok(Value::Bool(i != j))
         }
         BuiltinKind::Puts => {
-            assert_eq!(args.len(), 1);
-            if let Value::String(s) = &args[0] {
-                ctx.puts(s);
-            } else {
-                panic!("Expected string");
-            }
-            Value::Integer(0)
+            check_arity(&args, 1)?;
+            let s = expect_string(&args[0])?;
+            ctx.puts(s);
+            Ok(Value::Unit)
         }
         BuiltinKind::Puti => {
-            assert_eq!(args.len(), 1);
-            if let Value::Integer(i) = &args[0] {
-                ctx.puts(&i.to_string());
-            } else {
-                panic!("Expected integer");
+            check_arity(&args, 1)?;
+            let i = expect_integer(&args[0])?;
+            ctx.puts(&i.to_string());
+            Ok(Value::Unit)
+        }
+        BuiltinKind::StrEq => {
+            check_arity(&args, 2)?;
+            let s = expect_string(&args[0])?;
+            let t = expect_string(&args[1])?;
+            Ok(Value::Bool(s == t))
+        }
+        BuiltinKind::Panic => {
+            check_arity(&args, 1)?;
+            let s = expect_string(&args[0])?;
+            Err(RuntimeError::Panic((**s).clone()))
+        }
+        BuiltinKind::PanicWithInt => {
+            check_arity(&args, 2)?;
+            let s = expect_string(&args[0])?;
+            let n = expect_integer(&args[1])?;
+            Err(RuntimeError::Panic(format!("{}{}", s, n)))
+        }
+        BuiltinKind::Gets => {
+            check_arity(&args, 0)?;
+            let line = ctx
+                .gets()
+                .map(|line| Arc::new(Value::String(Arc::new(line))));
+            Ok(Value::Option(line))
+        }
+        BuiltinKind::OptionNone => {
+            check_arity(&args, 0)?;
+            Ok(Value::Option(None))
+        }
+        BuiltinKind::OptionSome => {
+            check_arity(&args, 1)?;
+            let value = args.into_iter().next().unwrap();
+            Ok(Value::Option(Some(Arc::new(value))))
+        }
+        BuiltinKind::IsSome => {
+            check_arity(&args, 1)?;
+            let value = expect_option(&args[0])?;
+            Ok(Value::Bool(value.is_some()))
+        }
+        BuiltinKind::Unwrap => {
+            check_arity(&args, 1)?;
+            let value = expect_option(&args[0])?;
+            value.as_deref().cloned().ok_or(RuntimeError::UnwrapNone)
+        }
+        BuiltinKind::CharAt => {
+            check_arity(&args, 2)?;
+            let s = expect_string(&args[0])?;
+            let index = expect_integer(&args[1])?;
+            usize::try_from(index)
+                .ok()
+                .and_then(|i| s.chars().nth(i))
+                .map(|ch| Value::Integer(ch as i64))
+                .ok_or_else(|| RuntimeError::IndexOutOfBounds {
+                    index,
+                    len: s.chars().count(),
+                })
+        }
+        BuiltinKind::TermWidth => {
+            check_arity(&args, 0)?;
+            // 0 stands for an unknown width
+            Ok(Value::Integer(
+                ctx.term_width().map_or(0, |width| width as i64),
+            ))
+        }
+        BuiltinKind::Putc => {
+            check_arity(&args, 1)?;
+            let code = expect_integer(&args[0])?;
+            let ch = u32::try_from(code)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or(RuntimeError::InvalidChar { code })?;
+            ctx.putc(ch);
+            Ok(Value::Unit)
+        }
+        BuiltinKind::Repeat => {
+            check_arity(&args, 2)?;
+            let s = expect_string(&args[0])?;
+            let count = expect_integer(&args[1])?;
+            // Negative counts repeat nothing
+            let count = usize::try_from(count).unwrap_or(0);
+            match s.len().checked_mul(count) {
+                Some(len) if len <= MAX_STRING_LEN => Ok(Value::String(Arc::new(s.repeat(count)))),
+                _ => Err(RuntimeError::OutOfMemory),
             }
-            Value::Integer(0)
         }
+        BuiltinKind::Args => {
+            check_arity(&args, 0)?;
+            let args = ctx
+                .args()
+                .into_iter()
+                .map(|arg| Value::String(Arc::new(arg)))
+                .collect::<Vec<_>>();
+            Ok(Value::Array(args.into()))
+        }
+        BuiltinKind::Index => {
+            check_arity(&args, 2)?;
+            let elems = expect_array(&args[0])?;
+            let index = expect_integer(&args[1])?;
+            usize::try_from(index)
+                .ok()
+                .and_then(|i| elems.get(i))
+                .cloned()
+                .ok_or(RuntimeError::IndexOutOfBounds {
+                    index,
+                    len: elems.len(),
+                })
+        }
+        BuiltinKind::Slice => {
+            check_arity(&args, 3)?;
+            let start = expect_integer(&args[1])?;
+            let end = expect_integer(&args[2])?;
+            match &args[0] {
+                Value::Array(elems) => {
+                    let range = check_range(start, end, elems.len())?;
+                    Ok(Value::Array(elems[range].into()))
+                }
+                Value::String(s) => {
+                    // Strings are indexed by chars, not bytes
+                    let range = check_range(start, end, s.chars().count())?;
+                    let sub = s.chars().skip(range.start).take(range.len()).collect();
+                    Ok(Value::String(Arc::new(sub)))
+                }
+                value => Err(RuntimeError::TypeMismatch {
+                    expected: "array or string",
+                    got: value.type_name(),
+                }),
+            }
+        }
+        BuiltinKind::Len => {
+            check_arity(&args, 1)?;
+            // Strings are measured in chars, just as they are sliced
+            let len = match &args[0] {
+                Value::Array(elems) => elems.len(),
+                Value::String(s) => s.chars().count(),
+                value => {
+                    return Err(RuntimeError::TypeMismatch {
+                        expected: "array or string",
+                        got: value.type_name(),
+                    })
+                }
+            };
+            Ok(Value::Integer(len as i64))
+        }
+    }
+}
+
+fn check_range(start: i64, end: i64, len: usize) -> Result<Range<usize>, RuntimeError> {
+    let to_bound = |index: i64| {
+        usize::try_from(index)
+            .ok()
+            .filter(|&i| i <= len)
+            .ok_or(RuntimeError::IndexOutOfBounds { index, len })
+    };
+    let start_bound = to_bound(start)?;
+    let end_bound = to_bound(end)?;
+    if start_bound > end_bound {
+        return Err(RuntimeError::IndexOutOfBounds { index: start, len });
+    }
+    Ok(start_bound..end_bound)
+}
+
+fn check_arity(args: &[Value], expected: usize) -> Result<(), RuntimeError> {
+    if args.len() != expected {
+        return Err(RuntimeError::ArityMismatch {
+            expected,
+            got: args.len(),
+        });
+    }
+    Ok(())
+}
+
+fn expect_integer(value: &Value) -> Result<i64, RuntimeError> {
+    if let Value::Integer(i) = value {
+        Ok(*i)
+    } else {
+        Err(RuntimeError::TypeMismatch {
+            expected: "integer",
+            got: value.type_name(),
+        })
+    }
+}
+
+fn expect_bool(value: &Value) -> Result<bool, RuntimeError> {
+    if let Value::Bool(b) = value {
+        Ok(*b)
+    } else {
+        Err(RuntimeError::TypeMismatch {
+            expected: "bool",
+            got: value.type_name(),
+        })
+    }
+}
+
+fn expect_array(value: &Value) -> Result<&Arc<[Value]>, RuntimeError> {
+    if let Value::Array(elems) = value {
+        Ok(elems)
+    } else {
+        Err(RuntimeError::TypeMismatch {
+            expected: "array",
+            got: value.type_name(),
+        })
+    }
+}
+
+fn expect_option(value: &Value) -> Result<&Option<Arc<Value>>, RuntimeError> {
+    if let Value::Option(value) = value {
+        Ok(value)
+    } else {
+        Err(RuntimeError::TypeMismatch {
+            expected: "option",
+            got: value.type_name(),
+        })
     }
 }
 
+fn expect_string(value: &Value) -> Result<&Arc<String>, RuntimeError> {
+    if let Value::String(s) = value {
+        Ok(s)
+    } else {
+        Err(RuntimeError::TypeMismatch {
+            expected: "string",
+            got: value.type_name(),
+        })
+    }
+}
+
+// Compound values are shared via `Arc` so that cloning a `Value` is always O(1).
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum Value {
+pub enum Value {
+    Unit,
     String(Arc<String>),
-    Integer(i32),
+    Integer(i64),
+    Bool(bool),
     Builtin(BuiltinKind),
     Closure {
         function_id: usize,
-        // captured_vars: Vec<Value>,
+        captured_vars: Arc<[Value]>,
     },
+    Array(Arc<[Value]>),
+    Option(Option<Arc<Value>>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Unit => "unit",
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Bool(_) => "bool",
+            Value::Builtin(_) => "builtin",
+            Value::Closure { .. } => "closure",
+            Value::Array(_) => "array",
+            Value::Option(_) => "option",
+        }
+    }
+}
+
+/// User-facing rendering, as the REPL echoes results.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Unit => write!(f, "()"),
+            Value::String(s) => write!(f, "{:?}", s.as_str()),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Builtin(kind) => write!(f, "<builtin {}>", kind),
+            Value::Closure { .. } => write!(f, "<closure>"),
+            Value::Array(elems) => {
+                write!(f, "[")?;
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", elem)?;
+                }
+                write!(f, "]")
+            }
+            Value::Option(None) => write!(f, "none"),
+            Value::Option(Some(value)) => write!(f, "some({})", value),
+        }
+    }
 }
 
 impl From<Literal> for Value {
     fn from(l: Literal) -> Self {
         match l {
-            Literal::Unit => Value::Integer(0),
+            Literal::Unit => Value::Unit,
             Literal::String(s) => Value::String(s),
-            Literal::Integer(i) => Value::Integer(i),
-            Literal::Bool(b) => Value::Integer(b as i32),
+            Literal::Integer(i) => Value::Integer(i64::from(i)),
+            Literal::Bool(b) => Value::Bool(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cctx::CCtx;
+    use crate::sir::Inst;
+    use crate::sir_compile::compile;
+    use crate::sir_typecheck::typecheck;
+    use crate::testing::MockRtCtx;
+
+    #[test]
+    fn test_value_display() {
+        assert_eq!(Value::Integer(42).to_string(), "42");
+        assert_eq!(
+            Value::String(Arc::new("a\"b".to_owned())).to_string(),
+            r#""a\"b""#
+        );
+        assert_eq!(Value::Bool(true).to_string(), "true");
+        assert_eq!(Value::Unit.to_string(), "()");
+        assert_eq!(
+            Value::Builtin(BuiltinKind::Puts).to_string(),
+            "<builtin puts>"
+        );
+        let array = Value::Array(Arc::from(vec![
+            Value::Integer(1),
+            Value::Array(Arc::from(vec![Value::String(Arc::new("x".to_owned()))])),
+        ]));
+        assert_eq!(array.to_string(), r#"[1, ["x"]]"#);
+        assert_eq!(
+            Value::Option(Some(Arc::new(Value::Integer(1)))).to_string(),
+            "some(1)"
+        );
+        assert_eq!(Value::Option(None).to_string(), "none");
+    }
+
+    #[test]
+    fn test_eval_builtin_type_mismatch() {
+        let cctx = CCtx::new();
+        // Ill-typed: puts receives an integer. Typecheck is skipped here on purpose.
+        let program_unit = ProgramUnit::simple(Function::simple(0, |[x, tmp1, puts1, tmp2]| {
+            BasicBlock::new(vec![
+                Inst::literal(x, 42),
+                Inst::builtin(puts1, BuiltinKind::Puts),
+                Inst::push_arg(x),
+                Inst::call(tmp2, puts1),
+                Inst::literal(tmp1, ()),
+                Inst::return_(tmp1),
+            ])
+        }));
+        let program_unit = compile(&cctx, &program_unit);
+        let ctx = MockRtCtx::new();
+        assert_eq!(
+            eval1(&ctx, &program_unit),
+            Err(RuntimeError::TypeMismatch {
+                expected: "string",
+                got: "integer",
+            })
+        );
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "");
+    }
+
+    #[test]
+    fn test_eval_call_non_function() {
+        let cctx = CCtx::new();
+        // Ill-typed: calls an integer. Typecheck is skipped here on purpose.
+        let program_unit = ProgramUnit::simple(Function::simple(0, |[x, tmp1, tmp2, puti1]| {
+            BasicBlock::new(vec![
+                Inst::literal(x, 42),
+                Inst::call(tmp2, x),
+                Inst::builtin(puti1, BuiltinKind::Puti),
+                Inst::push_arg(tmp2),
+                Inst::call(tmp1, puti1),
+                Inst::return_(tmp1),
+            ])
+        }));
+        let program_unit = compile(&cctx, &program_unit);
+        let ctx = MockRtCtx::new();
+        assert_eq!(
+            eval1(&ctx, &program_unit),
+            Err(RuntimeError::NotCallable { got: "integer" })
+        );
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "");
+    }
+
+    #[test]
+    fn test_eval_builtin_arity_mismatch() {
+        let cctx = CCtx::new();
+        let program_unit = ProgramUnit::simple(Function::simple(0, |[tmp1, puti1, tmp2]| {
+            BasicBlock::new(vec![
+                Inst::builtin(puti1, BuiltinKind::Puti),
+                Inst::call(tmp2, puti1),
+                Inst::literal(tmp1, ()),
+                Inst::return_(tmp1),
+            ])
+        }));
+        let program_unit = compile(&cctx, &program_unit);
+        let ctx = MockRtCtx::new();
+        let result = eval1(&ctx, &program_unit);
+        assert_eq!(
+            result,
+            Err(RuntimeError::BuiltinArityMismatch {
+                builtin: BuiltinKind::Puti,
+                expected: 1,
+                got: 0,
+            })
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "wrong number of arguments to `puti`: expected 1, got 0"
+        );
+    }
+
+    #[test]
+    fn test_eval_closure_arity_mismatch() {
+        let cctx = CCtx::new();
+        let program_unit = ProgramUnit::describe(|[main, lambda1]| {
+            vec![
+                (
+                    main,
+                    Function::simple(0, |[f, tmp1]| {
+                        BasicBlock::new(vec![
+                            Inst::closure(f, lambda1),
+                            Inst::call(tmp1, f),
+                            Inst::return_(tmp1),
+                        ])
+                    }),
+                ),
+                (
+                    lambda1,
+                    Function::simple(1, |[x]| BasicBlock::new(vec![Inst::return_(x)])),
+                ),
+            ]
+        });
+        let program_unit = compile(&cctx, &program_unit);
+        let ctx = MockRtCtx::new();
+        assert_eq!(
+            eval1(&ctx, &program_unit),
+            Err(RuntimeError::ArityMismatch {
+                expected: 1,
+                got: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_eval_call_dispatches_on_closure_value() {
+        let cctx = CCtx::new();
+        // The callee is var 3 while the closure refers to function 2
+        let program_unit = ProgramUnit::describe(|[main, lambda1, lambda2]| {
+            vec![
+                (
+                    main,
+                    Function::simple(0, |[tmp1, puti1, tmp2, f, tmp3]| {
+                        BasicBlock::new(vec![
+                            Inst::closure(f, lambda2),
+                            Inst::call(tmp2, f),
+                            Inst::builtin(puti1, BuiltinKind::Puti),
+                            Inst::push_arg(tmp2),
+                            Inst::call(tmp3, puti1),
+                            Inst::literal(tmp1, ()),
+                            Inst::return_(tmp1),
+                        ])
+                    }),
+                ),
+                (
+                    lambda1,
+                    Function::simple(0, |[x]| {
+                        BasicBlock::new(vec![Inst::literal(x, 1), Inst::return_(x)])
+                    }),
+                ),
+                (
+                    lambda2,
+                    Function::simple(0, |[x]| {
+                        BasicBlock::new(vec![Inst::literal(x, 2), Inst::return_(x)])
+                    }),
+                ),
+            ]
+        });
+        let program_unit = compile(&cctx, &program_unit);
+        let ctx = MockRtCtx::new();
+        eval1(&ctx, &program_unit).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "2\n");
+    }
+
+    #[test]
+    fn test_eval_generic_same_output() {
+        // let i = 0; while i < 1000 { puti(i); i = i + 1; }
+        let program_unit = ProgramUnit::simple(Function::describe(
+            0,
+            |[i, n, one, lt1, c, puti1, tmp1, add1, tmp2], [entry, cond, body, end]| {
+                vec![
+                    (
+                        entry,
+                        BasicBlock::new(vec![
+                            Inst::literal(i, 0),
+                            Inst::literal(n, 1000),
+                            Inst::literal(one, 1),
+                            Inst::jump(cond),
+                        ]),
+                    ),
+                    (
+                        cond,
+                        BasicBlock::new(vec![
+                            Inst::builtin(lt1, BuiltinKind::Lt),
+                            Inst::push_arg(i),
+                            Inst::push_arg(n),
+                            Inst::call(c, lt1),
+                            Inst::branch(c, body, end),
+                        ]),
+                    ),
+                    (
+                        body,
+                        BasicBlock::new(vec![
+                            Inst::builtin(puti1, BuiltinKind::Puti),
+                            Inst::push_arg(i),
+                            Inst::call(tmp1, puti1),
+                            Inst::builtin(add1, BuiltinKind::Add),
+                            Inst::push_arg(i),
+                            Inst::push_arg(one),
+                            Inst::call(i, add1),
+                            Inst::jump(cond),
+                        ]),
+                    ),
+                    (
+                        end,
+                        BasicBlock::new(vec![Inst::literal(tmp2, ()), Inst::return_(tmp2)]),
+                    ),
+                ]
+            },
+        ));
+        let program_unit = compile(&CCtx::new(), &program_unit);
+        let dyn_ctx = MockRtCtx::new();
+        eval1(&dyn_ctx as &dyn RtCtx, &program_unit).unwrap();
+        let generic_ctx = MockRtCtx::new();
+        eval1(&generic_ctx, &program_unit).unwrap();
+        let expected = (0..1000).map(|i| format!("{}\n", i)).collect::<String>();
+        assert_eq!(dyn_ctx.stdout.lock().unwrap().as_str(), expected);
+        assert_eq!(generic_ctx.stdout.lock().unwrap().as_str(), expected);
+    }
+
+    #[test]
+    fn test_eval_builtin_term_width() {
+        let ctx = MockRtCtx::new();
+        let width = eval_builtin(&ctx, BuiltinKind::TermWidth, vec![]).unwrap();
+        assert_eq!(width, Value::Integer(0));
+        let ctx = MockRtCtx::new().with_term_width(100);
+        let width = eval_builtin(&ctx, BuiltinKind::TermWidth, vec![]).unwrap();
+        assert_eq!(width, Value::Integer(100));
+    }
+
+    #[test]
+    fn test_eval_builtin_putc() {
+        let ctx = MockRtCtx::new();
+        eval_builtin(&ctx, BuiltinKind::Putc, vec![Value::Integer(65)]).unwrap();
+        eval_builtin(&ctx, BuiltinKind::Putc, vec![Value::Integer(0x3042)]).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "A\u{3042}");
+        for code in [-1, 0xD800, 0x110000] {
+            assert_eq!(
+                eval_builtin(&ctx, BuiltinKind::Putc, vec![Value::Integer(code)]),
+                Err(RuntimeError::InvalidChar { code })
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_builtin_repeat() {
+        let ctx = MockRtCtx::new();
+        let repeat = |s: &str, count: i64| {
+            eval_builtin(
+                &ctx,
+                BuiltinKind::Repeat,
+                vec![Value::String(Arc::new(s.to_owned())), Value::Integer(count)],
+            )
+        };
+        assert_eq!(
+            repeat("ab", 3),
+            Ok(Value::String(Arc::new("ababab".to_owned())))
+        );
+        assert_eq!(repeat("x", 0), Ok(Value::String(Arc::new(String::new()))));
+        assert_eq!(repeat("x", -1), Ok(Value::String(Arc::new(String::new()))));
+        assert_eq!(
+            repeat("", i64::MAX),
+            Ok(Value::String(Arc::new(String::new())))
+        );
+        assert_eq!(repeat("ab", i64::MAX), Err(RuntimeError::OutOfMemory));
+    }
+
+    #[test]
+    fn test_eval_builtin_add_beyond_i32() {
+        let ctx = MockRtCtx::new();
+        let sum = eval_builtin(
+            &ctx,
+            BuiltinKind::Add,
+            vec![Value::Integer(2000000000), Value::Integer(2000000000)],
+        )
+        .unwrap();
+        assert_eq!(sum, Value::Integer(4000000000));
+        eval_builtin(&ctx, BuiltinKind::Puti, vec![sum]).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "4000000000\n");
+    }
+
+    #[test]
+    fn test_eval_builtin_add_overflow() {
+        let ctx = MockRtCtx::new();
+        assert_eq!(
+            eval_builtin(
+                &ctx,
+                BuiltinKind::Add,
+                vec![Value::Integer(i64::MAX), Value::Integer(1)],
+            ),
+            Err(RuntimeError::IntegerOverflow)
+        );
+    }
+
+    #[test]
+    fn test_eval_builtin_sub_overflow() {
+        let ctx = MockRtCtx::new();
+        assert_eq!(
+            eval_builtin(
+                &ctx,
+                BuiltinKind::Sub,
+                vec![Value::Integer(i64::MIN), Value::Integer(1)],
+            ),
+            Err(RuntimeError::IntegerOverflow)
+        );
+    }
+
+    #[test]
+    fn test_eval_builtin_div() {
+        let ctx = MockRtCtx::new();
+        let div = |i: i64, j: i64| {
+            eval_builtin(
+                &ctx,
+                BuiltinKind::Div,
+                vec![Value::Integer(i), Value::Integer(j)],
+            )
+        };
+        assert_eq!(div(7, 2), Ok(Value::Integer(3)));
+        assert_eq!(div(-7, 2), Ok(Value::Integer(-3)));
+        assert_eq!(div(7, -2), Ok(Value::Integer(-3)));
+        assert_eq!(div(1, 0), Err(RuntimeError::DivisionByZero));
+        assert_eq!(div(i64::MIN, -1), Err(RuntimeError::IntegerOverflow));
+    }
+
+    #[test]
+    fn test_eval_builtin_mul_overflow() {
+        let ctx = MockRtCtx::new();
+        assert_eq!(
+            eval_builtin(
+                &ctx,
+                BuiltinKind::Mul,
+                vec![Value::Integer(i64::MAX), Value::Integer(2)],
+            ),
+            Err(RuntimeError::IntegerOverflow)
+        );
+    }
+
+    #[test]
+    fn test_eval_branch_integer_cond() {
+        let cctx = CCtx::new();
+        // Ill-typed: the condition is an integer. Typecheck is skipped here on purpose.
+        let program_unit =
+            ProgramUnit::simple(Function::describe(0, |[cond, tmp1], [bb0, bb1]| {
+                vec![
+                    (
+                        bb0,
+                        BasicBlock::new(vec![Inst::literal(cond, 1), Inst::branch(cond, bb1, bb1)]),
+                    ),
+                    (
+                        bb1,
+                        BasicBlock::new(vec![Inst::literal(tmp1, ()), Inst::return_(tmp1)]),
+                    ),
+                ]
+            }));
+        let program_unit = compile(&cctx, &program_unit);
+        let ctx = MockRtCtx::new();
+        assert_eq!(
+            eval1(&ctx, &program_unit),
+            Err(RuntimeError::TypeMismatch {
+                expected: "bool",
+                got: "integer",
+            })
+        );
+    }
+
+    #[test]
+    fn test_eval_branch_bool_cond() {
+        let cctx = CCtx::new();
+        let program_unit = ProgramUnit::simple(Function::describe(
+            0,
+            |[cond, tmp1, x, puti1, tmp2], [entry, branch_then, branch_else, cont]| {
+                vec![
+                    (
+                        entry,
+                        BasicBlock::new(vec![
+                            Inst::literal(cond, true),
+                            Inst::branch(cond, branch_then, branch_else),
+                        ]),
+                    ),
+                    (
+                        branch_then,
+                        BasicBlock::new(vec![Inst::literal(x, 1), Inst::jump(cont)]),
+                    ),
+                    (
+                        branch_else,
+                        BasicBlock::new(vec![Inst::literal(x, 2), Inst::jump(cont)]),
+                    ),
+                    (
+                        cont,
+                        BasicBlock::new(vec![
+                            Inst::builtin(puti1, BuiltinKind::Puti),
+                            Inst::push_arg(x),
+                            Inst::call(tmp2, puti1),
+                            Inst::literal(tmp1, ()),
+                            Inst::return_(tmp1),
+                        ]),
+                    ),
+                ]
+            },
+        ));
+        assert!(typecheck(&cctx, &program_unit).is_ok());
+        let program_unit = compile(&cctx, &program_unit);
+        let ctx = MockRtCtx::new();
+        eval1(&ctx, &program_unit).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "1\n");
+    }
+
+    #[test]
+    fn test_eval_builtin_comparisons() {
+        let ctx = MockRtCtx::new();
+        let compare = |f: BuiltinKind, i: i64, j: i64| {
+            eval_builtin(&ctx, f, vec![Value::Integer(i), Value::Integer(j)]).unwrap()
+        };
+        for (f, expected) in [
+            (BuiltinKind::Lt, [true, false, false]),
+            (BuiltinKind::Gt, [false, false, true]),
+            (BuiltinKind::Le, [true, true, false]),
+            (BuiltinKind::Ge, [false, true, true]),
+            (BuiltinKind::Eq, [false, true, false]),
+            (BuiltinKind::Ne, [true, false, true]),
+        ] {
+            let got = [compare(f, 1, 2), compare(f, 2, 2), compare(f, 3, 2)];
+            assert_eq!(got, expected.map(Value::Bool), "{:?}", f);
+        }
+    }
+
+    #[test]
+    fn test_eval_builtin_lt_yields_bool() {
+        let ctx = MockRtCtx::new();
+        assert_eq!(
+            eval_builtin(
+                &ctx,
+                BuiltinKind::Lt,
+                vec![Value::Integer(1), Value::Integer(2)],
+            ),
+            Ok(Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_eval_reached_unreachable() {
+        let cctx = CCtx::new();
+        let program_unit =
+            ProgramUnit::simple(Function::describe(0, |[cond, tmp1], [bb0, bb1, bb2]| {
+                vec![
+                    (
+                        bb0,
+                        BasicBlock::new(vec![
+                            Inst::literal(cond, false),
+                            Inst::branch(cond, bb1, bb2),
+                        ]),
+                    ),
+                    (
+                        bb1,
+                        BasicBlock::new(vec![Inst::literal(tmp1, ()), Inst::return_(tmp1)]),
+                    ),
+                    (bb2, BasicBlock::new(vec![Inst::unreachable()])),
+                ]
+            }));
+        let program_unit = compile(&cctx, &program_unit);
+        let ctx = MockRtCtx::new();
+        assert_eq!(
+            eval1(&ctx, &program_unit),
+            Err(RuntimeError::ReachedUnreachable)
+        );
+    }
+
+    #[test]
+    fn test_value_clone_is_shallow() {
+        let mut value = Value::Integer(0);
+        for _ in 0..1000 {
+            value = Value::Closure {
+                function_id: 0,
+                captured_vars: vec![value].into(),
+            };
         }
+        let cloned = value.clone();
+        let (
+            Value::Closure {
+                captured_vars: orig,
+                ..
+            },
+            Value::Closure {
+                captured_vars: copy,
+                ..
+            },
+        ) = (&value, &cloned)
+        else {
+            unreachable!();
+        };
+        assert!(Arc::ptr_eq(orig, copy));
     }
 }
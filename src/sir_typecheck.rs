@@ -1,5 +1,3 @@
-use std::borrow::Cow;
-
 use crate::cctx::CCtx;
 use crate::sir::{BasicBlock, BuiltinKind, Function, InstKind, Literal, ProgramUnit};
 
@@ -19,14 +17,6 @@ impl TyCtx {
         self.ty_vars.push(None);
         ty
     }
-    fn expand_shallow<'a>(&self, ty: &'a Type) -> Cow<'a, Type> {
-        if let Type::Var { var_id: id } = ty {
-            if let Some(ty) = &self.ty_vars[*id] {
-                return Cow::Owned(self.expand_shallow(ty).into_owned());
-            }
-        }
-        Cow::Borrowed(ty)
-    }
     fn unify(&mut self, ty1: &Type, ty2: &Type) -> Result<(), TypeError> {
         if let Type::Var { var_id: id } = ty1 {
             if let Some(ty1a) = &self.ty_vars[*id] {
@@ -60,6 +50,8 @@ impl TyCtx {
             (Type::String, Type::String) => Ok(()),
             (Type::Integer, Type::Integer) => Ok(()),
             (Type::Bool, Type::Bool) => Ok(()),
+            (Type::Array(elem1), Type::Array(elem2)) => self.unify(elem1, elem2),
+            (Type::Optional(value1), Type::Optional(value2)) => self.unify(value1, value2),
             (
                 Type::Function {
                     args: args1,
@@ -100,6 +92,8 @@ impl TyCtx {
                 args.iter().any(|arg| self.has_ty_var(arg, needle_id))
                     || self.has_ty_var(ret, needle_id)
             }
+            Type::Array(elem) => self.has_ty_var(elem, needle_id),
+            Type::Optional(value) => self.has_ty_var(value, needle_id),
         }
     }
     fn has_any_ty_var(&self, ty: &Type) -> bool {
@@ -118,6 +112,8 @@ impl TyCtx {
             Type::Function { args, ret } => {
                 args.iter().any(|arg| self.has_any_ty_var(arg)) || self.has_any_ty_var(ret)
             }
+            Type::Array(elem) => self.has_any_ty_var(elem),
+            Type::Optional(value) => self.has_any_ty_var(value),
         }
     }
 }
@@ -221,6 +217,7 @@ fn typecheck_bb(
             InstKind::Return { rhs } => {
                 ty_ctx.unify(&state.vars[*rhs], return_type)?;
             }
+            InstKind::Unreachable => {}
             InstKind::Copy { lhs, rhs } => {
                 ty_ctx.unify(&state.vars[*lhs], &state.vars[*rhs])?;
             }
@@ -229,40 +226,56 @@ fn typecheck_bb(
                 ty_ctx.unify(&state.vars[*lhs], &Type::of_literal(value))?;
             }
             InstKind::Closure { lhs, function_id } => {
-                if !args.is_empty() {
-                    todo!("Variable-capturing closure");
-                }
                 let function_type = &pctx.functions[*function_id];
+                // Pushed arguments are captured as the first arguments of the function
+                if args.len() > function_type.args.len() {
+                    return Err(TypeError);
+                }
+                for (arg, function_arg) in args.iter().zip(&function_type.args) {
+                    ty_ctx.unify(arg, function_arg)?;
+                }
                 ty_ctx.unify(
                     &state.vars[*lhs],
                     &Type::Function {
-                        args: function_type.args.clone(),
+                        args: function_type.args[args.len()..].to_vec(),
                         ret: Box::new(function_type.ret.clone()),
                     },
                 )?;
+                args.clear();
             }
             InstKind::Builtin { lhs, builtin } => {
-                ty_ctx.unify(&state.vars[*lhs], &builtin_type(*builtin))?;
+                let ty = builtin_type(ty_ctx, *builtin);
+                ty_ctx.unify(&state.vars[*lhs], &ty)?;
             }
-            InstKind::PushArg { value_ref } => {
-                args.push(state.vars[*value_ref].clone());
-            }
-            InstKind::Call { lhs, callee } => {
-                let callee_type = &state.vars[*callee];
-                let (callee_args, callee_ret) =
-                    match ty_ctx.expand_shallow(callee_type).into_owned() {
-                        Type::Function { args, ret } => (args, ret),
-                        _ => return Err(TypeError),
-                    };
-                if args.len() != callee_args.len() {
-                    return Err(TypeError);
-                }
-                for (arg, callee_arg) in args.iter().zip(callee_args) {
-                    ty_ctx.unify(arg, &callee_arg)?;
+            InstKind::Array { lhs } => {
+                let elem_type = ty_ctx.fresh();
+                for arg in &args {
+                    ty_ctx.unify(arg, &elem_type)?;
                 }
-                ty_ctx.unify(&state.vars[*lhs], &callee_ret)?;
+                ty_ctx.unify(&state.vars[*lhs], &Type::Array(Box::new(elem_type)))?;
                 args.clear();
             }
+            InstKind::PushArg { value_ref } | InstKind::PushArgCopy { value_ref } => {
+                args.push(state.vars[*value_ref].clone());
+            }
+            InstKind::Call { lhs, callee } | InstKind::TryCall { lhs, callee } => {
+                // The callee may not be known to be a function yet,
+                // e.g. when it is a closure returned from another call
+                let callee_ret = ty_ctx.fresh();
+                ty_ctx.unify(
+                    &state.vars[*callee],
+                    &Type::Function {
+                        args: std::mem::take(&mut args),
+                        ret: Box::new(callee_ret.clone()),
+                    },
+                )?;
+                let ret_type = if let InstKind::TryCall { .. } = &inst.kind {
+                    Type::Optional(Box::new(callee_ret))
+                } else {
+                    callee_ret
+                };
+                ty_ctx.unify(&state.vars[*lhs], &ret_type)?;
+            }
         }
     }
     if !args.is_empty() {
@@ -271,13 +284,20 @@ fn typecheck_bb(
     Ok(())
 }
 
-fn builtin_type(f: BuiltinKind) -> Type {
+fn builtin_type(ty_ctx: &mut TyCtx, f: BuiltinKind) -> Type {
     match f {
-        BuiltinKind::Add => Type::Function {
-            args: vec![Type::Integer, Type::Integer],
-            ret: Box::new(Type::Integer),
-        },
-        BuiltinKind::Lt => Type::Function {
+        BuiltinKind::Add | BuiltinKind::Sub | BuiltinKind::Mul | BuiltinKind::Div => {
+            Type::Function {
+                args: vec![Type::Integer, Type::Integer],
+                ret: Box::new(Type::Integer),
+            }
+        }
+        BuiltinKind::Lt
+        | BuiltinKind::Gt
+        | BuiltinKind::Le
+        | BuiltinKind::Ge
+        | BuiltinKind::Eq
+        | BuiltinKind::Ne => Type::Function {
             args: vec![Type::Integer, Type::Integer],
             ret: Box::new(Type::Bool),
         },
@@ -289,6 +309,84 @@ fn builtin_type(f: BuiltinKind) -> Type {
             args: vec![Type::Integer],
             ret: Box::new(Type::Unit),
         },
+        BuiltinKind::StrEq => Type::Function {
+            args: vec![Type::String, Type::String],
+            ret: Box::new(Type::Bool),
+        },
+        // Panics never return, so the result may be used as any type
+        BuiltinKind::Panic => Type::Function {
+            args: vec![Type::String],
+            ret: Box::new(ty_ctx.fresh()),
+        },
+        BuiltinKind::PanicWithInt => Type::Function {
+            args: vec![Type::String, Type::Integer],
+            ret: Box::new(ty_ctx.fresh()),
+        },
+        BuiltinKind::Index => {
+            let elem_type = ty_ctx.fresh();
+            Type::Function {
+                args: vec![Type::Array(Box::new(elem_type.clone())), Type::Integer],
+                ret: Box::new(elem_type),
+            }
+        }
+        // Works on both strings and arrays; the evaluator rejects other values.
+        BuiltinKind::Slice => {
+            let seq_type = ty_ctx.fresh();
+            Type::Function {
+                args: vec![seq_type.clone(), Type::Integer, Type::Integer],
+                ret: Box::new(seq_type),
+            }
+        }
+        BuiltinKind::Len => Type::Function {
+            args: vec![ty_ctx.fresh()],
+            ret: Box::new(Type::Integer),
+        },
+        BuiltinKind::Gets => Type::Function {
+            args: vec![],
+            ret: Box::new(Type::Optional(Box::new(Type::String))),
+        },
+        BuiltinKind::OptionNone => Type::Function {
+            args: vec![],
+            ret: Box::new(Type::Optional(Box::new(ty_ctx.fresh()))),
+        },
+        BuiltinKind::OptionSome => {
+            let value_type = ty_ctx.fresh();
+            Type::Function {
+                args: vec![value_type.clone()],
+                ret: Box::new(Type::Optional(Box::new(value_type))),
+            }
+        }
+        BuiltinKind::IsSome => Type::Function {
+            args: vec![Type::Optional(Box::new(ty_ctx.fresh()))],
+            ret: Box::new(Type::Bool),
+        },
+        BuiltinKind::Unwrap => {
+            let value_type = ty_ctx.fresh();
+            Type::Function {
+                args: vec![Type::Optional(Box::new(value_type.clone()))],
+                ret: Box::new(value_type),
+            }
+        }
+        BuiltinKind::CharAt => Type::Function {
+            args: vec![Type::String, Type::Integer],
+            ret: Box::new(Type::Integer),
+        },
+        BuiltinKind::TermWidth => Type::Function {
+            args: vec![],
+            ret: Box::new(Type::Integer),
+        },
+        BuiltinKind::Args => Type::Function {
+            args: vec![],
+            ret: Box::new(Type::Array(Box::new(Type::String))),
+        },
+        BuiltinKind::Putc => Type::Function {
+            args: vec![Type::Integer],
+            ret: Box::new(Type::Unit),
+        },
+        BuiltinKind::Repeat => Type::Function {
+            args: vec![Type::String, Type::Integer],
+            ret: Box::new(Type::String),
+        },
     }
 }
 
@@ -299,6 +397,8 @@ enum Type {
     Integer,
     Bool,
     Function { args: Vec<Type>, ret: Box<Type> },
+    Array(Box<Type>),
+    Optional(Box<Type>),
     Var { var_id: usize },
 }
 
@@ -338,6 +438,106 @@ mod tests {
         assert!(typecheck(&cctx, &program_unit).is_ok());
     }
 
+    #[test]
+    fn test_typecheck_closure_capture() {
+        let cctx = CCtx::new();
+        let program_unit = ProgramUnit::describe(|[main, lambda1]| {
+            vec![
+                (
+                    main,
+                    Function::simple(0, |[y, f, tmp1, tmp2]| {
+                        BasicBlock::new(vec![
+                            Inst::literal(y, 1),
+                            Inst::push_arg(y),
+                            Inst::closure(f, lambda1),
+                            Inst::literal(tmp2, 2),
+                            Inst::push_arg(tmp2),
+                            Inst::call(tmp1, f),
+                            Inst::return_(tmp1),
+                        ])
+                    }),
+                ),
+                (
+                    lambda1,
+                    Function::simple(2, |[y, x, tmp1, add1]| {
+                        BasicBlock::new(vec![
+                            Inst::builtin(add1, BuiltinKind::Add),
+                            Inst::push_arg(x),
+                            Inst::push_arg(y),
+                            Inst::call(tmp1, add1),
+                            Inst::return_(tmp1),
+                        ])
+                    }),
+                ),
+            ]
+        });
+        assert!(typecheck(&cctx, &program_unit).is_ok());
+    }
+
+    #[test]
+    fn test_typecheck_failure_closure_capture_type_mismatch() {
+        let cctx = CCtx::new();
+        let program_unit = ProgramUnit::describe(|[main, lambda1]| {
+            vec![
+                (
+                    main,
+                    Function::simple(0, |[y, f, tmp1, tmp2]| {
+                        BasicBlock::new(vec![
+                            Inst::literal(y, "one"),
+                            Inst::push_arg(y),
+                            Inst::closure(f, lambda1),
+                            Inst::literal(tmp2, 2),
+                            Inst::push_arg(tmp2),
+                            Inst::call(tmp1, f),
+                            Inst::return_(tmp1),
+                        ])
+                    }),
+                ),
+                (
+                    lambda1,
+                    Function::simple(2, |[y, x, tmp1, add1]| {
+                        BasicBlock::new(vec![
+                            Inst::builtin(add1, BuiltinKind::Add),
+                            Inst::push_arg(x),
+                            Inst::push_arg(y),
+                            Inst::call(tmp1, add1),
+                            Inst::return_(tmp1),
+                        ])
+                    }),
+                ),
+            ]
+        });
+        assert!(typecheck(&cctx, &program_unit).is_err());
+    }
+
+    #[test]
+    fn test_typecheck_failure_return_type_mismatch() {
+        let cctx = CCtx::new();
+        let program_unit = ProgramUnit::simple(Function::describe(
+            0,
+            |[c, x, y], [entry, branch_then, branch_else]| {
+                vec![
+                    (
+                        entry,
+                        BasicBlock::new(vec![
+                            Inst::literal(c, true),
+                            Inst::branch(c, branch_then, branch_else),
+                        ]),
+                    ),
+                    (
+                        branch_then,
+                        BasicBlock::new(vec![Inst::literal(x, "Hello, world!"), Inst::return_(x)]),
+                    ),
+                    (
+                        branch_else,
+                        BasicBlock::new(vec![Inst::literal(y, 42), Inst::return_(y)]),
+                    ),
+                ]
+            },
+        ));
+        assert!(typecheck(&cctx, &program_unit).is_err());
+    }
+
     #[test]
     fn test_typecheck_failure_too_few_arg() {
         let cctx = CCtx::new();
@@ -398,4 +598,26 @@ mod tests {
         }));
         assert!(typecheck(&cctx, &program_unit).is_err());
     }
+
+    #[test]
+    fn test_typecheck_failure_integer_branch_cond() {
+        let cctx = CCtx::new();
+        let program_unit =
+            ProgramUnit::simple(Function::describe(0, |[cond, tmp1], [entry, cont]| {
+                vec![
+                    (
+                        entry,
+                        BasicBlock::new(vec![
+                            Inst::literal(cond, 1),
+                            Inst::branch(cond, cont, cont),
+                        ]),
+                    ),
+                    (
+                        cont,
+                        BasicBlock::new(vec![Inst::literal(tmp1, ()), Inst::return_(tmp1)]),
+                    ),
+                ]
+            }));
+        assert!(typecheck(&cctx, &program_unit).is_err());
+    }
 }
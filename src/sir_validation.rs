@@ -1,8 +1,10 @@
 use std::fmt;
 
+use bit_set::BitSet;
 use thiserror::Error;
 
-use crate::sir::{BasicBlock, Function, Inst, ProgramUnit};
+use crate::sir::{BasicBlock, Function, Inst, InstKind, ProgramUnit};
+use crate::CompileOptions;
 
 #[derive(Debug, Error)]
 pub enum SirValidationError {
@@ -18,6 +20,16 @@ pub enum SirValidationError {
     InvalidTargetBlock { pos: SirPosition },
     #[error("invalid function id at {pos}")]
     InvalidFunctionId { pos: SirPosition },
+    #[error("use of possibly uninitialized variable at {pos}")]
+    UninitializedVariable { pos: SirPosition },
+    #[error("closure captures more values than the function takes at {pos}")]
+    ExcessCaptures { pos: SirPosition },
+    #[error("missing entry block at {pos}")]
+    MissingEntryBlock { pos: SirPosition },
+    #[error("jump/branch to the entry block at {pos}")]
+    EntryBlockTargeted { pos: SirPosition },
+    #[error("too many arguments for a call at {pos}")]
+    TooManyArgs { pos: SirPosition },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -42,6 +54,17 @@ impl fmt::Display for SirPosition {
 
 impl ProgramUnit {
     pub fn validate_insts(&self) -> Result<(), SirValidationError> {
+        self.validate_insts_impl(None)
+    }
+
+    /// Same as `validate_insts`, but also rejects calls with more than `max_args` arguments.
+    ///
+    /// This is a defensive limit for untrusted input.
+    pub fn validate_insts_with_max_args(&self, max_args: usize) -> Result<(), SirValidationError> {
+        self.validate_insts_impl(Some(max_args))
+    }
+
+    fn validate_insts_impl(&self, max_args: Option<usize>) -> Result<(), SirValidationError> {
         for (function_id, function) in self.functions.iter().enumerate() {
             function.validate_insts(
                 self,
@@ -50,42 +73,180 @@ impl ProgramUnit {
                     block_id: None,
                     inst_id: None,
                 },
+                max_args,
             )?;
         }
         Ok(())
     }
 }
 
+impl ProgramUnit {
+    /// Checks the lowered program before `compile`, with the limits in the options.
+    pub fn validate(&self, options: &CompileOptions) -> Result<(), SirValidationError> {
+        if let Some(max_args) = options.max_args {
+            self.validate_insts_with_max_args(max_args)?;
+        }
+        self.validate_init()
+    }
+
+    /// Checks that no variable is read before it is assigned on every path.
+    ///
+    /// This is meant for the SIR before `compile`; moves are not tracked here.
+    pub fn validate_init(&self) -> Result<(), SirValidationError> {
+        for (function_id, function) in self.functions.iter().enumerate() {
+            function.validate_init(SirPosition {
+                function_id,
+                block_id: None,
+                inst_id: None,
+            })?;
+        }
+        Ok(())
+    }
+}
+
 impl Function {
     pub fn validate_insts(
         &self,
         program_unit: &ProgramUnit,
         pos: SirPosition,
+        max_args: Option<usize>,
     ) -> Result<(), SirValidationError> {
         if self.num_args > self.num_vars {
             return Err(SirValidationError::ExcessNumArgs { pos });
         }
+        // Evaluation starts at block 0 and `insert_copy` seeds the arguments there,
+        // so block 0 must exist and must not be entered from elsewhere.
+        if self.body.is_empty() {
+            return Err(SirValidationError::MissingEntryBlock { pos });
+        }
         for (block_id, block) in self.body.iter().enumerate() {
-            block.validate_insts(
-                program_unit,
-                self,
-                SirPosition {
-                    block_id: Some(block_id),
-                    ..pos
-                },
-            )?;
+            let block_pos = SirPosition {
+                block_id: Some(block_id),
+                ..pos
+            };
+            block.validate_insts(program_unit, self, block_pos, max_args)?;
+            if block.successors().contains(&0) {
+                return Err(SirValidationError::EntryBlockTargeted {
+                    pos: SirPosition {
+                        inst_id: Some(block.insts.len() - 1),
+                        ..block_pos
+                    },
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Function {
+    pub fn validate_init(&self, pos: SirPosition) -> Result<(), SirValidationError> {
+        if self.body.is_empty() {
+            return Ok(());
+        }
+        // Variables that are definitely initialized at the beginning of each block.
+        // None means the block has not been reached (yet).
+        let mut init_in = vec![None::<BitSet<usize>>; self.body.len()];
+        init_in[0] = Some((0..self.num_args).collect());
+        let mut updated = true;
+        while updated {
+            updated = false;
+            for (bb_id, bb) in self.body.iter().enumerate() {
+                let Some(mut init) = init_in[bb_id].clone() else {
+                    continue;
+                };
+                for inst in &bb.insts {
+                    update_init(inst, &mut init);
+                }
+                for succ in bb.successors() {
+                    if let Some(succ_init) = &mut init_in[succ] {
+                        let old_len = succ_init.len();
+                        succ_init.intersect_with(&init);
+                        if succ_init.len() != old_len {
+                            updated = true;
+                        }
+                    } else {
+                        init_in[succ] = Some(init.clone());
+                        updated = true;
+                    }
+                }
+            }
+        }
+
+        for (block_id, bb) in self.body.iter().enumerate() {
+            // Unreachable blocks are not checked
+            let Some(mut init) = init_in[block_id].clone() else {
+                continue;
+            };
+            for (inst_id, inst) in bb.insts.iter().enumerate() {
+                if let Some(used) = used_var_of(inst) {
+                    if !init.contains(used) {
+                        return Err(SirValidationError::UninitializedVariable {
+                            pos: SirPosition {
+                                block_id: Some(block_id),
+                                inst_id: Some(inst_id),
+                                ..pos
+                            },
+                        });
+                    }
+                }
+                update_init(inst, &mut init);
+            }
         }
         Ok(())
     }
 }
 
+fn update_init(inst: &Inst, init: &mut BitSet<usize>) {
+    match &inst.kind {
+        InstKind::Drop { rhs } => {
+            init.remove(*rhs);
+        }
+        InstKind::Copy { lhs, .. }
+        | InstKind::Literal { lhs, .. }
+        | InstKind::Closure { lhs, .. }
+        | InstKind::Builtin { lhs, .. }
+        | InstKind::Array { lhs }
+        | InstKind::Call { lhs, .. }
+        | InstKind::TryCall { lhs, .. } => {
+            init.insert(*lhs);
+        }
+        InstKind::Jump { .. }
+        | InstKind::Branch { .. }
+        | InstKind::Return { .. }
+        | InstKind::Unreachable
+        | InstKind::PushArg { .. }
+        | InstKind::PushArgCopy { .. } => {}
+    }
+}
+
+fn used_var_of(inst: &Inst) -> Option<usize> {
+    match &inst.kind {
+        InstKind::Jump { .. } => None,
+        InstKind::Branch { cond, .. } => Some(*cond),
+        InstKind::Return { rhs } => Some(*rhs),
+        InstKind::Unreachable => None,
+        InstKind::Copy { rhs, .. } => Some(*rhs),
+        InstKind::Drop { rhs } => Some(*rhs),
+        InstKind::Literal { .. } => None,
+        InstKind::Closure { .. } => None,
+        InstKind::Builtin { .. } => None,
+        InstKind::Array { .. } => None,
+        InstKind::PushArg { value_ref } | InstKind::PushArgCopy { value_ref } => Some(*value_ref),
+        InstKind::Call { callee, .. } => Some(*callee),
+        InstKind::TryCall { callee, .. } => Some(*callee),
+    }
+}
+
 impl BasicBlock {
     pub fn validate_insts(
         &self,
         program_unit: &ProgramUnit,
         function: &Function,
         pos: SirPosition,
+        max_args: Option<usize>,
     ) -> Result<(), SirValidationError> {
+        // Arguments pushed so far for the next Call/Closure/Array
+        let mut num_pushed_args = 0;
         for (inst_id, inst) in self.insts.iter().enumerate() {
             let is_last = inst_id == self.insts.len() - 1;
             if is_last && !inst.kind.is_tail() {
@@ -93,14 +254,31 @@ impl BasicBlock {
             } else if !is_last && inst.kind.is_tail() {
                 return Err(SirValidationError::UnexpectedTailInstruction { pos });
             }
-            inst.validate_inst(
-                program_unit,
-                function,
-                SirPosition {
-                    inst_id: Some(inst_id),
-                    ..pos
-                },
-            )?;
+            let inst_pos = SirPosition {
+                inst_id: Some(inst_id),
+                ..pos
+            };
+            inst.validate_inst(program_unit, function, inst_pos)?;
+            match &inst.kind {
+                InstKind::PushArg { .. } | InstKind::PushArgCopy { .. } => num_pushed_args += 1,
+                InstKind::Closure { function_id, .. } => {
+                    // Captured values are received as the first arguments of the function
+                    if num_pushed_args > program_unit.functions[*function_id].num_args {
+                        return Err(SirValidationError::ExcessCaptures { pos: inst_pos });
+                    }
+                    num_pushed_args = 0;
+                }
+                InstKind::Call { .. } | InstKind::TryCall { .. } => {
+                    if max_args.is_some_and(|max_args| num_pushed_args > max_args) {
+                        return Err(SirValidationError::TooManyArgs { pos: inst_pos });
+                    }
+                    num_pushed_args = 0;
+                }
+                InstKind::Array { .. } => {
+                    num_pushed_args = 0;
+                }
+                _ => {}
+            }
         }
         Ok(())
     }
@@ -136,6 +314,7 @@ impl Inst {
                     return Err(SirValidationError::InvalidVariableId { pos });
                 }
             }
+            crate::sir::InstKind::Unreachable => {}
             crate::sir::InstKind::Copy { lhs, rhs } => {
                 if *lhs >= function.num_vars || *rhs >= function.num_vars {
                     return Err(SirValidationError::InvalidVariableId { pos });
@@ -164,12 +343,19 @@ impl Inst {
                     return Err(SirValidationError::InvalidVariableId { pos });
                 }
             }
-            crate::sir::InstKind::PushArg { value_ref } => {
+            crate::sir::InstKind::Array { lhs } => {
+                if *lhs >= function.num_vars {
+                    return Err(SirValidationError::InvalidVariableId { pos });
+                }
+            }
+            crate::sir::InstKind::PushArg { value_ref }
+            | crate::sir::InstKind::PushArgCopy { value_ref } => {
                 if *value_ref >= function.num_vars {
                     return Err(SirValidationError::InvalidVariableId { pos });
                 }
             }
-            crate::sir::InstKind::Call { lhs, callee } => {
+            crate::sir::InstKind::Call { lhs, callee }
+            | crate::sir::InstKind::TryCall { lhs, callee } => {
                 if *lhs >= function.num_vars || *callee >= function.num_vars {
                     return Err(SirValidationError::InvalidVariableId { pos });
                 }
@@ -178,3 +364,129 @@ impl Inst {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sir::{BuiltinKind, Inst};
+
+    #[test]
+    fn test_validate_too_many_args() {
+        let program_unit = ProgramUnit::simple(Function::simple(0, |[x, puti1, tmp1]| {
+            let mut insts = vec![Inst::literal(x, 1), Inst::builtin(puti1, BuiltinKind::Puti)];
+            insts.extend((0..5).map(|_| Inst::push_arg(x)));
+            insts.push(Inst::call(tmp1, puti1));
+            insts.push(Inst::return_(tmp1));
+            BasicBlock::new(insts)
+        }));
+        assert!(program_unit.validate_insts().is_ok());
+        assert!(program_unit.validate_insts_with_max_args(5).is_ok());
+        assert!(matches!(
+            program_unit.validate_insts_with_max_args(4),
+            Err(SirValidationError::TooManyArgs {
+                pos: SirPosition {
+                    function_id: 0,
+                    block_id: Some(0),
+                    inst_id: Some(7),
+                }
+            })
+        ));
+    }
+
+    #[test]
+    fn test_validate_excess_captures() {
+        let program_unit = ProgramUnit::describe(|[main, lambda1]| {
+            vec![
+                (
+                    main,
+                    Function::simple(0, |[x, y, f, tmp1]| {
+                        BasicBlock::new(vec![
+                            Inst::literal(x, 1),
+                            Inst::literal(y, 2),
+                            Inst::push_arg(x),
+                            Inst::push_arg(y),
+                            Inst::closure(f, lambda1),
+                            Inst::literal(tmp1, ()),
+                            Inst::return_(tmp1),
+                        ])
+                    }),
+                ),
+                (
+                    lambda1,
+                    Function::simple(1, |[x]| BasicBlock::new(vec![Inst::return_(x)])),
+                ),
+            ]
+        });
+        assert!(matches!(
+            program_unit.validate_insts(),
+            Err(SirValidationError::ExcessCaptures {
+                pos: SirPosition {
+                    function_id: 0,
+                    block_id: Some(0),
+                    inst_id: Some(4),
+                }
+            })
+        ));
+    }
+
+    #[test]
+    fn test_validate_captures_within_args() {
+        let program_unit = ProgramUnit::describe(|[main, lambda1]| {
+            vec![
+                (
+                    main,
+                    Function::simple(0, |[x, f, tmp1]| {
+                        BasicBlock::new(vec![
+                            Inst::literal(x, 1),
+                            Inst::push_arg(x),
+                            Inst::closure(f, lambda1),
+                            Inst::literal(tmp1, ()),
+                            Inst::return_(tmp1),
+                        ])
+                    }),
+                ),
+                (
+                    lambda1,
+                    Function::simple(2, |[x, _y]| BasicBlock::new(vec![Inst::return_(x)])),
+                ),
+            ]
+        });
+        assert!(program_unit.validate_insts().is_ok());
+    }
+
+    #[test]
+    fn test_validate_missing_entry_block() {
+        let program_unit = ProgramUnit::new(vec![Function::new(0, 0, vec![])]);
+        assert!(matches!(
+            program_unit.validate_insts(),
+            Err(SirValidationError::MissingEntryBlock {
+                pos: SirPosition {
+                    function_id: 0,
+                    block_id: None,
+                    inst_id: None,
+                }
+            })
+        ));
+    }
+
+    #[test]
+    fn test_validate_entry_block_targeted() {
+        let program_unit = ProgramUnit::simple(Function::describe(0, |[], [bb0, bb1]| {
+            vec![
+                (bb0, BasicBlock::new(vec![Inst::jump(bb1)])),
+                (bb1, BasicBlock::new(vec![Inst::jump(bb0)])),
+            ]
+        }));
+        assert!(matches!(
+            program_unit.validate_insts(),
+            Err(SirValidationError::EntryBlockTargeted {
+                pos: SirPosition {
+                    function_id: 0,
+                    block_id: Some(1),
+                    inst_id: Some(0),
+                }
+            })
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+/// Converts byte offsets in a source text into line/column positions.
+#[derive(Debug, Clone)]
+pub struct SourceMap<'a> {
+    source: &'a str,
+    /// Byte offset of the beginning of each line
+    line_starts: Vec<usize>,
+    /// Number of columns a `\t` advances
+    tab_width: usize,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            source,
+            line_starts,
+            tab_width: 1,
+        }
+    }
+
+    pub fn with_tab_width(mut self, tab_width: usize) -> Self {
+        self.tab_width = tab_width;
+        self
+    }
+
+    /// Returns the 1-based `(line, column)` of the byte offset.
+    ///
+    /// Columns are counted in characters, except that a tab counts as `tab_width` columns.
+    /// Offsets past the end are clamped to the end.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.source.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let line_text = &self.source[self.line_starts[line]..offset];
+        let column = line_text
+            .chars()
+            .map(|ch| if ch == '\t' { self.tab_width } else { 1 })
+            .sum::<usize>();
+        (line + 1, column + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_col() {
+        let source_map = SourceMap::new("abc\nde\n\nfgh");
+        assert_eq!(source_map.line_col(0), (1, 1));
+        assert_eq!(source_map.line_col(2), (1, 3));
+        // The newline itself belongs to the line it terminates
+        assert_eq!(source_map.line_col(3), (1, 4));
+        assert_eq!(source_map.line_col(4), (2, 1));
+        assert_eq!(source_map.line_col(6), (2, 3));
+        assert_eq!(source_map.line_col(7), (3, 1));
+        assert_eq!(source_map.line_col(8), (4, 1));
+        assert_eq!(source_map.line_col(11), (4, 4));
+        assert_eq!(source_map.line_col(100), (4, 4));
+    }
+
+    #[test]
+    fn test_line_col_multibyte() {
+        let source_map = SourceMap::new("あい\nう");
+        assert_eq!(source_map.line_col(3), (1, 2));
+        assert_eq!(source_map.line_col(7), (2, 1));
+        assert_eq!(source_map.line_col(10), (2, 2));
+    }
+
+    #[test]
+    fn test_line_col_tab_width() {
+        let source = "do {\n\tputs(x);\n}";
+        assert_eq!(SourceMap::new(source).line_col(6), (2, 2));
+        let source_map = SourceMap::new(source).with_tab_width(4);
+        assert_eq!(source_map.line_col(5), (2, 1));
+        assert_eq!(source_map.line_col(6), (2, 5));
+        assert_eq!(source_map.line_col(11), (2, 10));
+    }
+
+    #[test]
+    fn test_line_col_trailing_newline() {
+        let source_map = SourceMap::new("a\n");
+        assert_eq!(source_map.line_col(1), (1, 2));
+        assert_eq!(source_map.line_col(2), (2, 1));
+    }
+}
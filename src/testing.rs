@@ -1,23 +1,273 @@
 use crate::rt_ctx::RtCtx;
+use crate::RunError;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Clone)]
 pub struct MockRtCtx {
     pub stdout: Arc<Mutex<String>>,
+    /// Input not read yet
+    pub stdin: Arc<Mutex<String>>,
+    pub term_width: Option<usize>,
+    pub args: Vec<String>,
+    /// Limit of `stdout` in bytes, beyond which the output is dropped
+    pub max_stdout_len: Option<usize>,
+    /// Whether some output has been dropped due to `max_stdout_len`
+    pub truncated: Arc<AtomicBool>,
 }
 
 impl MockRtCtx {
     pub fn new() -> Self {
         Self {
             stdout: Arc::new(Mutex::new(String::new())),
+            stdin: Arc::new(Mutex::new(String::new())),
+            term_width: None,
+            args: vec![],
+            max_stdout_len: None,
+            truncated: Arc::new(AtomicBool::new(false)),
         }
     }
+
+    pub fn with_stdin(self, stdin: &str) -> Self {
+        *self.stdin.lock().unwrap() = stdin.to_owned();
+        self
+    }
+
+    pub fn with_term_width(self, term_width: usize) -> Self {
+        Self {
+            term_width: Some(term_width),
+            ..self
+        }
+    }
+
+    pub fn with_args(self, args: &[&str]) -> Self {
+        Self {
+            args: args.iter().map(|&arg| arg.to_owned()).collect(),
+            ..self
+        }
+    }
+
+    pub fn with_max_stdout_len(self, max_stdout_len: usize) -> Self {
+        Self {
+            max_stdout_len: Some(max_stdout_len),
+            ..self
+        }
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated.load(Ordering::Relaxed)
+    }
+
+    /// Appends to `stdout` as much of `s` as fits in `max_stdout_len`,
+    /// cutting at a character boundary.
+    ///
+    /// Once something is dropped, later output is dropped as well,
+    /// so that `stdout` is always a prefix of the whole output.
+    fn write(&self, s: &str) {
+        if self.is_truncated() {
+            return;
+        }
+        let mut stdout = self.stdout.lock().unwrap();
+        let Some(max_stdout_len) = self.max_stdout_len else {
+            stdout.push_str(s);
+            return;
+        };
+        let room = max_stdout_len.saturating_sub(stdout.len());
+        if s.len() <= room {
+            stdout.push_str(s);
+            return;
+        }
+        let end = (0..=room).rev().find(|&i| s.is_char_boundary(i)).unwrap();
+        stdout.push_str(&s[..end]);
+        self.truncated.store(true, Ordering::Relaxed);
+    }
 }
 
 impl RtCtx for MockRtCtx {
     fn puts(&self, s: &str) {
-        let mut stdout = self.stdout.lock().unwrap();
-        stdout.push_str(s);
-        stdout.push('\n');
+        self.write(s);
+        self.write("\n");
+    }
+    fn putc(&self, c: char) {
+        self.write(c.encode_utf8(&mut [0; 4]));
+    }
+    fn gets(&self) -> Option<String> {
+        let mut stdin = self.stdin.lock().unwrap();
+        if stdin.is_empty() {
+            return None;
+        }
+        let line_len = stdin.find('\n').map_or(stdin.len(), |i| i + 1);
+        let mut line = stdin.drain(..line_len).collect::<String>();
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        Some(line)
+    }
+    fn term_width(&self) -> Option<usize> {
+        self.term_width
+    }
+    fn args(&self) -> Vec<String> {
+        self.args.clone()
+    }
+}
+
+/// Runs the program at `source_path` in-process and returns what it printed.
+///
+/// Meant for golden tests that would otherwise spawn `cargo run`.
+pub fn run_to_stdout(source_path: impl AsRef<Path>) -> Result<String, RunError> {
+    let ctx = MockRtCtx::new();
+    crate::run(&ctx, source_path.as_ref())?;
+    let stdout = ctx.stdout.lock().unwrap().clone();
+    Ok(stdout)
+}
+
+/// SIR of the following program, shared by the tests of several passes.
+///
+/// ```text
+/// let fib = fn(n) {
+///     if n < 2 {
+///         n
+///     } else {
+///         fib(n - 1) + fib(n - 2)
+///     }
+/// };
+/// puti(fib(10));
+/// ```
+#[cfg(test)]
+pub(crate) fn fib_program() -> crate::sir::ProgramUnit {
+    use crate::sir::{BasicBlock, BuiltinKind, Function, Inst, ProgramUnit};
+
+    ProgramUnit::describe(|[entry, fib]| {
+        vec![
+            (
+                entry,
+                Function::simple(0, |[tmp1, fib1, tmp2, tmp3, puti1, tmp4]| {
+                    BasicBlock::new(vec![
+                        // tmp1 = 10;
+                        Inst::literal(tmp1, 10),
+                        // tmp2 = fib(tmp1);
+                        Inst::closure(fib1, fib),
+                        Inst::push_arg(tmp1),
+                        Inst::call(tmp2, fib1),
+                        // puti(tmp2);
+                        Inst::builtin(puti1, BuiltinKind::Puti),
+                        Inst::push_arg(tmp2),
+                        Inst::call(tmp4, puti1),
+                        // return;
+                        Inst::literal(tmp3, ()),
+                        Inst::return_(tmp3),
+                    ])
+                }),
+            ),
+            (
+                fib,
+                Function::describe(
+                    1,
+                    |[n, tmp1, lt1, tmp2, tmp3, tmp4, add1, tmp5, fib1, tmp6, tmp7, tmp8, tmp9],
+                     [entry, branch_then, branch_else]| {
+                        vec![
+                            (
+                                entry,
+                                BasicBlock::new(vec![
+                                    // tmp1 = n < 2;
+                                    Inst::literal(tmp2, 2),
+                                    Inst::builtin(lt1, BuiltinKind::Lt),
+                                    Inst::push_arg(n),
+                                    Inst::push_arg(tmp2),
+                                    Inst::call(tmp1, lt1),
+                                    // if tmp1 { goto branch_then; } else { goto branch_else; };
+                                    Inst::branch(tmp1, branch_then, branch_else),
+                                ]),
+                            ),
+                            (
+                                branch_then,
+                                BasicBlock::new(vec![
+                                    // return n;
+                                    Inst::return_(n),
+                                ]),
+                            ),
+                            (
+                                branch_else,
+                                BasicBlock::new(vec![
+                                    // tmp4 = n - 1;
+                                    Inst::literal(tmp5, -1),
+                                    Inst::builtin(add1, BuiltinKind::Add),
+                                    Inst::push_arg(n),
+                                    Inst::push_arg(tmp5),
+                                    Inst::call(tmp4, add1),
+                                    // tmp6 = fib(tmp4);
+                                    Inst::closure(fib1, fib),
+                                    Inst::push_arg(tmp4),
+                                    Inst::call(tmp6, fib1),
+                                    // tmp7 = n - 2;
+                                    Inst::literal(tmp8, -2),
+                                    Inst::builtin(add1, BuiltinKind::Add),
+                                    Inst::push_arg(n),
+                                    Inst::push_arg(tmp8),
+                                    Inst::call(tmp7, add1),
+                                    // tmp9 = fib(tmp7);
+                                    Inst::closure(fib1, fib),
+                                    Inst::push_arg(tmp7),
+                                    Inst::call(tmp9, fib1),
+                                    // tmp3 = tmp6 + tmp9;
+                                    Inst::builtin(add1, BuiltinKind::Add),
+                                    Inst::push_arg(tmp6),
+                                    Inst::push_arg(tmp9),
+                                    Inst::call(tmp3, add1),
+                                    // return tmp3;
+                                    Inst::return_(tmp3),
+                                ]),
+                            ),
+                        ]
+                    },
+                ),
+            ),
+        ]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gets_until_end() {
+        let ctx = MockRtCtx::new().with_stdin("a\n\nb");
+        assert_eq!(ctx.gets(), Some("a".to_owned()));
+        // An empty line is distinct from the end of input
+        assert_eq!(ctx.gets(), Some("".to_owned()));
+        // The last line needs no terminator
+        assert_eq!(ctx.gets(), Some("b".to_owned()));
+        assert_eq!(ctx.gets(), None);
+        assert_eq!(ctx.gets(), None);
+    }
+
+    #[test]
+    fn test_max_stdout_len() {
+        let ctx = MockRtCtx::new().with_max_stdout_len(8);
+        ctx.puts("abc");
+        assert!(!ctx.is_truncated());
+        // 4 bytes left, and `\u{3042}` takes 3 of them
+        ctx.puts("\u{3042}\u{3044}");
+        ctx.putc('x');
+        ctx.puts("more");
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "abc\n\u{3042}");
+        assert!(ctx.is_truncated());
+    }
+
+    #[test]
+    fn test_run_with_max_stdout_len() {
+        let source = r#"use lang::"0.0.1";
+let i = 0;
+while i < 1000 {
+  puts("spam");
+  i = i + 1;
+};
+"#;
+        let ctx = MockRtCtx::new().with_max_stdout_len(12);
+        crate::run_source(&ctx, source).unwrap();
+        assert_eq!(ctx.stdout.lock().unwrap().as_str(), "spam\nspam\nsp");
+        assert!(ctx.is_truncated());
     }
 }
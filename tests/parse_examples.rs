@@ -0,0 +1,23 @@
+use std::fs;
+use std::path::Path;
+
+use umo::CheckError;
+
+#[test]
+fn test_parse_examples() {
+    let mut source_paths = fs::read_dir("examples")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "umo"))
+        .collect::<Vec<_>>();
+    source_paths.sort();
+    assert!(!source_paths.is_empty());
+    for source_path in &source_paths {
+        // Only syntax errors are of interest here
+        if let Err(e @ (CheckError::Parse { .. } | CheckError::NestingTooDeep { .. })) =
+            umo::check(Path::new(source_path))
+        {
+            panic!("{}: {}", source_path.display(), e);
+        }
+    }
+}
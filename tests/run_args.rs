@@ -0,0 +1,21 @@
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn test_run_args_after_double_dash() {
+    let source_path = std::env::temp_dir().join("umo_test_run_args.umo");
+    fs::write(
+        &source_path,
+        "use lang::\"0.0.1\";\nlet args = args();\nputi(len(args));\nputs(args[0]);\n",
+    )
+    .unwrap();
+    let output = Command::new("cargo")
+        .args(["run", "--"])
+        .arg(&source_path)
+        .args(["--", "-x", "y"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // The separator itself is not passed to the program
+    assert_eq!(output.stdout, b"2\n-x\n");
+}
@@ -0,0 +1,63 @@
+use std::fs;
+use std::process::Command;
+
+fn run_check(source_path: &str) -> std::process::Output {
+    run_check_with_flags(source_path, &[])
+}
+
+fn run_check_with_flags(source_path: &str, flags: &[&str]) -> std::process::Output {
+    Command::new("cargo")
+        .args(&["run", "--", "--check"])
+        .args(flags)
+        .arg(source_path)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_check_valid() {
+    let output = run_check("examples/sum.umo");
+    assert!(output.status.success());
+    // The program is not run
+    assert_eq!(output.stdout, b"");
+}
+
+#[test]
+fn test_check_ill_typed() {
+    let source_path = std::env::temp_dir().join("umo_test_check_ill_typed.umo");
+    fs::write(&source_path, "use lang::\"0.0.1\";\nputi(\"three\");\n").unwrap();
+    let output = run_check(source_path.to_str().unwrap());
+    assert!(!output.status.success());
+    assert_eq!(output.stdout, b"");
+}
+
+#[test]
+fn test_check_deny_warnings() {
+    let source_path = std::env::temp_dir().join("umo_test_check_deny_warnings.umo");
+    fs::write(
+        &source_path,
+        "use lang::\"0.0.1\";\nlet puti = 1;\nputs(repeat(\"-\", puti));\n",
+    )
+    .unwrap();
+    let source_path = source_path.to_str().unwrap();
+    // Shadowing a builtin is only a warning
+    assert!(run_check(source_path).status.success());
+    let output = run_check_with_flags(source_path, &["--deny-warnings"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 warning(s) treated as errors"));
+}
+
+#[test]
+fn test_emit_types_parse_error() {
+    let source_path = std::env::temp_dir().join("umo_test_emit_types_parse_error.umo");
+    fs::write(&source_path, "use lang::\"0.0.1\";\nlet x = 1 +;\n").unwrap();
+    let output = Command::new("cargo")
+        .args(["run", "--", "--emit=types"])
+        .arg(&source_path)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("error: parse error at 2:12"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
@@ -0,0 +1,7 @@
+use umo::testing::run_to_stdout;
+
+#[test]
+fn test_run_grade() {
+    let stdout = run_to_stdout("examples/grade.umo").unwrap();
+    assert_eq!(stdout, "B\nF\nA\n");
+}
@@ -0,0 +1,11 @@
+#[test]
+fn test_run_list() {
+    // Run a new process for cargo run examples/list.umo
+    let output = std::process::Command::new("cargo")
+        .args(["run", "examples/list.umo"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"6\n");
+}